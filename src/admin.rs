@@ -0,0 +1,265 @@
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+
+use crate::com::{config::ClusterConfig, AsError};
+
+// CLUSTERS is the registry of the running clusters which can be operated through the admin endpoints.
+static CLUSTERS: OnceLock<RwLock<HashMap<String, Arc<dyn ClusterAdmin>>>> = OnceLock::new();
+
+// ClusterAdmin is the set of operations a running cluster exposes to the admin endpoints.
+// The clusters run on their own runtimes, so the implementations must not rely on the caller runtime.
+pub(crate) trait ClusterAdmin: Send + Sync {
+    // reconnect establishes new connections to the given backend and only then replaces the ones it
+    // has, so a backend failing to be dialed keeps its connections
+    fn reconnect(&self, addr: &str) -> Reconnect;
+
+    // gc closes the backend connections idle for longer than the given duration, or the configured
+    // backend idle timeout if None, and returns how many are closed. They are reconnected on demand.
+    fn gc(&self, idle: Option<Duration>) -> usize;
+
+    // info returns the summary of the cluster shown by the /clusters endpoint
+    fn info(&self) -> ClusterInfo;
+
+    // inflight returns the number of the commands each backend connection is yet to reply
+    fn inflight(&self) -> Vec<BackendInflight>;
+
+    // ring returns the ketama layout of every backend set of the cluster
+    fn ring(&self) -> Vec<RingInfo>;
+
+    // reload applies the servers of the given config, connecting the added backends and dropping the
+    // removed ones while the connections of the others are kept
+    fn reload(&self, cc: &ClusterConfig) -> Result<(), AsError>;
+
+    // refresh_slots fetches the slots of a redis cluster and applies them at once rather than at the
+    // next periodic refresh, and returns their coverage
+    fn refresh_slots(&self) -> RefreshSlots;
+}
+
+// RefreshSlots is the pending refresh of the slots of a cluster, run on the runtime of the cluster
+pub(crate) type RefreshSlots = Pin<Box<dyn Future<Output = Result<SlotsInfo, AsError>> + Send>>;
+
+// ClusterInfo is the summary of a running cluster for quick human inspection.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct ClusterInfo {
+    pub name: String,
+
+    // commands is the total number of commands forwarded to the backends
+    pub commands: u64,
+
+    // ops_per_sec is the moving average of the forwarded commands per second
+    pub ops_per_sec: f64,
+}
+
+// BackendInflight is the number of the commands pipelined to a backend and not replied yet. A backend
+// with a growing count is blocking the commands queued behind the slow ones.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct BackendInflight {
+    pub pool: String,
+    pub backend: String,
+    pub inflight: usize,
+}
+
+// Reconnect is the pending reconnection of a backend, run on the runtime of the cluster
+pub(crate) type Reconnect = Pin<Box<dyn Future<Output = Result<(), AsError>> + Send>>;
+
+// RingInfo is the ketama layout of a backend set, used to verify the balance after weight changes.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct RingInfo {
+    pub pool: String,
+
+    // nodes is the configured weight and the owned share of the hash space of each backend
+    pub nodes: Vec<RingNode>,
+
+    // points is the virtual nodes of the ring in the ring order
+    pub points: Vec<RingPoint>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct RingNode {
+    pub node: String,
+    pub weight: usize,
+
+    // points is the number of virtual nodes of the backend
+    pub points: usize,
+
+    // share is the fraction of the hash space owned by the backend
+    pub share: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct RingPoint {
+    pub hash: u64,
+    pub node: String,
+}
+
+// SlotsInfo is the coverage of the slots of a redis cluster, used to verify a resharding is applied.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct SlotsInfo {
+    // covered is the number of the slots served by a master, all of them in a healthy cluster
+    pub covered: usize,
+
+    // masters is the slots of each master in the order of their addresses
+    pub masters: Vec<SlotsNode>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct SlotsNode {
+    pub node: String,
+    pub slots: usize,
+
+    // ranges are the inclusive ranges of the slots served by the master
+    pub ranges: Vec<[usize; 2]>,
+}
+
+fn clusters() -> &'static RwLock<HashMap<String, Arc<dyn ClusterAdmin>>> {
+    CLUSTERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+// register adds the cluster to the admin registry, replacing any cluster with the same name
+pub(crate) fn register(name: &str, cluster: Arc<dyn ClusterAdmin>) {
+    clusters()
+        .write()
+        .unwrap()
+        .insert(name.to_string(), cluster);
+}
+
+fn get_cluster(name: &str) -> Option<Arc<dyn ClusterAdmin>> {
+    clusters().read().unwrap().get(name).cloned()
+}
+
+// reload applies the servers of the given config to the running cluster of the same name
+pub(crate) fn reload(cc: &ClusterConfig) -> Result<(), AsError> {
+    match get_cluster(&cc.name) {
+        Some(cluster) => cluster.reload(cc),
+        None => Err(AsError::ClusterStopped(cc.name.clone())),
+    }
+}
+
+// router returns the admin endpoints to be served next to the metrics
+pub(crate) fn router() -> Router {
+    Router::new()
+        .route("/clusters", get(clusters_handler))
+        .route(
+            "/cluster/:name/nodes/:addr/reconnect",
+            post(reconnect_handler),
+        )
+        .route("/cluster/:name/gc", post(gc_handler))
+        .route("/cluster/:name/ring", get(ring_handler))
+        .route("/cluster/:name/inflight", get(inflight_handler))
+        .route("/cluster/:name/refresh-slots", post(refresh_slots_handler))
+}
+
+async fn clusters_handler() -> Json<Vec<ClusterInfo>> {
+    let mut infos: Vec<_> = clusters()
+        .read()
+        .unwrap()
+        .values()
+        .map(|cluster| cluster.info())
+        .collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(infos)
+}
+
+async fn ring_handler(
+    Path(name): Path<String>,
+) -> Result<Json<Vec<RingInfo>>, (StatusCode, String)> {
+    match get_cluster(&name) {
+        Some(cluster) => Ok(Json(cluster.ring())),
+        None => Err((StatusCode::NOT_FOUND, format!("cluster {} not found", name))),
+    }
+}
+
+async fn inflight_handler(
+    Path(name): Path<String>,
+) -> Result<Json<Vec<BackendInflight>>, (StatusCode, String)> {
+    match get_cluster(&name) {
+        Some(cluster) => Ok(Json(cluster.inflight())),
+        None => Err((StatusCode::NOT_FOUND, format!("cluster {} not found", name))),
+    }
+}
+
+async fn refresh_slots_handler(
+    Path(name): Path<String>,
+) -> Result<Json<SlotsInfo>, (StatusCode, String)> {
+    let cluster = match get_cluster(&name) {
+        Some(cluster) => cluster,
+        None => return Err((StatusCode::NOT_FOUND, format!("cluster {} not found", name))),
+    };
+
+    match cluster.refresh_slots().await {
+        Ok(slots) => {
+            info!(
+                "admin refreshed the slots of cluster {}, {} of them are covered",
+                name, slots.covered
+            );
+            Ok(Json(slots))
+        }
+        Err(err @ AsError::NotSlotted(_)) => Err((StatusCode::BAD_REQUEST, err.to_string())),
+        Err(err) => {
+            warn!(
+                "admin failed to refresh the slots of cluster {} due to {}",
+                name, err
+            );
+            Err((StatusCode::BAD_GATEWAY, err.to_string()))
+        }
+    }
+}
+
+// GcParams is the query of the gc endpoint
+#[derive(Debug, Deserialize)]
+struct GcParams {
+    // idle_ms overrides the backend idle timeout of the cluster
+    idle_ms: Option<u64>,
+}
+
+async fn gc_handler(
+    Path(name): Path<String>,
+    Query(params): Query<GcParams>,
+) -> (StatusCode, String) {
+    let cluster = match get_cluster(&name) {
+        Some(cluster) => cluster,
+        None => return (StatusCode::NOT_FOUND, format!("cluster {} not found", name)),
+    };
+
+    let closed = cluster.gc(params.idle_ms.map(Duration::from_millis));
+    info!(
+        "admin closed {} idle backend connections of cluster {}",
+        closed, name
+    );
+    (StatusCode::OK, closed.to_string())
+}
+
+async fn reconnect_handler(Path((name, addr)): Path<(String, String)>) -> (StatusCode, String) {
+    let cluster = match get_cluster(&name) {
+        Some(cluster) => cluster,
+        None => return (StatusCode::NOT_FOUND, format!("cluster {} not found", name)),
+    };
+
+    match cluster.reconnect(&addr).await {
+        Ok(()) => {
+            info!("admin reconnected backend {} of cluster {}", addr, name);
+            (StatusCode::OK, "OK".to_string())
+        }
+        Err(err @ AsError::UnknownBackend(_)) => (StatusCode::NOT_FOUND, err.to_string()),
+        Err(err) => {
+            warn!(
+                "admin failed to reconnect backend {} of cluster {} due to {}",
+                addr, name, err
+            );
+            (StatusCode::BAD_GATEWAY, err.to_string())
+        }
+    }
+}
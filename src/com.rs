@@ -4,6 +4,7 @@ pub mod meta;
 pub mod config;
 // Path: src/com/config.rs
 
+use config::WarmingReply;
 use std::num;
 use thiserror::Error;
 use toml::de::Error as TOMLError;
@@ -31,15 +32,72 @@ pub enum AsError {
     #[error("WRONGPASS invalid username-password pair or user is disabled.")]
     AuthWrong,
 
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+
     #[error("inline request don't support multi keys")]
     RequestInlineWithMultiKeys,
 
+    #[error("wrong number of arguments for command")]
+    WrongArity,
+
+    #[error("Protocol error: multibulk length exceeds {}", _0)]
+    MultiBulkTooLong(usize),
+
+    #[error("reply bulk of {} bytes exceeds the maximum length", _0)]
+    BulkTooLarge(usize),
+
+    #[error("message body of {} bytes exceeds the maximum length", _0)]
+    BodyTooLarge(usize),
+
+    #[error("key exceeds the maximum length of {} bytes", _0)]
+    KeyTooLong(usize),
+
+    #[error("offset exceeds the maximum of {}", _0)]
+    OffsetTooLarge(u64),
+
+    #[error(
+        "ERR APPEND, GETRANGE, SETRANGE and STRLEN are not supported with the value compression"
+    )]
+    PartialCompressedValue,
+
+    #[error("READONLY You can't write against a read only proxy.")]
+    ReadOnly,
+
     #[error("message reply is bad")]
     BadReply,
 
     #[error("command timeout")]
     CmdTimeout,
 
+    #[error("ERR overloaded, try again")]
+    Overloaded,
+
+    // MSETNX is checked and set by a command per key on its backend, so a key set by another client in
+    // between fails only its own pair while the others stay set
+    #[error("ERR MSETNX is not atomic across the backends, a concurrent writer set some of its keys after they were checked so only the others are set")]
+    MSetNxPartial,
+
+    // a subscribed connection is streamed to its backend, which only takes the pub/sub commands
+    #[error("ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context")]
+    PubSubOnly,
+
+    #[error("ERR max subscriptions {} of the cluster reached", _0)]
+    MaxSubscriptions(usize),
+
+    #[error("ERR max channels {} of the connection reached", _0)]
+    MaxConnChannels(usize),
+
+    // the messages are published to the backend of their channel, so a subscribed connection only takes
+    // the channels of the backend it is streamed to
+    #[error(
+        "CROSSSLOT Channels in request don't hash to the backend of the subscribed connection"
+    )]
+    CrossSlotChannels,
+
+    #[error("proxy timeout must not exceed {} ms", _0)]
+    ProxyTimeoutTooLarge(u64),
+
     #[error("proxy fail")]
     ProxyFail,
 
@@ -67,12 +125,27 @@ pub enum AsError {
     #[error("remote connection has been active closed: {}", _0)]
     BackendClosedError(String),
 
+    #[error("backend {} is not part of the cluster", _0)]
+    UnknownBackend(String),
+
+    #[error("cluster {} has no slots, it is not a redis cluster", _0)]
+    NotSlotted(String),
+
+    #[error("fail to resolve SRV record {}", _0)]
+    SrvLookupError(String),
+
     #[error("fail to redirect command")]
     RedirectFailError,
 
     #[error("fail to init cluster {} due to all seed nodes is die", _0)]
     ClusterAllSeedsDie(String),
 
+    #[error("cluster {} stopped serving", _0)]
+    ClusterStopped(String),
+
+    #[error("{} cluster is connecting to the backends", _0)]
+    ClusterWarming(WarmingReply),
+
     #[error("fail to load config toml error {}", _0)]
     ConfigError(TOMLError), // de error
 
@@ -83,6 +156,59 @@ pub enum AsError {
     None,
 }
 
+impl AsError {
+    // kind returns the variant name of the error, used as the `kind` label of the error counter
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AsError::BadConfig(_) => "BadConfig",
+            AsError::StrParseIntError(_) => "StrParseIntError",
+            AsError::BadMessage => "BadMessage",
+            AsError::BadRequest => "BadRequest",
+            AsError::RequestNotSupport => "RequestNotSupport",
+            AsError::NoAuth => "NoAuth",
+            AsError::AuthWrong => "AuthWrong",
+            AsError::NoProto => "NoProto",
+            AsError::RequestInlineWithMultiKeys => "RequestInlineWithMultiKeys",
+            AsError::WrongArity => "WrongArity",
+            AsError::MultiBulkTooLong(_) => "MultiBulkTooLong",
+            AsError::BulkTooLarge(_) => "BulkTooLarge",
+            AsError::BodyTooLarge(_) => "BodyTooLarge",
+            AsError::KeyTooLong(_) => "KeyTooLong",
+            AsError::OffsetTooLarge(_) => "OffsetTooLarge",
+            AsError::ReadOnly => "ReadOnly",
+            AsError::PartialCompressedValue => "PartialCompressedValue",
+            AsError::BadReply => "BadReply",
+            AsError::CmdTimeout => "CmdTimeout",
+            AsError::Overloaded => "Overloaded",
+            AsError::MSetNxPartial => "MSetNxPartial",
+            AsError::PubSubOnly => "PubSubOnly",
+            AsError::MaxSubscriptions(_) => "MaxSubscriptions",
+            AsError::MaxConnChannels(_) => "MaxConnChannels",
+            AsError::CrossSlotChannels => "CrossSlotChannels",
+            AsError::ProxyTimeoutTooLarge(_) => "ProxyTimeoutTooLarge",
+            AsError::ProxyFail => "ProxyFail",
+            AsError::ConnClosed(_) => "ConnClosed",
+            AsError::RequestReachMaxCycle => "RequestReachMaxCycle",
+            AsError::ParseIntError(_) => "ParseIntError",
+            AsError::WrongClusterSlotsReplyType => "WrongClusterSlotsReplyType",
+            AsError::WrongClusterSlotsReplySlot => "WrongClusterSlotsReplySlot",
+            AsError::ClusterFailDispatch => "ClusterFailDispatch",
+            AsError::IoError(_) => "IoError",
+            AsError::BackendClosedError(_) => "BackendClosedError",
+            AsError::UnknownBackend(_) => "UnknownBackend",
+            AsError::NotSlotted(_) => "NotSlotted",
+            AsError::SrvLookupError(_) => "SrvLookupError",
+            AsError::RedirectFailError => "RedirectFailError",
+            AsError::ClusterAllSeedsDie(_) => "ClusterAllSeedsDie",
+            AsError::ClusterStopped(_) => "ClusterStopped",
+            AsError::ClusterWarming(_) => "ClusterWarming",
+            AsError::ConfigError(_) => "ConfigError",
+            AsError::SystemError => "SystemError",
+            AsError::None => "None",
+        }
+    }
+}
+
 impl PartialEq for AsError {
     fn eq(&self, other: &AsError) -> bool {
         match (self, other) {
@@ -92,24 +218,63 @@ impl PartialEq for AsError {
             (Self::RequestNotSupport, Self::RequestNotSupport) => true,
             (Self::NoAuth, Self::NoAuth) => true,
             (Self::AuthWrong, Self::AuthWrong) => true,
+            (Self::NoProto, Self::NoProto) => true,
             (Self::RequestInlineWithMultiKeys, Self::RequestInlineWithMultiKeys) => true,
+            (Self::WrongArity, Self::WrongArity) => true,
+            (Self::MultiBulkTooLong(inner), Self::MultiBulkTooLong(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::BulkTooLarge(inner), Self::BulkTooLarge(other_inner)) => inner == other_inner,
+            (Self::BodyTooLarge(inner), Self::BodyTooLarge(other_inner)) => inner == other_inner,
+            (Self::KeyTooLong(inner), Self::KeyTooLong(other_inner)) => inner == other_inner,
+            (Self::ProxyTimeoutTooLarge(inner), Self::ProxyTimeoutTooLarge(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::OffsetTooLarge(inner), Self::OffsetTooLarge(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::ReadOnly, Self::ReadOnly) => true,
+            (Self::PartialCompressedValue, Self::PartialCompressedValue) => true,
             (Self::BadReply, Self::BadReply) => true,
             (Self::ProxyFail, Self::ProxyFail) => true,
+            (Self::Overloaded, Self::Overloaded) => true,
+            (Self::MSetNxPartial, Self::MSetNxPartial) => true,
+            (Self::PubSubOnly, Self::PubSubOnly) => true,
+            (Self::MaxSubscriptions(inner), Self::MaxSubscriptions(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::MaxConnChannels(inner), Self::MaxConnChannels(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::CrossSlotChannels, Self::CrossSlotChannels) => true,
             (Self::RequestReachMaxCycle, Self::RequestReachMaxCycle) => true,
             (Self::WrongClusterSlotsReplyType, Self::WrongClusterSlotsReplyType) => true,
             (Self::WrongClusterSlotsReplySlot, Self::WrongClusterSlotsReplySlot) => true,
             (Self::ClusterFailDispatch, Self::ClusterFailDispatch) => true,
             (Self::RedirectFailError, Self::RedirectFailError) => true,
+            (Self::SrvLookupError(inner), Self::SrvLookupError(other_inner)) => {
+                inner == other_inner
+            }
             (Self::ParseIntError(inner), Self::ParseIntError(other_inner)) => inner == other_inner,
             (Self::BackendClosedError(inner), Self::BackendClosedError(other_inner)) => {
                 inner == other_inner
             }
+            (Self::UnknownBackend(inner), Self::UnknownBackend(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::NotSlotted(inner), Self::NotSlotted(other_inner)) => inner == other_inner,
             (Self::StrParseIntError(inner), Self::StrParseIntError(other_inner)) => {
                 inner == other_inner
             }
             (Self::ClusterAllSeedsDie(inner), Self::ClusterAllSeedsDie(other_inner)) => {
                 inner == other_inner
             }
+            (Self::ClusterStopped(inner), Self::ClusterStopped(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::ClusterWarming(inner), Self::ClusterWarming(other_inner)) => {
+                inner == other_inner
+            }
 
             (Self::IoError(inner), Self::IoError(other_inner)) => {
                 inner.kind() == other_inner.kind()
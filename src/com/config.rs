@@ -1,24 +1,63 @@
-use log::{error, info};
+use log::{error, info, warn};
 use serde::Deserialize;
-use socket2::{Domain, Socket, Type};
-use std::collections::{BTreeMap, BTreeSet};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::fs;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        client::Resumption, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
 
 use crate::com::AsError;
+use crate::protocol::CmdType;
 
 const ENV_REPUST_DEFAULT_THREADS: &str = "REPUST_DEFAULT_THREAD";
 const DEFAULT_FETCH_INTERVAL_MS: u64 = 30 * 60 * 1000;
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_BACKEND_IDLE_TIMEOUT_MS: u64 = 60 * 1000;
+const DEFAULT_PREWARM_TIMEOUT_MS: u64 = 5 * 1000;
+const DEFAULT_DRAIN_GRACE_MS: u64 = 5 * 1000;
+const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 30 * 1000;
+const DEFAULT_BACKEND_QUEUE_SIZE: usize = 8 * 1024;
+const DEFAULT_BACKEND_FLUSH_BATCH: usize = 1;
+const DEFAULT_BACKEND_FLUSH_DELAY_US: u64 = 50;
+const DEFAULT_MAX_KEYS_PER_CMD: usize = 10000;
+const DEFAULT_VALUE_COMPRESSION_MIN_BYTES: usize = 1024;
+const DEFAULT_MAX_SHED_FRACTION: f64 = 0.5;
 
-pub const CODE_PORT_IN_USE: i32 = 1;
+// BACKEND_TLS_SESSIONS is the number of the TLS sessions kept to resume the backend connections of a
+// cluster, enough for a session of each of its backends
+const BACKEND_TLS_SESSIONS: usize = 1024;
+
+// SYSTEM_CA_BUNDLES are the usual paths of the CA bundle of the system, the first one found verifies the
+// backends served over TLS if no backend_tls_ca is configured
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    // include is the glob patterns of the files whose clusters are added to the ones of this file,
+    // relative to the directory of this file
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    // shutdown_grace_ms bounds the time the clients are given to be replied the commands they already
+    // sent once SIGTERM or SIGINT is received, the ones left are closed with their commands
+    pub shutdown_grace_ms: Option<u64>,
+
     #[serde(default)]
     pub log: LogConfig,
 
@@ -39,6 +78,10 @@ impl Config {
         None
     }
 
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_millis(self.shutdown_grace_ms.unwrap_or(DEFAULT_SHUTDOWN_GRACE_MS))
+    }
+
     fn servers_map(&self) -> BTreeMap<String, BTreeSet<String>> {
         self.clusters
             .iter()
@@ -56,6 +99,80 @@ impl Config {
     }
 
     pub fn valid(&self) -> Result<(), AsError> {
+        self.metrics.tls()?;
+        let mut names = BTreeSet::new();
+        for cluster in &self.clusters {
+            if !names.insert(cluster.name.as_str()) {
+                return Err(AsError::BadConfig(format!(
+                    "clusters: duplicate name {}",
+                    cluster.name
+                )));
+            }
+            cluster.hash_tag_bytes()?;
+            cluster.backend_source_ip()?;
+            cluster.command_renames()?;
+            cluster.pool_routes()?;
+            for (field, value) in cluster.info_overrides.iter() {
+                let bad = |x: &str| x.contains(['\r', '\n']);
+                if field.is_empty() || field.contains(':') || bad(field) || bad(value) {
+                    return Err(AsError::BadConfig(format!(
+                        "info_overrides of cluster {} has a bad field {}",
+                        cluster.name, field
+                    )));
+                }
+            }
+            cluster.tls_acceptor()?;
+            cluster.backend_tls_connector()?;
+            if let Some(option) = cluster.redis_cluster_unsupported() {
+                return Err(AsError::BadConfig(format!(
+                    "{} of cluster {} is not supported by redis_cluster",
+                    option, cluster.name
+                )));
+            }
+            if let Some(fraction) = cluster.max_shed_fraction {
+                if !(0.0..=1.0).contains(&fraction) {
+                    return Err(AsError::BadConfig(format!(
+                        "max_shed_fraction of cluster {} must be between 0 and 1",
+                        cluster.name
+                    )));
+                }
+            }
+            if matches!(cluster.cache_type, CacheType::MemcacheBinary)
+                && !cluster.auth.is_empty()
+                && !cluster.auth.contains(':')
+            {
+                return Err(AsError::BadConfig(format!(
+                    "auth of cluster {} must be user:password for the SASL authentication",
+                    cluster.name
+                )));
+            }
+            if let Some(fill) = cluster.overload_queue_fill {
+                if !(fill > 0.0 && fill <= 1.0) {
+                    return Err(AsError::BadConfig(format!(
+                        "overload_queue_fill of cluster {} must be above 0 and at most 1",
+                        cluster.name
+                    )));
+                }
+            }
+            if cluster.backend_queue_size() == 0 {
+                return Err(AsError::BadConfig(format!(
+                    "backend_queue_size of cluster {} must be at least 1",
+                    cluster.name
+                )));
+            }
+            if cluster.backend_flush_batch() == 0 {
+                return Err(AsError::BadConfig(format!(
+                    "backend_flush_batch of cluster {} must be at least 1",
+                    cluster.name
+                )));
+            }
+            if cluster.max_keys_per_cmd() == 0 {
+                return Err(AsError::BadConfig(format!(
+                    "max_keys_per_cmd of cluster {} must be at least 1",
+                    cluster.name
+                )));
+            }
+        }
         Ok(())
     }
 
@@ -64,21 +181,59 @@ impl Config {
         info!("load config data {}", data);
 
         let mut cfg: Config = toml::from_str(&data)?;
+        cfg.include_clusters(p.as_ref())?;
         let thread = Config::load_thread_from_env();
         for cluster in &mut cfg.clusters[..] {
             if cluster.thread.is_none() {
                 cluster.thread = Some(thread);
             }
+            cluster.expand_env()?;
         }
+        cfg.valid()?;
         Ok(cfg)
     }
 
+    // include_clusters appends the clusters of the included files in the order of the patterns, the
+    // files matched by the same pattern being taken in the alphabetical order
+    fn include_clusters(&mut self, path: &Path) -> Result<(), AsError> {
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for pattern in &self.include {
+            let pattern = base.join(pattern).to_string_lossy().to_string();
+            let files = glob::glob(&pattern)
+                .map_err(|err| AsError::BadConfig(format!("include:{} {}", pattern, err)))?;
+            for file in files {
+                let file = file.map_err(|err| AsError::IoError(err.into_error()))?;
+                info!("include config file {}", file.display());
+
+                let data = fs::read_to_string(&file)?;
+                let included: ClusterFile = toml::from_str(&data).map_err(|err| {
+                    error!(
+                        "fail to load included config {} due to {}",
+                        file.display(),
+                        err
+                    );
+                    err
+                })?;
+                self.clusters.extend(included.clusters);
+            }
+        }
+        Ok(())
+    }
+
     fn load_thread_from_env() -> usize {
         let thread_str = env::var(ENV_REPUST_DEFAULT_THREADS).unwrap_or_else(|_| "4".to_string());
         thread_str.parse::<usize>().unwrap_or(4)
     }
 }
 
+// ClusterFile is a file included by the main config, it only holds clusters
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ClusterFile {
+    #[serde(default)]
+    clusters: Vec<ClusterConfig>,
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct LogConfig {
     pub level: String,
@@ -86,11 +241,36 @@ pub struct LogConfig {
     pub stdout: bool,
     pub directory: String,
     pub file_name: String,
+
+    // unknown_commands logs and counts the commands of the clients which are not supported by the proxy,
+    // to find the ones the clients need. The verbs past the first 64 distinct ones are counted as "other".
+    #[serde(default)]
+    pub unknown_commands: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct MetricsConfig {
     pub port: usize,
+
+    // tls_cert is the path of the PEM certificate chain to serve the metrics over HTTPS
+    pub tls_cert: Option<String>,
+
+    // tls_key is the path of the PEM private key of the tls_cert
+    pub tls_key: Option<String>,
+}
+
+impl MetricsConfig {
+    // tls returns the certificate and key paths if the metrics must be served over HTTPS.
+    // Both or none of them must be set, plain HTTP being the default.
+    pub fn tls(&self) -> Result<Option<(String, String)>, AsError> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert.clone(), key.clone()))),
+            (None, None) => Ok(None),
+            _ => Err(AsError::BadConfig(
+                "metrics.tls_cert and metrics.tls_key must be set together".to_string(),
+            )),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Copy, Default)]
@@ -109,6 +289,65 @@ pub enum CacheType {
     RedisCluster,
 }
 
+// LoadBalance is the policy picking the backend of each command among the servers of a cluster
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalance {
+    // Ketama routes the keys on the consistent hash ring, so adding or removing a server only moves
+    // the keys of its share
+    #[serde(rename = "ketama")]
+    #[default]
+    Ketama,
+
+    // Modulo routes the keys by their hash modulo the total weight, which moves most of the keys on
+    // any change of the servers
+    #[serde(rename = "modulo")]
+    Modulo,
+
+    // Random, RoundRobin and LeastConnections ignore the keys, so they are only meant for the backends
+    // holding the same data, e.g. replicas or stateless services
+    #[serde(rename = "random")]
+    Random,
+
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+
+    #[serde(rename = "least_connections")]
+    LeastConnections,
+}
+
+// HashMethod is the hash of the keys the backends of a cluster are picked by
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashMethod {
+    // Fnv1a64 is the FNV-1a hash of 64 bits
+    #[serde(rename = "fnv1a64")]
+    #[default]
+    Fnv1a64,
+
+    // Crc16 is the hash of the keys of Redis Cluster, routing alike the keys of the same hash tag there.
+    // Its 16 bits are spread over the 32 bits of the ketama ring.
+    #[serde(rename = "crc16")]
+    Crc16,
+}
+
+// WarmingReply is the retryable error replied to the commands received before any backend is connected
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmingReply {
+    #[serde(rename = "loading")]
+    Loading,
+
+    #[serde(rename = "tryagain")]
+    TryAgain,
+}
+
+impl std::fmt::Display for WarmingReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarmingReply::Loading => write!(f, "LOADING"),
+            WarmingReply::TryAgain => write!(f, "TRYAGAIN"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct ClusterConfig {
     pub name: String,
@@ -123,40 +362,584 @@ pub struct ClusterConfig {
     #[serde(default)]
     pub servers: Vec<String>,
 
+    // canary_servers receive canary_weight percent of the traffic drawn at random, regardless of the
+    // key hash. It breaks the key affinity, so it is only meant for read-only canaries.
+    #[serde(default)]
+    pub canary_servers: Vec<String>,
+    pub canary_weight: Option<u8>,
+    // deterministic_routing is the seed of the random selectors, e.g. the canary split, making the
+    // routing of each connection reproducible. The selectors are seeded randomly if absent.
+    pub deterministic_routing: Option<u64>,
+
+    // migrate_target receives a copy of the write commands while migrating to a new backend set.
+    // Its replies are ignored and the reads are only served by the servers. The copies are dropped rather
+    // than waited on while its queues are full.
+    #[serde(default)]
+    pub migrate_target: Vec<String>,
+
+    // read_servers serves the read commands of the connections which sent READONLY, e.g. a dedicated
+    // replica set for the heavy reads. The writes and the other commands are served by the servers.
+    #[serde(default)]
+    pub read_servers: Vec<String>,
+
+    // pools are the named backend sets serving the command types routed to them by pool_routes, e.g. the
+    // scans by a dedicated replica set, and pool_routes maps the command types, named as the `type` label
+    // of the command metrics, to the pools. The command types not routed are served by the servers.
+    #[serde(default)]
+    pub pools: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub pool_routes: BTreeMap<String, String>,
+
     // cluster special
+    // fetch_interval is the milliseconds between the CLUSTER SLOTS refreshes, 30 minutes by default
     pub fetch_interval: Option<u64>,
+    // read_from_slave serves the read commands of the connections which sent READONLY from the replicas
+    // of the slot, the master by default
     pub read_from_slave: Option<bool>,
 
     // proxy special
     pub ping_fail_limit: Option<u8>,
     pub ping_interval: Option<u64>,
     pub ping_success_interval: Option<u64>,
+    // max_client_timeout bounds the timeout a client is allowed to set with PROXY TIMEOUT in milliseconds
+    pub max_client_timeout: Option<u64>,
+    // max_key_bytes rejects the commands with any key longer than it, unlimited by default
+    pub max_key_bytes: Option<usize>,
+    // max_keys_per_cmd rejects the multi-key commands, e.g. MGET, MSET and DEL, with more keys than it,
+    // 10000 by default
+    pub max_keys_per_cmd: Option<usize>,
+    // max_multibulk_len rejects the requests with larger arrays before parsing their elements,
+    // 1048576 by default
+    pub max_multibulk_len: Option<usize>,
+    // max_reply_bulk_len fails the commands replied with a larger bulk string rather than buffering it,
+    // and closes the connections sending a memcache binary message with a larger body, 536870912 by
+    // default
+    pub max_reply_bulk_len: Option<usize>,
+    // strip_command_prefix is a namespace token the legacy clients put before each command verb, e.g.
+    // "app." for "app.GET key". It is removed before the command is classified and forwarded.
+    pub strip_command_prefix: Option<String>,
+    // rename_commands maps the command verbs to the tokens the clients must use instead, e.g.
+    // { FLUSHALL = "SECRET_FLUSH" }. The renamed verbs are not supported anymore.
+    #[serde(default)]
+    pub rename_commands: HashMap<String, String>,
+    // info_overrides sets the fields of the INFO replies to the given values, e.g.
+    // { redis_version = "6.2.0" } for the clients gating their features on it. An empty value hides
+    // the field.
+    #[serde(default)]
+    pub info_overrides: HashMap<String, String>,
+    // max_setrange_offset rejects the SETRANGE commands with a larger offset, which makes the backend
+    // allocate the whole string up to the offset. Unlimited by default.
+    pub max_setrange_offset: Option<u64>,
+    // max_subscriptions rejects the (P)SUBSCRIBE commands beyond this number of the channels subscribed
+    // by all the clients of the cluster, and max_channels_per_conn beyond this number of the channels
+    // subscribed by a connection. Unlimited by default.
+    pub max_subscriptions: Option<usize>,
+    pub max_channels_per_conn: Option<usize>,
+    // read_only rejects all the write commands, e.g. to serve an analytics replica
+    pub read_only: Option<bool>,
+    // max_accept_rate bounds the new connections accepted per second, the excess waits in the backlog
+    pub max_accept_rate: Option<u32>,
+    // backend_source_addr is the local IP address the backend connections are made from, e.g. to egress
+    // through the data network interface. Chosen by the OS if absent.
+    pub backend_source_addr: Option<String>,
+    // backend_idle_timeout is the time in milliseconds after which an unused backend connection is
+    // closed by the gc admin endpoint
+    pub backend_idle_timeout: Option<u64>,
+    // slow_start_secs is the window in seconds over which the backends added to a running cluster ramp
+    // from a small share of the keys to their configured weight. Disabled if absent or zero.
+    pub slow_start_secs: Option<u64>,
+    // backend_keepalive_ping_secs is the idle time in seconds after which a PING, or a version request for
+    // memcached, is sent on the backend connection so the NATs and the stateful firewalls do not drop it
+    // silently. Disabled if absent or zero.
+    pub backend_keepalive_ping_secs: Option<u64>,
+    // prewarm_connections is the number of the backend connections established before the clients are
+    // accepted, all of them if larger. The clients are accepted right away if absent or zero.
+    pub prewarm_connections: Option<usize>,
+    // prewarm_timeout_ms bounds the wait for the prewarm_connections, the clients are accepted anyway
+    // once it passes. 5000 by default.
+    pub prewarm_timeout_ms: Option<u64>,
+    // drain_grace_ms bounds the time the backend removed from the servers keeps serving its queued
+    // commands, the ones left are failed once it passes. 5000 by default.
+    pub drain_grace_ms: Option<u64>,
+    // backend_queue_size is the number of the commands queued for each backend connection before the
+    // clients wait to forward theirs. 8192 by default.
+    pub backend_queue_size: Option<usize>,
+    // backend_flush_batch is the number of the commands written to a backend connection before they are
+    // flushed to its socket, trading the latency of the commands for fewer writes. 1 by default, which
+    // flushes each command at once.
+    pub backend_flush_batch: Option<usize>,
+    // backend_flush_delay_us bounds the time in microseconds the commands of an incomplete batch wait
+    // to be flushed, so the quiet connections are still flushed promptly. 50 by default.
+    pub backend_flush_delay_us: Option<u64>,
+    // load_balance is the policy picking the backend of each command, ketama by default
+    pub load_balance: Option<LoadBalance>,
+    // hash_method is the hash of the keys, or of their hash_tag if any, the load_balance routes by.
+    // fnv1a64 by default. Changing it moves most of the keys.
+    pub hash_method: Option<HashMethod>,
+    // rehash_double_read_secs is the window in seconds after the servers or the load_balance of a running
+    // cluster change in which the single key reads missing on their new backend are read again from
+    // the one of the ring before the change, so a cache isn't emptied by the rehash. It is only meant
+    // for the cache workloads, as a key deleted or expired on its new backend may be found on its old
+    // one. The backends removed by the change are not read. Disabled if absent or zero.
+    pub rehash_double_read_secs: Option<u64>,
+    // warming_reply is replied to the commands received while the cluster is starting and none of its
+    // backends is connected yet, so the clients retry them. Either "loading" or "tryagain", the
+    // commands are forwarded as usual if absent.
+    pub warming_reply: Option<WarmingReply>,
+    // fail_fast_on_dead_node fails the commands of the backends which can not be connected at once and
+    // keeps reconnecting to them in the background, instead of failing them one at a time until the
+    // connection is established again by the admin endpoints.
+    pub fail_fast_on_dead_node: Option<bool>,
+    // retry_budget_per_sec bounds the commands resent or redirected to the backends per second, unlimited
+    // if absent
+    pub retry_budget_per_sec: Option<u32>,
+    // slo_p99_ms is the p99 latency in milliseconds the cluster aims for. While the p99 of the recent
+    // commands exceeds it, a growing fraction of the new commands, up to max_shed_fraction, is replied
+    // "-ERR overloaded, try again" instead of being forwarded. The connection setup commands, e.g. AUTH,
+    // are never shed. Disabled if absent or zero, and not supported by redis_cluster.
+    pub slo_p99_ms: Option<u64>,
+    // max_shed_fraction bounds the fraction of the commands shed to meet slo_p99_ms, 0.5 by default
+    pub max_shed_fraction: Option<f64>,
+    // overload_queue_fill pauses accepting new clients once the fullest backend queue stays filled above
+    // this fraction of backend_queue_size or the commands are shed, and resumes once the queues drain below
+    // half of it with nothing shed. The clients wait in the listen backlog meanwhile. Disabled if absent.
+    pub overload_queue_fill: Option<f64>,
+    // value_compression compresses the SET and GETSET values of at least value_compression_min_bytes and
+    // decompresses the GET, MGET, GETSET and SET ... GET replies. The values are stored compressed, so all
+    // the access must go through the proxy, and APPEND, GETRANGE, SETRANGE and STRLEN are rejected as
+    // they work on a part of the stored bytes.
+    pub value_compression: Option<bool>,
+    pub value_compression_min_bytes: Option<usize>,
+    // default_ttl_secs is appended as EX to the SET and MSET commands which set no expiry of their own, so
+    // a cache-only cluster can't grow unbounded. It changes the semantics: the keys the clients meant to
+    // keep forever expire, and MSET is sent to the backends as separate SET commands. Disabled if absent.
+    pub default_ttl_secs: Option<u64>,
+    // tls_cert is the path of the PEM certificate chain the clients are served over TLS with, and tls_key
+    // is the path of its PEM private key. Both or none of them must be set, plain TCP being the default.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // backend_tls connects to the backends over TLS, verifying their certificates with the PEM CA bundle
+    // at backend_tls_ca or the one of the system if absent. The nodes and the seeds of a redis_cluster are
+    // connected over TLS too, the unix socket backends are kept plain.
+    pub backend_tls: Option<bool>,
+    pub backend_tls_ca: Option<String>,
+    // node_connections is the number of the connections to each backend of the standalone clusters, taken
+    // in turn by the commands so a single busy backend is not bound by one connection. 1 by default.
+    pub node_connections: Option<usize>,
+
+    // dial_timeout is the time in milliseconds a backend connection, along with its TLS handshake, must be
+    // established within. It defaults to the timeout, which bounds the replies of the backends.
+    pub dial_timeout: Option<u64>,
 
     // dead codes
 
-    // command not support now
-    pub dial_timeout: Option<u64>,
     // dead option: not support other proto
     pub listen_proto: Option<String>,
 
-    // dead option: always 1
-    pub node_connections: Option<usize>,
-
-    // password to connect to node, and for auth for client
+    // password to connect to node, and for auth for client. The memcache_binary backends are authenticated
+    // with SASL PLAIN as user:password instead, and the memcache ones are not as the text protocol has no
+    // authentication.
     pub auth: String,
 }
 
 impl ClusterConfig {
-    pub(crate) fn hash_tag_bytes(&self) -> Vec<u8> {
-        self.hash_tag
-            .as_ref()
-            .map(|x| x.as_bytes().to_vec())
-            .unwrap_or_default()
+    // hash_tag_bytes returns the opening and closing bytes of the hash tag, which can be any two ASCII
+    // characters like {}, [] or $$. It is empty if no hash tag is configured.
+    pub(crate) fn hash_tag_bytes(&self) -> Result<Vec<u8>, AsError> {
+        match self.hash_tag.as_deref() {
+            None | Some("") => Ok(Vec::new()),
+            Some(tag) if tag.len() == 2 && tag.is_ascii() => Ok(tag.as_bytes().to_vec()),
+            Some(tag) => Err(AsError::BadConfig(format!(
+                "hash_tag {} of cluster {} must be exactly two ASCII characters",
+                tag, self.name
+            ))),
+        }
+    }
+
+    // redis_cluster_unsupported returns the first option set on a redis_cluster which it would ignore, as
+    // its keys are routed to the masters of their slots rather than on the rings of the servers
+    fn redis_cluster_unsupported(&self) -> Option<&'static str> {
+        if !matches!(self.cache_type, CacheType::RedisCluster) {
+            return None;
+        }
+        let options = [
+            ("slo_p99_ms", self.slo_p99_ms.is_some()),
+            ("canary_servers", !self.canary_servers.is_empty()),
+            ("migrate_target", !self.migrate_target.is_empty()),
+            ("read_servers", !self.read_servers.is_empty()),
+            ("pools", !self.pools.is_empty()),
+            ("pool_routes", !self.pool_routes.is_empty()),
+            (
+                "node_connections",
+                self.node_connections.is_some_and(|x| x > 1),
+            ),
+            ("value_compression", self.value_compression.unwrap_or(false)),
+            ("hash_method", self.hash_method.is_some()),
+            (
+                "hash_tag",
+                self.hash_tag.as_deref().is_some_and(|x| x != "{}"),
+            ),
+            (
+                "rehash_double_read_secs",
+                self.rehash_double_read_secs.is_some(),
+            ),
+        ];
+        options
+            .into_iter()
+            .find(|(_, set)| *set)
+            .map(|(option, _)| option)
+    }
+
+    // backend_source_ip returns the local address the backend connections are bound to, if configured
+    pub(crate) fn backend_source_ip(&self) -> Result<Option<IpAddr>, AsError> {
+        match self.backend_source_addr.as_deref() {
+            None | Some("") => Ok(None),
+            Some(addr) => addr.parse().map(Some).map_err(|_| {
+                AsError::BadConfig(format!(
+                    "backend_source_addr {} of cluster {} must be an IP address",
+                    addr, self.name
+                ))
+            }),
+        }
+    }
+
+    // tls_acceptor loads the certificate the clients are served over TLS with, None if they are served
+    // over plain TCP
+    pub(crate) fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>, AsError> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(AsError::BadConfig(format!(
+                    "tls_cert and tls_key of cluster {} must be set together",
+                    self.name
+                )))
+            }
+        };
+        let bad_tls = |field: &str, path: &str, err: &dyn std::fmt::Display| {
+            error!(
+                "fail to load {} {} of cluster {} due to {}",
+                field, path, self.name, err
+            );
+            AsError::BadConfig(format!("{} of cluster {}", field, self.name))
+        };
+
+        let certs = rustls_pemfile::certs(&mut fs::read(cert)?.as_slice())
+            .map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| bad_tls("tls_cert", cert, &err))?;
+        let key = rustls_pemfile::private_key(&mut fs::read(key)?.as_slice())
+            .map_err(|err| bad_tls("tls_key", key, &err))?
+            .map(|key| PrivateKey(key.secret_der().to_vec()))
+            .ok_or_else(|| bad_tls("tls_key", key, &"no private key found"))?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| bad_tls("tls_cert", cert, &err))?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    // backend_tls_connector loads the CA certificates the backends are verified with, None if they are
+    // connected over plain TCP
+    pub(crate) fn backend_tls_connector(&self) -> Result<Option<TlsConnector>, AsError> {
+        if !self.backend_tls.unwrap_or(false) {
+            return Ok(None);
+        }
+        let ca = match self.backend_tls_ca.as_deref() {
+            Some(ca) => ca,
+            None => SYSTEM_CA_BUNDLES
+                .iter()
+                .copied()
+                .find(|x| Path::new(x).exists())
+                .ok_or_else(|| {
+                    AsError::BadConfig(format!(
+                        "backend_tls_ca of cluster {} must be set as no system CA bundle is found",
+                        self.name
+                    ))
+                })?,
+        };
+
+        let certs = rustls_pemfile::certs(&mut fs::read(ca)?.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| {
+                error!(
+                    "fail to load backend_tls_ca {} of cluster {} due to {}",
+                    ca, self.name, err
+                );
+                AsError::BadConfig(format!("backend_tls_ca of cluster {}", self.name))
+            })?;
+        let mut roots = RootCertStore::empty();
+        if roots.add_parsable_certificates(&certs).0 == 0 {
+            return Err(AsError::BadConfig(format!(
+                "backend_tls_ca {} of cluster {} has no CA certificate",
+                ca, self.name
+            )));
+        }
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        // the connector is shared by all the backend connections of the cluster, so a reconnect resumes
+        // the session of the previous connection to its backend rather than making a full handshake
+        config.resumption = Resumption::in_memory_sessions(BACKEND_TLS_SESSIONS);
+        Ok(Some(TlsConnector::from(Arc::new(config))))
+    }
+
+    // command_renames returns the upper cased verbs of the renamed commands keyed by the tokens the
+    // clients must use instead
+    pub(crate) fn command_renames(&self) -> Result<HashMap<Vec<u8>, Vec<u8>>, AsError> {
+        let mut renames = HashMap::new();
+        for (verb, token) in self.rename_commands.iter() {
+            if verb.is_empty() || token.is_empty() {
+                return Err(AsError::BadConfig(format!(
+                    "rename_commands of cluster {} must not have empty commands",
+                    self.name
+                )));
+            }
+            let token = token.to_ascii_uppercase().into_bytes();
+            if renames
+                .insert(token, verb.to_ascii_uppercase().into_bytes())
+                .is_some()
+            {
+                return Err(AsError::BadConfig(format!(
+                    "rename_commands of cluster {} renames two commands to {}",
+                    self.name, self.rename_commands[verb]
+                )));
+            }
+        }
+        Ok(renames)
     }
 
     pub(crate) fn fetch_interval_ms(&self) -> u64 {
         self.fetch_interval.unwrap_or(DEFAULT_FETCH_INTERVAL_MS)
     }
+
+    pub(crate) fn read_from_slave(&self) -> bool {
+        self.read_from_slave.unwrap_or(false)
+    }
+
+    // expand_env replaces the ${VAR} references of the string fields with the environment variables
+    fn expand_env(&mut self) -> Result<(), AsError> {
+        self.name = expand_env(&self.name)?;
+        self.listen_addr = expand_env(&self.listen_addr)?;
+        self.auth = expand_env(&self.auth)?;
+        let optional = [
+            &mut self.hash_tag,
+            &mut self.backend_source_addr,
+            &mut self.tls_cert,
+            &mut self.tls_key,
+            &mut self.backend_tls_ca,
+        ];
+        for value in optional.into_iter().flatten() {
+            *value = expand_env(value)?;
+        }
+        for server in &mut self.servers[..] {
+            *server = expand_env(server)?;
+        }
+        for server in &mut self.canary_servers[..] {
+            *server = expand_env(server)?;
+        }
+        for server in &mut self.migrate_target[..] {
+            *server = expand_env(server)?;
+        }
+        for server in &mut self.read_servers[..] {
+            *server = expand_env(server)?;
+        }
+        for server in self.pools.values_mut().flatten() {
+            *server = expand_env(server)?;
+        }
+        Ok(())
+    }
+
+    // pool_routes returns the command types with the name of the pool each is routed to. The types must be
+    // known and the pools configured with their servers.
+    pub(crate) fn pool_routes(&self) -> Result<Vec<(CmdType, &str)>, AsError> {
+        self.pool_routes
+            .iter()
+            .map(|(cmd_type, pool)| {
+                let cmd_type = CmdType::from_name(cmd_type).ok_or_else(|| {
+                    AsError::BadConfig(format!(
+                        "pool_routes of cluster {} has an unknown command type {}",
+                        self.name, cmd_type
+                    ))
+                })?;
+                match self.pools.get(pool) {
+                    Some(servers) if !servers.is_empty() => Ok((cmd_type, pool.as_str())),
+                    _ => Err(AsError::BadConfig(format!(
+                        "pool_routes of cluster {} routes to the pool {} without servers",
+                        self.name, pool
+                    ))),
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn backend_idle_timeout_ms(&self) -> u64 {
+        self.backend_idle_timeout
+            .unwrap_or(DEFAULT_BACKEND_IDLE_TIMEOUT_MS)
+    }
+
+    // prewarm returns the number of the backend connections to wait for before accepting, and how long
+    pub(crate) fn prewarm(&self) -> Option<(usize, Duration)> {
+        let timeout = self
+            .prewarm_timeout_ms
+            .unwrap_or(DEFAULT_PREWARM_TIMEOUT_MS);
+        self.prewarm_connections
+            .filter(|x| *x > 0)
+            .map(|x| (x, Duration::from_millis(timeout)))
+    }
+
+    pub(crate) fn drain_grace(&self) -> Duration {
+        Duration::from_millis(self.drain_grace_ms.unwrap_or(DEFAULT_DRAIN_GRACE_MS))
+    }
+
+    // backend_auth returns the auth the backend connections are authenticated with, empty for the memcache
+    // clusters as the text protocol has no authentication
+    pub(crate) fn backend_auth(&self) -> String {
+        match self.cache_type {
+            CacheType::Memcache => {
+                if !self.auth.is_empty() {
+                    warn!(
+                        "cluster {} ignores auth, use memcache_binary to authenticate with SASL",
+                        self.name
+                    );
+                }
+                String::new()
+            }
+            _ => self.auth.clone(),
+        }
+    }
+
+    pub(crate) fn node_connections(&self) -> usize {
+        self.node_connections.unwrap_or(1).max(1)
+    }
+
+    pub(crate) fn max_keys_per_cmd(&self) -> usize {
+        self.max_keys_per_cmd.unwrap_or(DEFAULT_MAX_KEYS_PER_CMD)
+    }
+
+    pub(crate) fn backend_queue_size(&self) -> usize {
+        self.backend_queue_size
+            .unwrap_or(DEFAULT_BACKEND_QUEUE_SIZE)
+    }
+
+    pub(crate) fn backend_flush_batch(&self) -> usize {
+        self.backend_flush_batch
+            .unwrap_or(DEFAULT_BACKEND_FLUSH_BATCH)
+    }
+
+    pub(crate) fn backend_flush_delay(&self) -> Duration {
+        Duration::from_micros(
+            self.backend_flush_delay_us
+                .unwrap_or(DEFAULT_BACKEND_FLUSH_DELAY_US),
+        )
+    }
+
+    pub(crate) fn load_balance(&self) -> LoadBalance {
+        self.load_balance.unwrap_or_default()
+    }
+
+    pub(crate) fn hash_method(&self) -> HashMethod {
+        self.hash_method.unwrap_or_default()
+    }
+
+    // rehash_double_read returns the window the reads are read again from the ring before a change, if
+    // enabled
+    pub(crate) fn rehash_double_read(&self) -> Option<Duration> {
+        self.rehash_double_read_secs
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs)
+    }
+
+    // slow_start returns the ramp window of the newly added backends, if enabled
+    pub(crate) fn slow_start(&self) -> Option<Duration> {
+        self.slow_start_secs
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs)
+    }
+
+    // backend_keepalive_ping returns the idle time after which a backend connection is pinged, if enabled
+    pub(crate) fn backend_keepalive_ping(&self) -> Option<Duration> {
+        self.backend_keepalive_ping_secs
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs)
+    }
+
+    // slo_shedding returns the p99 latency aimed for and the maximum fraction of the commands shed to meet
+    // it, if enabled
+    pub(crate) fn slo_shedding(&self) -> Option<(Duration, f64)> {
+        let max_fraction = self.max_shed_fraction.unwrap_or(DEFAULT_MAX_SHED_FRACTION);
+        self.slo_p99_ms
+            .filter(|x| *x > 0)
+            .map(|x| (Duration::from_millis(x), max_fraction))
+    }
+
+    pub(crate) fn canary_weight_percent(&self) -> u8 {
+        self.canary_weight.unwrap_or(0).min(100)
+    }
+
+    // value_compression_threshold returns the minimum size of the values to be compressed if enabled
+    pub(crate) fn value_compression_threshold(&self) -> Option<usize> {
+        match self.value_compression.unwrap_or(false) {
+            true => Some(
+                self.value_compression_min_bytes
+                    .unwrap_or(DEFAULT_VALUE_COMPRESSION_MIN_BYTES),
+            ),
+            false => None,
+        }
+    }
+
+    // default_ttl returns the expiry in seconds given to the keys set without one, None if disabled
+    pub(crate) fn default_ttl(&self) -> Option<u64> {
+        self.default_ttl_secs.filter(|x| *x > 0)
+    }
+
+    pub(crate) fn timeout_ms(&self) -> u64 {
+        self.timeout.unwrap_or(DEFAULT_TIMEOUT_MS)
+    }
+
+    // dial_timeout returns the time a backend connection must be established within, the timeout by default
+    pub(crate) fn dial_timeout(&self) -> Duration {
+        Duration::from_millis(self.dial_timeout.unwrap_or_else(|| self.timeout_ms()))
+    }
+
+    // max_client_timeout_ms defaults to the cluster timeout, so clients can only tighten their deadline
+    pub(crate) fn max_client_timeout_ms(&self) -> u64 {
+        self.max_client_timeout.unwrap_or_else(|| self.timeout_ms())
+    }
+}
+
+// expand_env expands each ${VAR} in the value with the value of the VAR environment variable, while $${
+// is kept as a literal ${, e.g. for a password holding it. It fails if the variable is not set or the
+// reference is not closed.
+fn expand_env(value: &str) -> Result<String, AsError> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(begin) = rest.find("${") {
+        if let Some(literal) = rest[..begin].strip_suffix('$') {
+            expanded.push_str(literal);
+            expanded.push_str("${");
+            rest = &rest[begin + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..begin]);
+
+        let end = rest[begin..]
+            .find('}')
+            .ok_or_else(|| AsError::BadConfig(format!("unclosed env reference in {}", value)))?;
+        let name = &rest[begin + 2..begin + end];
+        let var = env::var(name)
+            .map_err(|_| AsError::BadConfig(format!("env {} referenced by {}", name, value)))?;
+        expanded.push_str(&var);
+
+        rest = &rest[begin + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
 }
 
 #[cfg(windows)]
@@ -167,8 +950,8 @@ pub(crate) fn create_reuse_port_listener(addr: SocketAddr) -> Result<TcpListener
     socket
         .set_reuse_address(true)
         .expect("os not support SO_REUSEADDR");
-    socket.bind(&socket2::SockAddr::from(addr));
-    socket.listen(std::i32::MAX);
+    socket.bind(&socket2::SockAddr::from(addr))?;
+    socket.listen(std::i32::MAX)?;
 
     TcpListener::from_std(socket.into())
 }
@@ -187,12 +970,9 @@ pub(crate) fn create_reuse_port_listener(addr: SocketAddr) -> Result<TcpListener
     socket
         .set_reuse_port(true)
         .expect("os not support SO_REUSEADDR");
-    socket
-        .bind(&socket2::SockAddr::from(addr))
-        .expect("socket binding should be ok");
-    socket
-        .listen(std::i32::MAX)
-        .expect("listening to socket should not return error");
+    // the address may be taken by another process, which only fails the cluster listening on it
+    socket.bind(&socket2::SockAddr::from(addr))?;
+    socket.listen(std::i32::MAX)?;
 
     TcpListener::from_std(socket.into())
 }
@@ -231,6 +1011,64 @@ pub fn set_read_write_timeout(
     Ok(stream)
 }
 
+// connect_from connects to the given address from the given local IP address, if any
+pub(crate) async fn connect_from(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+) -> Result<TcpStream, std::io::Error> {
+    let source = match source {
+        Some(source) => source,
+        None => return TcpStream::connect(addr).await,
+    };
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(addr).await
+}
+
+// SocketOptions is the state of the latency related options of a connection as reported by the OS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SocketOptions {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<bool>,
+    pub user_timeout: Option<Duration>,
+}
+
+impl SocketOptions {
+    // read reads back the options actually applied to the socket, None if the OS fails to report one
+    pub(crate) fn read(socket: &TcpStream) -> SocketOptions {
+        let sock = SockRef::from(socket);
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        let user_timeout = sock.tcp_user_timeout().ok().flatten();
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        let user_timeout = None;
+
+        SocketOptions {
+            nodelay: socket.nodelay().ok(),
+            keepalive: sock.keepalive().ok(),
+            user_timeout,
+        }
+    }
+}
+
+impl std::fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |x: Option<bool>| x.map_or("unknown".to_string(), |x| x.to_string());
+        write!(
+            f,
+            "nodelay={} keepalive={} user_timeout={}",
+            flag(self.nodelay),
+            flag(self.keepalive),
+            self.user_timeout
+                .map_or("none".to_string(), |x| format!("{}ms", x.as_millis()))
+        )
+    }
+}
+
 pub(crate) fn get_host_by_name(name: &str) -> Result<SocketAddr, AsError> {
     let mut iter: std::vec::IntoIter<SocketAddr> = name.to_socket_addrs().map_err(|err| {
         error!("fail to resolve addr to {} by {}", name, err);
@@ -243,3 +1081,521 @@ pub(crate) fn get_host_by_name(name: &str) -> Result<SocketAddr, AsError> {
 
     Ok(addr)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand_env_listen_port() {
+        env::set_var("PORT", "7379");
+
+        let path = env::temp_dir().join(format!("repust-expand-env-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[[clusters]]\nname = \"test\"\nlisten_addr = \"0.0.0.0:${PORT}\"\ncache_type = \"redis\"\nservers = [\"127.0.0.1:6379:1\"]\nauth = \"\"\n",
+        )
+        .unwrap();
+        let cfg = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(cfg.unwrap().clusters[0].listen_addr, "0.0.0.0:7379");
+        assert_eq!(expand_env("plain").unwrap(), "plain");
+        assert!(expand_env("0.0.0.0:${REPUST_TEST_UNSET_PORT}").is_err());
+        assert!(expand_env("0.0.0.0:${PORT").is_err());
+        assert_eq!(expand_env("pa$${PORT}ss").unwrap(), "pa${PORT}ss");
+        assert_eq!(expand_env("$${PORT}:${PORT}").unwrap(), "${PORT}:7379");
+        assert_eq!(expand_env("$$${PORT}").unwrap(), "$${PORT}");
+    }
+
+    #[test]
+    fn test_expand_env_all_fields() {
+        env::set_var("REPUST_TEST_BACKEND_HOST", "10.0.0.1");
+        env::set_var("REPUST_TEST_CERTS", "/etc/repust");
+
+        let mut cc = ClusterConfig {
+            servers: vec!["${REPUST_TEST_BACKEND_HOST}:6379:1".to_string()],
+            canary_servers: vec!["${REPUST_TEST_BACKEND_HOST}:6380:1".to_string()],
+            backend_source_addr: Some("${REPUST_TEST_BACKEND_HOST}".to_string()),
+            tls_cert: Some("${REPUST_TEST_CERTS}/proxy.crt".to_string()),
+            tls_key: Some("${REPUST_TEST_CERTS}/proxy.key".to_string()),
+            backend_tls_ca: Some("${REPUST_TEST_CERTS}/ca.crt".to_string()),
+            ..Default::default()
+        };
+        cc.expand_env().unwrap();
+
+        assert_eq!(cc.servers, vec!["10.0.0.1:6379:1"]);
+        assert_eq!(cc.canary_servers, vec!["10.0.0.1:6380:1"]);
+        assert_eq!(cc.backend_source_addr.as_deref(), Some("10.0.0.1"));
+        assert_eq!(cc.tls_cert.as_deref(), Some("/etc/repust/proxy.crt"));
+        assert_eq!(cc.tls_key.as_deref(), Some("/etc/repust/proxy.key"));
+        assert_eq!(cc.backend_tls_ca.as_deref(), Some("/etc/repust/ca.crt"));
+    }
+
+    #[test]
+    fn test_include_cluster_files() {
+        let cluster = |name: &str, port: u16| {
+            format!(
+                "[[clusters]]\nname = \"{}\"\nlisten_addr = \"0.0.0.0:{}\"\ncache_type = \"redis\"\nservers = [\"127.0.0.1:6379:1\"]\nauth = \"\"\n",
+                name, port
+            )
+        };
+        let dir = env::temp_dir().join(format!("repust-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("clusters")).unwrap();
+        let main = dir.join("main.toml");
+        fs::write(
+            &main,
+            format!(
+                "include = [\"clusters/*.toml\"]\n\n{}",
+                cluster("main", 7000)
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("clusters/b.toml"), cluster("b", 7002)).unwrap();
+        fs::write(dir.join("clusters/a.toml"), cluster("a", 7001)).unwrap();
+        fs::write(dir.join("clusters/notes.txt"), "not a config").unwrap();
+
+        let names: Vec<String> = Config::load(&main)
+            .unwrap()
+            .clusters
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(names, vec!["main", "a", "b"]);
+
+        // the included files must not redefine a cluster
+        fs::write(dir.join("clusters/c.toml"), cluster("a", 7003)).unwrap();
+        assert_eq!(
+            Config::load(&main).unwrap_err().to_string(),
+            "config is bad for fields clusters: duplicate name a"
+        );
+
+        // nor hold anything but clusters
+        fs::write(dir.join("clusters/c.toml"), "[metrics]\nport = 2110\n").unwrap();
+        assert_eq!(Config::load(&main).unwrap_err().kind(), "ConfigError");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hash_tag_delimiters() {
+        let cluster = |tag: &str| ClusterConfig {
+            name: "test".to_string(),
+            hash_tag: Some(tag.to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(cluster("{}").hash_tag_bytes().unwrap(), b"{}");
+        assert_eq!(cluster("[]").hash_tag_bytes().unwrap(), b"[]");
+        assert_eq!(cluster("||").hash_tag_bytes().unwrap(), b"||");
+        assert!(cluster("").hash_tag_bytes().unwrap().is_empty());
+        assert!(ClusterConfig::default()
+            .hash_tag_bytes()
+            .unwrap()
+            .is_empty());
+
+        assert!(cluster("{").hash_tag_bytes().is_err());
+        assert!(cluster("{{}}").hash_tag_bytes().is_err());
+        assert!(cluster("§").hash_tag_bytes().is_err());
+        assert!(cluster("§§").hash_tag_bytes().is_err());
+    }
+
+    #[test]
+    fn test_backend_queue_size() {
+        let config = |size: Option<usize>| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                backend_queue_size: size,
+                ..Default::default()
+            }],
+        };
+
+        assert_eq!(config(None).clusters[0].backend_queue_size(), 8192);
+        assert!(config(None).valid().is_ok());
+        assert!(config(Some(1)).valid().is_ok());
+        assert_eq!(
+            config(Some(0)).valid().unwrap_err().to_string(),
+            "config is bad for fields backend_queue_size of cluster test must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_redis_cluster_unsupported_options() {
+        let config = |configure: fn(&mut ClusterConfig)| {
+            let mut cluster = ClusterConfig {
+                name: "test".to_string(),
+                cache_type: CacheType::RedisCluster,
+                hash_tag: Some("{}".to_string()),
+                node_connections: Some(1),
+                ..Default::default()
+            };
+            configure(&mut cluster);
+            Config {
+                include: Vec::new(),
+                shutdown_grace_ms: None,
+                log: LogConfig::default(),
+                metrics: MetricsConfig::default(),
+                clusters: vec![cluster],
+            }
+        };
+        let rejected =
+            |configure: fn(&mut ClusterConfig)| config(configure).valid().unwrap_err().to_string();
+
+        // the defaults of the options are accepted, as they match the routing by slots
+        assert!(config(|_| {}).valid().is_ok());
+        assert_eq!(
+            rejected(|cc| cc.canary_servers = vec!["127.0.0.1:6372:1".to_string()]),
+            "config is bad for fields canary_servers of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.migrate_target = vec!["127.0.0.1:6380:1".to_string()]),
+            "config is bad for fields migrate_target of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.read_servers = vec!["127.0.0.1:6373:1".to_string()]),
+            "config is bad for fields read_servers of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| {
+                cc.pools = BTreeMap::from([("replicas".to_string(), Vec::new())]);
+            }),
+            "config is bad for fields pools of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.node_connections = Some(2)),
+            "config is bad for fields node_connections of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.value_compression = Some(true)),
+            "config is bad for fields value_compression of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.hash_method = Some(HashMethod::Crc16)),
+            "config is bad for fields hash_method of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.hash_tag = Some("[]".to_string())),
+            "config is bad for fields hash_tag of cluster test is not supported by redis_cluster"
+        );
+        assert_eq!(
+            rejected(|cc| cc.rehash_double_read_secs = Some(300)),
+            "config is bad for fields rehash_double_read_secs of cluster test is not supported by redis_cluster"
+        );
+    }
+
+    #[test]
+    fn test_slo_shedding() {
+        let config = |cache_type: CacheType| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                cache_type,
+                slo_p99_ms: Some(20),
+                ..Default::default()
+            }],
+        };
+
+        assert!(config(CacheType::Redis).valid().is_ok());
+        assert_eq!(
+            config(CacheType::RedisCluster)
+                .valid()
+                .unwrap_err()
+                .to_string(),
+            "config is bad for fields slo_p99_ms of cluster test is not supported by redis_cluster"
+        );
+    }
+
+    #[test]
+    fn test_pool_routes() {
+        let config = |routes: &[(&str, &str)]| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                pools: BTreeMap::from([
+                    ("replicas".to_string(), vec!["127.0.0.1:6380:1".to_string()]),
+                    ("empty".to_string(), Vec::new()),
+                ]),
+                pool_routes: routes
+                    .iter()
+                    .map(|(cmd_type, pool)| (cmd_type.to_string(), pool.to_string()))
+                    .collect(),
+                ..Default::default()
+            }],
+        };
+
+        let valid = config(&[("read", "replicas"), ("scan", "replicas")]);
+        assert!(valid.valid().is_ok());
+        assert_eq!(
+            valid.clusters[0].pool_routes().unwrap(),
+            vec![(CmdType::Read, "replicas"), (CmdType::Scan, "replicas")]
+        );
+        assert_eq!(
+            config(&[("reads", "replicas")]).valid().unwrap_err().to_string(),
+            "config is bad for fields pool_routes of cluster test has an unknown command type reads"
+        );
+        for pool in ["empty", "missing"] {
+            assert_eq!(
+                config(&[("read", pool)]).valid().unwrap_err().to_string(),
+                format!(
+                    "config is bad for fields pool_routes of cluster test routes to the pool {} without servers",
+                    pool
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_overload_queue_fill() {
+        let config = |fill: Option<f64>| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                overload_queue_fill: fill,
+                ..Default::default()
+            }],
+        };
+
+        assert!(config(None).valid().is_ok());
+        assert!(config(Some(0.9)).valid().is_ok());
+        assert!(config(Some(1.0)).valid().is_ok());
+        for fill in [0.0, -0.5, 1.5, f64::NAN] {
+            assert_eq!(
+                config(Some(fill)).valid().unwrap_err().to_string(),
+                "config is bad for fields overload_queue_fill of cluster test must be above 0 and at most 1"
+            );
+        }
+    }
+
+    #[test]
+    fn test_backend_flush_batch() {
+        let config = |batch: Option<usize>| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                backend_flush_batch: batch,
+                ..Default::default()
+            }],
+        };
+
+        let cluster = &config(None).clusters[0];
+        assert_eq!(cluster.backend_flush_batch(), 1);
+        assert_eq!(cluster.backend_flush_delay(), Duration::from_micros(50));
+        assert!(config(Some(4)).valid().is_ok());
+        assert_eq!(
+            config(Some(0)).valid().unwrap_err().to_string(),
+            "config is bad for fields backend_flush_batch of cluster test must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_memcache_auth() {
+        let config = |cache_type: CacheType, auth: &str| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                cache_type,
+                auth: auth.to_string(),
+                ..Default::default()
+            }],
+        };
+
+        let binary = config(CacheType::MemcacheBinary, "user:pass");
+        assert!(binary.valid().is_ok());
+        assert_eq!(binary.clusters[0].backend_auth(), "user:pass");
+        assert!(config(CacheType::MemcacheBinary, "").valid().is_ok());
+        assert_eq!(
+            config(CacheType::MemcacheBinary, "pass")
+                .valid()
+                .unwrap_err()
+                .to_string(),
+            "config is bad for fields auth of cluster test must be user:password for the SASL authentication"
+        );
+
+        // the text protocol has no authentication
+        let text = config(CacheType::Memcache, "pass");
+        assert!(text.valid().is_ok());
+        assert!(text.clusters[0].backend_auth().is_empty());
+        assert_eq!(
+            config(CacheType::Redis, "pass").clusters[0].backend_auth(),
+            "pass"
+        );
+    }
+
+    #[test]
+    fn test_info_overrides() {
+        let config = |field: &str, value: &str| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                info_overrides: HashMap::from([(field.to_string(), value.to_string())]),
+                ..Default::default()
+            }],
+        };
+
+        assert!(config("redis_version", "6.2.0").valid().is_ok());
+        assert!(config("os", "").valid().is_ok());
+        assert_eq!(
+            config("os:Linux", "").valid().unwrap_err().to_string(),
+            "config is bad for fields info_overrides of cluster test has a bad field os:Linux"
+        );
+        assert!(config("", "6.2.0").valid().is_err());
+        assert!(config("redis_version", "6.2.0\r\nos:Linux")
+            .valid()
+            .is_err());
+    }
+
+    #[test]
+    fn test_max_keys_per_cmd() {
+        let config = |max: Option<usize>| Config {
+            include: Vec::new(),
+            shutdown_grace_ms: None,
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            clusters: vec![ClusterConfig {
+                name: "test".to_string(),
+                max_keys_per_cmd: max,
+                ..Default::default()
+            }],
+        };
+
+        assert_eq!(config(None).clusters[0].max_keys_per_cmd(), 10000);
+        assert!(config(Some(1)).valid().is_ok());
+        assert_eq!(
+            config(Some(0)).valid().unwrap_err().to_string(),
+            "config is bad for fields max_keys_per_cmd of cluster test must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_command_renames() {
+        let cluster = |renames: &[(&str, &str)]| ClusterConfig {
+            name: "test".to_string(),
+            rename_commands: renames
+                .iter()
+                .map(|(verb, token)| (verb.to_string(), token.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            cluster(&[("flushall", "Secret_Flush")])
+                .command_renames()
+                .unwrap(),
+            HashMap::from([(b"SECRET_FLUSH".to_vec(), b"FLUSHALL".to_vec())])
+        );
+        assert!(cluster(&[]).command_renames().unwrap().is_empty());
+        assert!(cluster(&[("FLUSHALL", "")]).command_renames().is_err());
+        assert!(cluster(&[("FLUSHALL", "HIDDEN"), ("FLUSHDB", "hidden")])
+            .command_renames()
+            .is_err());
+    }
+
+    #[test]
+    fn test_metrics_tls_paths() {
+        let metrics = |cert: Option<&str>, key: Option<&str>| MetricsConfig {
+            port: 2110,
+            tls_cert: cert.map(str::to_string),
+            tls_key: key.map(str::to_string),
+        };
+
+        assert_eq!(metrics(None, None).tls().unwrap(), None);
+        assert_eq!(
+            metrics(Some("cert.pem"), Some("key.pem")).tls().unwrap(),
+            Some(("cert.pem".to_string(), "key.pem".to_string()))
+        );
+        assert!(metrics(Some("cert.pem"), None).tls().is_err());
+        assert!(metrics(None, Some("key.pem")).tls().is_err());
+    }
+
+    #[test]
+    fn test_client_tls_paths() {
+        let cluster = |cert: Option<&str>, key: Option<&str>| ClusterConfig {
+            name: "test".to_string(),
+            tls_cert: cert.map(str::to_string),
+            tls_key: key.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert!(cluster(None, None).tls_acceptor().unwrap().is_none());
+        assert_eq!(
+            cluster(Some("cert.pem"), None)
+                .tls_acceptor()
+                .err()
+                .unwrap()
+                .to_string(),
+            "config is bad for fields tls_cert and tls_key of cluster test must be set together"
+        );
+        assert!(cluster(None, Some("key.pem")).tls_acceptor().is_err());
+        assert!(
+            cluster(Some("/nonexistent/cert.pem"), Some("/nonexistent/key.pem"))
+                .tls_acceptor()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_backend_tls_ca() {
+        let cluster = |cache_type: CacheType, ca: Option<&str>| ClusterConfig {
+            name: "test".to_string(),
+            cache_type,
+            backend_tls: Some(true),
+            backend_tls_ca: ca.map(str::to_string),
+            ..Default::default()
+        };
+
+        let plain = ClusterConfig {
+            backend_tls_ca: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(plain.backend_tls_connector().unwrap().is_none());
+        assert!(
+            cluster(CacheType::RedisCluster, Some("/nonexistent/ca.pem"))
+                .backend_tls_connector()
+                .is_err()
+        );
+        assert!(cluster(CacheType::Redis, Some("/nonexistent/ca.pem"))
+            .backend_tls_connector()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        socket.set_nodelay(true).unwrap();
+        let options = SocketOptions::read(&socket);
+        assert_eq!(options.nodelay, Some(true));
+        assert_eq!(options.keepalive, Some(false));
+
+        socket.set_nodelay(false).unwrap();
+        assert_eq!(SocketOptions::read(&socket).nodelay, Some(false));
+        assert!(options
+            .to_string()
+            .starts_with("nodelay=true keepalive=false"));
+    }
+}
@@ -13,29 +13,42 @@ mod com;
 mod metrics;
 // Path: src/metrics.rs
 
-use log::{error, info};
+mod admin;
+// Path: src/admin.rs
+
+use log::{error, info, warn};
 use prometheus::Registry;
+use std::time::Duration;
 use tokio::{runtime::Builder, task::JoinHandle};
 
 use crate::{
-    com::{config::ClusterConfig, meta::load_meta},
+    com::{config::ClusterConfig, meta::load_meta, AsError},
     metrics::init as metrics_init,
     protocol::mc::init_memcached_text_finder,
 };
 
 pub use crate::com::config::{CacheType, Config};
 pub use crate::metrics::{
-    init_instruments as init_metrics_instruments, thread_incr as metrics_thread_incr,
-    thread_incr_by as metrics_thread_incr_by,
+    config_reload_incr as metrics_config_reload_incr, init_instruments as init_metrics_instruments,
+    thread_incr as metrics_thread_incr, thread_incr_by as metrics_thread_incr_by,
 };
 use crate::protocol::redis::init_redis_supported_cmds;
-pub use crate::proxy::standalone::spawn;
+pub use crate::protocol::redis::init_redis_unknown_cmds_log;
+pub use crate::proxy::{standalone::spawn, Shutdown};
 
 const DEFAULT_THREAD_COUNT: usize = 4;
 
-pub fn spawn_worker<T>(cc: &ClusterConfig, spawn_fn: T)
+// CLUSTER_MAX_RESTARTS is the number of times a stopped cluster is restarted before giving up on it
+const CLUSTER_MAX_RESTARTS: u32 = 5;
+
+// CLUSTER_RESTART_DELAY is the time to wait before restarting a stopped cluster
+const CLUSTER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// spawn_worker runs the cluster on its own runtime until it fails to start, can not be kept serving or is
+// shut down
+pub fn spawn_worker<T>(cc: &ClusterConfig, spawn_fn: T, shutdown: Shutdown) -> Result<(), AsError>
 where
-    T: Fn(ClusterConfig) -> JoinHandle<()> + Copy + Send + 'static,
+    T: Fn(ClusterConfig, Shutdown) -> Result<JoinHandle<()>, AsError> + Copy + Send + 'static,
 {
     match cc.cache_type {
         CacheType::Redis | CacheType::RedisCluster => {
@@ -67,12 +80,148 @@ where
 
     metrics_thread_incr_by(cc.thread.unwrap() as u64);
 
+    runtime.block_on(supervise(
+        cc,
+        spawn_fn,
+        shutdown,
+        CLUSTER_MAX_RESTARTS,
+        CLUSTER_RESTART_DELAY,
+    ))
+}
+
+// supervise runs the cluster and restarts it whenever its task stops, e.g. when its accept loop breaks,
+// rather than leaving the process alive without serving. It returns the error once the cluster fails to
+// start or max_restarts are exhausted, and returns once the cluster stops for the shutdown.
+async fn supervise<T>(
+    cc: ClusterConfig,
+    spawn_fn: T,
+    shutdown: Shutdown,
+    max_restarts: u32,
+    restart_delay: Duration,
+) -> Result<(), AsError>
+where
+    T: Fn(ClusterConfig, Shutdown) -> Result<JoinHandle<()>, AsError>,
+{
+    let mut restarts = 0;
+    loop {
+        let stopped = spawn_fn(cc.clone(), shutdown.clone())?.await;
+        if shutdown.deadline().is_some() {
+            info!("cluster {} is stopped for the shutdown", cc.name);
+            return Ok(());
+        }
+        match stopped {
+            Ok(()) => error!("cluster {} stopped serving", cc.name),
+            Err(err) => error!("cluster {} failed due to {}", cc.name, err),
+        }
+
+        if restarts == max_restarts {
+            return Err(AsError::ClusterStopped(cc.name));
+        }
+        restarts += 1;
+        tokio::time::sleep(restart_delay).await;
+        warn!(
+            "restarting cluster {} ({}/{})",
+            cc.name, restarts, max_restarts
+        );
+    }
+}
+
+// wait_signal blocks until SIGTERM or SIGINT is received and starts the shutdown with the given grace.
+// Meanwhile the servers of the running clusters are reloaded from the config file on each SIGHUP.
+pub fn wait_signal(shutdown: Shutdown, grace: Duration, path: String, cfg: Config) {
+    let runtime = Builder::new_current_thread()
+        .thread_name("signal")
+        .enable_all()
+        .build()
+        .unwrap();
+
     runtime.block_on(async move {
-        let _ = spawn_fn(cc).await;
+        let signal = received_signal(&path, cfg).await;
+        info!("received {}, shutting down within {:?}", signal, grace);
+        shutdown.start(grace);
     });
 }
 
-pub fn spawn_metrics(registry: Registry, port: usize) {
+#[cfg(unix)]
+async fn received_signal(path: &str, cfg: Config) -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("listening SIGTERM should not fail");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("listening SIGINT should not fail");
+    let mut hangup = signal(SignalKind::hangup()).expect("listening SIGHUP should not fail");
+    let mut running = cfg.clone();
+    loop {
+        let (_, received, _) = futures::future::select_all([
+            Box::pin(terminate.recv()),
+            Box::pin(interrupt.recv()),
+            Box::pin(hangup.recv()),
+        ])
+        .await;
+        match received {
+            0 => return "SIGTERM",
+            1 => return "SIGINT",
+            _ => running = reload_config(path, &cfg, running),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn received_signal(_path: &str, _cfg: Config) -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+// reload_config loads the config file again and applies the changed servers to the clusters started
+// by the given config, keeping the connections of the unchanged backends. It returns the config to
+// compare the next reload against, the running one if the file fails to load or a cluster fails to
+// apply its servers, so the failed changes are applied again by the next reload. The clusters added,
+// removed or moved to another listen_addr and the other settings take effect on restart.
+fn reload_config(path: &str, started: &Config, running: Config) -> Config {
+    let cfg = match Config::load(path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("fail to reload config {} due to {}", path, err);
+            metrics_config_reload_incr(false);
+            return running;
+        }
+    };
+    if cfg.reload_equals(&running) {
+        info!("config {} is reloaded without server changes", path);
+        metrics_config_reload_incr(true);
+        return cfg;
+    }
+
+    let mut ok = true;
+    for cc in &cfg.clusters {
+        match started.cluster(&cc.name) {
+            None => warn!("cluster {} is added, it is started on restart", cc.name),
+            Some(old) if old.listen_addr != cc.listen_addr => warn!(
+                "cluster {} is moved to {}, it is moved on restart",
+                cc.name, cc.listen_addr
+            ),
+            // the slots of a redis cluster are refreshed from its nodes rather than the seeds
+            Some(_) if matches!(cc.cache_type, CacheType::RedisCluster) => {}
+            Some(_) => {
+                if let Err(err) = admin::reload(cc) {
+                    error!("fail to reload cluster {} due to {}", cc.name, err);
+                    ok = false;
+                }
+            }
+        }
+    }
+    for old in &started.clusters {
+        if cfg.cluster(&old.name).is_none() {
+            warn!("cluster {} is removed, it is stopped on restart", old.name);
+        }
+    }
+    metrics_config_reload_incr(ok);
+    if !ok {
+        return running;
+    }
+    cfg
+}
+
+pub fn spawn_metrics(registry: Registry, port: usize, tls: Option<(String, String)>) {
     let runtime = Builder::new_current_thread()
         .thread_name("metrics")
         .enable_all()
@@ -82,7 +231,7 @@ pub fn spawn_metrics(registry: Registry, port: usize) {
     metrics_thread_incr();
 
     runtime.block_on(async move {
-        match metrics_init(registry, port) {
+        match metrics_init(registry, port, tls).await {
             Ok(jh) => {
                 info!("metrics server started at port {}", port);
                 jh.await.unwrap();
@@ -96,3 +245,87 @@ pub fn spawn_metrics(registry: Registry, port: usize) {
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_supervise_restarts_stopped_cluster() {
+        static STARTS: AtomicUsize = AtomicUsize::new(0);
+
+        // the cluster task ends right away as if its accept loop broke
+        let spawn_fn = |_: ClusterConfig, _: Shutdown| {
+            STARTS.fetch_add(1, Ordering::SeqCst);
+            Ok(tokio::spawn(async {}))
+        };
+        let cc = ClusterConfig {
+            name: "supervised".to_string(),
+            ..Default::default()
+        };
+
+        let result = supervise(
+            cc,
+            spawn_fn,
+            Shutdown::default(),
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(
+            result,
+            Err(AsError::ClusterStopped("supervised".to_string()))
+        );
+        assert_eq!(STARTS.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_reload_config_keeps_running_on_failure() {
+        crate::metrics::init_test_instruments();
+        let config = |server: &str| {
+            format!(
+                "[[clusters]]\nname = \"reload-config-stopped\"\nlisten_addr = \"127.0.0.1:7000\"\ncache_type = \"redis\"\nservers = [\"{}\"]\nauth = \"\"\n",
+                server
+            )
+        };
+        let path = std::env::temp_dir().join(format!("repust-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, config("127.0.0.1:6379:1")).unwrap();
+        let running = Config::load(&path).unwrap();
+
+        // the cluster is not running, so its new servers fail to be applied and are applied again by
+        // the next reload
+        std::fs::write(&path, config("127.0.0.1:6380:1")).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let reloaded = reload_config(&path, &running, running.clone());
+        assert!(reloaded.reload_equals(&running));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_supervise_stops_on_shutdown() {
+        static STARTS: AtomicUsize = AtomicUsize::new(0);
+
+        // the cluster task ends once the shutdown is started
+        let spawn_fn = |_: ClusterConfig, shutdown: Shutdown| {
+            STARTS.fetch_add(1, Ordering::SeqCst);
+            Ok(tokio::spawn(shutdown.started()))
+        };
+        let cc = ClusterConfig {
+            name: "shut-down".to_string(),
+            ..Default::default()
+        };
+        let shutdown = Shutdown::default();
+        let supervised = tokio::spawn(supervise(
+            cc,
+            spawn_fn,
+            shutdown.clone(),
+            3,
+            Duration::from_millis(1),
+        ));
+
+        shutdown.start(Duration::from_secs(1));
+        assert_eq!(supervised.await.unwrap(), Ok(()));
+        assert_eq!(STARTS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,11 +1,18 @@
 use clap::{command, Parser};
 use crossbeam_utils::sync::WaitGroup;
 use librepust::{
-    init_metrics_instruments, metrics_thread_incr, spawn, spawn_metrics, spawn_worker, CacheType,
-    Config,
+    init_metrics_instruments, init_redis_unknown_cmds_log, metrics_thread_incr, spawn,
+    spawn_metrics, spawn_worker, wait_signal, Config, Shutdown,
+};
+use log::{error, info, warn};
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
 };
-use log::{info, warn};
-use std::thread;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug, Clone)]
@@ -62,12 +69,32 @@ fn main() {
     // blocking initiation of metrics instruments as they are needed asynchronously through out the program
     let registry = init_metrics_instruments(args.app_name);
 
+    // the unknown commands are counted, so they are only logged once the metrics are initiated
+    if cfg.log.unknown_commands {
+        init_redis_unknown_cmds_log();
+    }
+
+    let metrics_tls = cfg
+        .metrics
+        .tls()
+        .expect("metrics tls is checked on config load");
     thread::spawn(move || {
-        spawn_metrics(registry, args.metrics_port);
+        spawn_metrics(registry, args.metrics_port, metrics_tls);
         metrics_thread_incr();
     });
 
+    // the clusters stop accepting clients on SIGTERM or SIGINT and exit once their clients are drained,
+    // and their servers are reloaded from the config file on SIGHUP
+    let shutdown = Shutdown::default();
+    let signaled = shutdown.clone();
+    let grace = cfg.shutdown_grace();
+    let (config_file, running) = (args.config_file_addr.clone(), cfg.clone());
+    thread::spawn(move || {
+        wait_signal(signaled, grace, config_file, running);
+    });
+
     let wg = WaitGroup::new();
+    let failed = Arc::new(AtomicUsize::new(0));
     for cluster in cfg.clusters.into_iter() {
         if cluster.servers.is_empty() {
             warn!(
@@ -91,14 +118,16 @@ fn main() {
         );
 
         let wg = wg.clone();
+        let failed = failed.clone();
+        let shutdown = shutdown.clone();
         thread::spawn(move || {
-            match cluster.cache_type {
-                CacheType::Redis | CacheType::Memcache | CacheType::MemcacheBinary => {
-                    spawn_worker(&cluster, spawn);
-                }
-                _ => {
-                    todo!("not support yet");
-                }
+            // the other clusters keep serving when this one fails
+            if let Err(err) = spawn_worker(&cluster, spawn, shutdown) {
+                error!(
+                    "cluster {} in addr {} failed due to {}",
+                    cluster.name, cluster.listen_addr, err
+                );
+                failed.fetch_add(1, Ordering::SeqCst);
             }
             // one parent thread for each cluster
             metrics_thread_incr();
@@ -107,4 +136,11 @@ fn main() {
     }
 
     wg.wait();
+
+    // all the clusters are done, so the failures are surfaced to the orchestrator by the exit code
+    let failed = failed.load(Ordering::SeqCst);
+    if failed > 0 {
+        error!("{} clusters failed, exiting", failed);
+        process::exit(1);
+    }
 }
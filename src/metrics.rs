@@ -4,32 +4,77 @@ pub mod tracker;
 mod measurer;
 // Path: src/metrics/measurer.rs
 
+pub mod throughput;
+// Path: src/metrics/throughput.rs
+
+pub mod shedder;
+// Path: src/metrics/shedder.rs
+
 use axum::extract::State;
 use axum::{routing::get, Router};
+use axum_server::tls_rustls::RustlsConfig;
 use log::{error, info};
 use opentelemetry::metrics::{
     Counter, Histogram, MeterProvider as _, ObservableGauge, UpDownCounter,
 };
 use opentelemetry::KeyValue;
-use opentelemetry_sdk::metrics::MeterProvider;
+use opentelemetry_sdk::metrics::{new_view, Aggregation, Instrument, MeterProvider, Stream};
 use opentelemetry_sdk::Resource;
 use prometheus::{Registry, TextEncoder};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, OnceLock,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::task::JoinHandle;
 
+use crate::admin;
 use crate::com::{config::create_reuse_port_listener, AsError};
 use crate::metrics::measurer::Measurer;
+use crate::protocol::CmdType;
 
 // REPUST_METER_NAME is the name of the meter used to create the global metrics.
 const REPUST_METER_NAME: &str = "global";
 
+// TOTAL_TIMER_BOUNDS are the upper bounds in seconds of the buckets of REPUST_TOTAL_TIMER, from 0.1ms to 10s
+// in 1-2-5 steps, so the latency SLOs of the clusters fall on a bound.
+pub(crate) const TOTAL_TIMER_BOUNDS: [f64; 16] = [
+    0.0001, 0.0002, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0,
+    10.0,
+];
+
+// REGISTRY is the registry the global metrics are exported to, it is used to read back the histograms.
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
 // METER_PROVIDER is the global meter provider, it is used to create the global metrics.
 static METER_PROVIDER: OnceLock<MeterProvider> = OnceLock::new();
 
 // REPUST_CONNECTIONS is a global connection counter, it is used to count the global connections.
 static REPUST_CONNECTIONS: OnceLock<UpDownCounter<i64>> = OnceLock::new();
 
+// REPUST_CLUSTERS_SERVING is a global gauge of the clusters accepting connections, it is used to alert on a
+// cluster whose accept loop stopped while the process is alive.
+static REPUST_CLUSTERS_SERVING: OnceLock<UpDownCounter<i64>> = OnceLock::new();
+
+// REPUST_CLUSTER_OVERLOADED is a global gauge of the clusters which stopped accepting connections because
+// their backends can not keep up, it is one while the cluster is overloaded and zero otherwise.
+static REPUST_CLUSTER_OVERLOADED: OnceLock<UpDownCounter<i64>> = OnceLock::new();
+
+// REPUST_ACCEPT_LOOP_ALIVE is a global gauge of the unix timestamp each cluster accept loop was last seen
+// alive, it is used to alert on a frozen listener whose timestamp stops moving. It is observed on each
+// export from ACCEPT_LOOP_ALIVE_SECS.
+static REPUST_ACCEPT_LOOP_ALIVE: OnceLock<ObservableGauge<f64>> = OnceLock::new();
+
+// ACCEPT_LOOP_ALIVE_SECS is the unix timestamp each cluster accept loop was last seen alive
+static ACCEPT_LOOP_ALIVE_SECS: Mutex<BTreeMap<String, f64>> = Mutex::new(BTreeMap::new());
+
+// REPUST_BACKEND_UP is a global gauge of the established connections to each backend, it is used to alert
+// on a backend which goes dark while the proxy keeps serving the others. It is zero while the backend is
+// unreachable.
+static REPUST_BACKEND_UP: OnceLock<UpDownCounter<i64>> = OnceLock::new();
+
 // REPUST_MEMORY is a global memory usage gauge, it is used to count the global memory usage.
 static REPUST_MEMORY: OnceLock<ObservableGauge<f64>> = OnceLock::new();
 
@@ -48,25 +93,399 @@ static REPUST_TOTAL_TIMER: OnceLock<Histogram<f64>> = OnceLock::new();
 // REPUST_REMOTE_TIMER is a global remote timer histogram, it is used to count the global remote timer.
 static REPUST_REMOTE_TIMER: OnceLock<Histogram<f64>> = OnceLock::new();
 
-// front_conn_incr increments the global connection counter.
-pub fn front_conn_incr() {
-    REPUST_CONNECTIONS
+// REPUST_CONNECTION_COMMANDS is a global histogram of the commands served by each client connection,
+// recorded when the connection is closed.
+static REPUST_CONNECTION_COMMANDS: OnceLock<Histogram<u64>> = OnceLock::new();
+
+// REPUST_CONNECTION_ERRORS is a global histogram of the errors replied to each client connection,
+// recorded when the connection is closed.
+static REPUST_CONNECTION_ERRORS: OnceLock<Histogram<u64>> = OnceLock::new();
+
+// REPUST_CONNECTION_DURATION is a global histogram of the lifetime of the client connections in seconds.
+static REPUST_CONNECTION_DURATION: OnceLock<Histogram<f64>> = OnceLock::new();
+
+// REPUST_COMMANDS is a global command counter, it is used to count the commands forwarded to the backends
+// labeled by their type. The ops/sec is derived from it in Prometheus, e.g. rate(repust_commands_total[1m]).
+static REPUST_COMMANDS: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_BYTES_IN and REPUST_BYTES_OUT are global traffic counters, they are used to count the bytes the
+// proxy reads from and writes to both the clients and the backends, labeled by their cluster.
+static REPUST_BYTES_IN: OnceLock<Counter<u64>> = OnceLock::new();
+static REPUST_BYTES_OUT: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_COMMANDS_REJECTED is a global rejected command counter, it is used to count the commands which
+// are rejected by the proxy policies before reaching any backend.
+static REPUST_COMMANDS_REJECTED: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_FRAMING_ERRORS is a global framing error counter, it is used to count the requests of the clients
+// and the replies of the backends which can not be parsed, apart from the errors replied by the backends.
+static REPUST_FRAMING_ERRORS: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_UNKNOWN_COMMANDS is a global unknown command counter, it is used to count the commands of the
+// clients which are not supported by the proxy, to find the ones the clients need.
+static REPUST_UNKNOWN_COMMANDS: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_BACKEND_BUSY is a global backend busy counter, it is used to count the BUSY replies of the backends
+// which are running a long script.
+static REPUST_BACKEND_BUSY: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_POOL_REPLIES is a global backend reply counter, it is used to compare the replies of the stable
+// and the canary backend sets.
+static REPUST_POOL_REPLIES: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_RETRIES is a global retry counter, it is used to count the commands resent to the backends.
+static REPUST_RETRIES: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_RETRIES_DENIED is a global denied retry counter, it is used to count the commands failed
+// instead of being resent since the retry budget of the cluster was exhausted.
+static REPUST_RETRIES_DENIED: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_AUTH_FAILURES is a global auth failure counter, it is used to count the backend connections
+// whose authentication is rejected by the backend, e.g. a wrong password or a failed SASL exchange.
+static REPUST_AUTH_FAILURES: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_MIRROR_DROPPED is a global mirror counter, it is used to count the write copies dropped instead of
+// being sent to the migration target whose queue is full.
+static REPUST_MIRROR_DROPPED: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_BUGS is a global bug counter, it is used to count the states the proxy must never reach, labeled
+// by the bug, which are handled rather than left to strand the clients.
+static REPUST_BUGS: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_MEASURE_FAILURES is a global measure failure counter, it is used to count the failures to read
+// the process stats, e.g. in the restricted containers where /proc can not be read.
+static REPUST_MEASURE_FAILURES: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_CONFIG_RELOAD is a global config reload counter, it is used to confirm the reloads of the config
+// file are applied, labeled by their result.
+static REPUST_CONFIG_RELOAD: OnceLock<Counter<u64>> = OnceLock::new();
+
+// REPUST_CONFIG_LAST_RELOAD is a global gauge of the unix timestamp of the last successful config reload.
+// It is observed on each export from CONFIG_LAST_RELOAD_SECS.
+static REPUST_CONFIG_LAST_RELOAD: OnceLock<ObservableGauge<u64>> = OnceLock::new();
+
+// CONFIG_LAST_RELOAD_SECS is the unix timestamp of the last successful config reload, zero if none
+static CONFIG_LAST_RELOAD_SECS: AtomicU64 = AtomicU64::new(0);
+
+// RejectReason is the reason of a command being rejected by the proxy, used as the `reason` label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    // the command is unknown or explicitly not supported by the proxy
+    Unsupported,
+
+    // the command is called with the wrong number of arguments
+    Arity,
+
+    // a key of the command is longer than the configured maximum
+    KeyTooLong,
+
+    // the command writes to a read only cluster
+    ReadOnly,
+
+    // the offset of the command is larger than the configured maximum
+    OffsetTooLarge,
+
+    // the client is not authenticated, gave a wrong password or tried to authenticate the shared backend
+    // connections
+    Auth,
+
+    // the command reads or writes a part of a value stored compressed
+    Compressed,
+
+    // the multi-key command has more keys than the configured maximum
+    TooManyKeys,
+}
+
+impl RejectReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RejectReason::Unsupported => "unsupported",
+            RejectReason::Arity => "arity",
+            RejectReason::KeyTooLong => "key_too_long",
+            RejectReason::ReadOnly => "read_only",
+            RejectReason::OffsetTooLarge => "offset_too_large",
+            RejectReason::Auth => "auth",
+            RejectReason::Compressed => "compressed",
+            RejectReason::TooManyKeys => "too_many_keys",
+        }
+    }
+}
+
+// front_conn_incr increments the global connection counter of the given cluster.
+pub fn front_conn_incr(cluster: &str) {
+    REPUST_CONNECTIONS.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("connection_type", "inbound"),
+        ],
+    )
+}
+
+// front_conn_decr decrements the global connection counter of the given cluster.
+pub fn front_conn_decr(cluster: &str) {
+    REPUST_CONNECTIONS.get().unwrap().add(
+        -1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("connection_type", "inbound"),
+        ],
+    )
+}
+
+// cluster_serving_incr marks the given cluster as accepting connections.
+pub fn cluster_serving_incr(cluster: &str) {
+    REPUST_CLUSTERS_SERVING
         .get()
         .unwrap()
-        .add(1, &[KeyValue::new("connection_type", "inbound")])
+        .add(1, &[KeyValue::new("cluster", cluster.to_string())]);
 }
 
-// front_conn_decr decrements the global connection counter.
-pub fn front_conn_decr() {
-    REPUST_CONNECTIONS
+// cluster_serving_decr marks the given cluster as no longer accepting connections.
+pub fn cluster_serving_decr(cluster: &str) {
+    REPUST_CLUSTERS_SERVING
+        .get()
+        .unwrap()
+        .add(-1, &[KeyValue::new("cluster", cluster.to_string())]);
+}
+
+// cluster_overloaded_incr marks the given cluster as overloaded.
+pub fn cluster_overloaded_incr(cluster: &str) {
+    cluster_overloaded_add(cluster, 1);
+}
+
+// cluster_overloaded_decr marks the given cluster as recovered from the overload.
+pub fn cluster_overloaded_decr(cluster: &str) {
+    cluster_overloaded_add(cluster, -1);
+}
+
+// cluster_overloaded_add adds to the overload gauge of the given cluster, a zero reports the cluster as
+// not overloaded before it ever is.
+pub fn cluster_overloaded_add(cluster: &str, delta: i64) {
+    REPUST_CLUSTER_OVERLOADED
+        .get()
+        .unwrap()
+        .add(delta, &[KeyValue::new("cluster", cluster.to_string())]);
+}
+
+// accept_loop_alive marks the accept loop of the given cluster as alive now.
+pub fn accept_loop_alive(cluster: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    ACCEPT_LOOP_ALIVE_SECS
+        .lock()
+        .expect("accept loop alive lock must not be poisoned")
+        .insert(cluster.to_string(), now.as_secs_f64());
+}
+
+// backend_up marks a connection to the given backend of the cluster as established.
+pub fn backend_up(cluster: &str, backend: &str) {
+    REPUST_BACKEND_UP
+        .get()
+        .unwrap()
+        .add(1, &cluster_backend_labels(cluster, backend));
+}
+
+// backend_down marks an established connection to the given backend of the cluster as closed.
+pub fn backend_down(cluster: &str, backend: &str) {
+    REPUST_BACKEND_UP
+        .get()
+        .unwrap()
+        .add(-1, &cluster_backend_labels(cluster, backend));
+}
+
+// backend_unreachable reports the given backend of the cluster failed to connect, so it is exported as
+// down even if it was never up.
+pub fn backend_unreachable(cluster: &str, backend: &str) {
+    REPUST_BACKEND_UP
+        .get()
+        .unwrap()
+        .add(0, &cluster_backend_labels(cluster, backend));
+}
+
+// global_error_incr increments the global error counter labeled by the given cluster and the kind of the
+// given error.
+pub fn global_error_incr(cluster: &str, err: &AsError) {
+    REPUST_GLOBAL_ERROR.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("kind", err.kind()),
+        ],
+    );
+}
+
+// connection_closed records the lifetime, the served commands and the errors of a closed client connection
+// of the given cluster.
+pub fn connection_closed(cluster: &str, duration: Duration, commands: u64, errors: u64) {
+    let labels = [KeyValue::new("cluster", cluster.to_string())];
+    REPUST_CONNECTION_COMMANDS
+        .get()
+        .unwrap()
+        .record(commands, &labels);
+    REPUST_CONNECTION_ERRORS
+        .get()
+        .unwrap()
+        .record(errors, &labels);
+    REPUST_CONNECTION_DURATION
+        .get()
+        .unwrap()
+        .record(duration.as_secs_f64(), &labels);
+}
+
+// command_incr increments the global command counter labeled by the given cluster and command type.
+pub fn command_incr(cluster: &str, cmd_type: CmdType) {
+    REPUST_COMMANDS.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("type", cmd_type.as_str()),
+        ],
+    );
+}
+
+// bytes_in_incr increments the bytes read counter of the given cluster by the given length.
+pub fn bytes_in_incr(cluster: &str, len: usize) {
+    REPUST_BYTES_IN
+        .get()
+        .unwrap()
+        .add(len as u64, &[KeyValue::new("cluster", cluster.to_string())]);
+}
+
+// bytes_out_incr increments the bytes written counter of the given cluster by the given length.
+pub fn bytes_out_incr(cluster: &str, len: usize) {
+    REPUST_BYTES_OUT
+        .get()
+        .unwrap()
+        .add(len as u64, &[KeyValue::new("cluster", cluster.to_string())]);
+}
+
+// command_rejected_incr increments the rejected command counter labeled by the given cluster and reason.
+pub fn command_rejected_incr(cluster: &str, reason: RejectReason) {
+    REPUST_COMMANDS_REJECTED.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("reason", reason.as_str()),
+        ],
+    );
+}
+
+// framing_error_incr increments the framing error counter labeled by the given cluster and the side, front
+// for the requests of the clients and back for the replies of the backends.
+pub fn framing_error_incr(cluster: &str, side: &'static str) {
+    REPUST_FRAMING_ERRORS.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("side", side),
+        ],
+    );
+}
+
+// unknown_command_incr increments the unknown command counter labeled by the given cluster and the verb
+// of the command.
+pub fn unknown_command_incr(cluster: &str, cmd: &str) {
+    REPUST_UNKNOWN_COMMANDS.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("cmd", cmd.to_string()),
+        ],
+    );
+}
+
+// backend_busy_incr increments the backend busy counter labeled by the given cluster and backend address.
+pub fn backend_busy_incr(cluster: &str, backend: &str) {
+    REPUST_BACKEND_BUSY
         .get()
         .unwrap()
-        .add(-1, &[KeyValue::new("connection_type", "inbound")])
+        .add(1, &cluster_backend_labels(cluster, backend));
+}
+
+// pool_reply_incr increments the backend reply counter labeled by the given cluster and backend set.
+pub fn pool_reply_incr(cluster: &str, pool: &'static str) {
+    REPUST_POOL_REPLIES.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("pool", pool),
+        ],
+    );
 }
 
-// global_error_incr increments the global error counter.
-pub fn global_error_incr() {
-    REPUST_GLOBAL_ERROR.get().unwrap().add(1, &[]);
+// retry_incr increments the retry counter labeled by the given cluster and backend address.
+pub fn retry_incr(cluster: &str, backend: &str) {
+    REPUST_RETRIES
+        .get()
+        .unwrap()
+        .add(1, &cluster_backend_labels(cluster, backend));
+}
+
+// retry_denied_incr increments the denied retry counter labeled by the given cluster and backend address.
+pub fn retry_denied_incr(cluster: &str, backend: &str) {
+    REPUST_RETRIES_DENIED
+        .get()
+        .unwrap()
+        .add(1, &cluster_backend_labels(cluster, backend));
+}
+
+// cluster_backend_labels are the labels of the counters of a backend of the given cluster
+fn cluster_backend_labels(cluster: &str, backend: &str) -> [KeyValue; 2] {
+    [
+        KeyValue::new("cluster", cluster.to_string()),
+        KeyValue::new("backend", backend.to_string()),
+    ]
+}
+
+// auth_failure_incr increments the auth failure counter labeled by the given cluster and backend address.
+pub fn auth_failure_incr(cluster: &str, backend: &str) {
+    REPUST_AUTH_FAILURES
+        .get()
+        .unwrap()
+        .add(1, &cluster_backend_labels(cluster, backend));
+}
+
+// mirror_dropped_incr increments the mirror counter labeled by the cluster name.
+pub fn mirror_dropped_incr(cluster: &str) {
+    REPUST_MIRROR_DROPPED
+        .get()
+        .unwrap()
+        .add(1, &[KeyValue::new("cluster", cluster.to_string())]);
+}
+
+// bug_incr increments the bug counter labeled by the given cluster and bug.
+pub fn bug_incr(cluster: &str, bug: &'static str) {
+    REPUST_BUGS.get().unwrap().add(
+        1,
+        &[
+            KeyValue::new("cluster", cluster.to_string()),
+            KeyValue::new("bug", bug),
+        ],
+    );
+}
+
+// measure_failure_incr increments the global measure failure counter.
+pub fn measure_failure_incr() {
+    REPUST_MEASURE_FAILURES.get().unwrap().add(1, &[]);
+}
+
+// config_reload_incr records the result of a config reload. The timestamp is only updated by the
+// successful ones, so it tells when the running config took effect.
+pub fn config_reload_incr(ok: bool) {
+    let result = if ok { "ok" } else { "fail" };
+    REPUST_CONFIG_RELOAD
+        .get()
+        .unwrap()
+        .add(1, &[KeyValue::new("result", result)]);
+
+    if ok {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        CONFIG_LAST_RELOAD_SECS.store(now.as_secs(), Ordering::Relaxed);
+    }
 }
 
 // thread_incr increments the global thread counter.
@@ -89,6 +508,16 @@ fn init_meter_provider(app_name: String, registry: Registry) {
         .set(
             MeterProvider::builder()
                 .with_reader(exporter)
+                .with_view(
+                    new_view(
+                        Instrument::new().name("repust.total_timer"),
+                        Stream::new().aggregation(Aggregation::ExplicitBucketHistogram {
+                            boundaries: TOTAL_TIMER_BOUNDS.to_vec(),
+                            record_min_max: true,
+                        }),
+                    )
+                    .expect("creating view should not fail"),
+                )
                 .with_resource(Resource::new([KeyValue::new("service.name", app_name)]))
                 .build(),
         )
@@ -104,6 +533,9 @@ pub fn init_instruments(app_name: String) -> Registry {
     let registry = prometheus::Registry::new();
 
     init_meter_provider(app_name, registry.clone());
+    REGISTRY
+        .set(registry.clone())
+        .expect("initializing registry should not fail");
     let meter = METER_PROVIDER.get().unwrap().meter(REPUST_METER_NAME);
 
     REPUST_CONNECTIONS
@@ -115,6 +547,52 @@ pub fn init_instruments(app_name: String) -> Registry {
         )
         .expect("initializing metric should not fail");
 
+    REPUST_CLUSTERS_SERVING
+        .set(
+            meter
+                .i64_up_down_counter("repust.clusters_serving")
+                .with_description("clusters accepting connections")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_CLUSTER_OVERLOADED
+        .set(
+            meter
+                .i64_up_down_counter("repust.cluster_overloaded")
+                .with_description("clusters not accepting connections while overloaded")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_ACCEPT_LOOP_ALIVE
+        .set(
+            meter
+                .f64_observable_gauge("repust.accept_loop_alive")
+                .with_description(
+                    "unix timestamp the accept loop of each cluster was last seen alive",
+                )
+                .with_callback(|observer| {
+                    let alive = ACCEPT_LOOP_ALIVE_SECS
+                        .lock()
+                        .expect("accept loop alive lock must not be poisoned");
+                    for (cluster, secs) in alive.iter() {
+                        observer.observe(*secs, &[KeyValue::new("cluster", cluster.clone())]);
+                    }
+                })
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_BACKEND_UP
+        .set(
+            meter
+                .i64_up_down_counter("repust.backend_up")
+                .with_description("established connections to each backend")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
     REPUST_MEMORY
         .set(
             meter
@@ -169,26 +647,307 @@ pub fn init_instruments(app_name: String) -> Registry {
         )
         .expect("initializing metric should not fail");
 
+    REPUST_CONNECTION_COMMANDS
+        .set(
+            meter
+                .u64_histogram("repust.connection_commands")
+                .with_description("commands served by each closed client connection")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_CONNECTION_ERRORS
+        .set(
+            meter
+                .u64_histogram("repust.connection_errors")
+                .with_description("errors replied to each closed client connection")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_CONNECTION_DURATION
+        .set(
+            meter
+                .f64_histogram("repust.connection_duration_seconds")
+                .with_description("lifetime of each closed client connection in seconds")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_COMMANDS
+        .set(
+            meter
+                .u64_counter("repust.commands")
+                .with_description("total commands forwarded to the backends")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_BYTES_IN
+        .set(
+            meter
+                .u64_counter("repust.bytes_in")
+                .with_description("total bytes read from the clients and the backends")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_BYTES_OUT
+        .set(
+            meter
+                .u64_counter("repust.bytes_out")
+                .with_description("total bytes written to the clients and the backends")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_COMMANDS_REJECTED
+        .set(
+            meter
+                .u64_counter("repust.commands_rejected")
+                .with_description("total commands rejected by the proxy policies")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_FRAMING_ERRORS
+        .set(
+            meter
+                .u64_counter("repust.framing_errors")
+                .with_description("total requests and replies which can not be parsed")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_UNKNOWN_COMMANDS
+        .set(
+            meter
+                .u64_counter("repust.unknown_command")
+                .with_description("total commands of the clients which are not supported")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_BACKEND_BUSY
+        .set(
+            meter
+                .u64_counter("repust.backend_busy")
+                .with_description("total BUSY replies received from the backends")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_POOL_REPLIES
+        .set(
+            meter
+                .u64_counter("repust.pool_replies")
+                .with_description("total replies received from the stable and canary backend sets")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_RETRIES
+        .set(
+            meter
+                .u64_counter("repust.retries")
+                .with_description("total commands resent to the backends")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_RETRIES_DENIED
+        .set(
+            meter
+                .u64_counter("repust.retries_denied")
+                .with_description("total retries denied by the exhausted retry budget")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_AUTH_FAILURES
+        .set(
+            meter
+                .u64_counter("repust.auth_failures")
+                .with_description("total backend connections whose authentication is rejected")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_MIRROR_DROPPED
+        .set(
+            meter
+                .u64_counter("repust.mirror_dropped")
+                .with_description(
+                    "total write copies dropped by the full queue of the migration target",
+                )
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_BUGS
+        .set(
+            meter
+                .u64_counter("repust.bugs")
+                .with_description("total states the proxy must never reach")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_MEASURE_FAILURES
+        .set(
+            meter
+                .u64_counter("repust.measure_failures")
+                .with_description("total failures to measure the process stats")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_CONFIG_RELOAD
+        .set(
+            meter
+                .u64_counter("repust.config_reload")
+                .with_description("total config reloads by their result")
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
+    REPUST_CONFIG_LAST_RELOAD
+        .set(
+            meter
+                .u64_observable_gauge("repust.config_last_reload_timestamp")
+                .with_description("unix timestamp of the last successful config reload")
+                .with_callback(|observer| {
+                    observer.observe(CONFIG_LAST_RELOAD_SECS.load(Ordering::Relaxed), &[])
+                })
+                .init(),
+        )
+        .expect("initializing metric should not fail");
+
     registry
 }
 
-// TODO: use each cluster name for in-depth better observability
-pub fn init(registry: Registry, port: usize) -> Result<JoinHandle<()>, AsError> {
+// total_timer_buckets returns the cumulative count of the commands of the cluster within each bound of
+// TOTAL_TIMER_BOUNDS and the count of all of them, as recorded by REPUST_TOTAL_TIMER.
+pub(crate) fn total_timer_buckets(cluster: &str) -> (Vec<u64>, u64) {
+    let mut buckets = vec![0; TOTAL_TIMER_BOUNDS.len()];
+    let mut total = 0;
+    let families = match REGISTRY.get() {
+        Some(registry) => registry.gather(),
+        None => return (buckets, total),
+    };
+    let histograms = families
+        .iter()
+        .filter(|family| family.get_name() == "repust_total_timer")
+        .flat_map(|family| family.get_metric().iter())
+        .filter(|metric| {
+            metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == "cluster" && label.get_value() == cluster)
+        })
+        .map(|metric| metric.get_histogram());
+    for histogram in histograms {
+        total += histogram.get_sample_count();
+        for (count, bucket) in buckets.iter_mut().zip(histogram.get_bucket().iter()) {
+            *count += bucket.get_cumulative_count();
+        }
+    }
+    (buckets, total)
+}
+
+// init_test_instruments initializes the global instruments once for the whole test binary and returns
+// the registry they are exported to.
+#[cfg(test)]
+pub(crate) fn init_test_instruments() -> &'static Registry {
+    static TEST_REGISTRY: OnceLock<Registry> = OnceLock::new();
+    TEST_REGISTRY.get_or_init(|| init_instruments("repust-test".to_string()))
+}
+
+// test_metric_value returns the current value of a counter or gauge sample exported under the given
+// prometheus name, matching all the given labels. It returns 0 if the sample is absent.
+#[cfg(test)]
+pub(crate) fn test_metric_value(name: &str, labels: &[(&str, &str)]) -> f64 {
+    let families = init_test_instruments().gather();
+    families
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .filter(|metric| {
+            labels.iter().all(|(key, value)| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == *key && label.get_value() == *value)
+            })
+        })
+        .map(|metric| {
+            if metric.has_counter() {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        })
+        .sum()
+}
+
+// test_histogram_value returns the sample count and sum of a histogram exported under the given
+// prometheus name with the given labels. They are 0 if the histogram is absent.
+#[cfg(test)]
+pub(crate) fn test_histogram_value(name: &str, labels: &[(&str, &str)]) -> (u64, f64) {
+    init_test_instruments()
+        .gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .filter(|metric| {
+            labels.iter().all(|(key, value)| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == *key && label.get_value() == *value)
+            })
+        })
+        .map(|metric| metric.get_histogram())
+        .fold((0, 0.0), |(count, sum), histogram| {
+            (
+                count + histogram.get_sample_count(),
+                sum + histogram.get_sample_sum(),
+            )
+        })
+}
+
+// init serves the metrics and the admin endpoints over HTTPS if the tls certificate and key paths are
+// given, otherwise over plain HTTP.
+pub async fn init(
+    registry: Registry,
+    port: usize,
+    tls: Option<(String, String)>,
+) -> Result<JoinHandle<()>, AsError> {
     let measurer = Measurer::new(std::time::Duration::from_secs(10))
         .expect("initializing measurer should not fail");
 
     tokio::spawn(measurer);
 
     // TODO: add healthz route in the future
-    let app = Router::new().route("/metrics", get(exporter_handler).with_state(registry));
+    let app = Router::new()
+        .route("/metrics", get(exporter_handler).with_state(registry))
+        .merge(admin::router());
 
     let addr = format!("0.0.0.0:{}", port);
     let socket = addr
         .parse::<SocketAddr>()
         .expect("parse socket address should not fail");
 
-    match create_reuse_port_listener(socket) {
-        Ok(listener) => {
+    let listener = match create_reuse_port_listener(socket) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("fail to create reuse port listener due {}", err);
+            return Err(AsError::SystemError);
+        }
+    };
+
+    match tls {
+        None => {
             info!("listen http metrics port in addr {}", port);
 
             Ok(tokio::spawn(async move {
@@ -197,9 +956,99 @@ pub fn init(registry: Registry, port: usize) -> Result<JoinHandle<()>, AsError>
                     .expect("failed to serve metric on HTTP"); // Await the serve function call
             }))
         }
-        Err(err) => {
-            error!("fail to create reuse port listener due {}", err);
-            Err(AsError::SystemError)
+        Some((cert, key)) => {
+            let config = match RustlsConfig::from_pem_file(&cert, &key).await {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("fail to load metrics tls certificate {} due {}", cert, err);
+                    return Err(AsError::BadConfig("metrics.tls_cert".to_string()));
+                }
+            };
+
+            info!("listen https metrics port in addr {}", port);
+
+            let listener = listener.into_std()?;
+            Ok(tokio::spawn(async move {
+                axum_server::from_tcp_rustls(listener, config)
+                    .serve(app.into_make_service())
+                    .await
+                    .expect("failed to serve metric on HTTPS");
+            }))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, sync::Arc, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{
+        rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    #[tokio::test]
+    async fn test_scrape_over_https() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("repust-metrics-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("repust-metrics-{}.key", std::process::id()));
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let tls = Some((
+            cert_path.to_string_lossy().to_string(),
+            key_path.to_string_lossy().to_string(),
+        ));
+        let served = init(init_test_instruments().clone(), port as usize, tls).await;
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        assert!(served.is_ok());
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        // the server may close without a tls close_notify, the reply is read until then
+        let mut reply = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply)).await;
+        let reply = String::from_utf8_lossy(&reply);
+        assert!(reply.starts_with("HTTP/1.1 200 OK"), "reply {}", reply);
+    }
+
+    #[test]
+    fn test_config_reload_counted() {
+        init_test_instruments();
+        let reloads =
+            |result: &str| test_metric_value("repust_config_reload_total", &[("result", result)]);
+        let (ok, fail) = (reloads("ok"), reloads("fail"));
+
+        config_reload_incr(true);
+        assert_eq!(reloads("ok"), ok + 1.0);
+        assert_eq!(reloads("fail"), fail);
+        assert!(test_metric_value("repust_config_last_reload_timestamp", &[]) > 0.0);
+
+        config_reload_incr(false);
+        assert_eq!(reloads("fail"), fail + 1.0);
+    }
+}
@@ -9,7 +9,7 @@ use sysinfo::{CpuRefreshKind, MemoryRefreshKind, Pid, RefreshKind};
 use tokio::time::{self, Interval};
 
 use crate::com::AsError;
-use crate::metrics::{REPUST_CPU, REPUST_MEMORY};
+use crate::metrics::{measure_failure_incr, REPUST_CPU, REPUST_MEMORY};
 
 pub struct Measurer {
     // pid is the process id of the current repust proxy
@@ -47,7 +47,8 @@ impl Measurer {
 
         // First we update all information of our system struct.
         if !system.refresh_process(self.pid) {
-            return Ok(());
+            warn!("fail to refresh process info of pid {}", self.pid);
+            return Err(AsError::SystemError);
         }
 
         match system.process(self.pid) {
@@ -64,6 +65,16 @@ impl Measurer {
             }
         }
     }
+
+    // measure measures the system metrics and counts the failures, so the persistent ones are visible
+    // in the monitoring rather than only in the logs
+    fn measure(&self) {
+        debug!("measuring system metrics");
+        if let Err(err) = self.measure_system() {
+            warn!("fail to measure system metrics due {}", err);
+            measure_failure_incr();
+        }
+    }
 }
 
 impl Future for Measurer {
@@ -72,10 +83,7 @@ impl Future for Measurer {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         match self.interval.poll_tick(cx) {
             Poll::Ready(_) => {
-                debug!("measuring system metrics");
-                if let Err(err) = self.measure_system() {
-                    warn!("fail to measure system metrics due {}", err);
-                }
+                self.measure();
                 cx.waker().wake_by_ref();
             }
             Poll::Pending => {} // do nothing
@@ -83,3 +91,25 @@ impl Future for Measurer {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{init_test_instruments, test_metric_value};
+
+    #[tokio::test]
+    async fn test_measure_failure_counted() {
+        init_test_instruments();
+        let failures = || test_metric_value("repust_measure_failures_total", &[]);
+        let before = failures();
+
+        // the stats of a process which does not exist can not be read
+        let measurer = Measurer {
+            pid: Pid::from(u32::MAX as usize),
+            interval: time::interval(Duration::from_secs(10)),
+        };
+        measurer.measure();
+        measurer.measure();
+        assert_eq!(failures(), before + 2.0);
+    }
+}
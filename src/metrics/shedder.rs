@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::metrics::{total_timer_buckets, TOTAL_TIMER_BOUNDS};
+
+// SHED_SAMPLE_INTERVAL is the interval at which the latencies of the clusters are sampled and the shed
+// fraction is adjusted
+pub const SHED_SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+// SHED_STEP is the change of the shed fraction at each sample, so the shedding ramps up and down over a
+// few samples rather than flapping with the noise of a single one
+const SHED_STEP: f64 = 0.05;
+
+// SHED_MIN_SAMPLES is the number of the commands a sample needs for its p99 to be trusted. The fraction is
+// kept as is on the quieter samples.
+const SHED_MIN_SAMPLES: u64 = 20;
+
+// Shedder rejects a fraction of the commands of a cluster while the p99 of its recent latencies exceeds
+// the SLO. The latencies are read from the repust.total_timer histogram of the cluster, so the SLO is
+// rounded down to one of its bounds. The fraction is adjusted at each sample, growing by a step while the
+// SLO is missed up to the maximum and shrinking back by a step once it is met again.
+#[derive(Debug)]
+pub struct Shedder {
+    // cluster is the name of the cluster whose latencies are sampled
+    cluster: Arc<str>,
+
+    // slo is the index of the largest bound of TOTAL_TIMER_BOUNDS within the p99 latency the cluster aims
+    // for, None if the SLO is below all of them
+    slo: Option<usize>,
+
+    // max_fraction is the upper bound of the shed fraction
+    max_fraction: f64,
+
+    // sampled is the cumulative count of the commands within the SLO and of all of them at the last sample
+    sampled: Mutex<(u64, u64)>,
+
+    // fraction is the bits of the f64 fraction of the commands to be shed
+    fraction: AtomicU64,
+}
+
+impl Shedder {
+    pub fn new(cluster: Arc<str>, slo: Duration, max_fraction: f64) -> Shedder {
+        let slo = TOTAL_TIMER_BOUNDS
+            .iter()
+            .rposition(|x| *x <= slo.as_secs_f64());
+        let shedder = Shedder {
+            cluster,
+            slo,
+            max_fraction: max_fraction.clamp(0.0, 1.0),
+            sampled: Mutex::new((0, 0)),
+            fraction: AtomicU64::new(0.0f64.to_bits()),
+        };
+        // the commands of a previous cluster of the same name are not sampled
+        *shedder.sampled.lock().unwrap() = shedder.counts();
+        shedder
+    }
+
+    // counts returns the cumulative count of the commands within the SLO and of all of them
+    fn counts(&self) -> (u64, u64) {
+        let (buckets, total) = total_timer_buckets(&self.cluster);
+        let within = self.slo.and_then(|x| buckets.get(x)).copied().unwrap_or(0);
+        (within, total)
+    }
+
+    // sample adjusts the shed fraction by the p99 of the latencies recorded since the last call.
+    // It must be called periodically by a single task.
+    pub fn sample(&self) {
+        let (within, total) = self.counts();
+        let (last_within, last_total) =
+            std::mem::replace(&mut *self.sampled.lock().unwrap(), (within, total));
+        self.adjust(
+            within.saturating_sub(last_within),
+            total.saturating_sub(last_total),
+        );
+    }
+
+    // adjust adjusts the shed fraction by the count of the sampled commands replied within the SLO and of
+    // all of them
+    fn adjust(&self, within: u64, total: u64) {
+        if total < SHED_MIN_SAMPLES {
+            return;
+        }
+
+        // the p99 exceeds the SLO once more than one in a hundred commands do
+        let fraction = if (total - within.min(total)) * 100 > total {
+            (self.fraction() + SHED_STEP).min(self.max_fraction)
+        } else {
+            (self.fraction() - SHED_STEP).max(0.0)
+        };
+        self.fraction.store(fraction.to_bits(), Ordering::Relaxed);
+    }
+
+    // fraction returns the fraction of the commands to be shed, between zero and the maximum
+    pub fn fraction(&self) -> f64 {
+        f64::from_bits(self.fraction.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::tracker::total_tracker;
+
+    #[test]
+    fn test_shed_fraction_follows_slo() {
+        let shedder = Shedder::new("test-shedder".into(), Duration::from_millis(10), 0.3);
+        assert_eq!(shedder.fraction(), 0.0);
+
+        // the fraction grows by a step per sample while the SLO is missed, up to the maximum
+        for step in 1..=10 {
+            shedder.adjust(0, 100);
+            let expected = (SHED_STEP * step as f64).min(0.3);
+            assert!((shedder.fraction() - expected).abs() < 1e-9);
+        }
+
+        // the quiet samples keep the fraction
+        shedder.adjust(1, 1);
+        assert!((shedder.fraction() - 0.3).abs() < 1e-9);
+
+        // a single slow command in a hundred misses the p99 but two do
+        shedder.adjust(99, 100);
+        assert!((shedder.fraction() - 0.25).abs() < 1e-9);
+        shedder.adjust(98, 100);
+        assert!((shedder.fraction() - 0.3).abs() < 1e-9);
+
+        // the fraction shrinks back to zero once the SLO is met
+        for _ in 0..10 {
+            shedder.adjust(100, 100);
+        }
+        assert_eq!(shedder.fraction(), 0.0);
+    }
+
+    #[test]
+    fn test_shedder_samples_total_timer() {
+        crate::metrics::init_test_instruments();
+        let cluster: Arc<str> = "test-shedder-total-timer".into();
+        let shedder = Shedder::new(cluster.clone(), Duration::from_millis(10), 0.3);
+
+        // the commands replied within the SLO keep the fraction at zero
+        for _ in 0..100 {
+            drop(total_tracker(cluster.clone()));
+        }
+        shedder.sample();
+        assert_eq!(shedder.fraction(), 0.0);
+
+        // but the slow ones raise it
+        for _ in 0..100 {
+            let mut tracker = total_tracker(cluster.clone());
+            tracker.start -= Duration::from_millis(50);
+        }
+        shedder.sample();
+        assert!((shedder.fraction() - SHED_STEP).abs() < 1e-9);
+    }
+}
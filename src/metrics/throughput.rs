@@ -0,0 +1,85 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// THROUGHPUT_SAMPLE_INTERVAL is the interval at which the command counter of the clusters is sampled
+pub const THROUGHPUT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// THROUGHPUT_EWMA_ALPHA is the weight of the newest sample in the moving average.
+// With one second samples, it smooths the rate over roughly the last five seconds.
+const THROUGHPUT_EWMA_ALPHA: f64 = 0.2;
+
+// Throughput keeps the count of the commands of a cluster and an exponentially weighted moving average
+// of the commands per second, for quick human inspection without a Prometheus stack.
+#[derive(Debug, Default)]
+pub struct Throughput {
+    // commands is the total number of commands forwarded to the backends
+    commands: AtomicU64,
+
+    // sampled is the value of the commands counter at the last sample
+    sampled: AtomicU64,
+
+    // rate is the bits of the f64 moving average of the commands per second
+    rate: AtomicU64,
+}
+
+impl Throughput {
+    // incr counts a forwarded command
+    pub fn incr(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // commands returns the total number of the forwarded commands
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    // sample folds the commands counted since the last sample into the moving average.
+    // It must be called periodically by a single task, elapsed being the time since the last call.
+    pub fn sample(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+
+        let commands = self.commands();
+        let delta = commands.saturating_sub(self.sampled.swap(commands, Ordering::Relaxed));
+        let current = delta as f64 / secs;
+
+        let rate = THROUGHPUT_EWMA_ALPHA * current + (1.0 - THROUGHPUT_EWMA_ALPHA) * self.rate();
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    // rate returns the moving average of the commands per second
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_under_load() {
+        let throughput = Throughput::default();
+        assert_eq!(throughput.rate(), 0.0);
+
+        for _ in 0..5 {
+            for _ in 0..1000 {
+                throughput.incr();
+            }
+            throughput.sample(THROUGHPUT_SAMPLE_INTERVAL);
+        }
+        assert_eq!(throughput.commands(), 5000);
+        let busy = throughput.rate();
+        assert!(busy > 0.0 && busy <= 1000.0, "rate {}", busy);
+
+        // the rate decays back towards zero once the load stops
+        for _ in 0..5 {
+            throughput.sample(THROUGHPUT_SAMPLE_INTERVAL);
+        }
+        assert!(throughput.rate() < busy);
+    }
+}
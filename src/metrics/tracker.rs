@@ -1,15 +1,20 @@
-use std::time::Instant;
+use opentelemetry::KeyValue;
+use std::{sync::Arc, time::Instant};
 
 use crate::metrics::{REPUST_REMOTE_TIMER, REPUST_TOTAL_TIMER};
 
 pub enum TrackerType {
     Total,
-    Remote,
+    // Remote is the round trip of the command to the backend node of the given address
+    Remote(String),
 }
 
 pub struct Tracker {
     pub start: Instant,
     tracker_type: TrackerType,
+
+    // cluster is the name of the cluster the tracked command is received by
+    cluster: Arc<str>,
 }
 
 impl std::fmt::Debug for Tracker {
@@ -19,10 +24,11 @@ impl std::fmt::Debug for Tracker {
 }
 
 impl Tracker {
-    pub fn new(tracker_type: TrackerType) -> Tracker {
+    pub fn new(tracker_type: TrackerType, cluster: Arc<str>) -> Tracker {
         Self {
             start: Instant::now(),
             tracker_type,
+            cluster,
         }
     }
 }
@@ -30,27 +36,28 @@ impl Tracker {
 impl Drop for Tracker {
     fn drop(&mut self) {
         let dur = self.start.elapsed();
-        match self.tracker_type {
+        let cluster = KeyValue::new("cluster", self.cluster.clone());
+        match &self.tracker_type {
             TrackerType::Total => {
                 REPUST_TOTAL_TIMER
                     .get()
                     .unwrap()
-                    .record(dur.as_secs_f64(), &[]);
+                    .record(dur.as_secs_f64(), &[cluster]);
             }
-            TrackerType::Remote => {
-                REPUST_REMOTE_TIMER
-                    .get()
-                    .unwrap()
-                    .record(dur.as_secs_f64(), &[]);
+            TrackerType::Remote(node) => {
+                REPUST_REMOTE_TIMER.get().unwrap().record(
+                    dur.as_secs_f64(),
+                    &[cluster, KeyValue::new("node", node.clone())],
+                );
             }
         }
     }
 }
 
-pub fn total_tracker() -> Tracker {
-    Tracker::new(TrackerType::Total)
+pub fn total_tracker(cluster: Arc<str>) -> Tracker {
+    Tracker::new(TrackerType::Total, cluster)
 }
 
-pub fn remote_tracker() -> Tracker {
-    Tracker::new(TrackerType::Remote)
+pub fn remote_tracker(cluster: Arc<str>, node: &str) -> Tracker {
+    Tracker::new(TrackerType::Remote(node.to_string()), cluster)
 }
@@ -18,7 +18,7 @@ impl<T> IntoReply<T> for T {
 
 bitflags! {
     #[derive(Clone,Copy, Debug, PartialEq, Eq)]
-    pub struct CmdFlags: u8 {
+    pub struct CmdFlags: u16 {
         const DONE     = 0b00_000_001;
         // redis cluster only
         const ASK      = 0b00_000_010;
@@ -30,11 +30,42 @@ bitflags! {
         // retry
         const RETRY    = 0b00100000;
 
+        // the ping keeping an idle backend connection open, not a command of a client
+        const KEEPALIVE = 0b01_000_000;
+
         const ERROR    = 0b10_000_000;
+
+        // the SCAN sent to the last backend, its cursor 0 completes the scan
+        const SCAN_LAST = 0b100_000_000;
     }
 }
 
-#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// CMD_TYPES are all the command types, to find them by name
+const CMD_TYPES: &[CmdType] = &[
+    CmdType::Read,
+    CmdType::Write,
+    CmdType::Ctrl,
+    CmdType::NotSupport,
+    CmdType::MSet,
+    CmdType::MSetNx,
+    CmdType::MGet,
+    CmdType::Exists,
+    CmdType::Eval,
+    CmdType::Del,
+    CmdType::Auth,
+    CmdType::Info,
+    CmdType::ReadAll,
+    CmdType::CountAll,
+    CmdType::Command,
+    CmdType::Client,
+    CmdType::Module,
+    CmdType::Scan,
+    CmdType::Memory,
+    CmdType::Proxy,
+    CmdType::PubSub,
+];
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
 pub enum CmdType {
     Read,
     Write,
@@ -43,6 +74,7 @@ pub enum CmdType {
 
     // These commands are specific to Redis.
     MSet,     // Write
+    MSetNx,   // Write
     MGet,     // Read
     Exists,   // Read
     Eval,     // Write
@@ -56,4 +88,40 @@ pub enum CmdType {
     Module,   // Module
     Scan,     // Scan
     Memory,   // Memory
+    Proxy,    // Proxy
+    PubSub,   // PubSub
+}
+
+impl CmdType {
+    // as_str returns the name of the command type, used as the `type` label of the command metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CmdType::Read => "read",
+            CmdType::Write => "write",
+            CmdType::Ctrl => "ctrl",
+            CmdType::NotSupport => "not_support",
+            CmdType::MSet => "mset",
+            CmdType::MSetNx => "msetnx",
+            CmdType::MGet => "mget",
+            CmdType::Exists => "exists",
+            CmdType::Eval => "eval",
+            CmdType::Del => "del",
+            CmdType::Auth => "auth",
+            CmdType::Info => "info",
+            CmdType::ReadAll => "read_all",
+            CmdType::CountAll => "count_all",
+            CmdType::Command => "command",
+            CmdType::Client => "client",
+            CmdType::Module => "module",
+            CmdType::Scan => "scan",
+            CmdType::Memory => "memory",
+            CmdType::Proxy => "proxy",
+            CmdType::PubSub => "pub_sub",
+        }
+    }
+
+    // from_name returns the command type named by as_str, None if there is no such type
+    pub fn from_name(name: &str) -> Option<CmdType> {
+        CMD_TYPES.iter().copied().find(|x| x.as_str() == name)
+    }
 }
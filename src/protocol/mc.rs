@@ -8,9 +8,10 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use crate::com::AsError;
 use crate::metrics::tracker::{remote_tracker, total_tracker, Tracker};
-use crate::protocol::mc::msg::Message;
+use crate::metrics::{command_rejected_incr, framing_error_incr, global_error_incr, RejectReason};
+use crate::protocol::mc::msg::{BinType, Message, DEFAULT_MAX_BIN_BODY_LEN};
 use crate::protocol::{CmdFlags, CmdType, IntoReply};
-use crate::proxy::Request;
+use crate::proxy::{Policy, ProxyCmd, ProxyReply, Redirect, ReplyError, Request};
 use crate::utils::helper::trim_hash_tag;
 
 pub use crate::protocol::mc::msg::init_text_finder as init_memcached_text_finder;
@@ -21,11 +22,15 @@ const MAX_CYCLE: u8 = 1;
 pub struct Cmd {
     cmd: Arc<RwLock<Command>>,
     waker: Option<Waker>,
+
+    // encoded marks the handle written to a backend by its codec, which drops it while the command
+    // still waits for its reply
+    encoded: bool,
 }
 
 impl Drop for Cmd {
     fn drop(&mut self) {
-        if !self.is_done() {
+        if !self.encoded && !self.is_done() {
             self.set_error(&AsError::ProxyFail);
         }
     }
@@ -36,10 +41,36 @@ impl Request for Cmd {
     type FrontCodec = FrontCodec;
     type BackCodec = BackCodec;
 
+    fn front_codec(policy: &Policy) -> FrontCodec {
+        let cluster = policy.cluster.clone();
+        match policy.mc_binary {
+            true => FrontCodec::Binary(BinaryFrontCodec {
+                max_body_len: policy
+                    .max_reply_bulk_len
+                    .unwrap_or(DEFAULT_MAX_BIN_BODY_LEN),
+                cluster,
+            }),
+            false => FrontCodec::Text(TextFrontCodec { cluster }),
+        }
+    }
+
+    fn back_codec(policy: &Policy) -> BackCodec {
+        let cluster = policy.cluster.clone();
+        match policy.mc_binary {
+            true => BackCodec::Binary(BinaryBackCodec {
+                max_body_len: policy
+                    .max_reply_bulk_len
+                    .unwrap_or(DEFAULT_MAX_BIN_BODY_LEN),
+                cluster,
+            }),
+            false => BackCodec::Text(TextBackCodec { cluster }),
+        }
+    }
+
     fn ping_request() -> Self {
         let cmd = Command {
             ctype: CmdType::Read,
-            flags: CmdFlags::empty(),
+            flags: CmdFlags::KEEPALIVE,
             cycle: 0,
 
             req: Message::version_request(),
@@ -48,30 +79,67 @@ impl Request for Cmd {
 
             total_tracker: None,
             remote_tracker: None,
+            deadline: None,
+            cluster: None,
         };
         Cmd {
             cmd: Arc::new(RwLock::new(cmd)),
             waker: None,
+            encoded: false,
         }
     }
 
-    // FIXME
-    fn auth_request(_auth: &str) -> Self {
+    fn is_keepalive(&self) -> bool {
+        self.take_cmd().flags.contains(CmdFlags::KEEPALIVE)
+    }
+
+    // auth_request authenticates a backend connection with SASL PLAIN, only the binary protocol has it
+    fn auth_request(auth: &str) -> Self {
         let cmd = Command {
             ctype: CmdType::Auth,
             flags: CmdFlags::empty(),
             cycle: 0,
 
+            req: Message::sasl_auth_request(auth),
+            reply: None,
+            subs: None,
+
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: None,
+            cluster: None,
+        };
+        Cmd {
+            cmd: Arc::new(RwLock::new(cmd)),
+            waker: None,
+            encoded: false,
+        }
+    }
+
+    fn auth_failure(reply: &Message) -> Option<String> {
+        reply.sasl_failure()
+    }
+
+    // memcached has no replicas, the version request only keeps the connection order
+    fn read_only_request() -> Self {
+        let cmd = Command {
+            ctype: CmdType::Ctrl,
+            flags: CmdFlags::empty(),
+            cycle: 0,
+
             req: Message::version_request(),
             reply: None,
             subs: None,
 
             total_tracker: None,
             remote_tracker: None,
+            deadline: None,
+            cluster: None,
         };
         Cmd {
             cmd: Arc::new(RwLock::new(cmd)),
             waker: None,
+            encoded: false,
         }
     }
 
@@ -85,11 +153,17 @@ impl Request for Cmd {
         self.take_cmd().subs.clone()
     }
 
+    fn next_subs(&self) -> Option<Vec<Self>> {
+        None
+    }
+
     fn is_done(&self) -> bool {
-        if let Some(subs) = self.subs() {
-            subs.iter().all(|x| x.is_done())
-        } else {
-            self.take_cmd().is_done()
+        // the subs are borrowed rather than cloned, as dropping a clone of an unfinished sub fails it
+        // the command rejected as a whole is done without its subs being forwarded
+        let cmd = self.take_cmd();
+        match cmd.subs.as_ref() {
+            Some(subs) => cmd.is_done() || subs.iter().all(|x| x.is_done()),
+            None => cmd.is_done(),
         }
     }
 
@@ -100,11 +174,68 @@ impl Request for Cmd {
         self.take_cmd().can_cycle()
     }
 
+    fn set_moved(&self) {}
+
+    fn set_ask(&self) {}
+
+    fn take_ask(&self) -> bool {
+        false
+    }
+
     fn is_error(&self) -> bool {
         self.take_cmd().is_error()
     }
 
+    fn is_miss(&self) -> bool {
+        self.take_cmd()
+            .reply
+            .as_ref()
+            .is_some_and(|reply| reply.is_miss())
+    }
+
+    // the binary quits are answered by the proxy, as the backend would close the connection shared by
+    // the clients, and the commands which can not be forwarded to a single backend are rejected
     fn valid(&self) -> bool {
+        let (quit, unsupported) = {
+            let cmd = self.take_cmd();
+            (cmd.req.is_bin_quit(), cmd.req.is_bin_unsupported())
+        };
+        if quit {
+            let reply = self.take_cmd().req.bin_ok_reply();
+            self.take_cmd_mut().set_reply(reply);
+            return false;
+        }
+        if unsupported {
+            self.take_cmd_mut()
+                .set_error((&AsError::RequestNotSupport).into_reply());
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::Unsupported);
+            return false;
+        }
+        true
+    }
+
+    fn check_policy(&self, policy: &Policy) -> bool {
+        if policy.read_only && self.take_cmd().req.is_mutation() {
+            self.set_reply(AsError::ReadOnly);
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::ReadOnly);
+            return false;
+        }
+        if let Some(max_key_bytes) = policy.max_key_bytes {
+            let too_long = {
+                let cmd = self.take_cmd();
+                match cmd.subs.as_ref() {
+                    Some(subs) => subs
+                        .iter()
+                        .any(|sub| sub.take_cmd().req.get_key().len() > max_key_bytes),
+                    None => cmd.req.get_key().len() > max_key_bytes,
+                }
+            };
+            if too_long {
+                self.set_reply(AsError::KeyTooLong(max_key_bytes));
+                command_rejected_incr(self.take_cmd().cluster(), RejectReason::KeyTooLong);
+                return false;
+            }
+        }
         true
     }
 
@@ -119,21 +250,27 @@ impl Request for Cmd {
     fn set_reply<R: IntoReply<Message>>(&self, t: R) {
         let reply = t.into_reply();
         self.take_cmd_mut().set_reply(reply);
+        self.wakeup();
     }
 
     fn set_error(&self, t: &AsError) {
         let reply: Message = t.into_reply();
         self.take_cmd_mut().set_error(reply);
+        self.wakeup();
+
+        global_error_incr(self.take_cmd().cluster(), t);
     }
 
     fn mark_total(&self) {
-        let timer = total_tracker();
-        self.take_cmd_mut().total_tracker.replace(timer);
+        let mut cmd = self.take_cmd_mut();
+        let timer = total_tracker(cmd.cluster_label());
+        cmd.total_tracker.replace(timer);
     }
 
-    fn mark_sent(&self) {
-        let timer = remote_tracker();
-        self.take_cmd_mut().remote_tracker.replace(timer);
+    fn mark_sent(&self, node: &str) {
+        let mut cmd = self.take_cmd_mut();
+        let timer = remote_tracker(cmd.cluster_label(), node);
+        cmd.remote_tracker.replace(timer);
     }
 
     fn get_sent_time(&self) -> Option<Instant> {
@@ -147,6 +284,104 @@ impl Request for Cmd {
             None => None,
         }
     }
+
+    fn reset_sent(&self) {
+        let _ = self.take_cmd_mut().remote_tracker.take();
+    }
+
+    fn set_deadline(&self, deadline: Instant) {
+        let mut cmd = self.take_cmd_mut();
+        cmd.deadline = Some(deadline);
+        cmd.subs
+            .iter()
+            .flatten()
+            .for_each(|sub| sub.set_deadline(deadline));
+    }
+
+    fn get_deadline(&self) -> Option<Instant> {
+        self.take_cmd().deadline
+    }
+
+    fn set_cluster(&self, cluster: &Arc<str>) {
+        let mut cmd = self.take_cmd_mut();
+        cmd.cluster = Some(cluster.clone());
+        cmd.subs
+            .iter()
+            .flatten()
+            .for_each(|sub| sub.set_cluster(cluster));
+    }
+
+    // memcache has no proxy commands, every command is forwarded to the backends
+    fn proxy_cmd(&self) -> Option<ProxyCmd> {
+        None
+    }
+
+    fn scan_node(&self) -> Option<usize> {
+        None
+    }
+
+    fn set_scan_last(&self) {
+        unreachable!("memcache does not have any scan command to send")
+    }
+
+    fn finish_scan(&self) {
+        unreachable!("memcache does not have any scan command to finish")
+    }
+
+    fn set_proxy_reply(&self, _reply: ProxyReply) {
+        unreachable!("memcache does not have any proxy command to reply")
+    }
+
+    fn reply_error(_reply: &Message) -> Option<ReplyError> {
+        None
+    }
+
+    fn reply_redirect(_reply: &Message) -> Option<Redirect> {
+        None
+    }
+
+    fn reply_subscriptions(_reply: &Message) -> Option<usize> {
+        None
+    }
+
+    fn subscribe_channels(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    fn push_message(_reply: Message) -> Self {
+        unreachable!("memcache does not have any subscription to push messages")
+    }
+
+    fn is_write(&self) -> bool {
+        self.take_cmd().req.is_write()
+    }
+
+    fn is_read(&self) -> bool {
+        self.take_cmd().req.is_read()
+    }
+
+    // the memcached commands are only classified by their request, not by their type
+    fn cmd_type(&self) -> CmdType {
+        if self.is_write() {
+            CmdType::Write
+        } else if self.is_read() {
+            CmdType::Read
+        } else {
+            self.take_cmd().ctype
+        }
+    }
+
+    fn mirror(&self) -> Self {
+        Cmd::from_msg(self.take_cmd().req.clone())
+    }
+
+    // the value compression is only supported for redis
+    fn compress_value(&self, _min_bytes: usize) {}
+
+    fn decompress_reply(&self) {}
+
+    // the memcached commands carry their own exptime
+    fn set_default_ttl(&self, _ttl_secs: u64) {}
 }
 
 impl Cmd {
@@ -169,10 +404,14 @@ impl Cmd {
                     total_tracker: None,
 
                     remote_tracker: None,
+
+                    deadline: None,
+                    cluster: None,
                 };
                 Cmd {
                     cmd: Arc::new(RwLock::new(command)),
                     waker: None,
+                    encoded: false,
                 }
             })
             .collect();
@@ -188,10 +427,14 @@ impl Cmd {
             total_tracker: None,
 
             remote_tracker: None,
+
+            deadline: None,
+            cluster: None,
         };
         Cmd {
             cmd: Arc::new(RwLock::new(command)),
             waker: None,
+            encoded: false,
         }
     }
 
@@ -202,6 +445,13 @@ impl Cmd {
     pub fn take_cmd_mut(&self) -> RwLockWriteGuard<Command> {
         self.cmd.write().unwrap()
     }
+
+    // wakeup wakes the frontend waiting for the reply of the command
+    fn wakeup(&self) {
+        if let Some(waker) = self.waker.as_ref() {
+            waker.wake_by_ref();
+        }
+    }
 }
 
 impl From<Message> for Cmd {
@@ -224,9 +474,24 @@ pub struct Command {
     total_tracker: Option<Tracker>,
 
     remote_tracker: Option<Tracker>,
+
+    deadline: Option<Instant>,
+
+    // cluster is the name of the cluster the command is received by, labelling its metrics
+    cluster: Option<Arc<str>>,
 }
 
 impl Command {
+    // cluster returns the name of the cluster the command is received by, empty if not set
+    fn cluster(&self) -> &str {
+        self.cluster.as_deref().unwrap_or_default()
+    }
+
+    // cluster_label shares the name of the cluster with the trackers of the command
+    fn cluster_label(&self) -> Arc<str> {
+        self.cluster.clone().unwrap_or_default()
+    }
+
     fn is_done(&self) -> bool {
         self.flags & CmdFlags::DONE == CmdFlags::DONE
     }
@@ -260,30 +525,122 @@ impl Command {
     }
 }
 
-#[derive(Default)]
-pub struct FrontCodec {}
+// FrontCodec frames the clients in the text protocol, or in the binary one for the memcache_binary
+// clusters
+pub enum FrontCodec {
+    Text(TextFrontCodec),
+    Binary(BinaryFrontCodec),
+}
+
+impl Default for FrontCodec {
+    fn default() -> Self {
+        FrontCodec::Text(TextFrontCodec::default())
+    }
+}
 
 impl Decoder for FrontCodec {
     type Item = Cmd;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            FrontCodec::Text(codec) => codec.decode(src),
+            FrontCodec::Binary(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder<Cmd> for FrontCodec {
+    type Error = AsError;
+    fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            FrontCodec::Text(codec) => codec.encode(item, dst),
+            FrontCodec::Binary(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+// BackCodec frames the backends in the protocol of the clients of the cluster
+pub enum BackCodec {
+    Text(TextBackCodec),
+    Binary(BinaryBackCodec),
+}
+
+impl Default for BackCodec {
+    fn default() -> Self {
+        BackCodec::Text(TextBackCodec::default())
+    }
+}
+
+impl Decoder for BackCodec {
+    type Item = Message;
+    type Error = AsError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            BackCodec::Text(codec) => codec.decode(src),
+            BackCodec::Binary(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder<Cmd> for BackCodec {
+    type Error = AsError;
+    fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            BackCodec::Text(codec) => codec.encode(item, dst),
+            BackCodec::Binary(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TextFrontCodec {
+    // cluster is the name of the cluster of the clients, labelling the framing errors
+    cluster: Arc<str>,
+}
+
+impl Decoder for TextFrontCodec {
+    type Item = Cmd;
+    type Error = AsError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // the requests which can not be parsed are counted apart from the errors of the commands, even
+        // the bad ones replied rather than closing the connection
         match Message::parse(src).map(|x| x.map(Into::into)) {
             Ok(val) => Ok(val),
             Err(AsError::BadMessage) => {
+                framing_error_incr(&self.cluster, "front");
                 let cmd: Cmd = Message::raw_inline_reply().into();
+                cmd.set_cluster(&self.cluster);
                 cmd.set_error(&AsError::BadMessage);
                 Ok(Some(cmd))
             }
-            Err(err) => Err(err),
+            Err(err) => {
+                framing_error_incr(&self.cluster, "front");
+                Err(err)
+            }
         }
     }
 }
 
-impl Encoder<Cmd> for FrontCodec {
+impl Encoder<Cmd> for TextFrontCodec {
     type Error = AsError;
     fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let mut cmd = item.take_cmd_mut();
-        if let Some(subs) = cmd.subs.as_ref().cloned() {
+        // the retrieval rejected as a whole is replied with its own error rather than by its keys
+        let subs = cmd.subs.as_ref().filter(|_| cmd.reply.is_none()).cloned();
+        if let Some(subs) = subs {
+            // the keys are retrieved one by one, so the values of the hits are joined under a single END.
+            // A failed key fails the whole retrieval with its error line, which ends the reply in place of
+            // the END, so the client does not take the END as the reply of its next command.
+            let failed = subs.iter().find_map(|sub| {
+                let sub = sub.take_cmd();
+                sub.reply
+                    .as_ref()
+                    .filter(|reply| reply.is_retrieval_error())
+                    .map(|reply| (sub.req.clone(), reply.clone()))
+            });
+            if let Some((req, reply)) = failed {
+                return req.save_reply(reply, dst);
+            }
             for sub in subs {
                 self.encode(sub, dst)?;
             }
@@ -297,35 +654,120 @@ impl Encoder<Cmd> for FrontCodec {
 }
 
 #[derive(Default)]
-pub struct BackCodec {}
+pub struct TextBackCodec {
+    // cluster is the name of the cluster of the backends, labelling the framing errors
+    cluster: Arc<str>,
+}
 
-impl Decoder for BackCodec {
+impl Decoder for TextBackCodec {
     type Item = Message;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Message::parse(src)
+        Message::parse(src).inspect_err(|_| framing_error_incr(&self.cluster, "back"))
     }
 }
 
-impl Encoder<Cmd> for BackCodec {
+impl Encoder<Cmd> for TextBackCodec {
     type Error = AsError;
-    fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, mut item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encoded = true;
         item.take_cmd().req.save_req(dst)
     }
 }
 
+// BinaryFrontCodec frames the clients of the binary protocol. A bad frame can not be skipped, so the
+// client is closed as memcached does rather than replied, as well as the one with a body longer than
+// max_body_len.
+pub struct BinaryFrontCodec {
+    max_body_len: usize,
+    cluster: Arc<str>,
+}
+
+impl Decoder for BinaryFrontCodec {
+    type Item = Cmd;
+    type Error = AsError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let msg = Message::parse_bin(src, BinType::Req, self.max_body_len)
+            .inspect_err(|_| framing_error_incr(&self.cluster, "front"))?;
+        // the backend connections are shared by the clients and authenticated by the proxy, so a client
+        // is not let to authenticate them again as another user and is counted as an auth rejection
+        Ok(msg.map(|msg| match msg.is_bin_sasl() {
+            true => {
+                let cmd: Cmd = msg.into();
+                cmd.set_cluster(&self.cluster);
+                cmd.set_error(&AsError::RequestNotSupport);
+                command_rejected_incr(&self.cluster, RejectReason::Auth);
+                cmd
+            }
+            false => msg.into(),
+        }))
+    }
+}
+
+impl Encoder<Cmd> for BinaryFrontCodec {
+    type Error = AsError;
+    fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut cmd = item.take_cmd_mut();
+        let reply = cmd.reply.take().expect("reply must exits");
+        cmd.req.save_bin_reply(reply, dst)
+    }
+}
+
+// BinaryBackCodec frames the backends of the binary protocol. The connections are started by the text
+// version requests of the proxy, which would switch memcached to the text protocol, so they are sent
+// in the binary protocol as well.
+pub struct BinaryBackCodec {
+    max_body_len: usize,
+    cluster: Arc<str>,
+}
+
+impl Decoder for BinaryBackCodec {
+    type Item = Message;
+    type Error = AsError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Message::parse_bin(src, BinType::Resp, self.max_body_len)
+            .inspect_err(|_| framing_error_incr(&self.cluster, "back"))
+    }
+}
+
+impl Encoder<Cmd> for BinaryBackCodec {
+    type Error = AsError;
+    fn encode(&mut self, mut item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encoded = true;
+        item.take_cmd().req.save_bin_req(dst)
+    }
+}
+
 #[test]
 fn test_mc_parse_wrong_case() {
     test_mc_parse_error_in_path("../fuzz/corpus/fuzz_mc_parser/");
     test_mc_parse_error_in_path("../fuzz/artifacts/fuzz_mc_parser/");
 }
 
+#[test]
+fn test_mc_framing_errors_counted() {
+    use crate::metrics::{init_test_instruments, test_metric_value};
+
+    init_test_instruments();
+    init_memcached_text_finder();
+    let framing_errors = || test_metric_value("repust_framing_errors_total", &[("side", "front")]);
+    let before = framing_errors();
+
+    // the bad requests are replied rather than closing the connection, and counted all the same
+    let mut src = BytesMut::from(&b"\r\n"[..]);
+    let cmd = FrontCodec::default().decode(&mut src).unwrap().unwrap();
+    assert!(cmd.is_error());
+    assert!(framing_errors() >= before + 1.0);
+}
+
 #[cfg(test)]
 fn test_mc_parse_error_in_path(prefix: &str) {
     use std::fs::{self, File};
     use std::io::prelude::*;
     use std::io::BufReader;
 
+    crate::metrics::init_test_instruments();
+
     if let Ok(dir) = fs::read_dir(prefix) {
         for entry in dir {
             let entry = entry.unwrap();
@@ -1,10 +1,10 @@
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
 use byteorder::{BigEndian, ReadBytesExt};
-use bytes::Buf;
+use bytes::{Buf, BufMut};
 use bytes::{Bytes, BytesMut};
 use log::warn;
 use std::cmp::min;
-use std::io::{Cursor, Seek, SeekFrom};
+use std::io::Cursor;
 use std::sync::OnceLock;
 
 use crate::com::AsError;
@@ -22,7 +22,16 @@ const BYTES_SPACE: &[u8] = b" ";
 const BYTES_END: &[u8] = b"END\r\n";
 const BYTES_NOREPLY: &[u8] = b"noreply";
 
+const BIN_STATUS_OK: u16 = 0x0000u16;
 const BIN_STATUS_KEY_NOT_FOUND: u16 = 0x0001u16;
+const BIN_STATUS_INTERNAL_ERROR: u16 = 0x0084u16;
+
+// DEFAULT_MAX_BIN_BODY_LEN is the maximum length of the body of a binary message by default, the one of
+// the bulk strings of the redis replies, so the 4 GiB a header can claim is not buffered
+pub const DEFAULT_MAX_BIN_BODY_LEN: usize = 512 * 1024 * 1024;
+
+// SASL_MECH_PLAIN is the SASL mechanism the backends are authenticated with, sent as the key of SaslAuth
+const SASL_MECH_PLAIN: &[u8] = b"PLAIN";
 
 const TEXT_CMDS: &[&str] = &[
     "set", "add", "replace", "append", "prepend", "cas", // storage [0, 5]
@@ -119,6 +128,32 @@ pub enum TextCmd {
 }
 
 impl TextCmd {
+    fn is_write(&self) -> bool {
+        use TextCmd::*;
+        matches!(
+            self,
+            Set(_)
+                | Add(_)
+                | Replace(_)
+                | Append(_)
+                | Prepend(_)
+                | Cas(_)
+                | Delete(_)
+                | Incr(_)
+                | Decr(_)
+                | Touch(_)
+        )
+    }
+
+    // is_mutation covers all the commands modifying the data, including GAT and GATS touching the keys
+    fn is_mutation(&self) -> bool {
+        self.is_write() || matches!(self, TextCmd::Gat(..) | TextCmd::Gats(..))
+    }
+
+    fn is_read(&self) -> bool {
+        matches!(self, TextCmd::Get(_) | TextCmd::Gets(_))
+    }
+
     fn key_range(&self) -> Range {
         use TextCmd::*;
 
@@ -220,6 +255,7 @@ pub enum BinMsgType {
     Incr = 0x05,
     Decr = 0x06,
     Quit = 0x07,
+    Flush = 0x08,
     GetQ = 0x09,
     Noop = 0x0a,
     Version = 0x0b,
@@ -242,6 +278,9 @@ pub enum BinMsgType {
     Touch = 0x1c,
     GAT = 0x1d,
     GATQ = 0x1e,
+    SaslListMechs = 0x20,
+    SaslAuth = 0x21,
+    SaslStep = 0x22,
     RGet = 0x30,
     RSet = 0x31,
     RSetQ = 0x32,
@@ -258,9 +297,118 @@ pub enum BinMsgType {
 }
 
 impl BinMsgType {
+    // is_quiet covers the commands the server only replies on a miss or a failure, the reads only
+    // replied on a hit
     pub(crate) fn is_quiet(self) -> bool {
         use BinMsgType::*;
-        matches!(&self, GetQ | GetKQ)
+        matches!(
+            &self,
+            GetQ | GetKQ
+                | SetQ
+                | AddQ
+                | ReplaceQ
+                | DeleteQ
+                | IncrementQ
+                | DecrementQ
+                | QuitQ
+                | FlushQ
+                | AppendQ
+                | PrependQ
+                | GATQ
+        )
+    }
+
+    // is_quiet_read covers the quiet commands which are not replied on a miss rather than on a success
+    fn is_quiet_read(self) -> bool {
+        use BinMsgType::*;
+        matches!(&self, GetQ | GetKQ | GATQ)
+    }
+
+    // noisy returns the command replied by the server in any case which the quiet command stands for
+    fn noisy(self) -> BinMsgType {
+        use BinMsgType::*;
+        match self {
+            GetQ => Get,
+            GetKQ => GetK,
+            SetQ => Set,
+            AddQ => Add,
+            ReplaceQ => Replace,
+            DeleteQ => Delete,
+            IncrementQ => Incr,
+            DecrementQ => Decr,
+            QuitQ => Quit,
+            FlushQ => Flush,
+            AppendQ => Append,
+            PrependQ => Prepend,
+            GATQ => GAT,
+            other => other,
+        }
+    }
+
+    // is_write only covers the writes replied by the server, the quiet ones are not replied on success
+    fn is_write(self) -> bool {
+        use BinMsgType::*;
+        matches!(
+            &self,
+            Set | Add | Replace | Delete | Incr | Decr | Append | Prepend | Touch
+        )
+    }
+
+    // is_mutation covers all the commands modifying the data, including the quiet ones
+    fn is_mutation(self) -> bool {
+        use BinMsgType::*;
+        self.is_write()
+            || matches!(
+                &self,
+                SetQ | AddQ
+                    | GAT
+                    | GATQ
+                    | Flush
+                    | ReplaceQ
+                    | DeleteQ
+                    | IncrementQ
+                    | DecrementQ
+                    | FlushQ
+                    | AppendQ
+                    | PrependQ
+                    | RSet
+                    | RSetQ
+                    | RAppend
+                    | RAppendQ
+                    | RPrepend
+                    | RPrependQ
+                    | RDelete
+                    | RDeleteQ
+                    | RIncr
+                    | RIncrQ
+                    | RDecr
+                    | RDecrQ
+            )
+    }
+
+    fn is_read(self) -> bool {
+        use BinMsgType::*;
+        matches!(&self, Get | GetQ | GetK | GetKQ)
+    }
+
+    // is_quit covers the commands closing the connection they are sent on, which must not reach the
+    // backend connection shared by the clients
+    fn is_quit(self) -> bool {
+        matches!(&self, BinMsgType::Quit | BinMsgType::QuitQ)
+    }
+
+    // is_sasl covers the authentication commands, only sent by the proxy itself on its backend connections
+    fn is_sasl(self) -> bool {
+        use BinMsgType::*;
+        matches!(&self, SaslListMechs | SaslAuth | SaslStep)
+    }
+
+    // is_unsupported covers the commands the proxy can not forward to a single backend: the flushes wipe
+    // whichever backend the empty key hashes to, and the stats are replied with many packets while every
+    // command is paired with a single reply
+    fn is_unsupported(self) -> bool {
+        use BinMsgType::*;
+        matches!(&self, Flush | FlushQ | Stat)
     }
 
     fn from_u8(data: u8) -> Result<BinMsgType, AsError> {
@@ -275,6 +423,7 @@ impl BinMsgType {
             0x05 => Incr,
             0x06 => Decr,
             0x07 => Quit,
+            0x08 => Flush,
             0x09 => GetQ,
             0x0a => Noop,
             0x0b => Version,
@@ -297,6 +446,9 @@ impl BinMsgType {
             0x1c => Touch,
             0x1d => GAT,
             0x1e => GATQ,
+            0x20 => SaslListMechs,
+            0x21 => SaslAuth,
+            0x22 => SaslStep,
             0x30 => RGet,
             0x31 => RSet,
             0x32 => RSetQ,
@@ -332,6 +484,58 @@ impl BinType {
     }
 }
 
+// BinHeader is the header of every message of the binary protocol, followed by the extras, the key and
+// the value of the message
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BinHeader {
+    pub magic: u8,
+    pub opcode: u8,
+    pub key_len: u16,
+    pub extras_len: u8,
+    pub data_type: u8,
+
+    // status is the vbucket of the requests and the status of the responses
+    pub status: u16,
+
+    // body_len is the total length of the extras, the key and the value
+    pub body_len: u32,
+
+    // opaque is copied by the server from each request to its response
+    pub opaque: u32,
+    pub cas: u64,
+}
+
+impl BinHeader {
+    // read parses the header at the start of the data, None if it is not complete yet
+    pub fn read(data: &[u8]) -> Option<BinHeader> {
+        let mut buf = data.get(..BIN_HEADER_LEN)?;
+        Some(BinHeader {
+            magic: buf.get_u8(),
+            opcode: buf.get_u8(),
+            key_len: buf.get_u16(),
+            extras_len: buf.get_u8(),
+            data_type: buf.get_u8(),
+            status: buf.get_u16(),
+            body_len: buf.get_u32(),
+            opaque: buf.get_u32(),
+            cas: buf.get_u64(),
+        })
+    }
+
+    pub fn write(&self, target: &mut BytesMut) {
+        target.reserve(BIN_HEADER_LEN);
+        target.put_u8(self.magic);
+        target.put_u8(self.opcode);
+        target.put_u16(self.key_len);
+        target.put_u8(self.extras_len);
+        target.put_u8(self.data_type);
+        target.put_u16(self.status);
+        target.put_u32(self.body_len);
+        target.put_u32(self.opaque);
+        target.put_u64(self.cas);
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum MsgType {
     TextReq(TextCmd),
@@ -397,7 +601,7 @@ impl Message {
         }
         // detect binary
         if data[0] == MSG_BIN_REQ || data[0] == MSG_BIN_RESP {
-            match Self::parse_binary(data) {
+            match Self::parse_binary(data, DEFAULT_MAX_BIN_BODY_LEN) {
                 Ok(msg) => return Ok(msg),
                 Err(AsError::BadMessage) => {
                     data.advance(BIN_HEADER_LEN);
@@ -628,6 +832,13 @@ impl Message {
             };
             btoi::btoi::<usize>(bs)?
         };
+        // the cas unique is forwarded untouched, but memcached takes the data block of a cas without a
+        // valid one for another command, which would be replied twice on the shared connection
+        let bad_unique = pat == TEXT_PAT_CAS
+            && iter
+                .next()
+                .and_then(|unique| btoi::btoi::<u64>(unique).ok())
+                .is_none();
         let mut flags = CmdFlags::empty();
         if let Some(last) = iter.last() {
             if last == BYTES_NOREPLY {
@@ -638,6 +849,10 @@ impl Message {
         if data.len() < total_size {
             return Ok(None);
         }
+        if bad_unique {
+            data.advance(total_size);
+            return Err(AsError::BadMessage);
+        }
 
         Ok(Some(Message {
             data: data.split_to(total_size).freeze(),
@@ -690,31 +905,43 @@ impl Message {
         }))
     }
 
-    pub(crate) fn parse_binary(data: &mut BytesMut) -> Result<Option<Message>, AsError> {
-        if data.len() < BIN_HEADER_LEN {
-            return Ok(None);
+    // parse_bin parses a message of the binary protocol of the given type only, with a body of at most
+    // max_body_len bytes. The frames can not be skipped once their header is not trusted, so the bad ones
+    // are left to fail the connection.
+    pub(crate) fn parse_bin(
+        data: &mut BytesMut,
+        btype: BinType,
+        max_body_len: usize,
+    ) -> Result<Option<Message>, AsError> {
+        match data.first() {
+            None => Ok(None),
+            Some(magic) if *magic == btype as u8 => Self::parse_binary(data, max_body_len),
+            Some(_) => Err(AsError::BadMessage),
+        }
+    }
+
+    // parse_binary fails the message whose header claims a body longer than max_body_len rather than
+    // buffering it until it arrives
+    pub(crate) fn parse_binary(
+        data: &mut BytesMut,
+        max_body_len: usize,
+    ) -> Result<Option<Message>, AsError> {
+        let header = match BinHeader::read(data) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let btype = BinType::from_u8(header.magic)?;
+        let bmtype = BinMsgType::from_u8(header.opcode)?;
+        let key_len = header.key_len as usize;
+        let extra_len = header.extras_len as usize;
+        if header.body_len as usize > max_body_len {
+            return Err(AsError::BodyTooLarge(header.body_len as usize));
         }
 
-        let btype = BinType::from_u8(data[0])?;
-        let bmtype = BinMsgType::from_u8(data[1])?;
-        let mut cursor = Cursor::new(&data[..BIN_HEADER_LEN]);
-        cursor
-            .seek(SeekFrom::Start(2))
-            .map_err(|_| AsError::BadMessage)?;
-        let key_len = cursor
-            .read_u16::<BigEndian>()
-            .map_err(|_| AsError::BadMessage)? as usize;
-
-        let extra_len = cursor.read_u8().map_err(|_| AsError::BadMessage)? as usize;
-        cursor
-            .seek(SeekFrom::Start(8))
-            .map_err(|_| AsError::BadMessage)?;
-
-        let body_len = cursor
-            .read_u32::<BigEndian>()
-            .map_err(|_| AsError::BadMessage)? as usize;
-
-        let tlen = BIN_HEADER_LEN + body_len;
+        let tlen = BIN_HEADER_LEN + header.body_len as usize;
+        if BIN_HEADER_LEN + extra_len + key_len > tlen {
+            return Err(AsError::BadMessage);
+        }
         if data.len() < tlen {
             return Ok(None);
         }
@@ -794,6 +1021,33 @@ impl Message {
         }
     }
 
+    // sasl_auth_request returns the SASL PLAIN authentication of the binary protocol of the given
+    // user:password, whose payload is the user and the password each after a NUL byte
+    pub(crate) fn sasl_auth_request(auth: &str) -> Message {
+        let (user, password) = auth.split_once(':').unwrap_or(("", auth));
+        let value = format!("\0{}\0{}", user, password);
+        let mut data = BytesMut::new();
+        BinHeader {
+            magic: MSG_BIN_REQ,
+            opcode: BinMsgType::SaslAuth as u8,
+            key_len: SASL_MECH_PLAIN.len() as u16,
+            body_len: (SASL_MECH_PLAIN.len() + value.len()) as u32,
+            ..Default::default()
+        }
+        .write(&mut data);
+        data.extend_from_slice(SASL_MECH_PLAIN);
+        data.extend_from_slice(value.as_bytes());
+        Message {
+            data: data.freeze(),
+            mtype: MsgType::Binary {
+                btype: BinType::Req,
+                bmtype: BinMsgType::SaslAuth,
+                key: Range::new(BIN_HEADER_LEN, BIN_HEADER_LEN + SASL_MECH_PLAIN.len()),
+            },
+            flags: CmdFlags::empty(),
+        }
+    }
+
     pub(crate) fn raw_inline_reply() -> Message {
         Message {
             data: Bytes::new(),
@@ -802,6 +1056,68 @@ impl Message {
         }
     }
 
+    pub(crate) fn is_write(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => cmd.is_write(),
+            MsgType::Binary { bmtype, .. } => bmtype.is_write(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_mutation(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => cmd.is_mutation(),
+            MsgType::Binary { bmtype, .. } => bmtype.is_mutation(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_read(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => cmd.is_read(),
+            MsgType::Binary { bmtype, .. } => bmtype.is_read(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_bin_quit(&self) -> bool {
+        matches!(&self.mtype, MsgType::Binary { bmtype, .. } if bmtype.is_quit())
+    }
+
+    pub(crate) fn is_bin_sasl(&self) -> bool {
+        matches!(&self.mtype, MsgType::Binary { bmtype, .. } if bmtype.is_sasl())
+    }
+
+    pub(crate) fn is_bin_unsupported(&self) -> bool {
+        matches!(&self.mtype, MsgType::Binary { bmtype, .. } if bmtype.is_unsupported())
+    }
+
+    // bin_ok_reply returns the successful response of the binary request, which answers the quits in
+    // place of the backend. It is not written for the quiet requests as any quiet success.
+    pub(crate) fn bin_ok_reply(&self) -> Message {
+        let (opcode, opaque) = match BinHeader::read(&self.data) {
+            Some(header) => (header.opcode, header.opaque),
+            None => (BinMsgType::Noop as u8, 0),
+        };
+        let mut data = BytesMut::new();
+        BinHeader {
+            magic: MSG_BIN_RESP,
+            opcode,
+            opaque,
+            ..Default::default()
+        }
+        .write(&mut data);
+        Message {
+            data: data.freeze(),
+            mtype: MsgType::Binary {
+                btype: BinType::Resp,
+                bmtype: BinMsgType::from_u8(opcode).unwrap_or(BinMsgType::Noop),
+                key: Range::new(BIN_HEADER_LEN, BIN_HEADER_LEN),
+            },
+            flags: CmdFlags::empty(),
+        }
+    }
+
     pub(crate) fn is_noreply(&self) -> bool {
         self.flags & CmdFlags::NOREPLY == CmdFlags::NOREPLY
     }
@@ -825,11 +1141,27 @@ impl Message {
             MsgType::TextInline => Range::new(0, 0),
             _ => unreachable!(),
         };
-        debug_assert!(self.data.len() > key.begin());
-        debug_assert!(self.data.len() > key.end());
+        debug_assert!(self.data.len() >= key.begin());
+        debug_assert!(self.data.len() >= key.end());
         &self.data[key.begin()..key.end()]
     }
 
+    // is_miss checks if the reply is the one of a get of a missing key
+    pub fn is_miss(&self) -> bool {
+        match &self.mtype {
+            MsgType::Binary { .. } => {
+                self.data.get(6..8) == Some(&BIN_STATUS_KEY_NOT_FOUND.to_be_bytes()[..])
+            }
+            _ => self.data.as_ref() == BYTES_END,
+        }
+    }
+
+    // is_retrieval_error checks if the reply of a text retrieval is an error line, neither the values of a
+    // hit nor the END of a miss
+    pub fn is_retrieval_error(&self) -> bool {
+        matches!(self.mtype, MsgType::TextInline) && self.data.as_ref() != BYTES_END
+    }
+
     pub fn save_reply(&self, reply: Message, target: &mut BytesMut) -> Result<(), AsError> {
         if self.is_noreply() {
             return Ok(());
@@ -848,23 +1180,23 @@ impl Message {
                 }
             }
 
-            MsgType::Binary { bmtype, .. } => match bmtype {
-                BinMsgType::GetKQ | BinMsgType::GetQ => {
-                    let mut cursor = Cursor::new(&self.data[6..]);
-                    let status = match cursor.read_u16::<BigEndian>() {
-                        Ok(status) => status,
-                        Err(err) => {
-                            warn!("fail to parse status code {}", err);
-                            target.extend_from_slice(reply.data.as_ref());
-                            return Ok(());
-                        }
-                    };
-                    if status == BIN_STATUS_KEY_NOT_FOUND {
+            MsgType::Binary {
+                bmtype: BinMsgType::GetKQ | BinMsgType::GetQ,
+                ..
+            } => {
+                let mut cursor = Cursor::new(&self.data[6..]);
+                let status = match cursor.read_u16::<BigEndian>() {
+                    Ok(status) => status,
+                    Err(err) => {
+                        warn!("fail to parse status code {}", err);
+                        target.extend_from_slice(reply.data.as_ref());
                         return Ok(());
                     }
+                };
+                if status == BIN_STATUS_KEY_NOT_FOUND {
+                    return Ok(());
                 }
-                _ => {}
-            },
+            }
             _ => {}
         }
 
@@ -915,6 +1247,89 @@ impl Message {
     }
 }
 
+impl Message {
+    // bin_status returns the status of a binary response
+    fn bin_status(&self) -> Option<u16> {
+        BinHeader::read(&self.data).map(|header| header.status)
+    }
+
+    // sasl_failure returns the status the SASL authentication is rejected with, None if accepted
+    pub(crate) fn sasl_failure(&self) -> Option<String> {
+        self.bin_status()
+            .filter(|status| *status != BIN_STATUS_OK)
+            .map(|status| format!("SASL authentication failed with status {:#06x}", status))
+    }
+
+    // save_bin_req writes the binary request to a backend. The quiet commands are sent as the noisy ones
+    // so that every command is replied, and the version requests of the proxy itself are translated.
+    pub fn save_bin_req(&self, target: &mut BytesMut) -> Result<(), AsError> {
+        match &self.mtype {
+            MsgType::Binary {
+                btype: BinType::Req,
+                bmtype,
+                ..
+            } => {
+                let begin = target.len();
+                target.extend_from_slice(self.data.as_ref());
+                target[begin + 1] = bmtype.noisy() as u8;
+                Ok(())
+            }
+            MsgType::TextReq(TextCmd::Version) => {
+                BinHeader {
+                    magic: MSG_BIN_REQ,
+                    opcode: BinMsgType::Version as u8,
+                    ..Default::default()
+                }
+                .write(target);
+                Ok(())
+            }
+            _ => Err(AsError::BadMessage),
+        }
+    }
+
+    // save_bin_reply writes the reply of the binary request to its client as the one of the request
+    // itself, the quiet requests only if the server replies them. The errors of the proxy are replied
+    // as internal errors.
+    pub fn save_bin_reply(&self, reply: Message, target: &mut BytesMut) -> Result<(), AsError> {
+        let (bmtype, header) = match (&self.mtype, BinHeader::read(&self.data)) {
+            (MsgType::Binary { bmtype, .. }, Some(header)) => (*bmtype, header),
+            _ => return Err(AsError::BadMessage),
+        };
+
+        if let MsgType::Binary {
+            btype: BinType::Resp,
+            ..
+        } = reply.mtype
+        {
+            let status = reply.bin_status();
+            let silent = match bmtype.is_quiet_read() {
+                true => status == Some(BIN_STATUS_KEY_NOT_FOUND),
+                false => bmtype.is_quiet() && status == Some(BIN_STATUS_OK),
+            };
+            if !silent {
+                let begin = target.len();
+                target.extend_from_slice(reply.data.as_ref());
+                target[begin + 1] = bmtype as u8;
+            }
+            return Ok(());
+        }
+
+        let data = reply.data.as_ref();
+        let text = data.strip_suffix(BYTES_CRLF).unwrap_or(data);
+        BinHeader {
+            magic: MSG_BIN_RESP,
+            opcode: bmtype as u8,
+            status: BIN_STATUS_INTERNAL_ERROR,
+            body_len: text.len() as u32,
+            opaque: header.opaque,
+            ..Default::default()
+        }
+        .write(target);
+        target.extend_from_slice(text);
+        Ok(())
+    }
+}
+
 impl From<AsError> for Message {
     fn from(oe: AsError) -> Message {
         (&oe).into()
@@ -1070,6 +1485,28 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_parse_cas_unique() {
+        init_text_finder();
+        let mut data = BytesMut::from(&b"cas k 0 0 1 18446744073709551615\r\nx\r\nget k\r\n"[..]);
+        let msg = Message::parse(&mut data).unwrap().unwrap();
+        assert_eq!(msg.get_key(), b"k");
+        let mut req = BytesMut::new();
+        msg.save_req(&mut req).unwrap();
+        assert_eq!(&req[..], b"cas k 0 0 1 18446744073709551615\r\nx\r\n");
+
+        // the cas without a valid unique is rejected with its data block, not taken for another command
+        for bad in ["cas k 0 0 1\r\nx\r\n", "cas k 0 0 1 abc noreply\r\nx\r\n"] {
+            let mut data = BytesMut::from(format!("{}get k\r\n", bad).as_bytes());
+            assert_eq!(Message::parse(&mut data), Err(AsError::BadMessage));
+            assert_eq!(&data[..], b"get k\r\n");
+        }
+
+        // the incomplete cas is waited for before it is checked
+        let mut data = BytesMut::from(&b"cas k 0 0 1\r\n"[..]);
+        assert_eq!(Message::parse(&mut data), Ok(None));
+    }
+
     #[test]
     fn test_parse_bin() {
         init_text_finder();
@@ -1127,6 +1564,107 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_bin_mutation() {
+        // the touching reads modify the expiry of the keys, so a read only proxy rejects them
+        for bmtype in [
+            BinMsgType::Touch,
+            BinMsgType::GAT,
+            BinMsgType::GATQ,
+            BinMsgType::Flush,
+        ] {
+            assert!(bmtype.is_mutation(), "{:?} must be a mutation", bmtype);
+        }
+        for bmtype in [BinMsgType::Get, BinMsgType::GetKQ, BinMsgType::Noop] {
+            assert!(!bmtype.is_mutation(), "{:?} must not be a mutation", bmtype);
+        }
+    }
+
+    #[test]
+    fn test_sasl_auth_request() {
+        let msg = Message::sasl_auth_request("user:pa:ss");
+        let mut req = BytesMut::new();
+        msg.save_bin_req(&mut req).unwrap();
+        let header = BinHeader::read(&req).unwrap();
+        assert_eq!(
+            (
+                header.magic,
+                header.opcode,
+                header.key_len,
+                header.extras_len
+            ),
+            (MSG_BIN_REQ, BinMsgType::SaslAuth as u8, 5, 0)
+        );
+        assert_eq!(header.body_len as usize, req.len() - BIN_HEADER_LEN);
+        assert_eq!(&req[BIN_HEADER_LEN..], b"PLAIN\0user\0pa:ss");
+        assert_eq!(msg.get_key(), b"PLAIN");
+
+        // the reply of the backend is parsed as any other binary response
+        let mut reply = BytesMut::new();
+        BinHeader {
+            magic: MSG_BIN_RESP,
+            opcode: BinMsgType::SaslAuth as u8,
+            body_len: 13,
+            ..Default::default()
+        }
+        .write(&mut reply);
+        reply.extend_from_slice(b"Authenticated");
+        assert!(
+            Message::parse_bin(&mut reply, BinType::Resp, DEFAULT_MAX_BIN_BODY_LEN)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_parse_bin_only() {
+        let header = BinHeader {
+            magic: MSG_BIN_REQ,
+            opcode: BinMsgType::GetQ as u8,
+            key_len: 3,
+            body_len: 3,
+            opaque: 7,
+            ..Default::default()
+        };
+        let mut data = BytesMut::new();
+        header.write(&mut data);
+        data.extend_from_slice(b"ABC");
+        assert_eq!(BinHeader::read(&data), Some(header));
+
+        // the requests are not taken for responses nor for text commands
+        assert!(
+            Message::parse_bin(&mut data.clone(), BinType::Resp, DEFAULT_MAX_BIN_BODY_LEN).is_err()
+        );
+        assert!(Message::parse_bin(
+            &mut BytesMut::from(&b"get k\r\n"[..]),
+            BinType::Req,
+            DEFAULT_MAX_BIN_BODY_LEN,
+        )
+        .is_err());
+        let msg = Message::parse_bin(&mut data, BinType::Req, DEFAULT_MAX_BIN_BODY_LEN)
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.get_key(), b"ABC");
+
+        // the quiet request is sent as the noisy one, and its miss is not replied
+        let mut req = BytesMut::new();
+        msg.save_bin_req(&mut req).unwrap();
+        assert_eq!(req[1], BinMsgType::Get as u8);
+        let mut miss = BytesMut::new();
+        BinHeader {
+            magic: MSG_BIN_RESP,
+            status: BIN_STATUS_KEY_NOT_FOUND,
+            ..Default::default()
+        }
+        .write(&mut miss);
+        let miss = Message::parse_bin(&mut miss, BinType::Resp, DEFAULT_MAX_BIN_BODY_LEN)
+            .unwrap()
+            .unwrap();
+        let mut reply = BytesMut::new();
+        msg.save_bin_reply(miss, &mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+
     #[test]
     fn test_parser_error() {
         init_text_finder();
@@ -1140,7 +1678,7 @@ mod test {
         ];
         assert!(fuzz_data.len() >= 24);
         let mut data = BytesMut::from(&fuzz_data[..]);
-        let msg_rslt = Message::parse_binary(&mut data);
+        let msg_rslt = Message::parse_binary(&mut data, DEFAULT_MAX_BIN_BODY_LEN);
         assert!(msg_rslt.is_err());
     }
 }
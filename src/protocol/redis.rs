@@ -5,9 +5,9 @@ mod resp;
 // Path src/protocol/redis/resp.rs
 
 use btoi::btoi;
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use log::{debug, error, trace, warn};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::task::Waker;
@@ -16,24 +16,42 @@ use std::u64;
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::com::{meta, AsError};
-use crate::metrics::global_error_incr;
 use crate::metrics::tracker::{remote_tracker, total_tracker, Tracker};
+use crate::metrics::{command_rejected_incr, framing_error_incr, global_error_incr, RejectReason};
 use crate::protocol::IntoReply;
 use crate::protocol::{CmdFlags, CmdType};
-use crate::proxy::Request;
+use crate::proxy::{
+    ClientCmd, Explain, Policy, ProxyCmd, ProxyReply, Redirect, ReplyError, Request,
+};
+use crate::utils::compress::{compress_value, decompress_value};
 use crate::utils::helper::{itoa, trim_hash_tag, upper};
 
 use resp::{Message, MessageMut, RespType};
-use resp::{RESP_ERROR, RESP_INT, RESP_STRING};
+use resp::{DEFAULT_MAX_BULK_LEN, DEFAULT_MAX_MULTIBULK_LEN, RESP_ERROR, RESP_INT, RESP_STRING};
 
 pub use cmd::init_cmds as init_redis_supported_cmds;
+pub use cmd::init_unknown_cmds_log as init_redis_unknown_cmds_log;
 
 pub const SLOTS_COUNT: usize = 16384;
 
 const BYTES_CMD_CLUSTER: &[u8] = b"CLUSTER";
 const BYTES_CMD_QUIT: &[u8] = b"QUIT";
+const BYTES_CMD_HELLO: &[u8] = b"HELLO";
+const BYTES_CMD_READONLY: &[u8] = b"READONLY";
+const BYTES_CMD_READWRITE: &[u8] = b"READWRITE";
+const BYTES_AUTH: &[u8] = b"AUTH";
+const BYTES_SETNAME: &[u8] = b"SETNAME";
 const BYTES_SLOTS: &[u8] = b"SLOTS";
 const BYTES_NODES: &[u8] = b"NODES";
+const BYTES_TIMEOUT: &[u8] = b"TIMEOUT";
+const BYTES_WHERE: &[u8] = b"WHERE";
+const BYTES_EXPLAIN: &[u8] = b"EXPLAIN";
+const BYTES_INFO: &[u8] = b"INFO";
+const BYTES_LIST: &[u8] = b"LIST";
+const BYTES_ERR_LOADING: &[u8] = b"LOADING";
+const BYTES_ERR_BUSY: &[u8] = b"BUSY";
+const BYTES_SUBSCRIPTION_KINDS: &[&[u8]] =
+    &[b"SUBSCRIBE", b"UNSUBSCRIBE", b"PSUBSCRIBE", b"PUNSUBSCRIBE"];
 
 #[derive(Clone, Debug)]
 pub struct Cmd {
@@ -47,9 +65,30 @@ impl Request for Cmd {
     type FrontCodec = RedisHandleCodec;
     type BackCodec = RedisNodeCodec;
 
+    fn front_codec(policy: &Policy) -> RedisHandleCodec {
+        RedisHandleCodec {
+            max_multibulk_len: policy
+                .max_multibulk_len
+                .unwrap_or(DEFAULT_MAX_MULTIBULK_LEN),
+            max_keys_per_cmd: policy.max_keys_per_cmd.unwrap_or(usize::MAX),
+            strip_command_prefix: policy.strip_command_prefix.clone(),
+            command_renames: policy.command_renames.clone(),
+            cluster: policy.cluster.clone(),
+            info_overrides: policy.info_overrides.clone(),
+        }
+    }
+
+    fn back_codec(policy: &Policy) -> RedisNodeCodec {
+        RedisNodeCodec {
+            max_bulk_len: policy.max_reply_bulk_len.unwrap_or(DEFAULT_MAX_BULK_LEN),
+            discard: 0,
+            cluster: policy.cluster.clone(),
+        }
+    }
+
     fn ping_request() -> Self {
         let msg = Message::new_ping_request();
-        let flags = CmdFlags::empty();
+        let flags = CmdFlags::KEEPALIVE;
         let cmd_type = CmdType::get_cmd_type(&msg);
 
         let cmd = Command {
@@ -63,10 +102,17 @@ impl Request for Cmd {
             total_tracker: None,
 
             remote_tracker: None,
+
+            deadline: None,
+            cluster: None,
         };
         cmd.into_cmd()
     }
 
+    fn is_keepalive(&self) -> bool {
+        self.take_cmd().flags.contains(CmdFlags::KEEPALIVE)
+    }
+
     fn auth_request(auth: &str) -> Self {
         let msg = Message::new_auth(auth);
         let flags = CmdFlags::empty();
@@ -81,10 +127,21 @@ impl Request for Cmd {
             subs: None,
             total_tracker: None,
             remote_tracker: None,
+            deadline: None,
+            cluster: None,
         };
         cmd.into_cmd()
     }
 
+    fn auth_failure(reply: &Message) -> Option<String> {
+        (reply.data.first() == Some(&RESP_ERROR))
+            .then(|| String::from_utf8_lossy(reply.data.trim_ascii_end()).into_owned())
+    }
+
+    fn read_only_request() -> Self {
+        new_read_only_cmd()
+    }
+
     fn key_hash(&self, hash_tag: &[u8], hasher: fn(&[u8]) -> u64) -> u64 {
         self.take_cmd().key_hash(hash_tag, hasher)
     }
@@ -93,6 +150,10 @@ impl Request for Cmd {
         self.take_cmd().subs.clone()
     }
 
+    fn next_subs(&self) -> Option<Vec<Self>> {
+        self.take_cmd_mut().next_subs()
+    }
+
     fn is_done(&self) -> bool {
         if let Some(subs) = self.subs() {
             subs.into_iter().all(|x| x.is_done())
@@ -105,6 +166,14 @@ impl Request for Cmd {
         self.take_cmd().is_error()
     }
 
+    fn is_miss(&self) -> bool {
+        let cmd = self.take_cmd();
+        matches!(
+            cmd.reply.as_ref().map(|x| x.data.as_ref()),
+            Some(BYTES_NULL_BULK) | Some(BYTES_NULL_ARRAY)
+        )
+    }
+
     fn add_cycle(&self) {
         self.take_cmd_mut().add_cycle()
     }
@@ -112,10 +181,76 @@ impl Request for Cmd {
         self.take_cmd().can_cycle()
     }
 
+    fn set_moved(&self) {
+        self.take_cmd_mut().set_moved()
+    }
+
+    fn set_ask(&self) {
+        self.take_cmd_mut().set_ask()
+    }
+
+    fn take_ask(&self) -> bool {
+        let mut cmd = self.take_cmd_mut();
+        let ask = cmd.is_ask();
+        cmd.unset_ask();
+        ask
+    }
+
     fn valid(&self) -> bool {
         self.check_valid()
     }
 
+    fn check_policy(&self, policy: &Policy) -> bool {
+        if policy.read_only && self.is_write() {
+            self.take_cmd_mut().set_reply(AsError::ReadOnly);
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::ReadOnly);
+            return false;
+        }
+        if let Some(max_key_bytes) = policy.max_key_bytes {
+            let too_long = match self.subs() {
+                Some(subs) => subs
+                    .iter()
+                    .any(|sub| sub.take_cmd().key_len() > max_key_bytes),
+                None => self.take_cmd().key_len() > max_key_bytes,
+            };
+            if too_long {
+                self.take_cmd_mut()
+                    .set_reply(AsError::KeyTooLong(max_key_bytes));
+                command_rejected_incr(self.take_cmd().cluster(), RejectReason::KeyTooLong);
+                return false;
+            }
+        }
+        // the compressed values can only be read or written whole
+        if policy.compression_threshold.is_some()
+            && self.take_cmd().is_verb_of(BYTES_CMDS_PARTIAL_VALUE)
+        {
+            self.take_cmd_mut()
+                .set_reply(AsError::PartialCompressedValue);
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::Compressed);
+            return false;
+        }
+        if let Some(max_offset) = policy.max_setrange_offset {
+            // a malformed offset is left to be rejected by the backend
+            let offset = {
+                let cmd = self.take_cmd();
+                match cmd.req.nth(COMMAND_POS) == Some(BYTES_CMD_SETRANGE) {
+                    true => cmd
+                        .req
+                        .nth(OFFSET_SETRANGE_POS)
+                        .and_then(|x| btoi::btou::<u64>(x).ok()),
+                    false => None,
+                }
+            };
+            if offset.is_some_and(|offset| offset > max_offset) {
+                self.take_cmd_mut()
+                    .set_reply(AsError::OffsetTooLarge(max_offset));
+                command_rejected_incr(self.take_cmd().cluster(), RejectReason::OffsetTooLarge);
+                return false;
+            }
+        }
+        true
+    }
+
     fn register_waker(&mut self, waker: Waker) {
         self.waker = Some(waker);
     }
@@ -132,21 +267,26 @@ impl Request for Cmd {
 
     fn set_error(&self, t: &AsError) {
         let reply: Message = t.into_reply();
-        let mut cmd = self.take_cmd_mut();
-        cmd.set_reply(reply);
-        cmd.set_error();
+        {
+            let mut cmd = self.take_cmd_mut();
+            cmd.set_reply(reply);
+            cmd.set_error();
+        }
+        self.wakeup();
 
-        global_error_incr();
+        global_error_incr(self.take_cmd().cluster(), t);
     }
 
     fn mark_total(&self) {
-        let timer = total_tracker();
-        self.take_cmd_mut().total_tracker.replace(timer);
+        let mut cmd = self.take_cmd_mut();
+        let timer = total_tracker(cmd.cluster_label());
+        cmd.total_tracker.replace(timer);
     }
 
-    fn mark_sent(&self) {
-        let timer = remote_tracker();
-        self.take_cmd_mut().remote_tracker.replace(timer);
+    fn mark_sent(&self, node: &str) {
+        let mut cmd = self.take_cmd_mut();
+        let timer = remote_tracker(cmd.cluster_label(), node);
+        cmd.remote_tracker.replace(timer);
     }
 
     fn get_sent_time(&self) -> Option<Instant> {
@@ -160,21 +300,222 @@ impl Request for Cmd {
             None => None,
         }
     }
-}
 
-impl Cmd {
-    pub fn cluster_mark_total(&self) {
-        let timer = total_tracker();
-        self.take_cmd_mut().total_tracker.replace(timer);
+    fn reset_sent(&self) {
+        let _ = self.take_cmd_mut().remote_tracker.take();
+    }
+
+    fn set_deadline(&self, deadline: Instant) {
+        self.take_cmd_mut().deadline = Some(deadline);
+        if let Some(subs) = self.subs() {
+            subs.iter().for_each(|sub| sub.set_deadline(deadline));
+        }
     }
 
-    pub fn cluster_mark_remote(&self) {
-        let timer = remote_tracker();
-        if self.take_cmd().remote_tracker.is_none() {
-            self.take_cmd_mut().remote_tracker.replace(timer);
+    fn get_deadline(&self) -> Option<Instant> {
+        self.take_cmd().deadline
+    }
+
+    fn set_cluster(&self, cluster: &Arc<str>) {
+        self.take_cmd_mut().cluster = Some(cluster.clone());
+        if let Some(subs) = self.subs() {
+            subs.iter().for_each(|sub| sub.set_cluster(cluster));
+        }
+    }
+
+    fn proxy_cmd(&self) -> Option<ProxyCmd> {
+        let cmd = self.take_cmd();
+        if !cmd.cmd_type.is_proxy() && !cmd.cmd_type.is_client() {
+            return None;
+        }
+        cmd.parse_proxy_cmd().ok()
+    }
+
+    fn scan_node(&self) -> Option<usize> {
+        self.take_cmd().scan_cursor().map(|(node, _)| node)
+    }
+
+    fn set_scan_last(&self) {
+        self.take_cmd_mut().flags |= CmdFlags::SCAN_LAST;
+    }
+
+    fn finish_scan(&self) {
+        self.set_reply(Synthetic::ScanDone.reply());
+    }
+
+    fn set_proxy_reply(&self, reply: ProxyReply) {
+        match reply {
+            ProxyReply::Ok => self.set_reply(Synthetic::Ok.reply()),
+            ProxyReply::Bulk(data) => self.set_reply(Message::bulk(&data)),
+        }
+    }
+
+    fn reply_error(reply: &Message) -> Option<ReplyError> {
+        match reply.error_code()? {
+            BYTES_ERR_LOADING => Some(ReplyError::Loading),
+            BYTES_ERR_BUSY => Some(ReplyError::Busy),
+            _ => None,
+        }
+    }
+
+    fn reply_redirect(reply: &Message) -> Option<Redirect> {
+        reply.check_redirect()
+    }
+
+    fn reply_subscriptions(reply: &Message) -> Option<usize> {
+        match &reply.resp_type {
+            RespType::Array(_, items) if items.len() == 3 => {}
+            _ => return None,
+        }
+        let kind = reply.nth(0)?.to_ascii_uppercase();
+        if !BYTES_SUBSCRIPTION_KINDS.contains(&kind.as_slice()) {
+            return None;
+        }
+        btoi::<usize>(reply.nth(2)?).ok()
+    }
+
+    fn subscribe_channels(&self) -> Vec<Vec<u8>> {
+        let cmd = self.take_cmd();
+        match cmd.req.nth(0) {
+            Some(b"SUBSCRIBE") | Some(b"PSUBSCRIBE") => {
+                cmd.req.iter().skip(1).map(|x| x.to_vec()).collect()
+            }
+            _ => Vec::new(),
         }
     }
 
+    fn push_message(reply: Message) -> Self {
+        let cmd = Command {
+            flags: CmdFlags::DONE,
+            cmd_type: CmdType::PubSub,
+            cycle: DEFAULT_CYCLE,
+            req: Message::array(&[]),
+            reply: Some(reply),
+            subs: None,
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: None,
+            cluster: None,
+        };
+        cmd.into_cmd()
+    }
+
+    fn is_write(&self) -> bool {
+        let cmd_type = self.take_cmd().cmd_type;
+        cmd_type.is_write()
+            || cmd_type.is_mset()
+            || cmd_type.is_msetnx()
+            || cmd_type.is_del()
+            || cmd_type.is_eval()
+    }
+
+    fn is_read(&self) -> bool {
+        self.take_cmd().is_read()
+    }
+
+    fn cmd_type(&self) -> CmdType {
+        self.take_cmd().cmd_type
+    }
+
+    fn mirror(&self) -> Self {
+        let cmd = self.take_cmd();
+        let command = Command {
+            flags: CmdFlags::empty(),
+            cmd_type: cmd.cmd_type,
+            cycle: DEFAULT_CYCLE,
+            req: cmd.req.clone(),
+            reply: None,
+            subs: cmd
+                .subs
+                .as_ref()
+                .map(|subs| subs.iter().map(|sub| sub.mirror()).collect()),
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: None,
+            cluster: None,
+        };
+        command.into_cmd()
+    }
+
+    fn compress_value(&self, min_bytes: usize) {
+        let mut cmd = self.take_cmd_mut();
+        if !cmd.is_verb_of(BYTES_CMDS_COMPRESSED_WRITE) {
+            return;
+        }
+
+        let compressed = match cmd.req.nth(VALUE_SET_POS) {
+            Some(value) if value.len() >= min_bytes => compress_value(value),
+            _ => None,
+        };
+        if let Some(compressed) = compressed {
+            let mut args: Vec<&[u8]> = cmd.req.iter().collect();
+            args[VALUE_SET_POS] = &compressed;
+            let req = Message::array(&args);
+            cmd.req = req;
+        }
+    }
+
+    fn decompress_reply(&self) {
+        if let Some(subs) = self.subs() {
+            subs.iter().for_each(|sub| sub.decompress_reply());
+            return;
+        }
+
+        // GETSET and SET with the GET option reply the old value, which is stored compressed as well
+        let mut cmd = self.take_cmd_mut();
+        if !cmd.cmd_type.is_mget() && !cmd.is_verb_of(BYTES_CMDS_COMPRESSED_READ) {
+            return;
+        }
+
+        let value = match cmd.reply.as_ref() {
+            Some(reply) if matches!(reply.resp_type, RespType::Bulk(..)) => {
+                reply.data().and_then(decompress_value)
+            }
+            _ => None,
+        };
+        if let Some(value) = value {
+            cmd.reply = Some(Message::bulk(&value));
+        }
+    }
+
+    fn set_default_ttl(&self, ttl_secs: u64) {
+        if let Some(subs) = self.subs() {
+            if self.cmd_type().is_mset() {
+                subs.iter().for_each(|sub| sub.set_default_ttl(ttl_secs));
+            }
+            return;
+        }
+
+        let mut cmd = self.take_cmd_mut();
+        let ttl = ttl_secs.to_string();
+        let req = if cmd.cmd_type.is_mset() {
+            // MSET has no expiry option, each of its pairs is sent as a SET instead
+            match (cmd.req.nth(KEY_RAW_POS), cmd.req.nth(VALUE_SET_POS)) {
+                (Some(key), Some(value)) => {
+                    Message::array(&[BYTES_CMD_SET, key, value, BYTES_EX, ttl.as_bytes()])
+                }
+                _ => return,
+            }
+        } else if cmd.req.nth(COMMAND_POS) == Some(BYTES_CMD_SET) {
+            let has_ttl =
+                cmd.req.iter().skip(VALUE_SET_POS + 1).any(|arg| {
+                    BYTES_SET_TTL_OPTIONS.contains(&arg.to_ascii_uppercase().as_slice())
+                });
+            if has_ttl {
+                return;
+            }
+            let mut args: Vec<&[u8]> = cmd.req.iter().collect();
+            args.extend_from_slice(&[BYTES_EX, ttl.as_bytes()]);
+            Message::array(&args)
+        } else {
+            return;
+        };
+        cmd.cmd_type = CmdType::get_cmd_type(&req);
+        cmd.req = req;
+    }
+}
+
+impl Cmd {
     pub fn take_cmd(&self) -> RwLockReadGuard<Command> {
         self.cmd.read().unwrap()
     }
@@ -206,21 +547,53 @@ impl Cmd {
 
     pub fn set_no_auth(&self) {
         self.take_cmd_mut().set_reply(AsError::NoAuth);
+        command_rejected_incr(self.take_cmd().cluster(), RejectReason::Auth);
     }
 
     pub fn set_auth_wrong(&self) {
         self.take_cmd_mut().set_reply(AsError::AuthWrong);
+        command_rejected_incr(self.take_cmd().cluster(), RejectReason::Auth);
     }
 
     pub fn check_valid(&self) -> bool {
         if self.take_cmd().cmd_type.is_not_support() {
             self.take_cmd_mut().set_reply(AsError::RequestNotSupport);
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::Unsupported);
             return false;
         }
         if self.take_cmd().is_done() {
             return true;
         }
 
+        let cmd_type = self.take_cmd().cmd_type;
+        if cmd_type.is_proxy() || cmd_type.is_client() {
+            let parsed = self.take_cmd().parse_proxy_cmd();
+            return match parsed {
+                Ok(_) => true,
+                Err(err) => {
+                    let reason = match err {
+                        AsError::RequestNotSupport => RejectReason::Unsupported,
+                        _ => RejectReason::Arity,
+                    };
+                    self.take_cmd_mut().set_reply(err);
+                    command_rejected_incr(self.take_cmd().cluster(), reason);
+                    false
+                }
+            };
+        }
+
+        // the subscriptions need a channel while the unsubscriptions without one drop all of them
+        if cmd_type.is_pub_sub() {
+            let cmd = self.take_cmd();
+            let is_subscribe = matches!(cmd.req.nth(0), Some(b"SUBSCRIBE") | Some(b"PSUBSCRIBE"));
+            if is_subscribe && cmd.req.nth(1).is_none() {
+                drop(cmd);
+                self.take_cmd_mut().set_reply(AsError::WrongArity);
+                command_rejected_incr(self.take_cmd().cluster(), RejectReason::Arity);
+                return false;
+            }
+        }
+
         if self.take_cmd().cmd_type.is_ctrl() {
             let is_quit = self
                 .take_cmd()
@@ -234,6 +607,13 @@ impl Cmd {
                 return false;
             }
 
+            let is_hello = self.take_cmd().req.nth(0) == Some(BYTES_CMD_HELLO);
+            if is_hello {
+                let reply = self.take_cmd().hello_reply();
+                self.take_cmd_mut().set_reply(reply);
+                return false;
+            }
+
             // check if is cluster
             let is_cluster = self
                 .take_cmd()
@@ -267,23 +647,20 @@ impl Cmd {
                 }
             }
             self.take_cmd_mut().set_reply(AsError::RequestNotSupport);
+            command_rejected_incr(self.take_cmd().cluster(), RejectReason::Unsupported);
             return false;
         }
         // and other conditions
         true
     }
 
-    pub fn change_info_resp(&mut self) {
+    // change_info_resp applies the overrides to the INFO reply of a backend, the keyspace aggregated by
+    // the proxy is replied as it is
+    pub fn change_info_resp(&self, overrides: &HashMap<String, String>) {
         let mut cmd = self.take_cmd_mut();
-
-        // Only for simple INFO command
         if cmd.cmd_type.is_info() {
-            if let RespType::Array(_, items) = &cmd.req.resp_type {
-                if items.len() == 1 {
-                    if let Some(msg) = &mut cmd.reply {
-                        msg.replace_info_resp();
-                    }
-                }
+            if let Some(msg) = &mut cmd.reply {
+                msg.replace_info_resp(overrides);
             }
         }
     }
@@ -300,6 +677,8 @@ impl Cmd {
                 subs: None,
                 total_tracker: None,
                 remote_tracker: None,
+                deadline: None,
+                cluster: None,
             };
 
             let mut sub_cmd = sub.into_cmd();
@@ -335,18 +714,35 @@ pub struct Command {
 
     total_tracker: Option<Tracker>,
     remote_tracker: Option<Tracker>,
+
+    // deadline is the end-to-end deadline of the command set by the client connection
+    deadline: Option<Instant>,
+
+    // cluster is the name of the cluster the command is received by, labelling its metrics
+    cluster: Option<Arc<str>>,
 }
 
-const BYTES_JUST_OK: &[u8] = b"+OK\r\n";
-const BYTES_NULL_ARRAY: &[u8] = b"*-1\r\n";
-const BYTES_ZERO_INT: &[u8] = b":0\r\n";
 const BYTES_CMD_PING: &[u8] = b"PING";
 const BYTES_CMD_COMMAND: &[u8] = b"COMMAND";
-const BYTES_REPLY_NULL_ARRAY: &[u8] = b"*-1\r\n";
-const STR_REPLY_PONG: &str = "PONG";
+const BYTES_CMD_SET: &[u8] = b"SET";
+const BYTES_CMD_EXISTS: &[u8] = b"EXISTS";
+const BYTES_CMD_MSETNX: &[u8] = b"MSETNX";
+const BYTES_CMD_SETRANGE: &[u8] = b"SETRANGE";
+// BYTES_CMDS_COMPRESSED_WRITE are the commands whose value is compressed, at the position of the SET one
+const BYTES_CMDS_COMPRESSED_WRITE: &[&[u8]] = &[b"SET", b"GETSET"];
+// BYTES_CMDS_COMPRESSED_READ are the commands whose bulk reply is decompressed
+const BYTES_CMDS_COMPRESSED_READ: &[&[u8]] = &[b"GET", b"GETSET", b"SET"];
+// BYTES_CMDS_PARTIAL_VALUE are the commands reading or writing a part of the value, which are rejected
+// while the values are stored compressed
+const BYTES_CMDS_PARTIAL_VALUE: &[&[u8]] = &[b"APPEND", b"GETRANGE", b"SETRANGE", b"STRLEN"];
+const BYTES_EX: &[u8] = b"EX";
+// BYTES_SET_TTL_OPTIONS are the options of SET which set or keep the expiry of the key
+const BYTES_SET_TTL_OPTIONS: &[&[u8]] = &[b"EX", b"PX", b"EXAT", b"PXAT", b"KEEPTTL"];
 const BYTES_CMD_INFO_KEYSPACE: &[u8] = b"*2\r\n$4\r\nINFO\r\n$8\r\nkeyspace\r\n";
 
 const BYTES_CRLF: &[u8] = b"\r\n";
+const BYTES_NULL_BULK: &[u8] = b"$-1\r\n";
+const BYTES_NULL_ARRAY: &[u8] = b"*-1\r\n";
 
 const BYTES_ARRAY: &[u8] = b"*";
 const BYTES_INTEGER: &[u8] = b":";
@@ -373,17 +769,44 @@ impl Command {
         }
     }
 
-    pub fn parse_cmd(buf: &mut BytesMut) -> Result<Option<Cmd>, AsError> {
-        let msg = MessageMut::parse(buf)?;
+    // not_supported creates the command replied as not supported without being classified
+    fn not_supported(msg: Message, cluster: &Arc<str>) -> Cmd {
+        let command = Command {
+            flags: CmdFlags::empty(),
+            cmd_type: CmdType::NotSupport,
+            cycle: DEFAULT_CYCLE,
+            req: msg,
+            reply: None,
+            subs: None,
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: None,
+            cluster: Some(cluster.clone()),
+        };
+        let cmd: Cmd = command.into_cmd();
+        cmd.set_reply(AsError::RequestNotSupport);
+        cmd
+    }
+
+    pub fn parse_cmd(
+        buf: &mut BytesMut,
+        max_multibulk_len: usize,
+        max_keys: usize,
+        cluster: &Arc<str>,
+    ) -> Result<Option<Cmd>, AsError> {
+        let msg = MessageMut::parse_limited(buf, max_multibulk_len)?;
         trace!("msg: {:?}", msg);
-        Ok(msg.map(Into::into))
+        Ok(msg.map(|msg| Command::from_msg(msg, max_keys, cluster)))
     }
 
     pub fn reply_cmd(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
-        if self.cmd_type.is_mset() || self.cmd_type.is_client() {
-            buf.extend_from_slice(BYTES_JUST_OK);
-            Ok(BYTES_JUST_OK.len())
+        if self.cmd_type.is_mset() {
+            Ok(Synthetic::Ok.save(buf))
+        } else if self.cmd_type.is_msetnx() {
+            self.reply_msetnx(buf)
         } else if self.cmd_type.is_mget() {
+            // the subs are created in the order of the request keys and the command is only replied once
+            // all of them are done, so the values keep the request order whichever backend replied first.
             if let Some(subs) = self.subs.as_ref() {
                 buf.extend_from_slice(BYTES_ARRAY);
 
@@ -398,8 +821,7 @@ impl Command {
                 Ok(buf.len() - begin)
             } else {
                 debug!("subs is empty");
-                buf.extend_from_slice(BYTES_NULL_ARRAY);
-                Ok(BYTES_NULL_ARRAY.len())
+                Ok(Synthetic::NullArray.save(buf))
             }
         } else if self.cmd_type.is_read_all() {
             if let Some(subs) = self.subs.as_ref() {
@@ -425,8 +847,7 @@ impl Command {
                 Ok(buf.len() - begin)
             } else {
                 debug!("subs is empty");
-                buf.extend_from_slice(BYTES_NULL_ARRAY);
-                Ok(BYTES_NULL_ARRAY.len())
+                Ok(Synthetic::NullArray.save(buf))
             }
         } else if self.is_info_keyspace() {
             if let Some(subs) = self.subs.as_ref() {
@@ -536,53 +957,10 @@ impl Command {
                 Ok(buf.len() - begin)
             } else {
                 debug!("subs is empty");
-                buf.extend_from_slice(BYTES_NULL_ARRAY);
-                Ok(BYTES_NULL_ARRAY.len())
+                Ok(Synthetic::NullArray.save(buf))
             }
         } else if self.cmd_type.is_scan() {
-            if let Some(subs) = self.subs.as_ref() {
-                buf.extend_from_slice(BYTES_ARRAY);
-                itoa(2, buf);
-                buf.extend_from_slice(BYTES_CRLF);
-
-                let begin = buf.len();
-                let next_idx = 0;
-                let mut len = 0;
-
-                for sub in subs {
-                    if let Some(reply) = &sub.take_cmd().reply {
-                        if let RespType::Array(_, array) = &reply.resp_type {
-                            if array.len() == 2 {
-                                let inner_arr = &array[1];
-                                if let RespType::Array(_, array) = inner_arr {
-                                    len += array.len();
-                                }
-                            }
-                        }
-                    }
-                }
-
-                buf.extend_from_slice(BYTES_BULK_STRING);
-                itoa(next_idx.to_string().len(), buf);
-
-                buf.extend_from_slice(BYTES_CRLF);
-                itoa(next_idx, buf);
-
-                buf.extend_from_slice(BYTES_CRLF);
-                buf.extend_from_slice(BYTES_ARRAY);
-                itoa(len, buf);
-
-                buf.extend_from_slice(BYTES_CRLF);
-
-                for sub in subs {
-                    sub.take_cmd().reply_inner_inner_array(buf)?;
-                }
-                Ok(buf.len() - begin)
-            } else {
-                debug!("subs is empty");
-                buf.extend_from_slice(BYTES_NULL_ARRAY);
-                Ok(BYTES_NULL_ARRAY.len())
-            }
+            self.reply_scan(buf)
         } else if self.cmd_type.is_del()
             || self.cmd_type.is_exists()
             || self.cmd_type.is_count_all()
@@ -602,14 +980,66 @@ impl Command {
                 buf.extend_from_slice(BYTES_CRLF);
                 Ok(buf.len() - begin)
             } else {
-                buf.extend_from_slice(BYTES_ZERO_INT);
-                Ok(BYTES_ZERO_INT.len())
+                Ok(Synthetic::ZeroInt.save(buf))
             }
+        } else if let Some(synthetic) = self.synthetic().filter(|x| self.is_replied_by(*x)) {
+            Ok(synthetic.save(buf))
         } else {
             self.reply_raw(buf)
         }
     }
 
+    // is_replied_by tells if the reply of the command is still the synthetic one, the errors replied
+    // in place of it, e.g. NOAUTH, are kept
+    fn is_replied_by(&self, synthetic: Synthetic) -> bool {
+        self.reply.as_ref().map(|x| x.data.as_ref()) == Some(synthetic.data())
+    }
+
+    // synthetic returns the reply the proxy makes itself to the control command, if any
+    fn synthetic(&self) -> Option<Synthetic> {
+        if !self.cmd_type.is_ctrl() {
+            return None;
+        }
+        match self.req.nth(COMMAND_POS)? {
+            BYTES_CMD_PING => Some(Synthetic::Pong),
+            BYTES_CMD_COMMAND => Some(Synthetic::NullArray),
+            _ => None,
+        }
+    }
+
+    // hello_reply replies the HELLO handshake. The replies of the backends are forwarded as they are, so
+    // only RESP2 is spoken to the clients and the ones asking for RESP3 are replied NOPROTO to fall back.
+    fn hello_reply(&self) -> Message {
+        if let Some(protover) = self.req.nth(1) {
+            if btoi::<u64>(protover) != Ok(2) {
+                return AsError::NoProto.into_reply();
+            }
+        }
+
+        // the clients are not authenticated by the proxy, and their names are accepted but not kept
+        let mut pos = 2;
+        while let Some(option) = self.req.nth(pos) {
+            match option.to_ascii_uppercase().as_slice() {
+                BYTES_AUTH => return AsError::RequestNotSupport.into_reply(),
+                BYTES_SETNAME if self.req.nth(pos + 1).is_some() => pos += 2,
+                BYTES_SETNAME => return AsError::WrongArity.into_reply(),
+                _ => return AsError::BadRequest.into_reply(),
+            }
+        }
+
+        let version = env!("CARGO_PKG_VERSION");
+        let reply = format!(
+            "*8\r\n$6\r\nserver\r\n$5\r\nredis\r\n$5\r\nproxy\r\n$6\r\nrepust\r\n$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:2\r\n",
+            version.len(),
+            version
+        );
+        let mut data = BytesMut::from(reply.as_bytes());
+        match MessageMut::parse(&mut data) {
+            Ok(Some(msg)) => msg.into(),
+            _ => unreachable!("hello reply must be a complete message"),
+        }
+    }
+
     fn reply_raw(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
         self.reply
             .as_ref()
@@ -629,25 +1059,102 @@ impl Command {
         Ok(size)
     }
 
-    fn reply_inner_inner_array(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
-        let mut size = 0usize;
-        if let Some(reply) = &self.reply {
-            if let RespType::Array(_, subs) = &reply.resp_type {
-                if subs.len() == 2 {
-                    let inner_arr = &subs[1];
-                    if let RespType::Array(_, subs) = inner_arr {
-                        for sub in subs {
-                            size += reply.save_by_resp_type(sub, buf);
-                        }
+    // reply_msetnx replies 1 once all the pairs of MSETNX are set, and 0 if none is as some of its keys
+    // exist. The errors of the subs are replied as they are.
+    fn reply_msetnx(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
+        let subs = match self.subs.as_ref() {
+            Some(subs) => subs,
+            None => return self.reply_raw(buf),
+        };
+
+        let mut set = 0;
+        for sub in subs {
+            let sub = sub.take_cmd();
+            match sub.reply.as_ref() {
+                Some(reply) if matches!(reply.resp_type, RespType::Integer(_)) => {
+                    if reply.nth(0) == Some(b"1") {
+                        set += 1;
                     }
                 }
+                _ => return sub.reply_raw(buf),
             }
         }
-        Ok(size)
+
+        if self.is_checking_keys() || set == 0 {
+            Ok(Synthetic::ZeroInt.save(buf))
+        } else if set == subs.len() {
+            Ok(Synthetic::OneInt.save(buf))
+        } else {
+            // the other keys were set by other clients between their check and the set of the pairs
+            let reply: Message = AsError::MSetNxPartial.into_reply();
+            Ok(reply.save(buf))
+        }
     }
+
+    // reply_scan replies the page of the backend with the cursor of the cluster, resuming the next backend
+    // once the cursor of this one is complete, or 0 once the one of the last backend is. The cursor holds
+    // the index of the backend in the ring, so a ring reloaded mid-scan resumes whichever backend is at
+    // that index then, and the keys may be skipped or repeated.
+    fn reply_scan(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
+        let reply = self.reply.as_ref().ok_or(AsError::BadReply)?;
+        let node = match self.scan_cursor() {
+            Some((node, _)) if !self.is_replied_by(Synthetic::ScanDone) => node,
+            _ => return self.reply_raw(buf),
+        };
+        let page = match &reply.resp_type {
+            RespType::Array(_, page) if page.len() == 2 => page,
+            _ => return self.reply_raw(buf),
+        };
+        let cursor = reply
+            .nth(0)
+            .and_then(|x| btoi::<u64>(x).ok())
+            .ok_or(AsError::BadReply)?;
+        let next = match cursor {
+            0 if self.flags.contains(CmdFlags::SCAN_LAST) => Some(0),
+            0 => pack_scan_cursor(node + 1, 0),
+            cursor => pack_scan_cursor(node, cursor),
+        }
+        .ok_or(AsError::BadReply)?;
+
+        let begin = buf.len();
+        buf.extend_from_slice(BYTES_LEN2_HEAD);
+        let next = next.to_string();
+        buf.extend_from_slice(BYTES_BULK_STRING);
+        itoa(next.len(), buf);
+        buf.extend_from_slice(BYTES_CRLF);
+        buf.extend_from_slice(next.as_bytes());
+        buf.extend_from_slice(BYTES_CRLF);
+        reply.save_by_resp_type(&page[1], buf);
+        Ok(buf.len() - begin)
+    }
+
+    // scan_cursor returns the index of the backend the SCAN resumes and the cursor of that backend,
+    // None if the command is not a SCAN or its cursor is not an integer
+    fn scan_cursor(&self) -> Option<(usize, u64)> {
+        if !self.cmd_type.is_scan() {
+            return None;
+        }
+        let cursor = btoi::<u64>(self.req.nth(1)?).ok()?;
+        Some(((cursor & SCAN_NODE_MASK) as usize, cursor >> SCAN_NODE_BITS))
+    }
+}
+
+// SCAN_NODE_BITS are the low bits of the SCAN cursors replied to the clients holding the index of the
+// backend, the high ones holding the cursor of that backend. The backends walk their hash tables with
+// cursors below the table size, so the 54 bits left are plenty.
+const SCAN_NODE_BITS: u32 = 10;
+const SCAN_NODE_MASK: u64 = (1 << SCAN_NODE_BITS) - 1;
+
+// pack_scan_cursor returns the cursor of the cluster resuming the given backend at its cursor, None if
+// they don't fit
+fn pack_scan_cursor(node: usize, cursor: u64) -> Option<u64> {
+    if node as u64 > SCAN_NODE_MASK || cursor.leading_zeros() < SCAN_NODE_BITS {
+        return None;
+    }
+    Some(cursor << SCAN_NODE_BITS | node as u64)
 }
 
-const BYTES_ASK: &[u8] = b"*1\r\n$3\r\nASK\r\n";
+const BYTES_ASKING: &[u8] = b"*1\r\n$6\r\nASKING\r\n";
 const BYTES_GET: &[u8] = b"$3\r\nGET\r\n";
 const BYTES_LEN2_HEAD: &[u8] = b"*2\r\n";
 const BYTES_LEN3_HEAD: &[u8] = b"*3\r\n";
@@ -657,7 +1164,7 @@ impl Command {
     /// save redis Command into given BytesMut
     pub fn send_req(&self, buf: &mut BytesMut) -> Result<(), AsError> {
         if self.is_ask() {
-            buf.extend_from_slice(BYTES_ASK);
+            buf.extend_from_slice(BYTES_ASKING);
         }
 
         if self.cmd_type.is_exists() || self.cmd_type.is_del() {
@@ -676,6 +1183,13 @@ impl Command {
                 }
             }
             return Ok(());
+        } else if let Some((_, cursor)) = self.scan_cursor() {
+            // the backend is sent its own cursor in place of the one of the cluster
+            let cursor = cursor.to_string();
+            let mut args: Vec<&[u8]> = (0..).map_while(|i| self.req.nth(i)).collect();
+            args[1] = cursor.as_bytes();
+            buf.extend_from_slice(&Message::array(&args).data);
+            return Ok(());
         } else if self.cmd_type.is_mget() {
             buf.extend_from_slice(BYTES_LEN2_HEAD);
             buf.extend_from_slice(BYTES_GET);
@@ -706,6 +1220,33 @@ impl Command {
         }
     }
 
+    // cluster returns the name of the cluster the command is received by, empty if not set
+    fn cluster(&self) -> &str {
+        self.cluster.as_deref().unwrap_or_default()
+    }
+
+    // cluster_label shares the name of the cluster with the trackers of the command
+    fn cluster_label(&self) -> Arc<str> {
+        self.cluster.clone().unwrap_or_default()
+    }
+
+    // key returns the key of the command the command is routed by, if any
+    fn key(&self) -> Option<&[u8]> {
+        self.req.nth(self.key_pos())
+    }
+
+    // is_verb_of checks if the command is one of the given ones, ignoring the case of its name
+    fn is_verb_of(&self, verbs: &[&[u8]]) -> bool {
+        self.req
+            .nth(COMMAND_POS)
+            .is_some_and(|verb| verbs.iter().any(|x| verb.eq_ignore_ascii_case(x)))
+    }
+
+    // key_len returns the length of the key of the command, zero if there is no key
+    fn key_len(&self) -> usize {
+        self.req.nth(self.key_pos()).map_or(0, |key| key.len())
+    }
+
     #[inline(always)]
     fn key_pos(&self) -> usize {
         if self.cmd_type.is_eval() {
@@ -726,6 +1267,77 @@ impl Command {
         self.subs = subs;
     }
 
+    // next_subs sets the pairs of MSETNX once its subs checking the keys found none of them. The keys are
+    // checked and set by separate commands, so a concurrent writer setting some of them in between leaves
+    // the keys partially set, which is replied with MSetNxPartial.
+    fn next_subs(&mut self) -> Option<Vec<Cmd>> {
+        if !self.cmd_type.is_msetnx() || !self.is_checking_keys() || !self.is_done() {
+            return None;
+        }
+        let missing = self.subs.as_ref()?.iter().all(|sub| {
+            let sub = sub.take_cmd();
+            sub.reply.as_ref().and_then(|x| x.nth(0)) == Some(b"0")
+        });
+        if !missing {
+            return None;
+        }
+
+        // each pair is set by a MSETNX of its own, so a key set by another client since its check is
+        // not overwritten
+        let subs: Vec<Cmd> = self
+            .msetnx_pairs()
+            .into_iter()
+            .map(|(key, value)| self.mk_sub(Message::array(&[BYTES_CMD_MSETNX, key, value])))
+            .collect();
+        self.subs = Some(subs.clone());
+        Some(subs)
+    }
+
+    // msetnx_pairs returns the pairs of MSETNX with a single one per key, holding the last value of the
+    // key as redis would set it. A repeated key would otherwise be found by its own earlier set.
+    fn msetnx_pairs(&self) -> Vec<(&[u8], &[u8])> {
+        let mut pairs: Vec<(&[u8], &[u8])> = Vec::new();
+        let mut positions: HashMap<&[u8], usize> = HashMap::new();
+        let all = (KEY_RAW_POS..)
+            .step_by(2)
+            .map_while(|i| Some((self.req.nth(i)?, self.req.nth(i + 1)?)));
+        for (key, value) in all {
+            match positions.get(key) {
+                Some(&pos) => pairs[pos].1 = value,
+                None => {
+                    positions.insert(key, pairs.len());
+                    pairs.push((key, value));
+                }
+            }
+        }
+        pairs
+    }
+
+    // is_checking_keys tells if MSETNX is still checking its keys with EXISTS rather than setting them
+    fn is_checking_keys(&self) -> bool {
+        self.subs
+            .as_ref()
+            .and_then(|subs| subs.first())
+            .is_some_and(|sub| sub.take_cmd().req.nth(COMMAND_POS) == Some(BYTES_CMD_EXISTS))
+    }
+
+    // mk_sub creates a sub command of the command sending the given request
+    fn mk_sub(&self, req: Message) -> Cmd {
+        let command = Command {
+            flags: CmdFlags::empty(),
+            cmd_type: self.cmd_type,
+            cycle: DEFAULT_CYCLE,
+            req,
+            reply: None,
+            subs: None,
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: self.deadline,
+            cluster: self.cluster.clone(),
+        };
+        command.into_cmd()
+    }
+
     pub fn is_done(&self) -> bool {
         if self.subs.is_some() {
             return self
@@ -838,21 +1450,164 @@ impl Command {
     pub fn req(&self) -> &Message {
         &self.req
     }
+
+    // parse_proxy_cmd parses the subcommand and arguments of a PROXY or a CLIENT command
+    fn parse_proxy_cmd(&self) -> Result<ProxyCmd, AsError> {
+        if self.cmd_type.is_client() {
+            return self.parse_client_cmd().map(ProxyCmd::Client);
+        }
+
+        let read_only = match self.req.nth(COMMAND_POS) {
+            Some(BYTES_CMD_READONLY) => Some(true),
+            Some(BYTES_CMD_READWRITE) => Some(false),
+            _ => None,
+        };
+        if let Some(read_only) = read_only {
+            if self.req.nth(1).is_some() {
+                return Err(AsError::WrongArity);
+            }
+            return Ok(ProxyCmd::ReadOnly(read_only));
+        }
+
+        let mut sub_cmd = self.req.nth(1).ok_or(AsError::WrongArity)?.to_vec();
+        upper(&mut sub_cmd);
+
+        if sub_cmd == BYTES_TIMEOUT {
+            if self.req.nth(3).is_some() {
+                return Err(AsError::WrongArity);
+            }
+            let millis = self.req.nth(2).ok_or(AsError::WrongArity)?;
+            let millis = btoi::<u64>(millis).map_err(|_| AsError::BadRequest)?;
+            return Ok(ProxyCmd::Timeout(millis));
+        }
+
+        if sub_cmd == BYTES_WHERE {
+            if self.req.nth(3).is_some() {
+                return Err(AsError::WrongArity);
+            }
+            let key = self.req.nth(2).ok_or(AsError::WrongArity)?;
+            return Ok(ProxyCmd::Where(key.to_vec()));
+        }
+
+        if sub_cmd == BYTES_EXPLAIN {
+            return self.parse_explain().map(ProxyCmd::Explain);
+        }
+
+        Err(AsError::RequestNotSupport)
+    }
+
+    // parse_client_cmd parses the subcommand and arguments of a CLIENT command. The connections are the
+    // ones of the proxy, so the subcommands other than INFO, LIST and SETNAME are acknowledged as is.
+    fn parse_client_cmd(&self) -> Result<ClientCmd, AsError> {
+        let mut sub_cmd = self.req.nth(1).ok_or(AsError::WrongArity)?.to_vec();
+        upper(&mut sub_cmd);
+
+        if sub_cmd == BYTES_INFO {
+            if self.req.nth(2).is_some() {
+                return Err(AsError::WrongArity);
+            }
+            return Ok(ClientCmd::Info);
+        }
+
+        // the filters of LIST are ignored
+        if sub_cmd == BYTES_LIST {
+            return Ok(ClientCmd::List);
+        }
+
+        if sub_cmd == BYTES_SETNAME {
+            if self.req.nth(3).is_some() {
+                return Err(AsError::WrongArity);
+            }
+            let name = self.req.nth(2).ok_or(AsError::WrongArity)?;
+            // the names are listed separated by spaces, so they can not contain any
+            if name.iter().any(|x| x.is_ascii_whitespace()) {
+                return Err(AsError::BadRequest);
+            }
+            return Ok(ClientCmd::SetName(
+                String::from_utf8_lossy(name).into_owned(),
+            ));
+        }
+
+        Ok(ClientCmd::Other)
+    }
+
+    // parse_explain parses the command given to PROXY EXPLAIN as if it was received from the client
+    fn parse_explain(&self) -> Result<Explain, AsError> {
+        let args: Vec<&[u8]> = (2..).map_while(|i| self.req.nth(i)).collect();
+        if args.is_empty() {
+            return Err(AsError::WrongArity);
+        }
+        let mut src = BytesMut::from(&Message::array(&args).data[..]);
+        let cmd = Command::parse_cmd(&mut src, usize::MAX, usize::MAX, &self.cluster_label())?
+            .ok_or(AsError::BadRequest)?;
+
+        let cmd_type = cmd.take_cmd().cmd_type;
+        if cmd_type.is_not_support() || cmd_type.is_proxy() || cmd_type.is_client() {
+            return Err(AsError::RequestNotSupport);
+        }
+        if cmd.is_error() {
+            return Err(AsError::BadRequest);
+        }
+
+        let kind = if cmd.is_write() {
+            "write"
+        } else if cmd.is_read() {
+            "read"
+        } else {
+            "other"
+        };
+        let key = |cmd: &Cmd| cmd.take_cmd().key().map(|x| x.to_vec());
+        let keys = match cmd.subs() {
+            Some(subs) => subs.iter().filter_map(key).collect(),
+            None => key(&cmd).into_iter().collect(),
+        };
+        let command = String::from_utf8_lossy(args[0]).to_uppercase();
+        Ok(Explain {
+            command,
+            kind,
+            cmd_type,
+            keys,
+        })
+    }
 }
 
 impl Command {
-    fn mk_mset(flags: CmdFlags, ctype: CmdType, msg: Message) -> Cmd {
+    fn mk_mset(
+        flags: CmdFlags,
+        ctype: CmdType,
+        msg: Message,
+        max_keys: usize,
+        cluster: &Arc<str>,
+    ) -> Cmd {
         let Message { resp_type, data } = msg.clone();
         if let RespType::Array(head, array) = resp_type {
             let array_len = array.len();
 
-            if array_len > MAX_KEY_COUNT {
-                // TODO: forbidden large request
-                unimplemented!();
+            // MSET must be followed by at least one key and value pair
+            if array_len < 3 || array_len % 2 == 0 {
+                return Command::mk_rejected(
+                    flags,
+                    ctype,
+                    msg,
+                    AsError::WrongArity,
+                    RejectReason::Arity,
+                    cluster,
+                );
             }
 
+            // the commands with too many pairs are rejected before their subs are allocated
             let cmd_count = array_len / 2;
-            let mut subs = Vec::with_capacity(cmd_count / 2);
+            if cmd_count > max_keys {
+                return Command::mk_rejected(
+                    flags,
+                    ctype,
+                    msg,
+                    AsError::BadRequest,
+                    RejectReason::TooManyKeys,
+                    cluster,
+                );
+            }
+            let mut subs = Vec::with_capacity(cmd_count);
 
             for chunk in array[1..].chunks(2) {
                 let key = chunk[0].clone();
@@ -872,6 +1627,8 @@ impl Command {
                     subs: None,
                     total_tracker: None,
                     remote_tracker: None,
+                    deadline: None,
+                    cluster: Some(cluster.clone()),
                 };
 
                 subs.push(sub_cmd.into_cmd());
@@ -886,6 +1643,8 @@ impl Command {
                 reply: None,
                 total_tracker: None,
                 remote_tracker: None,
+                deadline: None,
+                cluster: Some(cluster.clone()),
             };
             command.into_cmd()
         } else {
@@ -898,19 +1657,77 @@ impl Command {
                 subs: None,
                 total_tracker: None,
                 remote_tracker: None,
+                deadline: None,
+                cluster: Some(cluster.clone()),
             };
             let cmd = cmd.into_cmd();
             cmd.set_reply(&AsError::RequestInlineWithMultiKeys);
+            command_rejected_incr(cluster, RejectReason::Unsupported);
             cmd
         }
     }
 
-    fn mk_subs(flags: CmdFlags, cmd_type: CmdType, msg: Message) -> Cmd {
+    // mk_msetnx creates MSETNX checking all of its keys with EXISTS first, its pairs are only set once
+    // none of them is found. It is not atomic, as the keys may be on different backends, so a concurrent
+    // writer may leave its keys partially set.
+    fn mk_msetnx(
+        flags: CmdFlags,
+        ctype: CmdType,
+        msg: Message,
+        max_keys: usize,
+        cluster: &Arc<str>,
+    ) -> Cmd {
+        let cmd = Command::mk_mset(flags, ctype, msg, max_keys, cluster);
+        {
+            let mut command = cmd.take_cmd_mut();
+            let checks = command.subs.as_ref().map(|_| {
+                command
+                    .msetnx_pairs()
+                    .into_iter()
+                    .map(|(key, _)| command.mk_sub(Message::array(&[BYTES_CMD_EXISTS, key])))
+                    .collect()
+            });
+            command.subs = checks;
+        }
+        cmd
+    }
+
+    fn mk_subs(
+        flags: CmdFlags,
+        cmd_type: CmdType,
+        msg: Message,
+        max_keys: usize,
+        cluster: &Arc<str>,
+    ) -> Cmd {
         let Message { resp_type, data } = msg.clone();
         if let RespType::Array(head, array) = resp_type {
             let array_len = array.len();
             // TODO: maybe checking for the huge response size would be a good idea
 
+            // multi keys commands must be followed by at least one key
+            if array_len < 2 {
+                return Command::mk_rejected(
+                    flags,
+                    cmd_type,
+                    msg,
+                    AsError::WrongArity,
+                    RejectReason::Arity,
+                    cluster,
+                );
+            }
+
+            // the commands with too many keys are rejected before their subs are allocated
+            if array_len - 1 > max_keys {
+                return Command::mk_rejected(
+                    flags,
+                    cmd_type,
+                    msg,
+                    AsError::BadRequest,
+                    RejectReason::TooManyKeys,
+                    cluster,
+                );
+            }
+
             let mut subs = Vec::with_capacity(array_len - 1);
             for key in &array[1..] {
                 let sub = Message {
@@ -927,6 +1744,8 @@ impl Command {
                     subs: None,
                     total_tracker: None,
                     remote_tracker: None,
+                    deadline: None,
+                    cluster: Some(cluster.clone()),
                 };
 
                 subs.push(sub_cmd.into_cmd());
@@ -941,6 +1760,8 @@ impl Command {
                 subs: Some(subs),
                 total_tracker: None,
                 remote_tracker: None,
+                deadline: None,
+                cluster: Some(cluster.clone()),
             };
             cmd.into_cmd()
         } else {
@@ -953,52 +1774,82 @@ impl Command {
                 subs: None,
                 total_tracker: None,
                 remote_tracker: None,
+                deadline: None,
+                cluster: Some(cluster.clone()),
             };
             let cmd = cmd.into_cmd();
             cmd.set_reply(&AsError::RequestInlineWithMultiKeys);
+            command_rejected_incr(cluster, RejectReason::Unsupported);
             cmd
         }
     }
+
+    // mk_rejected creates a command which is already replied with the given error and records the rejection
+    fn mk_rejected(
+        flags: CmdFlags,
+        cmd_type: CmdType,
+        msg: Message,
+        err: AsError,
+        reason: RejectReason,
+        cluster: &Arc<str>,
+    ) -> Cmd {
+        let cmd = Command {
+            flags,
+            cycle: DEFAULT_CYCLE,
+            cmd_type,
+            req: msg,
+            reply: None,
+            subs: None,
+            total_tracker: None,
+            remote_tracker: None,
+            deadline: None,
+            cluster: Some(cluster.clone()),
+        };
+        let cmd = cmd.into_cmd();
+        cmd.set_reply(&err);
+        command_rejected_incr(cluster, reason);
+        cmd
+    }
 }
 
 const COMMAND_POS: usize = 0;
 const KEY_EVAL_POS: usize = 3;
 const KEY_RAW_POS: usize = 1;
 const KEY_MEMORY_POS: usize = 2;
-const MAX_KEY_COUNT: usize = 10000;
+const VALUE_SET_POS: usize = 2;
+const OFFSET_SETRANGE_POS: usize = 2;
 
 impl From<MessageMut> for Cmd {
-    fn from(mut msg_mut: MessageMut) -> Cmd {
+    fn from(msg_mut: MessageMut) -> Cmd {
+        Command::from_msg(msg_mut, usize::MAX, &Arc::default())
+    }
+}
+
+impl Command {
+    // from_msg creates the command of the request of a client of the cluster, rejecting the multi-key
+    // commands with more than max_keys keys, or MSET pairs, before splitting them into one sub command
+    // for each
+    fn from_msg(mut msg_mut: MessageMut, max_keys: usize, cluster: &Arc<str>) -> Cmd {
         // upper the given command
         if let Some(data) = msg_mut.nth_mut(COMMAND_POS) {
             upper(data);
         } else {
-            let msg = msg_mut.into();
-            let ctype = CmdType::NotSupport;
-            let flags = CmdFlags::empty();
-            let command = Command {
-                flags,
-                cmd_type: ctype,
-                cycle: DEFAULT_CYCLE,
-                req: msg,
-                reply: None,
-                subs: None,
-                total_tracker: None,
-                remote_tracker: None,
-            };
-            let cmd: Cmd = command.into_cmd();
-            cmd.set_reply(AsError::RequestNotSupport);
-            return cmd;
+            return Command::not_supported(msg_mut.into(), cluster);
         }
 
         let msg = msg_mut.into();
         let ctype = CmdType::get_cmd_type(&msg);
         let flags = CmdFlags::empty();
+        if ctype.is_not_support() {
+            cmd::unknown_cmd(cluster, &msg);
+        }
 
         if ctype.is_exists() || ctype.is_del() || ctype.is_mget() {
-            return Command::mk_subs(flags, ctype, msg);
+            return Command::mk_subs(flags, ctype, msg, max_keys, cluster);
         } else if ctype.is_mset() {
-            return Command::mk_mset(flags, ctype, msg);
+            return Command::mk_mset(flags, ctype, msg, max_keys, cluster);
+        } else if ctype.is_msetnx() {
+            return Command::mk_msetnx(flags, ctype, msg, max_keys, cluster);
         }
 
         let mut cmd = Command {
@@ -1010,53 +1861,214 @@ impl From<MessageMut> for Cmd {
             subs: None,
             total_tracker: None,
             remote_tracker: None,
+            deadline: None,
+            cluster: Some(cluster.clone()),
         };
-        if ctype.is_ctrl() {
-            if let Some(data) = msg.nth(COMMAND_POS) {
-                if data == BYTES_CMD_PING {
-                    cmd.set_reply(STR_REPLY_PONG);
-                    cmd.unset_error();
-                } else if data == BYTES_CMD_COMMAND {
-                    cmd.set_reply(BYTES_REPLY_NULL_ARRAY);
-                    cmd.unset_error();
-                } else {
-                    // unsupported commands
-                    trace!("unsupported commands");
-                }
+        match cmd.synthetic() {
+            Some(synthetic) => {
+                cmd.set_reply(synthetic.reply());
+                cmd.unset_error();
             }
+            // unsupported commands
+            None if ctype.is_ctrl() => trace!("unsupported commands"),
+            None => {}
         }
         cmd.into_cmd()
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct RedisHandleCodec {}
+// Synthetic is a reply the proxy makes itself rather than forwarding the one of a backend. The clients
+// speak RESP2 just like the backends, so it is made in the same form a backend would reply it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Synthetic {
+    Ok,
+    Pong,
+    NullArray,
+    ZeroInt,
+    OneInt,
+    // ScanDone is the empty last page of a SCAN walked past the last backend
+    ScanDone,
+}
+
+impl Synthetic {
+    fn data(self) -> &'static [u8] {
+        match self {
+            Synthetic::Ok => b"+OK\r\n",
+            Synthetic::Pong => b"+PONG\r\n",
+            Synthetic::NullArray => b"*-1\r\n",
+            Synthetic::ZeroInt => b":0\r\n",
+            Synthetic::ScanDone => b"*2\r\n$1\r\n0\r\n*0\r\n",
+            Synthetic::OneInt => b":1\r\n",
+        }
+    }
+
+    // save writes the reply and returns its length
+    fn save(self, buf: &mut BytesMut) -> usize {
+        let data = self.data();
+        buf.extend_from_slice(data);
+        data.len()
+    }
+
+    // reply returns the reply as the message of a command, as if a backend replied it
+    fn reply(self) -> Message {
+        let mut data = BytesMut::from(self.data());
+        match MessageMut::parse(&mut data) {
+            Ok(Some(msg)) => msg.into(),
+            _ => unreachable!("synthetic reply must be a complete message"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RedisHandleCodec {
+    // max_multibulk_len is the maximum number of elements of the request arrays
+    max_multibulk_len: usize,
+
+    // max_keys_per_cmd is the maximum number of the keys, or MSET pairs, of a multi-key command
+    max_keys_per_cmd: usize,
+
+    // strip_command_prefix is the token removed from the command verbs of the legacy clients
+    strip_command_prefix: Option<Vec<u8>>,
+
+    // command_renames maps the tokens the clients use to the verbs of the renamed commands
+    command_renames: HashMap<Vec<u8>, Vec<u8>>,
+
+    // cluster is the name of the cluster of the clients, labelling the metrics of their commands
+    cluster: Arc<str>,
+
+    // info_overrides maps the INFO fields to the values they are replied with, hidden if empty
+    info_overrides: HashMap<String, String>,
+}
+
+impl Default for RedisHandleCodec {
+    fn default() -> Self {
+        RedisHandleCodec {
+            max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN,
+            max_keys_per_cmd: usize::MAX,
+            strip_command_prefix: None,
+            command_renames: HashMap::new(),
+            cluster: Arc::default(),
+            info_overrides: HashMap::new(),
+        }
+    }
+}
+
+impl RedisHandleCodec {
+    fn decode_cmd(&mut self, src: &mut BytesMut) -> Result<Option<Cmd>, AsError> {
+        if self.strip_command_prefix.is_none() && self.command_renames.is_empty() {
+            return Command::parse_cmd(
+                src,
+                self.max_multibulk_len,
+                self.max_keys_per_cmd,
+                &self.cluster,
+            );
+        }
+        let mut msg = match MessageMut::parse_limited(src, self.max_multibulk_len)? {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+        if let Some(prefix) = self.strip_command_prefix.as_deref() {
+            msg = msg.strip_command_prefix(prefix);
+        }
+        if self.command_renames.is_empty() {
+            return Ok(Some(Command::from_msg(
+                msg,
+                self.max_keys_per_cmd,
+                &self.cluster,
+            )));
+        }
+
+        // the renamed commands are only reached by their new tokens
+        let verb = msg.nth(COMMAND_POS).map(|x| x.to_ascii_uppercase());
+        if let Some(verb) = verb {
+            if !self.command_renames.contains_key(&verb)
+                && self.command_renames.values().any(|x| *x == verb)
+            {
+                return Ok(Some(Command::not_supported(msg.into(), &self.cluster)));
+            }
+        }
+        let renames = &self.command_renames;
+        let msg = msg.replace_verb(|verb| renames.get(&verb.to_ascii_uppercase()).cloned());
+        Ok(Some(Command::from_msg(
+            msg,
+            self.max_keys_per_cmd,
+            &self.cluster,
+        )))
+    }
+}
 
 impl Decoder for RedisHandleCodec {
     type Item = Cmd;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Command::parse_cmd(src)
+        // the requests which can not be parsed are counted apart from the errors of the commands
+        self.decode_cmd(src)
+            .inspect_err(|_| framing_error_incr(&self.cluster, "front"))
     }
 }
 
 impl Encoder<Cmd> for RedisHandleCodec {
     type Error = AsError;
     fn encode(&mut self, item: Cmd, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if !self.info_overrides.is_empty() {
+            item.change_info_resp(&self.info_overrides);
+        }
         let _ = item.take_cmd().reply_cmd(dst)?;
         Ok(())
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct RedisNodeCodec {}
+#[derive(Clone, Debug)]
+pub struct RedisNodeCodec {
+    // max_bulk_len is the maximum length of the bulk strings of the replies
+    max_bulk_len: usize,
+
+    // discard is the number of the bytes of an oversized reply which are yet to be dropped
+    discard: usize,
+
+    // cluster is the name of the cluster of the backend, labelling the framing errors
+    cluster: Arc<str>,
+}
+
+impl Default for RedisNodeCodec {
+    fn default() -> Self {
+        RedisNodeCodec {
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            discard: 0,
+            cluster: Arc::default(),
+        }
+    }
+}
 
 impl Decoder for RedisNodeCodec {
     type Item = Message;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let reply = MessageMut::parse(src)?;
-        Ok(reply.map(Into::into))
+        // the data of an oversized reply is dropped as it arrives rather than buffered
+        if self.discard > 0 {
+            let dropped = self.discard.min(src.len());
+            src.advance(dropped);
+            self.discard -= dropped;
+            if self.discard > 0 {
+                return Ok(None);
+            }
+        }
+
+        let is_bulk = src.first() == Some(&resp::RESP_BULK);
+        match MessageMut::parse_reply(src, self.max_bulk_len) {
+            Ok(reply) => Ok(reply.map(Into::into)),
+            // the header of the oversized bulk is consumed, so its data and CRLF are skipped to keep
+            // the connection in sync. The ones nested in arrays fail the connection instead.
+            Err(AsError::BulkTooLarge(len)) if is_bulk => {
+                warn!("dropping a reply bulk of {} bytes", len);
+                self.discard = len + 2;
+                Ok(Some(AsError::BulkTooLarge(len).into_reply()))
+            }
+            Err(err) => {
+                framing_error_incr(&self.cluster, "back");
+                Err(err)
+            }
+        }
     }
 }
 
@@ -1081,6 +2093,8 @@ pub fn new_read_only_cmd() -> Cmd {
         subs: None,
         total_tracker: None,
         remote_tracker: None,
+        deadline: None,
+        cluster: None,
     };
     cmd.into_cmd()
 }
@@ -1099,6 +2113,8 @@ pub fn new_cluster_slots_cmd() -> Cmd {
         subs: None,
         total_tracker: None,
         remote_tracker: None,
+        deadline: None,
+        cluster: None,
     };
     cmd.into_cmd()
 }
@@ -1117,6 +2133,8 @@ pub fn new_auth_cmd(auth: &str) -> Cmd {
         subs: None,
         total_tracker: None,
         remote_tracker: None,
+        deadline: None,
+        cluster: None,
     };
     cmd.into_cmd()
 }
@@ -1131,8 +2149,9 @@ pub fn slots_reply_to_replicas(cmd: Cmd) -> Result<Option<ReplicaLayout>, AsErro
         .expect("reply must be non-empty");
     match msg.resp_type {
         RespType::Array(_, ref subs) => {
-            let mut mrepusts = BTreeMap::<usize, String>::new();
-            let mut replicas = BTreeMap::<usize, HashSet<String>>::new();
+            // the slots are indexed by their number, the ones not served by any node are left empty
+            let mut mrepusts = vec![String::new(); SLOTS_COUNT];
+            let mut replicas = vec![Vec::new(); SLOTS_COUNT];
 
             let get_data = |index: usize, each: &[RespType]| -> Result<&[u8], AsError> {
                 let frg = msg
@@ -1157,41 +2176,39 @@ pub fn slots_reply_to_replicas(cmd: Cmd) -> Result<Option<ReplicaLayout>, AsErro
                 }
             };
 
+            let mut covered = 0;
             for sub in subs {
                 if let RespType::Array(_, ref subs) = sub {
                     let begin = get_number(0, subs)?;
                     let end = get_number(1, subs)?;
+                    if begin > end || end >= SLOTS_COUNT {
+                        return Err(AsError::WrongClusterSlotsReplySlot);
+                    }
                     let mrepust =
                         get_addr(subs.get(2).ok_or(AsError::WrongClusterSlotsReplyType)?)?;
-                    for i in begin..=end {
-                        mrepusts.insert(i, mrepust.clone());
+                    let mut replica_set = HashSet::new();
+                    for resp in subs.iter().skip(3) {
+                        replica_set.insert(get_addr(resp)?);
                     }
-                    for i in begin..=end {
-                        replicas.insert(i, HashSet::new());
-                    }
-                    if subs.len() > 3 {
-                        let mut replica_set = HashSet::new();
-                        for resp in subs.iter().skip(3) {
-                            let replica = get_addr(resp)?;
-                            replica_set.insert(replica);
-                        }
-                        for i in begin..=end {
-                            replicas.insert(i, replica_set.clone());
+                    let replica_list: Vec<String> = replica_set.into_iter().collect();
+                    let slots = mrepusts[begin..=end]
+                        .iter_mut()
+                        .zip(replicas[begin..=end].iter_mut());
+                    for (slot_mrepust, slot_replicas) in slots {
+                        if slot_mrepust.is_empty() {
+                            covered += 1;
                         }
+                        *slot_mrepust = mrepust.clone();
+                        *slot_replicas = replica_list.clone();
                     }
                 } else {
                     return Err(AsError::WrongClusterSlotsReplyType);
                 }
             }
-            if mrepusts.len() != SLOTS_COUNT {
+            if covered != SLOTS_COUNT {
                 warn!("slots is not full covered but ignore it");
             }
-            let mrepust_list = mrepusts.into_values().collect();
-            let replicas_list = replicas
-                .into_values()
-                .map(|v| v.into_iter().collect())
-                .collect();
-            Ok(Some((mrepust_list, replicas_list)))
+            Ok(Some((mrepusts, replicas)))
         }
         _ => Err(AsError::WrongClusterSlotsReplyType),
     }
@@ -1284,7 +2301,12 @@ fn test_redis_parse_wrong_case() {
             let mut src = BytesMut::from(&data[..]);
 
             loop {
-                let result = Command::parse_cmd(&mut src);
+                let result = Command::parse_cmd(
+                    &mut src,
+                    DEFAULT_MAX_MULTIBULK_LEN,
+                    usize::MAX,
+                    &Arc::default(),
+                );
                 match result {
                     Ok(Some(_)) => {}
                     Ok(None) => break,
@@ -1294,3 +2316,785 @@ fn test_redis_parse_wrong_case() {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{init_test_instruments, test_metric_value};
+    use cmd::{UNKNOWN_CMDS_MAX, UNKNOWN_CMD_OTHER};
+
+    fn parse_cmd(data: &[u8]) -> Cmd {
+        parse_cluster_cmd(data, "")
+    }
+
+    // parse_cluster_cmd parses the command as if received from a client of the given cluster
+    fn parse_cluster_cmd(data: &[u8], cluster: &str) -> Cmd {
+        init_test_instruments();
+        init_redis_supported_cmds();
+        let mut src = BytesMut::from(data);
+        Command::parse_cmd(
+            &mut src,
+            DEFAULT_MAX_MULTIBULK_LEN,
+            usize::MAX,
+            &Arc::from(cluster),
+        )
+        .expect("command must be parsed")
+        .expect("command must be completed")
+    }
+
+    const REJECT_REASONS: [RejectReason; 8] = [
+        RejectReason::Unsupported,
+        RejectReason::Arity,
+        RejectReason::KeyTooLong,
+        RejectReason::ReadOnly,
+        RejectReason::OffsetTooLarge,
+        RejectReason::TooManyKeys,
+        RejectReason::Compressed,
+        RejectReason::Auth,
+    ];
+
+    // assert_rejected checks the commands of the cluster are rejected the given number of times for each
+    // given reason, and never for the other ones
+    fn assert_rejected(cluster: &str, expected: &[(RejectReason, f64)]) {
+        init_test_instruments();
+        for reason in REJECT_REASONS {
+            let count = test_metric_value(
+                "repust_commands_rejected_total",
+                &[("cluster", cluster), ("reason", reason.as_str())],
+            );
+            let want = expected
+                .iter()
+                .find(|(x, _)| *x == reason)
+                .map_or(0.0, |(_, count)| *count);
+            assert_eq!(count, want, "rejections of {:?}", reason);
+        }
+    }
+
+    fn explain(args: &[&[u8]]) -> Result<Explain, AsError> {
+        let mut req: Vec<&[u8]> = vec![b"PROXY", b"EXPLAIN"];
+        req.extend_from_slice(args);
+        let cmd = parse_cmd(&Message::array(&req).data);
+        let parsed = cmd.take_cmd().parse_proxy_cmd();
+        parsed.map(|x| match x {
+            ProxyCmd::Explain(explain) => explain,
+            other => panic!("unexpected proxy command {:?}", other),
+        })
+    }
+
+    #[test]
+    fn test_proxy_explain() {
+        let get = explain(&[b"get", b"foo"]).unwrap();
+        assert_eq!(get.command, "GET");
+        assert_eq!(get.kind, "read");
+        assert_eq!(get.cmd_type, CmdType::Read);
+        assert_eq!(get.keys, vec![b"foo".to_vec()]);
+
+        let set = explain(&[b"SET", b"foo", b"bar"]).unwrap();
+        assert_eq!(set.kind, "write");
+        assert_eq!(set.keys, vec![b"foo".to_vec()]);
+
+        let mget = explain(&[b"MGET", b"a", b"b", b"c"]).unwrap();
+        assert_eq!(mget.kind, "read");
+        assert_eq!(mget.keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        let mset = explain(&[b"MSET", b"a", b"1", b"b", b"2"]).unwrap();
+        assert_eq!(mset.kind, "write");
+        assert_eq!(mset.keys, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        let ping = explain(&[b"PING"]).unwrap();
+        assert_eq!(ping.kind, "other");
+        assert!(ping.keys.is_empty());
+
+        assert_eq!(explain(&[]).unwrap_err(), AsError::WrongArity);
+        // the arity is left to the backend, so no key is explained
+        assert!(explain(&[b"GET"]).unwrap().keys.is_empty());
+        assert_eq!(
+            explain(&[b"SHUTDOWN"]).unwrap_err(),
+            AsError::RequestNotSupport
+        );
+        assert_eq!(
+            explain(&[b"PROXY", b"EXPLAIN", b"GET", b"foo"]).unwrap_err(),
+            AsError::RequestNotSupport
+        );
+    }
+
+    #[test]
+    fn test_client_cmd() {
+        let client = |args: &[&[u8]]| {
+            let mut req: Vec<&[u8]> = vec![b"CLIENT"];
+            req.extend_from_slice(args);
+            let cmd = parse_cmd(&Message::array(&req).data);
+            let parsed = cmd.take_cmd().parse_proxy_cmd();
+            parsed.map(|x| match x {
+                ProxyCmd::Client(client) => client,
+                other => panic!("unexpected proxy command {:?}", other),
+            })
+        };
+
+        assert_eq!(client(&[b"info"]), Ok(ClientCmd::Info));
+        assert_eq!(client(&[b"LIST"]), Ok(ClientCmd::List));
+        assert_eq!(client(&[b"LIST", b"TYPE", b"normal"]), Ok(ClientCmd::List));
+        assert_eq!(
+            client(&[b"SETNAME", b"app"]),
+            Ok(ClientCmd::SetName("app".to_string()))
+        );
+        assert_eq!(client(&[b"GETNAME"]), Ok(ClientCmd::Other));
+
+        assert_eq!(client(&[]), Err(AsError::WrongArity));
+        assert_eq!(client(&[b"INFO", b"x"]), Err(AsError::WrongArity));
+        assert_eq!(client(&[b"SETNAME"]), Err(AsError::WrongArity));
+        assert_eq!(client(&[b"SETNAME", b"a b"]), Err(AsError::BadRequest));
+
+        // CLIENT is answered by the proxy, never forwarded
+        let cmd = parse_cmd(b"*2\r\n$6\r\nCLIENT\r\n$4\r\nLIST\r\n");
+        assert!(cmd.check_valid());
+        assert_eq!(cmd.proxy_cmd(), Some(ProxyCmd::Client(ClientCmd::List)));
+    }
+
+    #[test]
+    fn test_pub_sub_replies() {
+        let reply = |data: &[u8]| -> Message {
+            let mut src = BytesMut::from(data);
+            MessageMut::parse(&mut src)
+                .expect("reply must be parsed")
+                .expect("reply must be completed")
+                .into()
+        };
+        let subscribed = reply(b"*3\r\n$10\r\npsubscribe\r\n$2\r\nc*\r\n:2\r\n");
+        let unsubscribed = reply(b"*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n");
+        let message = reply(b"*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\n42\r\n");
+        assert_eq!(Cmd::reply_subscriptions(&subscribed), Some(2));
+        assert_eq!(Cmd::reply_subscriptions(&unsubscribed), Some(0));
+        assert_eq!(Cmd::reply_subscriptions(&message), None);
+        assert_eq!(Cmd::reply_subscriptions(&reply(b":0\r\n")), None);
+
+        // the pushed messages are replied as they are
+        let cmd = Cmd::push_message(message.clone());
+        assert!(cmd.is_done());
+        let mut buf = BytesMut::new();
+        cmd.take_cmd().reply_cmd(&mut buf).unwrap();
+        assert_eq!(&buf[..], message.raw_data());
+
+        // SUBSCRIBE needs a channel, UNSUBSCRIBE drops all of them without one
+        let cluster = "test-pub-sub-replies";
+        let parse = |data: &[u8]| parse_cluster_cmd(data, cluster);
+        assert!(!parse(b"*1\r\n$9\r\nSUBSCRIBE\r\n").check_valid());
+        assert!(parse(b"*1\r\n$11\r\nUNSUBSCRIBE\r\n").check_valid());
+        assert!(parse(b"*2\r\n$10\r\nPSUBSCRIBE\r\n$2\r\nc*\r\n").check_valid());
+        assert_rejected(cluster, &[(RejectReason::Arity, 1.0)]);
+    }
+
+    fn encode(cmd: Cmd) -> Vec<u8> {
+        let mut codec = RedisHandleCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(cmd, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_synthetic_replies() {
+        let ping = b"*1\r\n$4\r\nPING\r\n";
+        assert_eq!(encode(parse_cmd(ping)), b"+PONG\r\n");
+
+        let null = Synthetic::NullArray;
+        assert_eq!(null.data(), b"*-1\r\n");
+        assert_eq!(null.reply().data.as_ref(), b"*-1\r\n");
+        let mset = parse_cmd(b"*3\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n");
+        assert_eq!(encode(mset), b"+OK\r\n");
+
+        // the command holds the reply as if a backend replied it
+        assert_eq!(
+            parse_cmd(ping)
+                .take_cmd()
+                .reply
+                .as_ref()
+                .unwrap()
+                .data
+                .as_ref(),
+            b"+PONG\r\n"
+        );
+
+        // an error set in place of the synthetic reply is replied as is
+        let cmd = parse_cmd(ping);
+        cmd.set_auth_wrong();
+        assert_eq!(encode(cmd), Message::from(AsError::AuthWrong).data.as_ref());
+    }
+
+    #[test]
+    fn test_hello_handshake() {
+        let hello = |args: &[&[u8]]| {
+            let mut req: Vec<&[u8]> = vec![b"HELLO"];
+            req.extend_from_slice(args);
+            let cmd = parse_cmd(&Message::array(&req).data);
+            assert!(!cmd.valid());
+            let reply = cmd.take_cmd().reply.clone().unwrap();
+            reply
+        };
+
+        let version = env!("CARGO_PKG_VERSION");
+        let reply = format!(
+            "*8\r\n$6\r\nserver\r\n$5\r\nredis\r\n$5\r\nproxy\r\n$6\r\nrepust\r\n$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:2\r\n",
+            version.len(),
+            version
+        );
+        assert_eq!(hello(&[]).data.as_ref(), reply.as_bytes());
+        assert_eq!(hello(&[b"2"]).data.as_ref(), reply.as_bytes());
+        assert_eq!(
+            hello(&[b"2", b"setname", b"app"]).data.as_ref(),
+            reply.as_bytes()
+        );
+
+        // the clients probing RESP3 fall back to RESP2
+        assert_eq!(hello(&[b"3"]), AsError::NoProto.into_reply());
+        assert_eq!(hello(&[b"x"]), AsError::NoProto.into_reply());
+
+        assert_eq!(
+            hello(&[b"2", b"AUTH", b"user", b"pass"]),
+            AsError::RequestNotSupport.into_reply()
+        );
+        assert_eq!(hello(&[b"2", b"SETNAME"]), AsError::WrongArity.into_reply());
+        assert_eq!(hello(&[b"2", b"FOO"]), AsError::BadRequest.into_reply());
+    }
+
+    #[test]
+    fn test_scan_cursor() {
+        let scan = parse_cmd(b"*2\r\n$4\r\nSCAN\r\n$4\r\n5121\r\n");
+        assert_eq!(scan.take_cmd().scan_cursor(), Some((1, 5)));
+        let mut buf = BytesMut::new();
+        scan.take_cmd().send_req(&mut buf).unwrap();
+        assert_eq!(buf.as_ref(), b"*2\r\n$4\r\nSCAN\r\n$1\r\n5\r\n");
+
+        // the bad cursors are left to the backends to reject
+        let scan = parse_cmd(b"*2\r\n$4\r\nSCAN\r\n$1\r\nx\r\n");
+        assert_eq!(scan.take_cmd().scan_cursor(), None);
+        let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\n0\r\n");
+        assert_eq!(get.take_cmd().scan_cursor(), None);
+
+        // the end of the cursor of a backend resumes the next one, unless it is the last
+        let page = |cmd: &Cmd| {
+            cmd.set_reply(Message::array(&[b"0", b"k"]));
+            let mut buf = BytesMut::new();
+            cmd.take_cmd().reply_cmd(&mut buf).unwrap();
+            buf.to_vec()
+        };
+        let scan = parse_cmd(b"*2\r\n$4\r\nSCAN\r\n$4\r\n5121\r\n");
+        assert_eq!(page(&scan), b"*2\r\n$1\r\n2\r\n$1\r\nk\r\n");
+        let scan = parse_cmd(b"*2\r\n$4\r\nSCAN\r\n$4\r\n5121\r\n");
+        scan.set_scan_last();
+        assert_eq!(page(&scan), b"*2\r\n$1\r\n0\r\n$1\r\nk\r\n");
+
+        assert_eq!(pack_scan_cursor(1, 5), Some(5121));
+        assert_eq!(pack_scan_cursor(1024, 0), None);
+        assert_eq!(pack_scan_cursor(0, u64::MAX >> 10), Some(u64::MAX - 1023));
+        assert_eq!(pack_scan_cursor(0, 1 << 54), None);
+    }
+
+    #[test]
+    fn test_msetnx() {
+        init_test_instruments();
+        let req = b"*5\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        let reply = |cmd: &Cmd, replies: &[usize]| {
+            for (sub, reply) in cmd.subs().unwrap().iter().zip(replies) {
+                sub.set_reply(*reply);
+            }
+        };
+
+        // the keys are checked first and the pairs are set once none of them is found
+        let msetnx = parse_cmd(req);
+        let checks: Vec<Vec<u8>> = msetnx.subs().unwrap().iter().map(sent).collect();
+        assert_eq!(
+            checks,
+            vec![
+                b"*2\r\n$6\r\nEXISTS\r\n$1\r\na\r\n".to_vec(),
+                b"*2\r\n$6\r\nEXISTS\r\n$1\r\nb\r\n".to_vec(),
+            ]
+        );
+        reply(&msetnx, &[0, 0]);
+        let sets: Vec<Vec<u8>> = msetnx.next_subs().unwrap().iter().map(sent).collect();
+        assert_eq!(
+            sets,
+            vec![
+                b"*3\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec(),
+                b"*3\r\n$6\r\nMSETNX\r\n$1\r\nb\r\n$1\r\n2\r\n".to_vec(),
+            ]
+        );
+        assert!(!msetnx.is_done());
+        reply(&msetnx, &[1, 1]);
+        assert!(msetnx.next_subs().is_none());
+        assert_eq!(encode(msetnx), b":1\r\n");
+
+        // nothing is set if any of the keys exists
+        let msetnx = parse_cmd(req);
+        reply(&msetnx, &[0, 1]);
+        assert!(msetnx.next_subs().is_none());
+        assert_eq!(encode(msetnx), b":0\r\n");
+
+        // a key set by another client between the check and the set fails the others
+        let msetnx = parse_cmd(req);
+        reply(&msetnx, &[0, 0]);
+        msetnx.next_subs().unwrap();
+        reply(&msetnx, &[1, 0]);
+        assert_eq!(
+            encode(msetnx),
+            b"-ERR MSETNX is not atomic across the backends, a concurrent writer set some of its keys after they were checked so only the others are set\r\n"
+        );
+
+        // a repeated key is checked and set once, with its last value
+        let msetnx = parse_cmd(
+            b"*7\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\n3\r\n",
+        );
+        let checks: Vec<Vec<u8>> = msetnx.subs().unwrap().iter().map(sent).collect();
+        assert_eq!(
+            checks,
+            vec![
+                b"*2\r\n$6\r\nEXISTS\r\n$1\r\na\r\n".to_vec(),
+                b"*2\r\n$6\r\nEXISTS\r\n$1\r\nb\r\n".to_vec(),
+            ]
+        );
+        reply(&msetnx, &[0, 0]);
+        let sets: Vec<Vec<u8>> = msetnx.next_subs().unwrap().iter().map(sent).collect();
+        assert_eq!(
+            sets,
+            vec![
+                b"*3\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n3\r\n".to_vec(),
+                b"*3\r\n$6\r\nMSETNX\r\n$1\r\nb\r\n$1\r\n2\r\n".to_vec(),
+            ]
+        );
+        reply(&msetnx, &[1, 1]);
+        assert_eq!(encode(msetnx), b":1\r\n");
+
+        let msetnx = parse_cmd(b"*2\r\n$6\r\nMSETNX\r\n$1\r\na\r\n");
+        assert!(msetnx.subs().is_none());
+        assert!(msetnx.is_done());
+    }
+
+    #[test]
+    fn test_ask_redirect_sends_asking() {
+        let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+        let mut src = BytesMut::from(&b"-ASK 12182 127.0.0.1:7001\r\n"[..]);
+        let reply = RedisNodeCodec::default()
+            .decode(&mut src)
+            .unwrap()
+            .expect("reply must be decoded");
+        match Cmd::reply_redirect(&reply) {
+            Some(Redirect::Ask { slot: 12182, to }) => assert_eq!(to, "127.0.0.1:7001"),
+            redirect => panic!("unexpected redirect {:?}", redirect),
+        }
+
+        // the command is retried on the target node behind ASKING
+        cmd.set_ask();
+        let mut dst = BytesMut::new();
+        RedisNodeCodec::default()
+            .encode(cmd.clone(), &mut dst)
+            .unwrap();
+        assert!(dst.starts_with(BYTES_ASKING));
+        assert_eq!(
+            &dst[BYTES_ASKING.len()..],
+            b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n"
+        );
+
+        // the flag is cleared once ASKING is replied
+        assert!(cmd.take_ask());
+        assert!(!cmd.take_ask());
+    }
+
+    #[test]
+    fn test_rejected_reason_label() {
+        let cluster = "test-rejected-reason-label";
+        let parse = |data: &[u8]| parse_cluster_cmd(data, cluster);
+
+        // the unknown commands are rejected once parsed and the unsupported ones once checked
+        let cmd = parse(b"*1\r\n$8\r\nSHUTDOWN\r\n");
+        assert!(!cmd.check_valid());
+        assert_rejected(cluster, &[(RejectReason::Unsupported, 1.0)]);
+        let cmd = parse(b"*2\r\n$4\r\nECHO\r\n$1\r\na\r\n");
+        assert!(!cmd.check_valid());
+        assert_rejected(cluster, &[(RejectReason::Unsupported, 2.0)]);
+
+        let cmd = parse(b"*1\r\n$3\r\nDEL\r\n");
+        assert!(cmd.is_done());
+        assert_eq!(cmd.take_cmd().reply, Some(AsError::WrongArity.into_reply()));
+        let cmd = parse(b"*2\r\n$4\r\nMSET\r\n$1\r\na\r\n");
+        assert!(cmd.is_done());
+        assert_rejected(
+            cluster,
+            &[(RejectReason::Unsupported, 2.0), (RejectReason::Arity, 2.0)],
+        );
+
+        // both the missing and the wrong passwords are auth rejections
+        let ping = b"*1\r\n$4\r\nPING\r\n";
+        parse(ping).set_no_auth();
+        parse(ping).set_auth_wrong();
+        assert_rejected(
+            cluster,
+            &[
+                (RejectReason::Unsupported, 2.0),
+                (RejectReason::Arity, 2.0),
+                (RejectReason::Auth, 2.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_max_key_bytes() {
+        let cluster = "test-max-key-bytes";
+        let parse_cmd = |data: &[u8]| parse_cluster_cmd(data, cluster);
+        let policy = Policy {
+            max_key_bytes: Some(4),
+            ..Default::default()
+        };
+
+        let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$4\r\nabcd\r\n");
+        assert!(cmd.check_policy(&policy));
+        assert!(!cmd.is_done());
+
+        let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$5\r\nabcde\r\n");
+        assert!(!cmd.check_policy(&policy));
+        assert_eq!(
+            cmd.take_cmd().reply,
+            Some(AsError::KeyTooLong(4).into_reply())
+        );
+        assert_rejected(cluster, &[(RejectReason::KeyTooLong, 1.0)]);
+
+        // every key of a multi-key command is checked
+        let cmd = parse_cmd(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$5\r\nabcde\r\n");
+        assert!(!cmd.check_policy(&policy));
+        assert_rejected(cluster, &[(RejectReason::KeyTooLong, 2.0)]);
+
+        // unlimited by default
+        let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$5\r\nabcde\r\n");
+        assert!(cmd.check_policy(&Policy::default()));
+    }
+
+    #[test]
+    fn test_max_keys_per_cmd() {
+        init_test_instruments();
+        init_redis_supported_cmds();
+        let cluster = "test-max-keys-per-cmd";
+        let mut codec = Cmd::front_codec(&Policy {
+            max_keys_per_cmd: Some(2),
+            cluster: Arc::from(cluster),
+            ..Default::default()
+        });
+        let mut decode = |req: &Message| {
+            let mut src = BytesMut::from(&req.data[..]);
+            codec.decode(&mut src).unwrap().unwrap()
+        };
+
+        let cmd = decode(&Message::array(&[b"MGET", b"a", b"b"]));
+        assert_eq!(cmd.subs().map(|x| x.len()), Some(2));
+        let cmd = decode(&Message::array(&[b"MSET", b"a", b"1", b"b", b"2"]));
+        assert_eq!(cmd.subs().map(|x| x.len()), Some(2));
+
+        // the commands with too many keys are rejected before being split into their subs
+        for req in [
+            Message::array(&[b"MGET", b"a", b"b", b"c"]),
+            Message::array(&[b"DEL", b"a", b"b", b"c"]),
+            Message::array(&[b"EXISTS", b"a", b"b", b"c"]),
+            Message::array(&[b"MSET", b"a", b"1", b"b", b"2", b"c", b"3"]),
+            Message::array(&[b"MSETNX", b"a", b"1", b"b", b"2", b"c", b"3"]),
+        ] {
+            let cmd = decode(&req);
+            assert!(cmd.subs().is_none());
+            assert_eq!(cmd.take_cmd().reply, Some(AsError::BadRequest.into_reply()));
+        }
+        assert_rejected(cluster, &[(RejectReason::TooManyKeys, 5.0)]);
+
+        // the huge commands are parsed to be rejected rather than failing the parser
+        let mut req: Vec<&[u8]> = vec![b"MSET"];
+        req.extend(std::iter::repeat_n([&b"k"[..], &b"v"[..]], 20000).flatten());
+        let cmd = decode(&Message::array(&req));
+        assert!(cmd.subs().is_none() && cmd.is_done());
+    }
+
+    #[test]
+    fn test_max_setrange_offset() {
+        let cluster = "test-max-setrange-offset";
+        let parse_cmd = |data: &[u8]| parse_cluster_cmd(data, cluster);
+        let policy = Policy {
+            max_setrange_offset: Some(1024),
+            ..Default::default()
+        };
+
+        let cmd = parse_cmd(b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$4\r\n1024\r\n$1\r\nv\r\n");
+        assert!(cmd.check_policy(&policy));
+
+        let cmd = parse_cmd(b"*4\r\n$8\r\nsetrange\r\n$1\r\nk\r\n$10\r\n4294967295\r\n$1\r\nv\r\n");
+        assert!(!cmd.check_policy(&policy));
+        assert_eq!(
+            cmd.take_cmd().reply,
+            Some(AsError::OffsetTooLarge(1024).into_reply())
+        );
+        assert_rejected(cluster, &[(RejectReason::OffsetTooLarge, 1.0)]);
+
+        // the other commands and the malformed offsets are not checked
+        let cmd = parse_cmd(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\n4096\r\n");
+        assert!(cmd.check_policy(&policy));
+        let cmd = parse_cmd(b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$2\r\n-1\r\n$1\r\nv\r\n");
+        assert!(cmd.check_policy(&policy));
+    }
+
+    #[test]
+    fn test_read_only_rejects_writes() {
+        let cluster = "test-read-only-rejects-writes";
+        let parse_cmd = |data: &[u8]| parse_cluster_cmd(data, cluster);
+        let policy = Policy {
+            read_only: true,
+            ..Default::default()
+        };
+
+        for req in [
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".as_slice(),
+            b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n",
+            b"*2\r\n$6\r\nEXISTS\r\n$1\r\nk\r\n",
+        ] {
+            let cmd = parse_cmd(req);
+            assert!(cmd.check_policy(&policy));
+            assert!(!cmd.is_done());
+        }
+
+        for req in [
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n".as_slice(),
+            b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n",
+            b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n",
+        ] {
+            let cmd = parse_cmd(req);
+            assert!(!cmd.check_policy(&policy));
+            assert_eq!(cmd.take_cmd().reply, Some(AsError::ReadOnly.into_reply()));
+        }
+        assert_rejected(cluster, &[(RejectReason::ReadOnly, 3.0)]);
+
+        // the writes are allowed by default
+        let cmd = parse_cmd(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+        assert!(cmd.check_policy(&Policy::default()));
+    }
+
+    #[test]
+    fn test_framing_errors_counted() {
+        init_test_instruments();
+        init_redis_supported_cmds();
+        let framing_errors =
+            |side| test_metric_value("repust_framing_errors_total", &[("side", side)]);
+
+        let before = framing_errors("front");
+        let mut codec = Cmd::front_codec(&Policy::default());
+        let mut src = BytesMut::from(&b"*1\r\n$x\r\n"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        assert!(framing_errors("front") >= before + 1.0);
+
+        // the errors replied by the backends are parsed as usual
+        let before = framing_errors("back");
+        let mut codec = Cmd::back_codec(&Policy::default());
+        let mut src = BytesMut::from(&b"-ERR unknown command\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        let mut src = BytesMut::from(&b"$blabla\r\n"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        assert!(framing_errors("back") >= before + 1.0);
+    }
+
+    #[test]
+    fn test_unknown_commands_counted() {
+        init_test_instruments();
+        init_redis_unknown_cmds_log();
+        let unknown = |cmd| test_metric_value("repust_unknown_command_total", &[("cmd", cmd)]);
+
+        // the verbs are counted upper cased, as they are resolved
+        let before = unknown("FROBNICATE");
+        let cmd = parse_cmd(b"*2\r\n$10\r\nfrobnicate\r\n$1\r\nk\r\n");
+        assert!(cmd.take_cmd().cmd_type.is_not_support());
+        assert_eq!(unknown("FROBNICATE"), before + 1.0);
+
+        // the supported commands are not
+        let before = unknown("GET");
+        parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        assert_eq!(unknown("GET"), before);
+
+        // the verbs past the first ones are counted as the other ones
+        for i in 0..UNKNOWN_CMDS_MAX {
+            let verb = format!("FROB{:02}", i);
+            parse_cmd(format!("*1\r\n$6\r\n{}\r\n", verb).as_bytes());
+        }
+        let before = (unknown("FROBNICATE"), unknown(UNKNOWN_CMD_OTHER));
+        parse_cmd(b"*1\r\n$10\r\nFROBNICATE\r\n");
+        parse_cmd(b"*1\r\n$7\r\nFROBBED\r\n");
+        assert_eq!(unknown("FROBNICATE"), before.0 + 1.0);
+        assert_eq!(unknown(UNKNOWN_CMD_OTHER), before.1 + 1.0);
+        assert_eq!(unknown("FROBBED"), 0.0);
+    }
+
+    #[test]
+    fn test_reply_bulk_too_large() {
+        init_test_instruments();
+        let policy = Policy {
+            max_reply_bulk_len: Some(4),
+            ..Default::default()
+        };
+        let mut codec = Cmd::back_codec(&policy);
+        let mut src = BytesMut::from(&b"$10\r\n0123"[..]);
+
+        // the command of the oversized bulk is failed on its header
+        let reply = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            reply.raw_data(),
+            b"-reply bulk of 10 bytes exceeds the maximum length\r\n"
+        );
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.is_empty());
+
+        // and its data is dropped as it arrives, so the next reply is parsed as usual
+        src.extend_from_slice(b"456789\r");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"\n$4\r\nv");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"alu\r\n");
+        assert_eq!(
+            codec.decode(&mut src).unwrap().unwrap().raw_data(),
+            b"$4\r\nvalu\r\n"
+        );
+
+        // the oversized bulks nested in arrays fail the connection
+        let mut src = BytesMut::from(&b"*2\r\n$1\r\na\r\n$10\r\n"[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap_err(),
+            AsError::BulkTooLarge(10)
+        );
+    }
+
+    #[test]
+    fn test_command_renames() {
+        init_redis_supported_cmds();
+        let policy = Policy {
+            strip_command_prefix: Some(b"app.".to_vec()),
+            command_renames: HashMap::from([
+                (b"REMOVE".to_vec(), b"DEL".to_vec()),
+                (b"SECRET_FLUSH".to_vec(), b"FLUSHALL".to_vec()),
+            ]),
+            ..Default::default()
+        };
+        let mut codec = Cmd::front_codec(&policy);
+        let mut decode = |data: &[u8]| {
+            let mut src = BytesMut::from(data);
+            codec.decode(&mut src).unwrap().unwrap()
+        };
+
+        // the new token is classified and sent as the renamed command
+        let cmd = decode(b"*3\r\n$6\r\nremove\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(cmd.take_cmd().cmd_type, CmdType::Del);
+        assert_eq!(cmd.subs().map(|x| x.len()), Some(2));
+        let cmd = decode(b"app.REMOVE a\r\n");
+        let mut sent = BytesMut::new();
+        cmd.subs().unwrap()[0]
+            .take_cmd()
+            .send_req(&mut sent)
+            .unwrap();
+        assert_eq!(&sent[..], b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n");
+
+        // the renamed verbs are not supported anymore
+        for data in [&b"*2\r\n$3\r\ndel\r\n$1\r\na\r\n"[..], b"app.del a\r\n"] {
+            let cmd = decode(data);
+            assert_eq!(cmd.take_cmd().cmd_type, CmdType::NotSupport);
+            assert!(cmd.is_done());
+        }
+
+        // the other commands are left as they are
+        let cmd = decode(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        assert_eq!(cmd.take_cmd().cmd_type, CmdType::Read);
+    }
+
+    #[test]
+    fn test_strip_command_prefix() {
+        init_redis_supported_cmds();
+        let policy = Policy {
+            strip_command_prefix: Some(b"app.".to_vec()),
+            ..Default::default()
+        };
+        let mut codec = Cmd::front_codec(&policy);
+        let mut decode = |data: &[u8]| {
+            let mut src = BytesMut::from(data);
+            let cmd = codec.decode(&mut src).unwrap().unwrap();
+            let mut sent = BytesMut::new();
+            let cmd = cmd.take_cmd();
+            cmd.send_req(&mut sent).unwrap();
+            (cmd.cmd_type, sent)
+        };
+
+        let (cmd_type, sent) = decode(b"*3\r\n$7\r\nAPP.set\r\n$1\r\nk\r\n$1\r\nv\r\n");
+        assert_eq!(cmd_type, CmdType::Write);
+        assert_eq!(&sent[..], b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+
+        let (_, sent) = decode(b"app.get k\r\n");
+        assert_eq!(&sent[..], b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+
+        // the commands without the prefix are left as they are
+        let (_, sent) = decode(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        assert_eq!(&sent[..], b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        let (cmd_type, _) = decode(b"*1\r\n$4\r\napp.\r\n");
+        assert_eq!(cmd_type, CmdType::NotSupport);
+    }
+
+    #[test]
+    fn test_error_kind_label() {
+        init_test_instruments();
+        let errors = |kind: &str| test_metric_value("repust_error_total", &[("kind", kind)]);
+
+        let before = errors("CmdTimeout");
+        let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+        Request::set_error(&cmd, &AsError::CmdTimeout);
+        assert!(cmd.is_error());
+        assert!(errors("CmdTimeout") >= before + 1.0);
+    }
+
+    fn sent(cmd: &Cmd) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        RedisNodeCodec::default()
+            .encode(cmd.clone(), &mut dst)
+            .unwrap();
+        dst.to_vec()
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let set = parse_cmd(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n");
+        set.set_default_ttl(60);
+        assert_eq!(
+            sent(&set),
+            b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\nEX\r\n$2\r\n60\r\n"
+        );
+
+        // the expiry given by the client is kept, whatever its case
+        for req in [
+            b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\npx\r\n$4\r\n5000\r\n".as_slice(),
+            b"*4\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$7\r\nKEEPTTL\r\n",
+            b"*6\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\nNX\r\n$4\r\nEXAT\r\n$1\r\n9\r\n",
+        ] {
+            let set = parse_cmd(req);
+            set.set_default_ttl(60);
+            assert_eq!(sent(&set), req);
+        }
+
+        // a value looking like an option is not taken as an expiry
+        let set = parse_cmd(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$2\r\nEX\r\n");
+        set.set_default_ttl(60);
+        assert_eq!(
+            sent(&set),
+            b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$2\r\nEX\r\n$2\r\nEX\r\n$2\r\n60\r\n"
+        );
+
+        // each pair of MSET is set with the expiry
+        let mset = parse_cmd(b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        mset.set_default_ttl(60);
+        let subs: Vec<Vec<u8>> = mset.subs().unwrap().iter().map(sent).collect();
+        assert_eq!(
+            subs,
+            vec![
+                b"*5\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n$2\r\nEX\r\n$2\r\n60\r\n".to_vec(),
+                b"*5\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n$2\r\nEX\r\n$2\r\n60\r\n".to_vec(),
+            ]
+        );
+
+        // the other writes are left as they are
+        let incr = parse_cmd(b"*2\r\n$4\r\nINCR\r\n$3\r\nfoo\r\n");
+        incr.set_default_ttl(60);
+        assert_eq!(sent(&incr), b"*2\r\n$4\r\nINCR\r\n$3\r\nfoo\r\n");
+    }
+}
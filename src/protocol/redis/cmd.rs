@@ -1,12 +1,61 @@
-use std::collections::HashMap;
-use std::sync::OnceLock;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
 
+use crate::metrics::unknown_command_incr;
 use crate::protocol::redis::resp::Message;
 use crate::protocol::CmdType;
+use crate::utils::bucket::TokenBucket;
 
 // TODO: consider to std::sync::LazyLock when the API has been finalized
 static CMD_HASHMAP: OnceLock<HashMap<&[u8], CmdType>> = OnceLock::new();
 
+// UNKNOWN_CMDS_LOG limits the logs of the unknown commands, which are only logged and counted once it is set
+static UNKNOWN_CMDS_LOG: OnceLock<Mutex<TokenBucket>> = OnceLock::new();
+
+// UNKNOWN_CMDS_LOG_RATE is the number of the unknown commands logged per second
+const UNKNOWN_CMDS_LOG_RATE: u32 = 10;
+
+// UNKNOWN_CMD_MAX_LEN bounds the verbs labelling the unknown command counter, as they are chosen by the clients
+const UNKNOWN_CMD_MAX_LEN: usize = 32;
+
+// UNKNOWN_CMDS_MAX bounds the distinct verbs labelling the unknown command counter, the verbs seen past the
+// first ones are all counted as UNKNOWN_CMD_OTHER so the clients can't grow the series without bound
+pub(crate) const UNKNOWN_CMDS_MAX: usize = 64;
+pub(crate) const UNKNOWN_CMD_OTHER: &str = "other";
+
+// UNKNOWN_CMDS are the verbs labelling the unknown command counter
+static UNKNOWN_CMDS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+// init_unknown_cmds_log starts logging and counting the commands of the clients which are not supported
+pub fn init_unknown_cmds_log() {
+    UNKNOWN_CMDS_LOG.get_or_init(|| Mutex::new(TokenBucket::new(UNKNOWN_CMDS_LOG_RATE)));
+}
+
+// unknown_cmd logs and counts the command of a client of the cluster resolved to NotSupport by its verb,
+// if enabled
+pub fn unknown_cmd(cluster: &str, msg: &Message) {
+    let log = match UNKNOWN_CMDS_LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+    let verb = msg.nth(0).unwrap_or_default();
+    let verb = String::from_utf8_lossy(&verb[..verb.len().min(UNKNOWN_CMD_MAX_LEN)]);
+    {
+        let mut seen = UNKNOWN_CMDS.lock().unwrap();
+        let seen = seen.get_or_insert_with(HashSet::new);
+        if seen.contains(verb.as_ref()) || seen.len() < UNKNOWN_CMDS_MAX {
+            seen.insert(verb.to_string());
+            unknown_command_incr(cluster, &verb);
+        } else {
+            unknown_command_incr(cluster, UNKNOWN_CMD_OTHER);
+        }
+    }
+    if log.lock().unwrap().try_take() {
+        warn!("client sent the unknown command {}", verb);
+    }
+}
+
 pub fn init_cmds() {
     let mut cmds_hashmap: HashMap<&[u8], CmdType> = HashMap::new();
 
@@ -56,7 +105,7 @@ pub fn init_cmds() {
     cmds_hashmap.insert(&b"INCRBYFLOAT"[..], CmdType::Write);
     cmds_hashmap.insert(&b"MGET"[..], CmdType::MGet);
     cmds_hashmap.insert(&b"MSET"[..], CmdType::MSet);
-    cmds_hashmap.insert(&b"MSETNX"[..], CmdType::NotSupport);
+    cmds_hashmap.insert(&b"MSETNX"[..], CmdType::MSetNx);
     cmds_hashmap.insert(&b"PSETEX"[..], CmdType::Write);
     cmds_hashmap.insert(&b"SET"[..], CmdType::Write);
     cmds_hashmap.insert(&b"SETBIT"[..], CmdType::Write);
@@ -159,21 +208,34 @@ pub fn init_cmds() {
 
     // eval type
     cmds_hashmap.insert(&b"EVAL"[..], CmdType::Eval);
-    cmds_hashmap.insert(&b"EVALSHA"[..], CmdType::NotSupport);
+    // EVALSHA is routed by its first key like EVAL. The scripts are cached by each backend, so the
+    // NOSCRIPT replies are passed to the clients to send the body again with EVAL.
+    cmds_hashmap.insert(&b"EVALSHA"[..], CmdType::Eval);
 
     // ctrl type
     cmds_hashmap.insert(&b"AUTH"[..], CmdType::Auth);
     cmds_hashmap.insert(&b"ECHO"[..], CmdType::Ctrl);
     cmds_hashmap.insert(&b"PING"[..], CmdType::Ctrl);
+    cmds_hashmap.insert(&b"HELLO"[..], CmdType::Ctrl);
     cmds_hashmap.insert(&b"INFO"[..], CmdType::Info);
-    cmds_hashmap.insert(&b"PROXY"[..], CmdType::NotSupport);
+    cmds_hashmap.insert(&b"PROXY"[..], CmdType::Proxy);
     cmds_hashmap.insert(&b"SLOWLOG"[..], CmdType::NotSupport);
     cmds_hashmap.insert(&b"QUIT"[..], CmdType::Ctrl);
     cmds_hashmap.insert(&b"SELECT"[..], CmdType::NotSupport);
     cmds_hashmap.insert(&b"TIME"[..], CmdType::NotSupport);
     cmds_hashmap.insert(&b"CONFIG"[..], CmdType::NotSupport);
     cmds_hashmap.insert(&b"CLUSTER"[..], CmdType::Ctrl);
-    cmds_hashmap.insert(&b"READONLY"[..], CmdType::Ctrl);
+    // READONLY and READWRITE switch the reads of the connection between the replicas and the primaries
+    cmds_hashmap.insert(&b"READONLY"[..], CmdType::Proxy);
+    cmds_hashmap.insert(&b"READWRITE"[..], CmdType::Proxy);
+
+    // pub/sub type, the subscribed connections are streamed to the backend of their first channel and
+    // the messages are published to the backend of their channel
+    cmds_hashmap.insert(&b"SUBSCRIBE"[..], CmdType::PubSub);
+    cmds_hashmap.insert(&b"PSUBSCRIBE"[..], CmdType::PubSub);
+    cmds_hashmap.insert(&b"UNSUBSCRIBE"[..], CmdType::PubSub);
+    cmds_hashmap.insert(&b"PUNSUBSCRIBE"[..], CmdType::PubSub);
+    cmds_hashmap.insert(&b"PUBLISH"[..], CmdType::Write);
 
     // bloom filter type
     cmds_hashmap.insert(&b"BF.ADD"[..], CmdType::Write);
@@ -253,6 +315,10 @@ impl CmdType {
         CmdType::MSet == self
     }
 
+    pub fn is_msetnx(self) -> bool {
+        CmdType::MSetNx == self
+    }
+
     pub fn is_exists(self) -> bool {
         CmdType::Exists == self
     }
@@ -309,11 +375,20 @@ impl CmdType {
         CmdType::Memory == self
     }
 
+    pub fn is_proxy(self) -> bool {
+        CmdType::Proxy == self
+    }
+
+    pub fn is_pub_sub(self) -> bool {
+        CmdType::PubSub == self
+    }
+
     pub fn need_auth(self) -> bool {
         self.is_read()
             || self.is_write()
             || self.is_mget()
             || self.is_mset()
+            || self.is_msetnx()
             || self.is_exists()
             || self.is_eval()
             || self.is_del()
@@ -321,6 +396,7 @@ impl CmdType {
             || self.is_read_all()
             || self.is_count_all()
             || self.is_scan()
+            || self.is_pub_sub()
     }
 
     pub fn get_cmd_type(msg: &Message) -> CmdType {
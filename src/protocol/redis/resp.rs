@@ -1,6 +1,7 @@
 use aho_corasick::AhoCorasick;
 use btoi::btoi;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
 use std::sync::OnceLock;
 
 use crate::com::*;
@@ -17,6 +18,13 @@ pub const RESP_ARRAY: u8 = b'*';
 pub const BYTE_CR: u8 = b'\r';
 pub const BYTE_LF: u8 = b'\n';
 
+// DEFAULT_MAX_MULTIBULK_LEN is the maximum number of elements of an array parsed by default
+pub const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+// DEFAULT_MAX_BULK_LEN is the maximum length of a bulk string of the replies by default, which is the
+// largest string value a redis server accepts
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
 pub const BYTES_CMD_CLUSTER_SLOTS: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nSLOTS\r\n";
 pub const BYTES_CMD_CLUSTER_NODES: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nNODES\r\n";
 
@@ -63,7 +71,12 @@ impl MessageMut {
         }))
     }
 
-    fn parse_inner(cursor: usize, src: &[u8]) -> Result<Option<MsgPack>, AsError> {
+    fn parse_inner(
+        cursor: usize,
+        src: &[u8],
+        max_multibulk_len: usize,
+        max_bulk_len: usize,
+    ) -> Result<Option<MsgPack>, AsError> {
         let pos = if let Some(p) = simdfind::find_lf_simd(&src[cursor..]) {
             p
         } else {
@@ -108,13 +121,15 @@ impl MessageMut {
                 if csize == -1 {
                     return Ok(Some(MsgPack {
                         rtype: RespType::Bulk(
-                            Range::new(cursor, cursor + 5),
-                            Range::new(cursor, cursor + 5),
+                            Range::new(cursor, cursor + pos + 1),
+                            Range::new(cursor, cursor + pos + 1),
                         ),
-                        size: 5,
+                        size: pos + 1,
                     }));
                 } else if csize < 0 {
                     return Err(AsError::BadMessage);
+                } else if csize as usize > max_bulk_len {
+                    return Err(AsError::BulkTooLarge(csize as usize));
                 }
 
                 let total_size = (pos + 1) + (csize as usize) + 2;
@@ -135,17 +150,22 @@ impl MessageMut {
                     Err(_err) => return Err(AsError::BadMessage),
                 };
                 if csize == -1 {
+                    // the null array is sized by its header, which may be written as *-01 as well
                     return Ok(Some(MsgPack {
-                        rtype: RespType::Array(Range::new(cursor, cursor + 5), vec![]),
-                        size: 5,
+                        rtype: RespType::Array(Range::new(cursor, cursor + pos + 1), vec![]),
+                        size: pos + 1,
                     }));
                 } else if csize < 0 {
                     return Err(AsError::BadMessage);
+                } else if csize as usize > max_multibulk_len {
+                    return Err(AsError::MultiBulkTooLong(max_multibulk_len));
                 }
                 let mut mycursor = cursor + pos + 1;
                 let mut items = Vec::new();
                 for _ in 0..csize {
-                    if let Some(MsgPack { rtype, size }) = Self::parse_inner(mycursor, src)? {
+                    if let Some(MsgPack { rtype, size }) =
+                        Self::parse_inner(mycursor, src, max_multibulk_len, max_bulk_len)?
+                    {
                         mycursor += size;
                         items.push(rtype);
                     } else {
@@ -170,7 +190,34 @@ impl MessageMut {
     }
 
     pub fn parse(src: &mut BytesMut) -> Result<Option<MessageMut>, AsError> {
-        let rslt = match Self::parse_inner(0, &src[..]) {
+        Self::parse_limited(src, DEFAULT_MAX_MULTIBULK_LEN)
+    }
+
+    // parse_limited parses a message whose arrays have at most max_multibulk_len elements. The length is
+    // checked on the array header, before parsing and holding any of its elements.
+    pub fn parse_limited(
+        src: &mut BytesMut,
+        max_multibulk_len: usize,
+    ) -> Result<Option<MessageMut>, AsError> {
+        Self::parse_bounded(src, max_multibulk_len, usize::MAX)
+    }
+
+    // parse_reply parses a reply whose bulk strings have at most max_bulk_len bytes. The length is checked
+    // on the bulk header, before its data is buffered. The arrays are not limited since the backends reply
+    // with as many elements as the command asked for, like a large LRANGE or KEYS.
+    pub fn parse_reply(
+        src: &mut BytesMut,
+        max_bulk_len: usize,
+    ) -> Result<Option<MessageMut>, AsError> {
+        Self::parse_bounded(src, usize::MAX, max_bulk_len)
+    }
+
+    fn parse_bounded(
+        src: &mut BytesMut,
+        max_multibulk_len: usize,
+        max_bulk_len: usize,
+    ) -> Result<Option<MessageMut>, AsError> {
+        let rslt = match Self::parse_inner(0, &src[..], max_multibulk_len, max_bulk_len) {
             Ok(r) => r,
             Err(err) => {
                 // TODO: should change it as wrong bad command error
@@ -206,6 +253,46 @@ impl MessageMut {
         }
     }
 
+    // strip_command_prefix removes the given leading token from the command verb, compared ignoring the
+    // case. The message is rebuilt as an array of bulk strings if the verb has the prefix.
+    pub fn strip_command_prefix(self, prefix: &[u8]) -> MessageMut {
+        self.replace_verb(|verb| {
+            if verb.len() > prefix.len() && verb[..prefix.len()].eq_ignore_ascii_case(prefix) {
+                Some(verb[prefix.len()..].to_vec())
+            } else {
+                None
+            }
+        })
+    }
+
+    // replace_verb rebuilds the command as an array with the verb the given function returns for the
+    // current one, the command is left as it is if the function returns None
+    pub fn replace_verb<F>(self, replace: F) -> MessageMut
+    where
+        F: FnOnce(&[u8]) -> Option<Vec<u8>>,
+    {
+        let len = match &self.rtype {
+            RespType::Array(_, items) => items.len(),
+            RespType::Inline(fields) => fields.len(),
+            _ => return self,
+        };
+        let args: Option<Vec<&[u8]>> = (0..len).map(|i| self.nth(i)).collect();
+        let replaced = match args.as_deref() {
+            Some([verb, rest @ ..]) => replace(verb).and_then(|verb| {
+                let mut data = BytesMut::new();
+                data.extend_from_slice(format!("*{}\r\n", len).as_bytes());
+                for arg in std::iter::once(&verb[..]).chain(rest.iter().copied()) {
+                    data.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                    data.extend_from_slice(arg);
+                    data.extend_from_slice(b"\r\n");
+                }
+                Self::parse(&mut data).ok().flatten()
+            }),
+            _ => None,
+        };
+        replaced.unwrap_or(self)
+    }
+
     fn get_nth_data_range(&self, index: usize) -> Option<Range> {
         if let RespType::Array(_, items) = &self.rtype {
             if let Some(item) = items.get(index) {
@@ -245,9 +332,9 @@ impl MessageMut {
                 if len == 0 {
                     return Some(*rng);
                 }
-                if len > 0 && self.data[len - 1] == BYTE_LF {
+                if len > 0 && self.data[rng.end() - 1] == BYTE_LF {
                     end -= 1;
-                    if len > 1 && self.data[len - 2] == BYTE_CR {
+                    if len > 1 && self.data[rng.end() - 2] == BYTE_CR {
                         end -= 1;
                     }
                 }
@@ -354,6 +441,49 @@ impl Message {
         }
     }
 
+    pub fn bulk(data: &[u8]) -> Message {
+        let head = format!("${}\r\n", data.len());
+        let total_len = head.len() + data.len() + 2 /*\r\n*/;
+
+        let mut rdata = BytesMut::with_capacity(total_len);
+        rdata.put(head.as_bytes());
+        rdata.put(data);
+        rdata.put_u8(BYTE_CR);
+        rdata.put_u8(BYTE_LF);
+
+        Message {
+            data: rdata.into(),
+            resp_type: RespType::Bulk(Range::new(0, head.len()), Range::new(head.len(), total_len)),
+        }
+    }
+
+    // array creates an array message of the given bulk strings
+    pub fn array(items: &[&[u8]]) -> Message {
+        let head = format!("*{}\r\n", items.len());
+
+        let mut rdata = BytesMut::new();
+        rdata.put(head.as_bytes());
+
+        let mut subs = Vec::with_capacity(items.len());
+        for item in items {
+            let begin = rdata.len();
+            rdata.put(format!("${}\r\n", item.len()).as_bytes());
+            let body_begin = rdata.len();
+            rdata.put(*item);
+            rdata.put_u8(BYTE_CR);
+            rdata.put_u8(BYTE_LF);
+            subs.push(RespType::Bulk(
+                Range::new(begin, body_begin),
+                Range::new(body_begin, rdata.len()),
+            ));
+        }
+
+        Message {
+            data: rdata.into(),
+            resp_type: RespType::Array(Range::new(0, head.len()), subs),
+        }
+    }
+
     pub fn plain<I: Into<Bytes>>(data: I, resp_type: u8) -> Message {
         let bytes = data.into();
         let mut rdata = BytesMut::new();
@@ -454,9 +584,9 @@ impl Message {
                 if rng.begin() == rng.end() {
                     return Some(*rng);
                 }
-                if len > 0 && self.data[len - 1] == BYTE_LF {
+                if len > 0 && self.data[rng.end() - 1] == BYTE_LF {
                     end -= 1;
-                    if len > 1 && self.data[len - 2] == BYTE_CR {
+                    if len > 1 && self.data[rng.end() - 2] == BYTE_CR {
                         end -= 1;
                     }
                 }
@@ -522,10 +652,28 @@ impl Message {
         None
     }
 
-    pub fn replace_info_resp(&mut self) {
+    // error_code returns the leading code of an error reply, e.g. LOADING for "-LOADING Redis is loading"
+    pub fn error_code(&self) -> Option<&[u8]> {
+        match self.resp_type {
+            RespType::Error(_) => {}
+            _ => return None,
+        }
+        let data = self.data()?;
+        let end = data
+            .iter()
+            .position(|&x| x == BYTE_SPACE)
+            .unwrap_or(data.len());
+        Some(&data[..end])
+    }
+
+    // replace_info_resp sets the fields of the INFO reply to the values of the overrides, the fields
+    // overridden by an empty value are removed. The lines are rewritten without the terminator of the
+    // bulk, which is appended again once the body is rebuilt, so the last line can be removed as well.
+    pub fn replace_info_resp(&mut self, overrides: &HashMap<String, String>) {
         if let RespType::Bulk(_, body) = self.resp_type {
             if self.data.len() > 7 {
                 let data = &self.data.as_ref()[body.begin()..body.end()];
+                let data = data.strip_suffix(b"\r\n").unwrap_or(data);
 
                 let text = match std::str::from_utf8(data) {
                     Ok(s) => s.to_owned(),
@@ -538,19 +686,23 @@ impl Message {
                         return;
                     }
                 };
-                let text = text.replace("redis_mode:cluster", "redis_mode:standalone");
-                let text = text.replace("cluster_enabled:1", "cluster_enabled:0");
-
-                let body_len = text.len();
-                let head = format!("${}\r\n", body_len - 2);
-                let head_len = head.len();
-                let data = format!("${}\r\n{}", body_len - 2, text);
-                let data = Bytes::from(data);
-                self.resp_type = RespType::Bulk(
-                    Range::new(0, head_len),
-                    Range::new(head_len, head_len + body_len),
-                );
-                self.data = data;
+                let text: String = text
+                    .split_inclusive("\r\n")
+                    .filter_map(|line| {
+                        let (content, end) = match line.strip_suffix("\r\n") {
+                            Some(content) => (content, "\r\n"),
+                            None => (line, ""),
+                        };
+                        let field = content.split_once(':').map(|(field, _)| field);
+                        match field.and_then(|field| overrides.get(field).map(|x| (field, x))) {
+                            Some((_, value)) if value.is_empty() => None,
+                            Some((field, value)) => Some(format!("{}:{}{}", field, value, end)),
+                            None => Some(line.to_string()),
+                        }
+                    })
+                    .collect();
+
+                *self = Message::bulk(text.as_bytes());
             }
         }
     }
@@ -566,11 +718,12 @@ fn pattern_finder() -> &'static AhoCorasick {
     })
 }
 
+// parse_redirect parses the slot and the target address of a redirection error, e.g. MOVED 3999 127.0.0.1:6381
 fn parse_redirect(data: &[u8]) -> Option<Redirect> {
-    if let Some(mat) = pattern_finder().find(data) {
+    if let Some(mat) = pattern_finder().find(data).filter(|x| x.start() == 0) {
         let pat = mat.pattern();
         let end = mat.end();
-        let rdata = &data[end + 1..];
+        let rdata = data.get(end + 1..)?;
 
         let pos = rdata.iter().position(|&x| x == BYTE_SPACE)?;
 
@@ -591,6 +744,31 @@ fn parse_redirect(data: &[u8]) -> Option<Redirect> {
     None
 }
 
+#[test]
+fn test_check_redirect() {
+    let parse = |data: &[u8]| {
+        let mut src = BytesMut::from(data);
+        Message::from(MessageMut::parse(&mut src).unwrap().unwrap()).check_redirect()
+    };
+    assert_eq!(
+        parse(b"-MOVED 3999 127.0.0.1:6381\r\n"),
+        Some(Redirect::Move {
+            slot: 3999,
+            to: "127.0.0.1:6381".to_string()
+        })
+    );
+    assert_eq!(
+        parse(b"-ASK 3999 127.0.0.1:6381\r\n"),
+        Some(Redirect::Ask {
+            slot: 3999,
+            to: "127.0.0.1:6381".to_string()
+        })
+    );
+    assert_eq!(parse(b"-ERR slot MOVED 3999 127.0.0.1:6381\r\n"), None);
+    assert_eq!(parse(b"-MOVED\r\n"), None);
+    assert_eq!(parse(b"+MOVED 3999 127.0.0.1:6381\r\n"), None);
+}
+
 #[test]
 fn test_parse() {
     let data = b"*2\r\n$3\r\nget\r\n$4\r\nab\nc\r\n";
@@ -646,6 +824,43 @@ mod test {
         assert!(iter.next() == Some(b"ab\nc".as_ref()));
     }
 
+    #[test]
+    fn test_array() {
+        let msg = Message::array(&[b"SET", b"key", b"a\r\nb"]);
+        check!(msg.raw_data() == b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$4\r\na\r\nb\r\n");
+        let mut src = BytesMut::from(msg.raw_data());
+        let parsed: Message = MessageMut::parse(&mut src).unwrap().unwrap().into();
+        check!(parsed == msg);
+        check!(msg.nth(2) == Some(b"a\r\nb".as_ref()));
+    }
+
+    #[test]
+    fn test_replace_info_resp() {
+        let overrides = HashMap::from([
+            ("redis_version".to_string(), "6.2.0".to_string()),
+            ("os".to_string(), String::new()),
+        ]);
+        let replace = |info: &str| {
+            let data = format!("${}\r\n{}\r\n", info.len(), info);
+            let mut src = BytesMut::from(data.as_bytes());
+            let mut msg: Message = MessageMut::parse(&mut src).unwrap().unwrap().into();
+            msg.replace_info_resp(&overrides);
+            msg
+        };
+
+        let msg = replace("# Server\r\nredis_version:7.2.4\r\nos:Linux\r\n");
+        check!(msg.raw_data() == b"$31\r\n# Server\r\nredis_version:6.2.0\r\n\r\n");
+        check!(msg.data() == Some(b"# Server\r\nredis_version:6.2.0\r\n".as_ref()));
+
+        // the last line is hidden or replaced even when the terminator of the bulk is its only CRLF
+        let msg = replace("# Server\r\nos:Linux");
+        check!(msg.raw_data() == b"$10\r\n# Server\r\n\r\n");
+        let msg = replace("os:Linux");
+        check!(msg.raw_data() == b"$0\r\n\r\n");
+        let msg = replace("# Server\r\nredis_version:7.2.4");
+        check!(msg.raw_data() == b"$29\r\n# Server\r\nredis_version:6.2.0\r\n");
+    }
+
     #[test]
     fn test_iter_plain() {
         let data = b"+abcdef\r\n";
@@ -700,6 +915,19 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_inline_nth_trims_line_end() {
+        // the line end is trimmed from the last argument however long the arguments are
+        for data in [&b"GET ab\r\n"[..], b"GET ab\n"] {
+            let mut src = BytesMut::from(data);
+            let msg = MessageMut::parse(&mut src).unwrap().unwrap();
+            assert!(msg.nth(1) == Some(b"ab".as_ref()));
+            let msg: Message = msg.into();
+            assert!(msg.nth(0) == Some(b"GET".as_ref()));
+            assert!(msg.nth(1) == Some(b"ab".as_ref()));
+        }
+    }
+
     // ---------------------- test copy from redis/tests/unit/protocol.tcl ------------------------------------------ //
 
     #[test]
@@ -716,6 +944,22 @@ mod test {
         assert_eq!(src.len(), 0);
     }
 
+    #[test]
+    fn test_empty_multibulk() {
+        let data = b"*0\r\n*-1\r\n*-01\r\n*1\r\n$4\r\nPING\r\n";
+        let mut src = BytesMut::from(data.as_ref());
+        for (head, len) in [(&b"*0\r\n"[..], 0), (b"*-1\r\n", 0), (b"*-01\r\n", 0)] {
+            let msg = MessageMut::parse(&mut src).unwrap().unwrap();
+            assert_eq!(&msg.data[..], head);
+            assert_eq!(msg.rtype.array().map(|x| x.len()), Some(len));
+        }
+
+        // the parser is left at the start of the next command
+        let msg = MessageMut::parse(&mut src).unwrap().unwrap();
+        assert_eq!(msg.nth(0), Some(&b"PING"[..]));
+        assert_eq!(src.len(), 0);
+    }
+
     #[test]
     fn test_negative_multibulk_length() {
         let data = b"*-10\r\n";
@@ -744,4 +988,30 @@ mod test {
         let mut src = BytesMut::from(data.as_bytes());
         assert!(MessageMut::parse(&mut src).unwrap_err() == AsError::BadMessage);
     }
+
+    #[test]
+    fn test_oversized_multibulk_length() {
+        let data = "*999999999\r\n$3\r\nSET\r\n";
+        let mut src = BytesMut::from(data.as_bytes());
+        check!(
+            MessageMut::parse(&mut src).unwrap_err()
+                == AsError::MultiBulkTooLong(DEFAULT_MAX_MULTIBULK_LEN)
+        );
+        // only the header line is consumed, nothing is allocated for the announced elements
+        check!(src.len() == data.len() - 12);
+
+        let data = "*3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let mut src = BytesMut::from(data.as_bytes());
+        check!(MessageMut::parse_limited(&mut src, 2).unwrap_err() == AsError::MultiBulkTooLong(2));
+        let mut src = BytesMut::from(data.as_bytes());
+        check!(MessageMut::parse_limited(&mut src, 3).unwrap().is_some());
+
+        // the backend replies are not limited, the large array is waited for
+        let data = "*999999999\r\n$3\r\nSET\r\n";
+        let mut src = BytesMut::from(data.as_bytes());
+        check!(MessageMut::parse_reply(&mut src, usize::MAX)
+            .unwrap()
+            .is_none());
+        check!(src.len() == data.len());
+    }
 }
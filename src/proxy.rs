@@ -1,12 +1,48 @@
+pub mod cluster;
+// Path: src/proxy/cluster.rs
+
+mod front;
+// Path: src/proxy/front.rs
+
 pub mod standalone;
 // Path: src/proxy/standalone.rs
 
+#[cfg(test)]
+mod test_support;
+// Path: src/proxy/test_support.rs
+
+use bytes::BytesMut;
+use futures::Future;
+use log::{debug, warn};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::Waker;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::com::config::{CacheType, ClusterConfig, WarmingReply};
 use crate::com::AsError;
-use crate::protocol::IntoReply;
+use crate::metrics::{accept_loop_alive, bytes_in_incr, bytes_out_incr};
+use crate::protocol::{CmdType, IntoReply};
+
+// ACCEPT_HEARTBEAT_INTERVAL is the longest time the accept loop of a cluster waits for a client without
+// being marked alive
+const ACCEPT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// TLS_HANDSHAKE_TIMEOUT bounds the TLS handshake of a client or a backend, so the stalled ones do not hold
+// their socket
+pub(crate) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// DRAIN_CHECK_INTERVAL is the interval the clients left are counted at while shutting down
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 
 pub trait Request: Clone {
     type Reply: Clone + IntoReply<Self::Reply> + From<AsError>;
@@ -21,24 +57,55 @@ pub trait Request: Clone {
         + Default
         + Send;
 
+    // front_codec creates the codec of a client connection applying the limits of the policy
+    fn front_codec(_policy: &Policy) -> Self::FrontCodec {
+        Self::FrontCodec::default()
+    }
+
+    // back_codec creates the codec of a backend connection applying the limits of the policy
+    fn back_codec(_policy: &Policy) -> Self::BackCodec {
+        Self::BackCodec::default()
+    }
+
+    // ping_request creates the keepalive ping of an idle backend connection, it is left out of the
+    // latency trackers and the reply counters as no client sent it
     fn ping_request() -> Self;
+    fn is_keepalive(&self) -> bool;
     fn auth_request(auth: &str) -> Self;
+    // auth_failure returns why the backend rejected the auth_request with the given reply, None if accepted
+    fn auth_failure(reply: &Self::Reply) -> Option<String>;
+    // read_only_request creates the command which lets a replica serve the reads of its slots
+    fn read_only_request() -> Self;
     // fn reregister(&mut self, task: Task);
 
     fn key_hash(&self, hash_tag: &[u8], hasher: fn(&[u8]) -> u64) -> u64;
 
     fn subs(&self) -> Option<Vec<Self>>;
+    // next_subs moves the command done with its subs on to its next step, if any, and returns the subs
+    // of that step to be forwarded. MSETNX sets its pairs this way once none of its keys is found.
+    fn next_subs(&self) -> Option<Vec<Self>>;
 
     fn mark_total(&self);
-    fn mark_sent(&self);
+    // mark_sent starts the remote timer of the command sent to the backend node of the given address
+    fn mark_sent(&self, node: &str);
 
     fn is_done(&self) -> bool;
     fn is_error(&self) -> bool;
+    // is_miss checks if the command is replied with nil, e.g. a read of a missing key
+    fn is_miss(&self) -> bool;
 
     fn add_cycle(&self);
     fn can_cycle(&self) -> bool;
 
+    // set_moved marks the command as redirected by a MOVED reply of the backend
+    fn set_moved(&self);
+    // set_ask marks the command to be sent with a preceding ASKING to the node of an ASK redirection
+    fn set_ask(&self);
+    // take_ask clears the ASK mark of the command and reports if it was set
+    fn take_ask(&self) -> bool;
+
     fn valid(&self) -> bool;
+    fn check_policy(&self, policy: &Policy) -> bool;
 
     fn register_waker(&mut self, waker: Waker);
     fn waker(&self) -> Option<Waker>;
@@ -47,8 +114,244 @@ pub trait Request: Clone {
     fn set_error(&self, t: &AsError);
 
     fn get_sent_time(&self) -> Option<Instant>;
+    fn reset_sent(&self);
+
+    fn set_deadline(&self, deadline: Instant);
+    fn get_deadline(&self) -> Option<Instant>;
+
+    // set_cluster labels the metrics of the command and its sub commands with the given cluster name
+    fn set_cluster(&self, cluster: &Arc<str>);
+
+    // scan_node returns the index of the backend the SCAN cursor resumes, None if not a SCAN
+    fn scan_node(&self) -> Option<usize>;
+    // set_scan_last marks the SCAN sent to the last backend, so the end of its cursor ends the scan
+    fn set_scan_last(&self);
+    // finish_scan replies the SCAN resuming past the last backend as complete
+    fn finish_scan(&self);
+
+    fn proxy_cmd(&self) -> Option<ProxyCmd>;
+    fn set_proxy_reply(&self, reply: ProxyReply);
+
+    fn reply_error(reply: &Self::Reply) -> Option<ReplyError>;
+
+    // reply_redirect returns the redirection of a MOVED or ASK error reply of a cluster node
+    fn reply_redirect(reply: &Self::Reply) -> Option<Redirect>;
+
+    // reply_subscriptions returns the number of the subscriptions left to the connection by a
+    // (P)SUBSCRIBE or (P)UNSUBSCRIBE reply of a subscribed backend, None for the published messages
+    fn reply_subscriptions(reply: &Self::Reply) -> Option<usize>;
+    // subscribe_channels returns the channels, or the patterns, a (P)SUBSCRIBE subscribes to, none for
+    // the other commands
+    fn subscribe_channels(&self) -> Vec<Vec<u8>>;
+    // push_message creates the done command replying the message pushed by a subscribed backend
+    fn push_message(reply: Self::Reply) -> Self;
+
+    // is_write checks if the command modifies the data, e.g. to be mirrored to the migration target
+    fn is_write(&self) -> bool;
+    // is_read checks if the command only reads the data, e.g. to be served by the read pool
+    fn is_read(&self) -> bool;
+    // cmd_type returns the class of the command, e.g. to label the command metrics
+    fn cmd_type(&self) -> CmdType;
+    // mirror creates an independent copy of the command which is replied separately
+    fn mirror(&self) -> Self;
+
+    // compress_value compresses the written value if it is at least min_bytes long
+    fn compress_value(&self, min_bytes: usize);
+    // decompress_reply restores the values compressed by the proxy in the reply
+    fn decompress_reply(&self);
+    // set_default_ttl appends the expiry of ttl_secs to the written keys which have no expiry set
+    fn set_default_ttl(&self, ttl_secs: u64);
+}
+
+// ReplyError is a well known error reply of the backends which needs special handling by the proxy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplyError {
+    // Loading is replied while the backend is still loading its dataset into memory
+    Loading,
+
+    // Busy is replied while the backend is running a long script
+    Busy,
+}
+
+// Policy is the set of per cluster limits the client commands are checked against before forwarding
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    // timeout is the time after which a command waiting to be forwarded is considered as failed
+    pub timeout: Duration,
+
+    // dial_timeout is the time after which a backend connection not yet established is considered as failed
+    pub dial_timeout: Duration,
+
+    // max_key_bytes is the maximum length of each key of a command, unlimited if None
+    pub max_key_bytes: Option<usize>,
+
+    // max_keys_per_cmd is the maximum number of the keys of a multi-key command, unlimited if None
+    pub max_keys_per_cmd: Option<usize>,
+
+    // max_multibulk_len is the maximum number of elements of a request array, the parser default if None
+    pub max_multibulk_len: Option<usize>,
+
+    // max_reply_bulk_len is the maximum length of a bulk string of the replies, the parser default if None
+    pub max_reply_bulk_len: Option<usize>,
+
+    // max_setrange_offset is the maximum offset of the SETRANGE commands, unlimited if None
+    pub max_setrange_offset: Option<u64>,
+
+    // max_subscriptions is the maximum number of the channels subscribed by all the clients, and
+    // max_channels_per_conn by each connection, unlimited if None
+    pub max_subscriptions: Option<usize>,
+    pub max_channels_per_conn: Option<usize>,
+
+    // read_only rejects the commands which modify the data
+    pub read_only: bool,
+
+    // max_client_timeout is the upper bound of the timeout set by the clients using PROXY TIMEOUT
+    pub max_client_timeout: Duration,
+
+    // compression_threshold is the minimum size of the values compressed by the proxy, disabled if None
+    pub compression_threshold: Option<usize>,
+
+    // default_ttl is the expiry in seconds of the keys set without one, disabled if None
+    pub default_ttl: Option<u64>,
+
+    // strip_command_prefix is the token removed from the command verbs before they are classified
+    pub strip_command_prefix: Option<Vec<u8>>,
+
+    // command_renames maps the tokens the clients use to the verbs of the renamed commands
+    pub command_renames: HashMap<Vec<u8>, Vec<u8>>,
+
+    // info_overrides maps the INFO fields to the values they are replied with, hidden if empty
+    pub info_overrides: HashMap<String, String>,
+
+    // warming_reply is replied to the commands received before any backend is connected, if set
+    pub warming_reply: Option<WarmingReply>,
+
+    // drain_grace is the time a removed backend keeps serving the commands queued before its removal
+    pub drain_grace: Duration,
+
+    // fail_fast fails the commands of the unreachable backends at once while reconnecting to them
+    pub fail_fast: bool,
+
+    // backend_queue_size is the capacity of the channel of each backend connection
+    pub backend_queue_size: usize,
+
+    // keepalive_ping is the idle time after which a backend connection is pinged, never if None
+    pub keepalive_ping: Option<Duration>,
+
+    // flush_batch is the number of the commands written to a backend connection before it is flushed,
+    // and flush_delay the longest the commands of an incomplete batch wait to be flushed
+    pub flush_batch: usize,
+    pub flush_delay: Duration,
+
+    // mc_binary frames the memcached clients and backends in the binary protocol rather than the text one
+    pub mc_binary: bool,
+
+    // cluster is the name of the cluster, labelling the metrics of its commands
+    pub cluster: Arc<str>,
 }
 
+impl Policy {
+    pub(crate) fn new(cc: &ClusterConfig) -> Policy {
+        Policy {
+            timeout: Duration::from_millis(cc.timeout_ms()),
+            dial_timeout: cc.dial_timeout(),
+            max_key_bytes: cc.max_key_bytes,
+            max_keys_per_cmd: Some(cc.max_keys_per_cmd()),
+            max_multibulk_len: cc.max_multibulk_len,
+            max_reply_bulk_len: cc.max_reply_bulk_len,
+            max_setrange_offset: cc.max_setrange_offset,
+            max_subscriptions: cc.max_subscriptions,
+            max_channels_per_conn: cc.max_channels_per_conn,
+            read_only: cc.read_only.unwrap_or(false),
+            max_client_timeout: Duration::from_millis(cc.max_client_timeout_ms()),
+            compression_threshold: cc.value_compression_threshold(),
+            default_ttl: cc.default_ttl(),
+            strip_command_prefix: cc
+                .strip_command_prefix
+                .as_ref()
+                .filter(|x| !x.is_empty())
+                .map(|x| x.as_bytes().to_vec()),
+            // the renames are validated with the config
+            command_renames: cc.command_renames().unwrap_or_default(),
+            info_overrides: cc.info_overrides.clone(),
+            warming_reply: cc.warming_reply,
+            drain_grace: cc.drain_grace(),
+            fail_fast: cc.fail_fast_on_dead_node.unwrap_or(false),
+            backend_queue_size: cc.backend_queue_size(),
+            keepalive_ping: cc.backend_keepalive_ping(),
+            flush_batch: cc.backend_flush_batch(),
+            flush_delay: cc.backend_flush_delay(),
+            mc_binary: matches!(cc.cache_type, CacheType::MemcacheBinary),
+            cluster: Arc::from(cc.name.as_str()),
+        }
+    }
+}
+
+// ProxyCmd is a command addressed to the proxy itself which is handled by the frontend
+// instead of being forwarded to the backends.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProxyCmd {
+    // Timeout sets the end-to-end deadline of the connection commands in milliseconds.
+    // zero resets the connection back to the cluster timeout.
+    Timeout(u64),
+
+    // Where looks up the backend the given key is routed to.
+    Where(Vec<u8>),
+
+    // Explain describes the routing of the given command without executing it.
+    Explain(Explain),
+
+    // ReadOnly routes the reads of the connection to the replicas if set by READONLY, or to the
+    // primaries if cleared by READWRITE.
+    ReadOnly(bool),
+
+    // Client describes or names the connections of the proxy, in place of the CLIENT command of the
+    // backends.
+    Client(ClientCmd),
+}
+
+// ClientCmd is the subcommand of a CLIENT command answered by the proxy
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientCmd {
+    // Info describes the connection itself.
+    Info,
+
+    // List describes all the connections of the cluster.
+    List,
+
+    // SetName names the connection, an empty name clearing it.
+    SetName(String),
+
+    // Other is any other subcommand, acknowledged without effect.
+    Other,
+}
+
+// Explain is the classification and the keys of a command given to PROXY EXPLAIN
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Explain {
+    // command is the verb of the command
+    pub command: String,
+
+    // kind is the classification of the command, either read, write or other
+    pub kind: &'static str,
+
+    // cmd_type is the type of the command, selecting the backends it is routed to
+    pub cmd_type: CmdType,
+
+    // keys are the keys the command is routed by, one for each command sent to the backends
+    pub keys: Vec<Vec<u8>>,
+}
+
+// ProxyReply is the protocol independent reply of a handled proxy command
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProxyReply {
+    Ok,
+    Bulk(Vec<u8>),
+}
+
+// Redirector re-dispatches the commands redirected by the backends to the node of the redirection
+pub type Redirector<T> = Arc<dyn Fn(T, Redirect) + Send + Sync>;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Redirect {
     Move { slot: usize, to: String },
@@ -60,3 +363,216 @@ impl Redirect {
         matches!(self, Redirect::Ask { .. })
     }
 }
+
+// Shutdown stops the clusters sharing it gracefully. Once started the clusters stop accepting clients,
+// reply the commands their clients already sent and only then close their backend connections.
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<Option<Instant>>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown(Arc::new(watch::channel(None).0))
+    }
+}
+
+impl Shutdown {
+    // start starts the shutdown, the clients not drained within the grace are closed with their commands
+    pub fn start(&self, grace: Duration) {
+        self.0.send_replace(Some(Instant::now() + grace));
+    }
+
+    // deadline returns the time the clients must be drained by, None if the shutdown is not started
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        *self.0.borrow()
+    }
+
+    // started resolves once the shutdown is started
+    pub(crate) fn started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut deadline = self.0.subscribe();
+        async move {
+            let _ = deadline.wait_for(Option::is_some).await;
+        }
+    }
+}
+
+// Clients is the registry of the client connections of a cluster, which the CLIENT commands describe
+#[derive(Debug, Default)]
+pub(crate) struct Clients {
+    next_id: AtomicU64,
+    conns: Mutex<BTreeMap<u64, Arc<ClientConn>>>,
+}
+
+impl Clients {
+    // register adds the connection of the client of the given address with the next id of the cluster
+    pub(crate) fn register(&self, addr: &str) -> Arc<ClientConn> {
+        let conn = Arc::new(ClientConn {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            addr: addr.to_string(),
+            name: Mutex::new(String::new()),
+            connected_at: Instant::now(),
+        });
+        self.conns.lock().unwrap().insert(conn.id, conn.clone());
+        conn
+    }
+
+    // unregister removes the closed connection of the given id
+    pub(crate) fn unregister(&self, id: u64) {
+        self.conns.lock().unwrap().remove(&id);
+    }
+
+    // reply handles the CLIENT command of the given connection, laddr being the listen address of the cluster
+    pub(crate) fn reply(&self, cmd: ClientCmd, conn: &ClientConn, laddr: &str) -> ProxyReply {
+        match cmd {
+            ClientCmd::Info => ProxyReply::Bulk(conn.describe(laddr).into_bytes()),
+            ClientCmd::List => {
+                let conns = self.conns.lock().unwrap();
+                let list: String = conns.values().map(|x| x.describe(laddr)).collect();
+                ProxyReply::Bulk(list.into_bytes())
+            }
+            ClientCmd::SetName(name) => {
+                *conn.name.lock().unwrap() = name;
+                ProxyReply::Ok
+            }
+            ClientCmd::Other => ProxyReply::Ok,
+        }
+    }
+}
+
+// ClientConn is a client connection of a cluster as described by the CLIENT commands
+#[derive(Debug)]
+pub(crate) struct ClientConn {
+    pub(crate) id: u64,
+    addr: String,
+    name: Mutex<String>,
+    connected_at: Instant,
+}
+
+impl ClientConn {
+    // describe formats the connection as a line of CLIENT LIST
+    fn describe(&self, laddr: &str) -> String {
+        format!(
+            "id={} addr={} laddr={} name={} age={}\n",
+            self.id,
+            self.addr,
+            laddr,
+            self.name.lock().unwrap(),
+            self.connected_at.elapsed().as_secs()
+        )
+    }
+}
+
+// Metered wraps the codec of a client or a backend connection, counting the bytes it decodes and encodes
+// in the traffic metrics of the cluster
+pub(crate) struct Metered<C> {
+    codec: C,
+    cluster: Arc<str>,
+}
+
+impl<C> Metered<C> {
+    pub(crate) fn new(codec: C, cluster: &Arc<str>) -> Self {
+        Metered {
+            codec,
+            cluster: cluster.clone(),
+        }
+    }
+}
+
+impl<C: Decoder> Decoder for Metered<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = src.len();
+        let item = self.codec.decode(src);
+        if src.len() < len {
+            bytes_in_incr(&self.cluster, len - src.len());
+        }
+        item
+    }
+}
+
+impl<I, C: Encoder<I>> Encoder<I> for Metered<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = dst.len();
+        let res = self.codec.encode(item, dst);
+        if dst.len() > len {
+            bytes_out_incr(&self.cluster, dst.len() - len);
+        }
+        res
+    }
+}
+
+// handshake terminates the TLS of the client accepted by the cluster, None if it fails or times out
+pub(crate) async fn handshake(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+    cluster: &str,
+    client: SocketAddr,
+) -> Option<TlsStream<TcpStream>> {
+    match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(err)) => {
+            warn!(
+                "cluster {} fail to handshake TLS with client {} due to {}",
+                cluster, client, err
+            );
+            None
+        }
+        Err(_) => {
+            warn!(
+                "cluster {} timed out the TLS handshake with client {}",
+                cluster, client
+            );
+            None
+        }
+    }
+}
+
+// routing_rng returns the random source of a new client connection, seeded by the deterministic_routing
+// of the cluster if set
+pub(crate) fn routing_rng(cc: &ClusterConfig) -> StdRng {
+    match cc.deterministic_routing {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// drain waits until the given clients of the cluster are all closed or the deadline passes
+pub(crate) async fn drain(cluster: &str, fronts: &AtomicUsize, deadline: Instant) {
+    loop {
+        let left = fronts.load(Ordering::Relaxed);
+        if left == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "cluster {} closes its backends with {} clients not drained",
+                cluster, left
+            );
+            return;
+        }
+        time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+}
+
+// accept waits for the next client of the cluster, marking its accept loop alive on the accepted client
+// and on each heartbeat while waiting
+pub(crate) async fn accept(
+    listener: &TcpListener,
+    cluster: &str,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        match time::timeout(ACCEPT_HEARTBEAT_INTERVAL, listener.accept()).await {
+            Ok(accepted) => {
+                accept_loop_alive(cluster);
+                return accepted;
+            }
+            Err(_) => {
+                debug!("cluster {} is still serving", cluster);
+                accept_loop_alive(cluster);
+            }
+        }
+    }
+}
@@ -0,0 +1,1528 @@
+mod front;
+// Path: src/proxy/cluster/front.rs
+
+use crossbeam_channel::{Sender, TrySendError};
+use crossbeam_utils::sync::ShardedLock;
+use futures::{
+    future::{self, Either},
+    task::noop_waker,
+    SinkExt, StreamExt,
+};
+use log::{debug, error, info, warn};
+use rand::{rngs::StdRng, Rng};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::Handle,
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    admin::{
+        self, BackendInflight, ClusterAdmin, ClusterInfo, Reconnect, RefreshSlots, RingInfo,
+        SlotsInfo, SlotsNode,
+    },
+    com::{
+        config::{create_reuse_port_listener, ClusterConfig},
+        AsError,
+    },
+    metrics::{
+        accept_loop_alive, cluster_serving_decr, cluster_serving_incr,
+        throughput::{Throughput, THROUGHPUT_SAMPLE_INTERVAL},
+    },
+    protocol::redis::{
+        self, new_auth_cmd, new_cluster_slots_cmd, slots_reply_to_replicas, RedisNodeCodec,
+        ReplicaLayout, SLOTS_COUNT,
+    },
+    proxy::{
+        accept,
+        cluster::front::Front,
+        drain,
+        front::serve,
+        handshake,
+        standalone::{
+            connect, dial,
+            transport::{BackendAddr, BackendStream},
+            Pool,
+        },
+        Clients, Policy, Redirect, Redirector, Request, Shutdown,
+    },
+    utils::{bucket::RetryBudget, crc::crc16, helper::get_runtime_handle},
+};
+
+// SLOT_HASH_TAG is the hash tag of the redis cluster, only the part of the key in the braces is
+// hashed to find the slot if there is any
+const SLOT_HASH_TAG: &[u8] = b"{}";
+
+// RedisCluster proxies a redis cluster, the commands are routed to the master serving the slot of
+// their key as the CLUSTER SLOTS reply of the seed nodes describes it
+pub struct RedisCluster<T> {
+    pub cc: ClusterConfig,
+
+    // runtime is the handle of the runtime the cluster is running on
+    runtime: Handle,
+
+    // seeds are the nodes asked for the slots of the cluster, in the order of the servers
+    seeds: Vec<String>,
+    auth: String,
+
+    // slots is the address of the master of each slot, empty for the slots not served by any node
+    slots: ShardedLock<Vec<String>>,
+
+    // replicas are the addresses of the replicas of each slot, only kept if reading from them
+    replicas: ShardedLock<Vec<Vec<String>>>,
+
+    // conns is the connection to each node of the cluster, the masters and the read replicas
+    conns: ShardedLock<HashMap<String, Node<T>>>,
+
+    // read_from_slave routes the read commands to a live replica of their slot
+    read_from_slave: bool,
+
+    // policy is the set of the limits the client commands are checked against
+    policy: Policy,
+
+    // backend_source is the local IP address the backend connections are made from, if set
+    backend_source: Option<IpAddr>,
+
+    // backend_tls connects to the nodes over TLS, which are connected over plain TCP if None
+    backend_tls: Option<TlsConnector>,
+
+    // retry_budget bounds the redirections and the retries of all the node connections, unlimited if None
+    retry_budget: Option<Arc<RetryBudget>>,
+
+    // shutdown stops the cluster gracefully once started, and fronts is the number of the clients the
+    // cluster is serving, waited on before the node connections are closed
+    shutdown: Shutdown,
+    fronts: AtomicUsize,
+
+    // clients are the connections of the cluster described by CLIENT LIST
+    clients: Clients,
+
+    // tls terminates the TLS of the clients, which are served over plain TCP if None
+    tls: Option<TlsAcceptor>,
+
+    // throughput counts the forwarded commands and keeps their moving rate for the admin endpoints
+    throughput: Throughput,
+}
+
+impl<T> RedisCluster<T>
+where
+    T: Request + Send + Sync + 'static,
+{
+    pub(crate) fn new(cc: ClusterConfig) -> Result<RedisCluster<T>, AsError> {
+        // the seeds are plain addresses, the weights and the aliases only make sense for the rings
+        let seeds = cc
+            .servers
+            .iter()
+            .map(|server| match server.split_whitespace().next() {
+                Some(seed) => Ok(seed.to_string()),
+                None => Err(AsError::BadConfig("servers: empty server line".to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(RedisCluster {
+            runtime: get_runtime_handle(),
+            seeds,
+            auth: cc.auth.clone(),
+            slots: ShardedLock::new(vec![String::new(); SLOTS_COUNT]),
+            replicas: ShardedLock::new(vec![Vec::new(); SLOTS_COUNT]),
+            conns: ShardedLock::new(HashMap::new()),
+            read_from_slave: cc.read_from_slave(),
+            policy: Policy::new(&cc),
+            backend_source: cc.backend_source_ip()?,
+            backend_tls: cc.backend_tls_connector()?,
+            retry_budget: cc
+                .retry_budget_per_sec
+                .map(|x| Arc::new(RetryBudget::new(x))),
+            shutdown: Shutdown::default(),
+            fronts: AtomicUsize::new(0),
+            clients: Clients::default(),
+            tls: cc.tls_acceptor()?,
+            throughput: Throughput::default(),
+            cc,
+        })
+    }
+
+    // with_shutdown makes the cluster stop gracefully once the given shutdown is started
+    pub(crate) fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    // refresh_slots asks the given nodes for the slots of the cluster until one of them replies and
+    // connects to the masters serving them. The slots are kept as they are if none of them replies.
+    async fn refresh_slots(self: &Arc<Self>, nodes: &[String]) -> Result<(), AsError> {
+        for node in nodes {
+            match self.fetch_slots(node).await {
+                Ok((masters, replicas)) => {
+                    info!(
+                        "cluster {} fetched the slots from node {}",
+                        self.cc.name, node
+                    );
+                    self.update_slots(masters, replicas).await;
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(
+                        "cluster {} fail to fetch the slots from node {} due to {}",
+                        self.cc.name, node, err
+                    );
+                }
+            }
+        }
+        Err(AsError::ClusterAllSeedsDie(self.cc.name.clone()))
+    }
+
+    // fetch_slots asks the seed for the slots of the cluster on a connection of its own, made like the
+    // ones of the nodes from the backend source and over TLS if set
+    async fn fetch_slots(&self, seed: &str) -> Result<ReplicaLayout, AsError> {
+        let addr = BackendAddr::resolve_blocking(seed).await?;
+        let socket = dial(
+            seed,
+            &addr,
+            self.backend_source,
+            self.backend_tls.as_ref(),
+            self.policy.dial_timeout,
+        )
+        .await?;
+        let mut framed = redis::Cmd::back_codec(&self.policy).framed(socket);
+
+        if !self.auth.is_empty() {
+            request(&mut framed, new_auth_cmd(&self.auth), &self.policy).await?;
+        }
+
+        let cmd = new_cluster_slots_cmd();
+        let reply = request(&mut framed, cmd.clone(), &self.policy).await?;
+        cmd.set_reply(reply);
+        slots_reply_to_replicas(cmd)?.ok_or(AsError::WrongClusterSlotsReplyType)
+    }
+
+    // live_nodes returns the nodes to ask for the slots, the connected nodes before the seeds
+    fn live_nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self
+            .conns
+            .read()
+            .expect("cluster conns lock must not be poisoned")
+            .keys()
+            .cloned()
+            .collect();
+        nodes.sort();
+        for seed in self.seeds.iter() {
+            if !nodes.contains(seed) {
+                nodes.push(seed.clone());
+            }
+        }
+        nodes
+    }
+
+    // update_slots replaces the slots of the cluster, connecting to the new nodes and dropping the
+    // connections of the ones not serving any slot anymore. The commands are routed by either the old
+    // or the new slots and connections, never a mix of them. The new nodes are connected beforehand, so
+    // the routing of the fronts is only held while the slots and the connections are swapped.
+    async fn update_slots(self: &Arc<Self>, masters: Vec<String>, mut replicas: Vec<Vec<String>>) {
+        if !self.read_from_slave {
+            replicas = vec![Vec::new(); SLOTS_COUNT];
+        }
+        let addrs: HashSet<&str> = masters
+            .iter()
+            .chain(replicas.iter().flatten())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.as_str())
+            .collect();
+
+        let mut connected = HashMap::new();
+        for addr in addrs.iter() {
+            if self.get_node_sender(addr).is_none() {
+                if let Some(sender) = self.connect_node(addr).await {
+                    connected.insert(addr.to_string(), sender);
+                }
+            }
+        }
+
+        let mut slots = self
+            .slots
+            .write()
+            .expect("cluster slots lock must not be poisoned");
+        let mut slot_replicas = self
+            .replicas
+            .write()
+            .expect("cluster replicas lock must not be poisoned");
+        let mut conns = self
+            .conns
+            .write()
+            .expect("cluster conns lock must not be poisoned");
+        conns.retain(|addr, _| addrs.contains(addr.as_str()));
+        // a redirection may have connected to the node meanwhile, in which case its connection is kept
+        for (addr, node) in connected {
+            conns.entry(addr).or_insert(node);
+        }
+        *slots = masters;
+        *slot_replicas = replicas;
+    }
+
+    // connect_node resolves and connects to the node on a blocking thread of the cluster runtime, as the
+    // resolution of its host blocks, so neither the backend tasks nor the fronts wait on it
+    async fn connect_node(self: &Arc<Self>, addr: &str) -> Option<Node<T>> {
+        let cluster = self.clone();
+        let addr = addr.to_string();
+        self.runtime
+            .spawn_blocking(move || cluster.connect(&addr, None))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    // connect creates the connection to the node, taking over the socket established beforehand if any
+    fn connect(self: &Arc<Self>, addr: &str, socket: Option<BackendStream>) -> Option<Node<T>> {
+        debug!("trying to connect to {}", addr);
+
+        // the backends only hold the cluster weakly, so the stopped cluster is freed with its backends
+        let cluster = Arc::downgrade(self);
+        let redirector: Redirector<T> =
+            Arc::new(move |cmd: T, redirect: Redirect| match cluster.upgrade() {
+                Some(cluster) => cluster.redirect(cmd, redirect),
+                None => cmd.set_error(&AsError::RedirectFailError),
+            });
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        match connect(
+            addr,
+            Pool::Stable,
+            &self.policy,
+            self.retry_budget.clone(),
+            self.backend_source,
+            connected.clone(),
+            Arc::new(AtomicBool::new(false)),
+            outstanding.clone(),
+            Some(redirector),
+            self.backend_tls.clone(),
+            &self.auth,
+            socket,
+            Vec::new(),
+        ) {
+            Ok(sender) => {
+                // the roles change on failover, so every node is ready to serve the reads of a replica
+                if self.read_from_slave {
+                    prepare(&sender, T::read_only_request());
+                }
+                Some(Node {
+                    sender,
+                    connected,
+                    outstanding,
+                })
+            }
+            Err(err) => {
+                error!("fail to connect to {} due {:?}", addr, err);
+                None
+            }
+        }
+    }
+
+    // redirect sends the redirected command to the target node. The node of a MOVED redirection serves
+    // the slot from now on, the one of an ASK redirection only serves this command while the slot migrates.
+    // The redirection is made by the backend task, so a node not connected yet is connected on a task of
+    // its own rather than stalling the backend.
+    fn redirect(self: &Arc<Self>, cmd: T, redirect: Redirect) {
+        let (moved, to) = match redirect {
+            Redirect::Move { slot, to } if slot < SLOTS_COUNT => (Some(slot), to),
+            Redirect::Ask { slot, to } if slot < SLOTS_COUNT => (None, to),
+            _ => {
+                cmd.set_error(&AsError::RedirectFailError);
+                return;
+            }
+        };
+
+        if let Some(sender) = self.get_node_sender(&to) {
+            self.send_redirected(cmd, moved, &to, sender);
+            return;
+        }
+        let cluster = self.clone();
+        self.runtime.spawn(async move {
+            match cluster.get_conn(&to).await {
+                Some(sender) => cluster.send_redirected(cmd, moved, &to, sender),
+                None => cmd.set_error(&AsError::RedirectFailError),
+            }
+        });
+    }
+
+    // send_redirected sends the redirected command to the connection of its target node, moving the slot
+    // of a MOVED redirection to the node
+    fn send_redirected(&self, cmd: T, moved: Option<usize>, to: &str, sender: Sender<T>) {
+        // the slot is only moved once its new node is reachable, so a bogus redirection keeps it served
+        if let Some(slot) = moved {
+            info!("cluster {} slot {} is moved to {}", self.cc.name, slot, to);
+            self.slots
+                .write()
+                .expect("cluster slots lock must not be poisoned")[slot] = to.to_string();
+        }
+
+        // the redirection must not wait for the target queue, it is sent by a backend task
+        match sender.try_send(cmd) {
+            Ok(()) => debug!("cluster {} redirected a command to {}", self.cc.name, to),
+            Err(TrySendError::Full(cmd) | TrySendError::Disconnected(cmd)) => {
+                error!(
+                    "cluster {} fail to redirect a command to {}",
+                    self.cc.name, to
+                );
+                cmd.set_error(&AsError::RedirectFailError);
+            }
+        }
+    }
+
+    // get_conn returns the connection to the node of the given address, connecting to it if needed. The
+    // redirections name arbitrary nodes, so the unresolvable ones fail the command only.
+    async fn get_conn(self: &Arc<Self>, addr: &str) -> Option<Sender<T>> {
+        if let Some(sender) = self.get_node_sender(addr) {
+            return Some(sender);
+        }
+        let node = self.connect_node(addr).await?;
+
+        // another redirection may have connected to the node meanwhile, in which case its connection is
+        // kept and the new one is closed
+        let mut conns = self
+            .conns
+            .write()
+            .expect("cluster conns lock must not be poisoned");
+        Some(conns.entry(addr.to_string()).or_insert(node).sender.clone())
+    }
+
+    // get_sender returns the connection serving the slot of the given key hash. The reads are served by
+    // a live replica of the slot drawn from the given source if reading from them, the master otherwise.
+    fn get_sender(&self, hash: u64, read: bool, rng: &mut StdRng) -> Option<Sender<T>> {
+        let slot = (hash as usize) % SLOTS_COUNT;
+        let slots = self
+            .slots
+            .read()
+            .expect("cluster slots lock must not be poisoned");
+        let replicas = self
+            .replicas
+            .read()
+            .expect("cluster replicas lock must not be poisoned");
+        let conns = self
+            .conns
+            .read()
+            .expect("cluster conns lock must not be poisoned");
+
+        if read && self.read_from_slave {
+            let live: Vec<&Node<T>> = replicas
+                .get(slot)
+                .into_iter()
+                .flatten()
+                .filter_map(|addr| conns.get(addr))
+                .filter(|node| node.connected.load(Ordering::Relaxed))
+                .collect();
+            if !live.is_empty() {
+                return Some(live[rng.gen_range(0..live.len())].sender.clone());
+            }
+        }
+
+        let addr = slots.get(slot).filter(|x| !x.is_empty())?;
+        conns.get(addr).map(|node| node.sender.clone())
+    }
+
+    // masters returns the addresses of the masters serving any slot in order
+    fn masters(&self) -> Vec<String> {
+        let slots = self
+            .slots
+            .read()
+            .expect("cluster slots lock must not be poisoned");
+        let masters: BTreeSet<&String> = slots.iter().filter(|x| !x.is_empty()).collect();
+        masters.into_iter().cloned().collect()
+    }
+
+    // slots_info returns the slot ranges each master serves
+    fn slots_info(&self) -> SlotsInfo {
+        let slots = self
+            .slots
+            .read()
+            .expect("cluster slots lock must not be poisoned");
+        let mut masters: BTreeMap<&str, Vec<[usize; 2]>> = BTreeMap::new();
+        let mut covered = 0;
+        for (slot, addr) in slots.iter().enumerate() {
+            if addr.is_empty() {
+                continue;
+            }
+            covered += 1;
+            let ranges = masters.entry(addr).or_default();
+            match ranges.last_mut() {
+                Some(range) if range[1] + 1 == slot => range[1] = slot,
+                _ => ranges.push([slot, slot]),
+            }
+        }
+        SlotsInfo {
+            covered,
+            masters: masters
+                .into_iter()
+                .map(|(node, ranges)| SlotsNode {
+                    node: node.to_string(),
+                    slots: ranges.iter().map(|[begin, end]| end - begin + 1).sum(),
+                    ranges,
+                })
+                .collect(),
+        }
+    }
+
+    // get_node_sender returns the connection of the node of the given address
+    fn get_node_sender(&self, addr: &str) -> Option<Sender<T>> {
+        self.conns
+            .read()
+            .expect("cluster conns lock must not be poisoned")
+            .get(addr)
+            .map(|node| node.sender.clone())
+    }
+
+    // run starts serving the cluster once the slots are fetched from the seeds. Failing to listen only
+    // fails this cluster, the others keep running.
+    pub(crate) fn run(self) -> Result<JoinHandle<()>, AsError> {
+        let addr = self
+            .cc
+            .listen_addr
+            .parse::<SocketAddr>()
+            .expect("Listening address must be OK here");
+
+        let listener = create_reuse_port_listener(addr).map_err(|err| {
+            error!(
+                "cluster {} fail to listen on {} due to {}",
+                self.cc.name, addr, err
+            );
+            AsError::IoError(err)
+        })?;
+        info!("proxy is listening on {}", addr);
+
+        let this = Arc::new(self);
+        Ok(get_runtime_handle().spawn(async move {
+            let name = this.cc.name.clone();
+            // the supervisor of the worker restarts the cluster, so the seeds are asked again
+            if let Err(err) = this.refresh_slots(&this.seeds).await {
+                error!("{}", err);
+                return;
+            }
+
+            // the slots are refreshed to follow the resharding of the cluster
+            let refreshed = this.clone();
+            let refresh = get_runtime_handle().spawn(async move {
+                let period = Duration::from_millis(refreshed.cc.fetch_interval_ms());
+                let mut interval = time::interval_at(time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = refreshed.refresh_slots(&refreshed.live_nodes()).await {
+                        error!("{}, keeping the current slots", err);
+                    }
+                }
+            });
+            cluster_serving_incr(&name);
+            accept_loop_alive(&name);
+            let sampled = this.clone();
+            let sample = get_runtime_handle().spawn(async move {
+                let mut interval = time::interval(THROUGHPUT_SAMPLE_INTERVAL);
+                let mut last = interval.tick().await;
+                loop {
+                    let now = interval.tick().await;
+                    sampled.throughput.sample(now - last);
+                    last = now;
+                }
+            });
+            admin::register(&name, Arc::new(this.clone()));
+            let mut shutdown = pin!(this.shutdown.started());
+
+            loop {
+                let accepted =
+                    match future::select(pin!(accept(&listener, &name)), shutdown.as_mut()).await {
+                        Either::Left((accepted, _)) => accepted,
+                        Either::Right(_) => break,
+                    };
+                match accepted {
+                    Ok((socket, addr)) => {
+                        debug!("accepting connection from client at {}", addr);
+                        if socket.set_nodelay(true).is_err() {
+                            warn!(" cluster {} failed to set nodelay for {}", name, addr);
+                        }
+
+                        match this.tls.clone() {
+                            None => this.serve(addr, socket),
+                            Some(acceptor) => {
+                                let cluster = this.clone();
+                                get_runtime_handle().spawn(async move {
+                                    if let Some(socket) =
+                                        handshake(&acceptor, socket, &cluster.cc.name, addr).await
+                                    {
+                                        cluster.serve(addr, socket);
+                                    }
+                                });
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("fail to accept connection due to {}", err);
+                        break;
+                    }
+                }
+            }
+
+            cluster_serving_decr(&name);
+            refresh.abort();
+            sample.abort();
+            match this.shutdown.deadline() {
+                Some(deadline) => this.close(deadline).await,
+                None => error!("cluster {} stopped accepting connections on {}", name, addr),
+            }
+        }))
+    }
+
+    // serve spawns the frontend of the accepted client, over plain TCP or TLS
+    fn serve<S>(self: &Arc<Self>, client: SocketAddr, socket: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        serve(client, socket, &self.policy, |client, stream, sink| {
+            Front::new(client, self.clone(), stream, sink)
+        });
+    }
+
+    // close waits until the clients are replied the commands they sent before the shutdown, or until the
+    // deadline, and only then closes the node connections
+    async fn close(&self, deadline: Instant) {
+        info!("cluster {} is shutting down", self.cc.name);
+        drain(&self.cc.name, &self.fronts, deadline).await;
+
+        // the nodes serve the commands already queued to them once their senders are dropped
+        self.conns.write().unwrap().clear();
+        info!("cluster {} is shut down", self.cc.name);
+    }
+}
+
+// Node is the connection to a node of the cluster
+struct Node<T> {
+    sender: Sender<T>,
+
+    // connected is set once the connection to the node is established
+    connected: Arc<AtomicBool>,
+
+    // outstanding is the number of the commands sent to the node and not replied yet
+    outstanding: Arc<AtomicUsize>,
+}
+
+// the admin operations refresh the slots and connect to the nodes, which needs the shared cluster
+impl<T> ClusterAdmin for Arc<RedisCluster<T>>
+where
+    T: Request + Send + Sync + 'static,
+{
+    fn reconnect(&self, addr: &str) -> Reconnect {
+        // the connection is dialed on the cluster runtime rather than the caller one
+        let cluster = self.clone();
+        let addr = addr.to_string();
+        let reconnect = self.runtime.spawn(async move {
+            let known = cluster
+                .conns
+                .read()
+                .expect("cluster conns lock must not be poisoned")
+                .contains_key(&addr);
+            if !known {
+                return Err(AsError::UnknownBackend(addr));
+            }
+
+            // the node keeps its connection unless the new one is established
+            let backend = BackendAddr::resolve_blocking(&addr).await?;
+            let socket = dial(
+                &addr,
+                &backend,
+                cluster.backend_source,
+                cluster.backend_tls.as_ref(),
+                cluster.policy.dial_timeout,
+            )
+            .await
+            .map_err(AsError::IoError)?;
+            if let Some(node) = cluster.connect(&addr, Some(socket)) {
+                cluster
+                    .conns
+                    .write()
+                    .expect("cluster conns lock must not be poisoned")
+                    .insert(addr, node);
+            }
+            Ok(())
+        });
+        let name = self.cc.name.clone();
+        Box::pin(async move {
+            reconnect
+                .await
+                .unwrap_or(Err(AsError::ClusterStopped(name)))
+        })
+    }
+
+    fn reload(&self, _cc: &ClusterConfig) -> Result<(), AsError> {
+        // the nodes follow the slots of the cluster, the servers are only the seeds asked at start
+        Err(AsError::BadConfig(format!(
+            "cluster {} must be restarted to change its seeds",
+            self.cc.name
+        )))
+    }
+
+    fn gc(&self, _idle: Option<Duration>) -> usize {
+        // every node serves its slots, so its connection is kept however idle it is
+        0
+    }
+
+    fn info(&self) -> ClusterInfo {
+        ClusterInfo {
+            name: self.cc.name.clone(),
+            commands: self.throughput.commands(),
+            ops_per_sec: self.throughput.rate(),
+        }
+    }
+
+    fn inflight(&self) -> Vec<BackendInflight> {
+        let mut backends: Vec<_> = self
+            .conns
+            .read()
+            .expect("cluster conns lock must not be poisoned")
+            .iter()
+            .map(|(addr, node)| BackendInflight {
+                pool: Pool::Stable.as_str().to_string(),
+                backend: addr.clone(),
+                inflight: node.outstanding.load(Ordering::Relaxed),
+            })
+            .collect();
+        backends.sort_by(|a, b| a.backend.cmp(&b.backend));
+        backends
+    }
+
+    fn ring(&self) -> Vec<RingInfo> {
+        // the keys are routed by the slots rather than a ketama ring
+        Vec::new()
+    }
+
+    fn refresh_slots(&self) -> RefreshSlots {
+        // the refresh connects to the new nodes, so it is run on the cluster runtime
+        let cluster = self.clone();
+        let refresh = self.runtime.spawn(async move {
+            RedisCluster::refresh_slots(&cluster, &cluster.live_nodes()).await?;
+            Ok(cluster.slots_info())
+        });
+        let name = self.cc.name.clone();
+        Box::pin(async move { refresh.await.unwrap_or(Err(AsError::ClusterStopped(name))) })
+    }
+}
+
+// prepare sends the command setting up a new connection, its reply is only consumed by the backend
+fn prepare<T: Request>(sender: &Sender<T>, mut cmd: T) {
+    cmd.register_waker(noop_waker());
+    let _ = sender.send(cmd);
+}
+
+// slot_hash returns the hash of the key the slot is taken from, the one of the redis cluster
+pub(crate) fn slot_hash<T: Request>(cmd: &T) -> u64 {
+    cmd.key_hash(SLOT_HASH_TAG, crc16)
+}
+
+// request sends the command on the seed connection and waits for its reply
+async fn request(
+    framed: &mut Framed<BackendStream, RedisNodeCodec>,
+    cmd: redis::Cmd,
+    policy: &Policy,
+) -> Result<<redis::Cmd as Request>::Reply, AsError> {
+    framed.send(cmd).await?;
+    match time::timeout(policy.timeout, framed.next()).await {
+        Ok(Some(reply)) => reply,
+        Ok(None) => Err(AsError::BackendClosedError("seed".to_string())),
+        Err(_) => Err(AsError::CmdTimeout),
+    }
+}
+
+pub fn spawn(cc: ClusterConfig, shutdown: Shutdown) -> Result<JoinHandle<()>, AsError> {
+    RedisCluster::<redis::Cmd>::new(cc)?
+        .with_shutdown(shutdown)
+        .run()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{init_test_instruments, test_metric_value};
+    use crate::protocol::redis::init_redis_supported_cmds;
+    use crate::proxy::test_support::{self, serve_fake_backend, Client, TEST_REPLY_TIMEOUT};
+    use std::sync::{atomic::Ordering, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::{TcpListener, TcpStream};
+    use tower::ServiceExt;
+
+    // spawn_node starts a fake cluster node which replies the given layout to CLUSTER SLOTS and the
+    // handler result to the other commands
+    fn spawn_node<F>(listener: TcpListener, slots: Layout, handler: F)
+    where
+        F: Fn(&[Vec<u8>]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve_node(socket, slots.clone(), handler.clone()));
+            }
+        });
+    }
+
+    // spawn_tls_node is like spawn_node but serves over TLS, returning the path of the certificate the
+    // node is verified with along with the addresses the node is connected from
+    fn spawn_tls_node<F>(
+        listener: TcpListener,
+        slots: Layout,
+        handler: F,
+    ) -> (String, Arc<Mutex<Vec<IpAddr>>>)
+    where
+        F: Fn(&[Vec<u8>]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let (config, cert_path) = test_support::tls_server_config();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let connected = peers.clone();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((socket, peer)) = listener.accept().await {
+                connected.lock().unwrap().push(peer.ip());
+                let (acceptor, slots, handler) = (acceptor.clone(), slots.clone(), handler.clone());
+                tokio::spawn(async move {
+                    if let Ok(socket) = acceptor.accept(socket).await {
+                        serve_node(socket, slots, handler).await;
+                    }
+                });
+            }
+        });
+        (cert_path, peers)
+    }
+
+    // serve_node replies to the commands of a connection of the fake node
+    async fn serve_node<S, F>(socket: S, slots: Layout, handler: Arc<F>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        F: Fn(&[Vec<u8>]) -> Vec<u8>,
+    {
+        let reply = move |args: &[Vec<u8>]| {
+            Some(match args[0].eq_ignore_ascii_case(b"CLUSTER") {
+                true => slots.lock().unwrap().clone(),
+                false => handler(args),
+            })
+        };
+        serve_fake_backend(socket, Duration::ZERO, Arc::new(reply)).await;
+    }
+
+    // Layout is the CLUSTER SLOTS reply of the fake nodes, shared to be changed while they run
+    type Layout = Arc<Mutex<Vec<u8>>>;
+
+    // slots_reply returns the CLUSTER SLOTS reply of the given slot ranges and their masters
+    fn slots_reply(ranges: &[(usize, usize, SocketAddr)]) -> Layout {
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|&(b, e, addr)| (b, e, addr, &[][..]))
+            .collect();
+        replicated_slots_reply(&ranges)
+    }
+
+    // replicated_slots_reply returns the CLUSTER SLOTS reply of the given slot ranges, their masters
+    // and their replicas
+    fn replicated_slots_reply(ranges: &[(usize, usize, SocketAddr, &[SocketAddr])]) -> Layout {
+        let node = |addr: &SocketAddr| format!("*2\r\n$9\r\n127.0.0.1\r\n:{}\r\n", addr.port());
+        let mut reply = format!("*{}\r\n", ranges.len());
+        for (begin, end, master, replicas) in ranges {
+            reply += &format!("*{}\r\n:{}\r\n:{}\r\n", 3 + replicas.len(), begin, end);
+            reply += &node(master);
+            for replica in replicas.iter() {
+                reply += &node(replica);
+            }
+        }
+        Arc::new(Mutex::new(reply.into_bytes()))
+    }
+
+    // spawn_proxy runs a redis cluster proxy seeded by the given node and returns its address
+    fn spawn_proxy<F>(seed: SocketAddr, configure: F) -> String
+    where
+        F: FnOnce(&mut ClusterConfig),
+    {
+        let cc = ClusterConfig {
+            name: "test-redis-cluster".to_string(),
+            cache_type: crate::com::config::CacheType::RedisCluster,
+            servers: vec![seed.to_string()],
+            ..Default::default()
+        };
+        test_support::spawn_proxy(cc, configure, |cc| {
+            spawn(cc, Shutdown::default()).unwrap();
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_route_by_slot() {
+        // the slots 0-8191 are served by the first node and the others by the second one
+        let low = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let high = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (low_addr, high_addr) = (low.local_addr().unwrap(), high.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 8191, low_addr), (8192, 16383, high_addr)]);
+        let own_addr = |addr: SocketAddr| move |_: &[Vec<u8>]| bulk(addr);
+        spawn_node(low, slots.clone(), own_addr(low_addr));
+        spawn_node(high, slots, own_addr(high_addr));
+
+        let mut client = Client::connect(&spawn_proxy(high_addr, |_| {})).await;
+
+        // CRC16 puts foo in the slot 12182, bar in 5061 and {bar}foo in the slot of bar
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+            bulk(high_addr)
+        );
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nbar\r\n").await,
+            bulk(low_addr)
+        );
+        assert_eq!(
+            client
+                .request(b"*2\r\n$3\r\nGET\r\n$8\r\n{bar}foo\r\n")
+                .await,
+            bulk(low_addr)
+        );
+        assert_eq!(
+            client
+                .request(b"*3\r\n$4\r\nMGET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+                .await,
+            [&b"*2\r\n"[..], &bulk(high_addr), &bulk(low_addr)].concat()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shutdown_closes_drained_clients() {
+        init_test_instruments();
+        init_redis_supported_cmds();
+        let node = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap();
+        spawn_node(node, slots_reply(&[(0, 16383, node_addr)]), move |_| {
+            bulk(node_addr)
+        });
+
+        let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|x| x.local_addr())
+            .unwrap()
+            .to_string();
+        let shutdown = Shutdown::default();
+        let serving = spawn(
+            ClusterConfig {
+                name: "redis-cluster-shutdown".to_string(),
+                listen_addr: listen_addr.clone(),
+                cache_type: crate::com::config::CacheType::RedisCluster,
+                servers: vec![node_addr.to_string()],
+                ..Default::default()
+            },
+            shutdown.clone(),
+        )
+        .unwrap();
+
+        let mut client = Client::connect(&listen_addr).await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        assert_eq!(client.request(get).await, bulk(node_addr));
+        shutdown.start(TEST_REPLY_TIMEOUT);
+
+        // the drained client is closed and the cluster stops accepting
+        let closed = time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+            .await
+            .expect("drained client must be closed");
+        assert!(closed.is_none());
+        time::timeout(TEST_REPLY_TIMEOUT, serving)
+            .await
+            .expect("cluster must stop after the shutdown")
+            .unwrap();
+        assert!(TcpStream::connect(&listen_addr).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_moved_redirect() {
+        // the first node owns all the slots by CLUSTER SLOTS but the slot of foo has moved to the second
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (old_addr, new_addr) = (old.local_addr().unwrap(), new.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, old_addr)]);
+        let moved = Arc::new(AtomicUsize::new(0));
+        let counter = moved.clone();
+        spawn_node(old, slots.clone(), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            format!("-MOVED 12182 {}\r\n", new_addr).into_bytes()
+        });
+        spawn_node(new, slots, |_| b"$1\r\nv\r\n".to_vec());
+
+        let mut client = Client::connect(&spawn_proxy(old_addr, |_| {})).await;
+        for _ in 0..3 {
+            assert_eq!(
+                client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+                b"$1\r\nv\r\n"
+            );
+        }
+        // the slot is served by the new node once redirected
+        assert_eq!(moved.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_moved_redirect_loop() {
+        // the nodes redirect the slot of foo to each other
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, first_addr)]);
+        let moved_to = |addr: SocketAddr| {
+            move |_: &[Vec<u8>]| format!("-MOVED 12182 {}\r\n", addr).into_bytes()
+        };
+        spawn_node(first, slots.clone(), moved_to(second_addr));
+        spawn_node(second, slots, moved_to(first_addr));
+
+        // the client gets the raw error once the command can not be redirected anymore
+        let mut client = Client::connect(&spawn_proxy(first_addr, |_| {})).await;
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+            format!("-MOVED 12182 {}\r\n", first_addr).into_bytes()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_moved_redirect_unresolvable() {
+        // the node redirects the slot of foo once to an address which can't be resolved
+        let node = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let moved = Arc::new(AtomicUsize::new(0));
+        let counter = moved.clone();
+        spawn_node(node, slots_reply(&[(0, 16383, node_addr)]), move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                b"-MOVED 12182 unresolvable.invalid:6379\r\n".to_vec()
+            } else {
+                bulk(node_addr)
+            }
+        });
+
+        // the failed redirection keeps the slot served by the node
+        let mut client = Client::connect(&spawn_proxy(node_addr, |_| {})).await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        assert!(client.request(get).await.starts_with(b"-"));
+        assert_eq!(client.request(get).await, bulk(node_addr));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ask_redirect() {
+        // the slot of foo is migrating from the first node, which asks the second one to serve it
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (source_addr, target_addr) =
+            (source.local_addr().unwrap(), target.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, source_addr)]);
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        spawn_node(source, slots.clone(), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            format!("-ASK 12182 {}\r\n", target_addr).into_bytes()
+        });
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let requests = received.clone();
+        spawn_node(target, slots, move |args| {
+            requests.lock().unwrap().push(args[0].clone());
+            if args[0] == b"ASKING" {
+                b"+OK\r\n".to_vec()
+            } else {
+                b"$1\r\nv\r\n".to_vec()
+            }
+        });
+
+        let mut client = Client::connect(&spawn_proxy(source_addr, |_| {})).await;
+        for _ in 0..2 {
+            assert_eq!(
+                client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+                b"$1\r\nv\r\n"
+            );
+        }
+
+        // the slot is still served by the source node, each command is asked again
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                b"ASKING".to_vec(),
+                b"GET".to_vec(),
+                b"ASKING".to_vec(),
+                b"GET".to_vec()
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ask_redirect_timeout() {
+        // the first node asks the second one to serve all of its keys, which stalls the first ASKING
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (source_addr, target_addr) =
+            (source.local_addr().unwrap(), target.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, source_addr)]);
+        spawn_node(source, slots.clone(), move |args| {
+            let slot = crc16(&args[1]) as usize % SLOTS_COUNT;
+            format!("-ASK {} {}\r\n", slot, target_addr).into_bytes()
+        });
+        let stalled = Arc::new(AtomicUsize::new(0));
+        spawn_node(target, slots, move |args| {
+            if args[0] != b"ASKING" {
+                return [b"$3\r\n".to_vec(), args[1].clone(), b"\r\n".to_vec()].concat();
+            }
+            if stalled.fetch_add(1, Ordering::SeqCst) == 0 {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            b"+OK\r\n".to_vec()
+        });
+
+        let mut client = Client::connect(&spawn_proxy(source_addr, |cc| {
+            cc.timeout = Some(100);
+        }))
+        .await;
+
+        // both late replies of the timed out command are skipped, so the next one gets its own
+        assert!(client
+            .request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n")
+            .await
+            .starts_with(b"-"));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nbar\r\n").await,
+            b"$3\r\nbar\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_redirect_retry_budget() {
+        // the slot of foo is migrating from the first node, which asks the second one to serve it
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (source_addr, target_addr) =
+            (source.local_addr().unwrap(), target.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, source_addr)]);
+        let ask = format!("-ASK 12182 {}\r\n", target_addr).into_bytes();
+        let asked = ask.clone();
+        spawn_node(source, slots.clone(), move |_| asked.clone());
+        spawn_node(target, slots, |args| {
+            if args[0] == b"ASKING" {
+                b"+OK\r\n".to_vec()
+            } else {
+                b"$1\r\nv\r\n".to_vec()
+            }
+        });
+
+        let mut client = Client::connect(&spawn_proxy(source_addr, |cc| {
+            cc.retry_budget_per_sec = Some(1);
+        }))
+        .await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+
+        // the first redirection spends the whole budget, so the next one is replied as is
+        assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+        assert_eq!(client.request(get).await, ask);
+        let source_addr = source_addr.to_string();
+        let retries = |name: &str| test_metric_value(name, &[("backend", source_addr.as_str())]);
+        assert_eq!(retries("repust_retries_total"), 1.0);
+        assert_eq!(retries("repust_retries_denied_total"), 1.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_refresh_slots() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, first_addr)]);
+        let own_addr = |addr: SocketAddr| move |_: &[Vec<u8>]| bulk(addr);
+        spawn_node(first, slots.clone(), own_addr(first_addr));
+        spawn_node(second, slots.clone(), own_addr(second_addr));
+
+        let mut client = Client::connect(&spawn_proxy(first_addr, |cc| {
+            cc.fetch_interval = Some(20);
+        }))
+        .await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        assert_eq!(client.request(get).await, bulk(first_addr));
+
+        // the cluster is resharded to the second node
+        let resharded = slots_reply(&[(0, 16383, second_addr)]);
+        *slots.lock().unwrap() = resharded.lock().unwrap().clone();
+        let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+        while client.request(get).await != bulk(second_addr) && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.request(get).await, bulk(second_addr));
+
+        // the slots are kept while no node replies them
+        *slots.lock().unwrap() = b"-ERR cluster is down\r\n".to_vec();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.request(get).await, bulk(second_addr));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_admin_cluster_endpoints() {
+        let node = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = node.local_addr().unwrap();
+        spawn_node(node, slots_reply(&[(0, 16383, addr)]), move |_| bulk(addr));
+        let mut client = Client::connect(&spawn_proxy(addr, |cc| {
+            cc.name = "admin-redis-cluster".to_string();
+        }))
+        .await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        assert_eq!(client.request(get).await, bulk(addr));
+
+        let admin = |req: axum::http::Request<axum::body::Body>| async move {
+            let resp = admin::router().oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(bytes.to_vec()).unwrap())
+        };
+        let post = |uri: String| {
+            axum::http::Request::post(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        // the replied commands leave nothing in flight on the node
+        let inflight = axum::http::Request::get("/cluster/admin-redis-cluster/inflight")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(
+            admin(inflight).await,
+            (
+                axum::http::StatusCode::OK,
+                format!(
+                    "[{{\"pool\":\"stable\",\"backend\":\"{}\",\"inflight\":0}}]",
+                    addr
+                )
+            )
+        );
+
+        // the nodes are kept however idle they are, and reconnected on demand
+        assert_eq!(
+            admin(post(
+                "/cluster/admin-redis-cluster/gc?idle_ms=0".to_string()
+            ))
+            .await,
+            (axum::http::StatusCode::OK, "0".to_string())
+        );
+        let reconnect = |node: String| {
+            post(format!(
+                "/cluster/admin-redis-cluster/nodes/{}/reconnect",
+                node
+            ))
+        };
+        assert_eq!(
+            admin(reconnect(addr.to_string())).await.0,
+            axum::http::StatusCode::OK
+        );
+        assert_eq!(client.request(get).await, bulk(addr));
+        assert_eq!(
+            admin(reconnect("127.0.0.1:1".to_string())).await.0,
+            axum::http::StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_admin_refresh_slots() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let slots = slots_reply(&[(0, 16383, first_addr)]);
+        let own_addr = |addr: SocketAddr| move |_: &[Vec<u8>]| bulk(addr);
+        spawn_node(first, slots.clone(), own_addr(first_addr));
+        spawn_node(second, slots.clone(), own_addr(second_addr));
+
+        // the periodic refresh is too slow to pick up the resharding during the test
+        let mut client = Client::connect(&spawn_proxy(first_addr, |cc| {
+            cc.name = "admin-refresh-slots".to_string();
+            cc.fetch_interval = Some(3_600_000);
+        }))
+        .await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        assert_eq!(client.request(get).await, bulk(first_addr));
+
+        // the slot of foo is resharded to the second node
+        let resharded = slots_reply(&[(0, 8191, first_addr), (8192, 16383, second_addr)]);
+        *slots.lock().unwrap() = resharded.lock().unwrap().clone();
+        let refresh = |name: &str| {
+            admin::router().oneshot(
+                axum::http::Request::post(format!("/cluster/{}/refresh-slots", name))
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+        };
+        let resp = refresh("admin-refresh-slots").await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut masters = [
+            (first_addr.to_string(), [0, 8191]),
+            (second_addr.to_string(), [8192, 16383]),
+        ];
+        masters.sort();
+        let masters: Vec<_> = masters
+            .iter()
+            .map(|(node, [begin, end])| {
+                format!(
+                    "{{\"node\":\"{}\",\"slots\":8192,\"ranges\":[[{},{}]]}}",
+                    node, begin, end
+                )
+            })
+            .collect();
+        assert_eq!(
+            String::from_utf8(bytes.to_vec()).unwrap(),
+            format!("{{\"covered\":16384,\"masters\":[{}]}}", masters.join(","))
+        );
+        assert_eq!(client.request(get).await, bulk(second_addr));
+
+        // the slots are kept while no node replies them
+        *slots.lock().unwrap() = b"-ERR cluster is down\r\n".to_vec();
+        let resp = refresh("admin-refresh-slots").await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_GATEWAY);
+        assert_eq!(client.request(get).await, bulk(second_addr));
+
+        let resp = refresh("admin-refresh-slots-unknown").await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+
+        // the seeds are not applied without a restart, so the reload reports it
+        let cc = ClusterConfig {
+            name: "admin-refresh-slots".to_string(),
+            servers: vec![second_addr.to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(admin::reload(&cc), Err(AsError::BadConfig(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_from_slave() {
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (master_addr, replica_addr) =
+            (master.local_addr().unwrap(), replica.local_addr().unwrap());
+        let slots = replicated_slots_reply(&[(0, 16383, master_addr, &[replica_addr])]);
+        spawn_node(master, slots.clone(), move |_| bulk(master_addr));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = received.clone();
+        spawn_node(replica, slots, move |args| {
+            requests.lock().unwrap().push(args[0].clone());
+            bulk(replica_addr)
+        });
+
+        let mut client = Client::connect(&spawn_proxy(master_addr, |cc| {
+            cc.read_from_slave = Some(true);
+        }))
+        .await;
+        assert_eq!(
+            client.request(b"*1\r\n$8\r\nREADONLY\r\n").await,
+            b"+OK\r\n"
+        );
+
+        // the reads are served by the master until the replica is connected
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+        while client.request(get).await != bulk(replica_addr) && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.request(get).await, bulk(replica_addr));
+        assert_eq!(
+            client
+                .request(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+                .await,
+            bulk(master_addr)
+        );
+        assert_eq!(received.lock().unwrap()[0], b"READONLY");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_write_connection() {
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (master_addr, replica_addr) =
+            (master.local_addr().unwrap(), replica.local_addr().unwrap());
+        let slots = replicated_slots_reply(&[(0, 16383, master_addr, &[replica_addr])]);
+        spawn_node(master, slots.clone(), move |_| bulk(master_addr));
+        spawn_node(replica, slots, move |_| bulk(replica_addr));
+
+        let mut client = Client::connect(&spawn_proxy(master_addr, |cc| {
+            cc.read_from_slave = Some(true);
+        }))
+        .await;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+
+        // the fresh connections read from the masters until READONLY switches them to the replicas
+        assert_eq!(client.request(get).await, bulk(master_addr));
+        assert_eq!(
+            client.request(b"*1\r\n$8\r\nREADONLY\r\n").await,
+            b"+OK\r\n"
+        );
+        let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+        while client.request(get).await != bulk(replica_addr) && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.request(get).await, bulk(replica_addr));
+
+        // READWRITE pins them to the masters again
+        assert_eq!(
+            client.request(b"*1\r\n$9\r\nREADWRITE\r\n").await,
+            b"+OK\r\n"
+        );
+        assert_eq!(client.request(get).await, bulk(master_addr));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_client_info_and_list() {
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let master_addr = master.local_addr().unwrap();
+        spawn_node(master, slots_reply(&[(0, 16383, master_addr)]), move |_| {
+            bulk(master_addr)
+        });
+        let proxy = spawn_proxy(master_addr, |_| {});
+        let mut client = Client::connect(&proxy).await;
+        let other = Client::connect(&proxy).await;
+
+        assert_eq!(
+            client
+                .request(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\napp\r\n")
+                .await,
+            b"+OK\r\n"
+        );
+        let info = client
+            .request(b"*2\r\n$6\r\nCLIENT\r\n$4\r\nINFO\r\n")
+            .await;
+        let info = String::from_utf8(info).unwrap();
+        let addr = client.requests.local_addr().unwrap();
+        assert!(info.contains(&format!(" addr={} laddr={} name=app ", addr, proxy)));
+
+        // the connections of the proxy are listed, not the ones of the nodes
+        let list = client
+            .request(b"*2\r\n$6\r\nCLIENT\r\n$4\r\nLIST\r\n")
+            .await;
+        let list = String::from_utf8(list).unwrap();
+        let other_addr = other.requests.local_addr().unwrap();
+        assert_eq!(list.matches("id=").count(), 2);
+        assert!(list.contains(&format!(" addr={} ", other_addr)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_from_slave_fallback() {
+        // the replica of the slot is down, so its reads are served by the master
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let master_addr = master.local_addr().unwrap();
+        let replica_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let slots = replicated_slots_reply(&[(0, 16383, master_addr, &[replica_addr])]);
+        spawn_node(master, slots, move |_| bulk(master_addr));
+
+        let mut client = Client::connect(&spawn_proxy(master_addr, |cc| {
+            cc.read_from_slave = Some(true);
+        }))
+        .await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+            bulk(master_addr)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_from_master_by_default() {
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (master_addr, replica_addr) =
+            (master.local_addr().unwrap(), replica.local_addr().unwrap());
+        let slots = replicated_slots_reply(&[(0, 16383, master_addr, &[replica_addr])]);
+        spawn_node(master, slots.clone(), move |_| bulk(master_addr));
+        let connected = Arc::new(AtomicBool::new(false));
+        let replica_connected = connected.clone();
+        tokio::spawn(async move {
+            let _conn = replica.accept().await;
+            replica_connected.store(true, Ordering::SeqCst);
+        });
+
+        let mut client = Client::connect(&spawn_proxy(master_addr, |_| {})).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+            bulk(master_addr)
+        );
+        // the replicas are not even connected to
+        assert!(!connected.load(Ordering::SeqCst));
+    }
+
+    fn bulk(addr: SocketAddr) -> Vec<u8> {
+        let addr = addr.to_string();
+        format!("${}\r\n{}\r\n", addr.len(), addr).into_bytes()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_proxy_timeout() {
+        // the node never replies to the slow key
+        let node = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = node.local_addr().unwrap();
+        let slots = slots_reply(&[(0, 16383, addr)]);
+        spawn_node(node, slots, move |args| match args[1].as_slice() {
+            b"slow" => Vec::new(),
+            _ => bulk(addr),
+        });
+
+        let mut client = Client::connect(&spawn_proxy(addr, |cc| {
+            cc.timeout = Some(5_000);
+            cc.max_client_timeout = Some(1_000);
+        }))
+        .await;
+        assert_eq!(
+            client
+                .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$4\r\n2000\r\n")
+                .await,
+            b"-proxy timeout must not exceed 1000 ms\r\n"
+        );
+        assert_eq!(
+            client
+                .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$3\r\n100\r\n")
+                .await,
+            b"+OK\r\n"
+        );
+
+        // the deadline of the connection fails the command long before the timeout of the cluster
+        let start = std::time::Instant::now();
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nslow\r\n").await,
+            b"-command timeout\r\n"
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_backend_tls_and_source_addr() {
+        let node = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = node.local_addr().unwrap();
+        let (ca, peers) =
+            spawn_tls_node(node, slots_reply(&[(0, 16383, addr)]), move |_| bulk(addr));
+
+        // the slots are fetched and the commands served over TLS, from the backend source address. The
+        // whole 127.0.0.0/8 block is routed to the loopback interface on linux.
+        let mut client = Client::connect(&spawn_proxy(addr, |cc| {
+            cc.backend_tls = Some(true);
+            cc.backend_tls_ca = Some(ca.clone());
+            cc.backend_source_addr = Some("127.0.0.2".to_string());
+        }))
+        .await;
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await,
+            bulk(addr)
+        );
+        let peers = peers.lock().unwrap().clone();
+        assert!(peers.len() >= 2);
+        assert!(peers.iter().all(|x| x.to_string() == "127.0.0.2"));
+        std::fs::remove_file(&ca).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use futures::{Future, Sink, Stream};
+use log::debug;
+use pin_project::{pin_project, pinned_drop};
+use rand::rngs::StdRng;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    com::AsError,
+    proxy::{
+        cluster::{slot_hash, RedisCluster},
+        front::{
+            dispatch, poll_command, send, set_client_timeout, Closing, ConnStats, Head, SentQueue,
+        },
+        routing_rng, ClientConn, ProxyCmd, ProxyReply, Request,
+    },
+};
+
+#[pin_project(PinnedDrop)]
+pub struct Front<T, I, O>
+where
+    T: Request,
+    O: Sink<T, Error = AsError>,
+    I: Stream<Item = Result<T, AsError>>,
+{
+    // client is the name of the client, usually the address of the client
+    client: String,
+
+    // conn is the connection of the client registered to the cluster, as described by CLIENT INFO
+    conn: Arc<ClientConn>,
+
+    // cluster is the cluster the client is connected to, holding the slots and the policy
+    cluster: Arc<RedisCluster<T>>,
+
+    // downstream is the stream which takes commands from the client
+    #[pin]
+    downstream: I,
+
+    // upstream is the sink which sends the replies to the client
+    #[pin]
+    upstream: O,
+
+    // client_timeout is the end-to-end deadline of the commands set by the client using PROXY TIMEOUT
+    client_timeout: Option<Duration>,
+
+    // read_only routes the reads to the replicas of their slots when the cluster reads from them. Like
+    // the ones of a Redis Cluster, the connections start reading from the masters until READONLY sets it,
+    // and READWRITE clears it again.
+    read_only: bool,
+
+    // rng draws the replica serving each read, seeded by deterministic_routing if set
+    rng: StdRng,
+
+    // sent_queue holds the commands which are forwarded but not yet replied to the client, in the
+    // order they were received
+    sent_queue: SentQueue<T, ()>,
+
+    // stats describes the connection for the metrics recorded on close
+    stats: ConnStats,
+
+    // closing drains the connection once the cluster shuts down
+    closing: Closing,
+}
+
+impl<T, I, O> Front<T, I, O>
+where
+    T: Request,
+    O: Sink<T, Error = AsError>,
+    I: Stream<Item = Result<T, AsError>>,
+{
+    pub fn new(client: String, cluster: Arc<RedisCluster<T>>, downstream: I, upstream: O) -> Self {
+        cluster.fronts.fetch_add(1, Ordering::Relaxed);
+        Front {
+            conn: cluster.clients.register(&client),
+            closing: Closing::new(cluster.shutdown.started()),
+            client,
+            rng: routing_rng(&cluster.cc),
+            cluster,
+            downstream,
+            upstream,
+            client_timeout: None,
+            read_only: false,
+            sent_queue: SentQueue::new(),
+            stats: ConnStats::new(),
+        }
+    }
+}
+
+impl<T, I, O> Future for Front<T, I, O>
+where
+    T: Request + Send + Sync + 'static,
+    O: Sink<T, Error = AsError>,
+    I: Stream<Item = Result<T, AsError>>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        let cluster = &**this.cluster;
+
+        let downstream = this.downstream;
+        let mut upstream = this.upstream;
+
+        // the commands of several steps forward the subs of their next one and stay queued until those are
+        // done too
+        let (client, read_only, rng) = (&**this.client, *this.read_only, &mut *this.rng);
+        let head = |queue: &mut VecDeque<(T, ())>, cx: &mut Context| match queue
+            .front()
+            .and_then(|(cmd, _)| cmd.next_subs())
+        {
+            Some(subs) => {
+                for mut sub in subs {
+                    sub.register_waker(cx.waker().clone());
+                    forward(sub, cluster, client, read_only, rng);
+                }
+                Head::Requeued
+            }
+            None => Head::Reply,
+        };
+        if this
+            .sent_queue
+            .poll_replies(cx, upstream.as_mut(), client, this.stats, head, |_| {})
+            .is_ready()
+        {
+            return Poll::Ready(());
+        }
+
+        let replied = this.sent_queue.is_empty();
+        if let Some(closed) = this
+            .closing
+            .poll_close(cx, upstream.as_mut(), client, replied)
+        {
+            return closed;
+        }
+
+        let mut cmd = match poll_command(cx, downstream, client, this.stats) {
+            Poll::Ready(Some(cmd)) => cmd,
+            Poll::Ready(None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        // label the metrics of the command by the cluster and start its end-to-end timer
+        cmd.set_cluster(&cluster.policy.cluster);
+        cmd.mark_total();
+
+        // the invalid and the done commands are replied immediately
+        if cmd.valid() && !cmd.is_done() && cmd.check_policy(&cluster.policy) {
+            if let Some(proxy_cmd) = cmd.proxy_cmd() {
+                match proxy_cmd {
+                    ProxyCmd::Timeout(millis) => {
+                        set_client_timeout(&cmd, millis, this.client_timeout, &cluster.policy)
+                    }
+                    ProxyCmd::ReadOnly(set) => {
+                        *this.read_only = set;
+                        cmd.set_proxy_reply(ProxyReply::Ok);
+                    }
+                    ProxyCmd::Client(client_cmd) => {
+                        let laddr = &cluster.cc.listen_addr;
+                        cmd.set_proxy_reply(cluster.clients.reply(client_cmd, this.conn, laddr));
+                    }
+                    // the keys of a cluster are routed by their slots, not by a ring to inspect
+                    ProxyCmd::Where(_) | ProxyCmd::Explain(_) => {
+                        cmd.set_error(&AsError::RequestNotSupport)
+                    }
+                }
+            } else if cmd.cmd_type().is_pub_sub() {
+                // the subscriptions would take over the connections shared by the clients
+                cmd.set_error(&AsError::RequestNotSupport);
+            } else {
+                cmd.register_waker(cx.waker().clone());
+                if let Some(client_timeout) = this.client_timeout {
+                    cmd.set_deadline(Instant::now() + *client_timeout);
+                }
+                if let Some(ttl_secs) = cluster.policy.default_ttl {
+                    cmd.set_default_ttl(ttl_secs);
+                }
+
+                // the sub commands are routed to the slots of their own keys
+                let read_only = *this.read_only;
+                dispatch(&cmd, cx.waker(), |cmd| {
+                    forward(cmd, cluster, client, read_only, this.rng)
+                });
+            }
+        }
+        this.sent_queue.push_back(cmd, ());
+
+        // poll again until the client has no more commands ready
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// forward sends the command to the master serving the slot of its key, or to a replica of the slot if
+// it is a read of a read only connection. The SCAN cursor resumes the master which replied it instead,
+// and the scan is complete past the last one.
+fn forward<T: Request + Send + Sync + 'static>(
+    cmd: T,
+    cluster: &RedisCluster<T>,
+    client: &str,
+    read_only: bool,
+    rng: &mut StdRng,
+) {
+    let sender = match cmd.scan_node() {
+        Some(node) => match cluster.masters().get(node..) {
+            Some([addr, rest @ ..]) => {
+                if rest.is_empty() {
+                    cmd.set_scan_last();
+                }
+                cluster.get_node_sender(addr)
+            }
+            _ => {
+                cmd.finish_scan();
+                return;
+            }
+        },
+        None => cluster.get_sender(slot_hash(&cmd), read_only && cmd.is_read(), rng),
+    };
+    send(sender, cmd, &cluster.policy, &cluster.throughput, client);
+}
+
+#[pinned_drop]
+impl<T, I, O> PinnedDrop for Front<T, I, O>
+where
+    T: Request,
+    O: Sink<T, Error = AsError>,
+    I: Stream<Item = Result<T, AsError>>,
+{
+    fn drop(self: Pin<&mut Self>) {
+        debug!("frontend dropped for client {}", self.client);
+        self.cluster.fronts.fetch_sub(1, Ordering::Relaxed);
+        self.cluster.clients.unregister(self.conn.id);
+        self.stats.closed(&self.cluster.policy.cluster);
+    }
+}
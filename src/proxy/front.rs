@@ -0,0 +1,332 @@
+use crossbeam_channel::{SendTimeoutError, Sender};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{Future, Sink, Stream, StreamExt};
+use log::{debug, error};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    com::AsError,
+    metrics::{
+        command_incr, connection_closed, front_conn_decr, front_conn_incr, throughput::Throughput,
+    },
+    proxy::{Metered, Policy, ProxyReply, Request},
+    utils::helper::get_runtime_handle,
+};
+
+// FRONTEND_MAX_POLL_ERROR is the number of the replies failed to be sent after which the client connection
+// is considered as unstable and closed
+const FRONTEND_MAX_POLL_ERROR: u8 = 10;
+
+// ClientSink and ClientStream are the halves of the framed client connection the fronts reply to and
+// read the commands from
+pub(crate) type ClientSink<T, S> = SplitSink<Framed<S, Metered<<T as Request>::FrontCodec>>, T>;
+pub(crate) type ClientStream<T, S> = SplitStream<Framed<S, Metered<<T as Request>::FrontCodec>>>;
+
+// serve frames the accepted client with the front codec of the cluster and spawns the front created for it,
+// over plain TCP or TLS
+pub(crate) fn serve<T, S, F, R>(client: SocketAddr, socket: S, policy: &Policy, front: F)
+where
+    T: Request,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: FnOnce(String, ClientStream<T, S>, ClientSink<T, S>) -> R,
+    R: Future<Output = ()> + Send + 'static,
+{
+    let codec = Metered::new(T::front_codec(policy), &policy.cluster);
+    let (sink, stream) = codec.framed(socket).split();
+
+    get_runtime_handle().spawn(front(client.to_string(), stream, sink));
+    front_conn_incr(&policy.cluster);
+}
+
+// Head is what the front does with the done command at the head of its queue
+pub(crate) enum Head {
+    // Reply sends the reply of the command to the client
+    Reply,
+
+    // Requeued is returned once the head is forwarded again, e.g. to the next step of the command or to
+    // another backend, so it is checked again once done
+    Requeued,
+}
+
+// SentQueue holds the commands of the client which are not replied yet in the order they were received,
+// each along with the state its front keeps for it
+pub(crate) struct SentQueue<T, X> {
+    queue: VecDeque<(T, X)>,
+
+    // poll_errors is the counter to record the send errors of the replies
+    poll_errors: u8,
+}
+
+impl<T: Request, X> SentQueue<T, X> {
+    pub(crate) fn new() -> Self {
+        SentQueue {
+            queue: VecDeque::new(),
+            poll_errors: 0,
+        }
+    }
+
+    pub(crate) fn push_back(&mut self, cmd: T, state: X) {
+        self.queue.push_back((cmd, state));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // poll_replies sends the replies of all the done commands in the order the commands were received. The
+    // wakeups of the commands replied together are coalesced, so a single poll must drain all of them.
+    // head is called with the queue whose head is done before the head is replied, and replying with each
+    // command on its way to the client. It is ready once the client is too unstable to be replied.
+    pub(crate) fn poll_replies<O>(
+        &mut self,
+        cx: &mut Context,
+        mut upstream: Pin<&mut O>,
+        client: &str,
+        stats: &mut ConnStats,
+        mut head: impl FnMut(&mut VecDeque<(T, X)>, &mut Context) -> Head,
+        mut replying: impl FnMut(&T),
+    ) -> Poll<()>
+    where
+        O: Sink<T, Error = AsError>,
+    {
+        let mut replied = false;
+        while self.queue.front().is_some_and(|(cmd, _)| cmd.is_done()) {
+            if let Head::Requeued = head(&mut self.queue, cx) {
+                continue;
+            }
+
+            match upstream.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    debug!("command is done, sending the reply to the client");
+                    let (cmd, _) = self.queue.pop_front().expect("front command must exist");
+                    replying(&cmd);
+                    if cmd.is_error() {
+                        stats.errors += 1;
+                    }
+
+                    if let Err(err) = upstream.as_mut().start_send(cmd) {
+                        error!(
+                            "frontend {} failed to send reply to client: {}",
+                            client, err
+                        );
+                    } else {
+                        replied = true;
+                    }
+                }
+                Poll::Ready(Err(err)) => {
+                    error!(
+                        "frontend {} failed to send reply to client: {}",
+                        client, err
+                    );
+                    self.queue.pop_front();
+
+                    self.poll_errors += 1;
+                    if self.poll_errors > FRONTEND_MAX_POLL_ERROR {
+                        error!(
+                            "frontend {} is not stable to send replies, closing the connection",
+                            client
+                        );
+                        return Poll::Ready(());
+                    }
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        if replied {
+            let _ = upstream.as_mut().poll_flush(cx);
+        }
+        Poll::Pending
+    }
+}
+
+// Closing drains the client once the cluster shuts down, after which no more commands are read and the
+// connection is closed once the commands already read are replied
+pub(crate) struct Closing {
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+    closing: bool,
+}
+
+impl Closing {
+    pub(crate) fn new(shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        Closing {
+            shutdown: Box::pin(shutdown),
+            closing: false,
+        }
+    }
+
+    // poll_close is None while the client is served as usual, and polls the connection closed once the
+    // cluster shuts down and the commands of the client are all replied
+    pub(crate) fn poll_close<T, O>(
+        &mut self,
+        cx: &mut Context,
+        mut upstream: Pin<&mut O>,
+        client: &str,
+        replied: bool,
+    ) -> Option<Poll<()>>
+    where
+        O: Sink<T, Error = AsError>,
+    {
+        if !self.closing && self.shutdown.as_mut().poll(cx).is_ready() {
+            debug!("frontend {} is draining for the shutdown", client);
+            self.closing = true;
+        }
+        if !self.closing {
+            return None;
+        }
+        if !replied {
+            return Some(Poll::Pending);
+        }
+        Some(upstream.as_mut().poll_close(cx).map(|_| ()))
+    }
+}
+
+// ConnStats describes the client connection for the metrics recorded on close
+pub(crate) struct ConnStats {
+    connected_at: Instant,
+    commands: u64,
+    errors: u64,
+}
+
+impl ConnStats {
+    pub(crate) fn new() -> Self {
+        ConnStats {
+            connected_at: Instant::now(),
+            commands: 0,
+            errors: 0,
+        }
+    }
+
+    // closed records the metrics of the closed client connection
+    pub(crate) fn closed(&self, cluster: &str) {
+        front_conn_decr(cluster);
+        connection_closed(
+            cluster,
+            self.connected_at.elapsed(),
+            self.commands,
+            self.errors,
+        );
+    }
+}
+
+// poll_command reads the next command of the client, counting it in the connection metrics. It is ready
+// with None once the client closes the connection, and pending on a command which fails to be decoded,
+// waking the front to find the stream ended by the failure and close the client.
+pub(crate) fn poll_command<T, I>(
+    cx: &mut Context,
+    downstream: Pin<&mut I>,
+    client: &str,
+    stats: &mut ConnStats,
+) -> Poll<Option<T>>
+where
+    I: Stream<Item = Result<T, AsError>>,
+{
+    match downstream.poll_next(cx) {
+        Poll::Ready(Some(Ok(cmd))) => {
+            stats.commands += 1;
+            Poll::Ready(Some(cmd))
+        }
+        Poll::Ready(Some(Err(err))) => {
+            stats.errors += 1;
+            error!(
+                "frontend {} failed to receive command from client due to: {}",
+                client, err
+            );
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Poll::Ready(None) => {
+            debug!("frontend terminated for client {}", client);
+            Poll::Ready(None)
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+// set_client_timeout replies to PROXY TIMEOUT, setting the end-to-end deadline of the next commands of the
+// connection. A timeout of 0 clears it, and the ones above the max_client_timeout of the cluster are rejected.
+pub(crate) fn set_client_timeout<T: Request>(
+    cmd: &T,
+    millis: u64,
+    client_timeout: &mut Option<Duration>,
+    policy: &Policy,
+) {
+    let timeout = Duration::from_millis(millis);
+    if millis == 0 {
+        *client_timeout = None;
+        cmd.set_proxy_reply(ProxyReply::Ok);
+    } else if timeout > policy.max_client_timeout {
+        cmd.set_reply(T::Reply::from(AsError::ProxyTimeoutTooLarge(
+            policy.max_client_timeout.as_millis() as u64,
+        )));
+    } else {
+        *client_timeout = Some(timeout);
+        cmd.set_proxy_reply(ProxyReply::Ok);
+    }
+}
+
+// dispatch forwards the subs of the command, each routed by its own key and waking the front with the
+// given waker once done, or the command itself if it has none. It reports if the command is forwarded as
+// a whole.
+pub(crate) fn dispatch<T: Request>(cmd: &T, waker: &Waker, mut forward: impl FnMut(T)) -> bool {
+    match cmd.subs() {
+        Some(subs) => {
+            for mut sub in subs {
+                sub.register_waker(waker.clone());
+                forward(sub);
+            }
+            false
+        }
+        None => {
+            forward(cmd.clone());
+            true
+        }
+    }
+}
+
+// send queues the command to the backend connection, counting it in the throughput of the cluster. It is
+// failed if the queue of the backend stays full for the timeout of the cluster, or if no backend is found
+// for it.
+pub(crate) fn send<T: Request>(
+    output: Option<Sender<T>>,
+    cmd: T,
+    policy: &Policy,
+    throughput: &Throughput,
+    client: &str,
+) {
+    let output = match output {
+        Some(output) => output,
+        None => {
+            error!(
+                "frontend {} failed to find the backend serving the command",
+                client
+            );
+            cmd.set_error(&AsError::ClusterFailDispatch);
+            return;
+        }
+    };
+    let cmd_type = cmd.cmd_type();
+    match output.send_timeout(cmd, policy.timeout) {
+        Ok(_) => {
+            command_incr(&policy.cluster, cmd_type);
+            throughput.incr();
+            debug!("frontend {} forwarded command to back", client)
+        }
+        Err(SendTimeoutError::Timeout(cmd)) => {
+            error!("frontend {} faced timeout to forward command", client);
+            cmd.set_error(&AsError::CmdTimeout);
+        }
+        Err(SendTimeoutError::Disconnected(cmd)) => {
+            error!("frontend {} has no backend consumer", client);
+            cmd.set_error(&AsError::ClusterFailDispatch);
+        }
+    }
+}
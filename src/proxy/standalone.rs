@@ -13,49 +13,173 @@ mod ketama;
 mod parser;
 // Path: src/proxy/standalone/parser.rs
 
-use crossbeam_channel::{bounded, Sender};
+mod pubsub;
+// Path: src/proxy/standalone/pubsub.rs
+
+pub(crate) mod transport;
+// Path: src/proxy/standalone/transport.rs
+
+#[cfg(test)]
+mod tests;
+// Path: src/proxy/standalone/tests.rs
+
+use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
 use crossbeam_utils::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
-use futures::StreamExt;
+use futures::{
+    future::{self, Either},
+    SinkExt, StreamExt,
+};
 use log::{debug, error, info, warn};
+use rand::{rngs::StdRng, Rng};
 use std::{
-    collections::{HashMap, HashSet},
-    net::SocketAddr,
-    process,
-    sync::Arc,
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::Handle,
+    sync::watch,
+    task::JoinHandle,
+    time,
 };
-use tokio::{net::TcpStream, task::JoinHandle};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tokio_util::codec::Decoder;
 
 use crate::{
+    admin::{
+        self, BackendInflight, ClusterAdmin, ClusterInfo, Reconnect, RefreshSlots, RingInfo,
+        RingNode, RingPoint,
+    },
     com::{
         config::{
-            create_reuse_port_listener, get_host_by_name, CacheType, ClusterConfig,
-            CODE_PORT_IN_USE,
+            create_reuse_port_listener, CacheType, ClusterConfig, HashMethod, LoadBalance,
+            SocketOptions,
         },
         AsError,
     },
-    metrics::front_conn_incr,
-    protocol::{mc, redis},
+    metrics::{
+        accept_loop_alive, auth_failure_incr, backend_unreachable, backend_up,
+        cluster_overloaded_add, cluster_overloaded_decr, cluster_overloaded_incr,
+        cluster_serving_decr, cluster_serving_incr,
+        shedder::{Shedder, SHED_SAMPLE_INTERVAL},
+        throughput::{Throughput, THROUGHPUT_SAMPLE_INTERVAL},
+    },
+    protocol::{mc, redis, CmdType},
     proxy::{
+        accept, drain,
+        front::serve,
+        handshake,
         standalone::{
             back::{Back, BlackHole},
             front::Front,
             ketama::HashRing,
-            parser::ServerLine,
+            parser::{DnsSrvResolver, ServerLine},
+            transport::{tls_host, BackendAddr, BackendStream},
         },
-        Request,
+        Clients, Metered, Policy, Redirector, Request, Shutdown,
+    },
+    utils::{
+        bucket::{RetryBudget, TokenBucket},
+        crc::crc16,
+        helper::{get_runtime_handle, trim_hash_tag},
     },
-    utils::helper::get_runtime_handle,
 };
 
+// SLOW_START_SCALE is the factor the weights are scaled by while a backend ramps up, so the ramp has
+// enough steps even for the backends of weight 1
+const SLOW_START_SCALE: usize = 100;
+
+// SLOW_START_STEPS is the number of ring updates over the slow start window
+const SLOW_START_STEPS: u32 = 10;
+
+// PREWARM_CHECK_INTERVAL is the interval the established backend connections are counted at while
+// prewarming
+const PREWARM_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// FAIL_FAST_CHECK_INTERVAL is the interval the commands queued to an unreachable backend are failed at,
+// and FAIL_FAST_RECONNECT_INTERVAL the one it is reconnected at, when failing fast on the dead backends
+const FAIL_FAST_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+const FAIL_FAST_RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+// DRAIN_CHECK_INTERVAL is the interval a backend reconnected after its connections are closed while idle
+// checks the closed ones are drained at
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// OVERLOAD_SAMPLE_INTERVAL is the interval the backend queues are checked for the overload at, and
+// OVERLOAD_SAMPLES the number of the saturated samples in a row the cluster is overloaded after
+const OVERLOAD_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const OVERLOAD_SAMPLES: u32 = 3;
+
 pub struct StandaloneCluster<T> {
     pub cc: ClusterConfig,
 
+    // runtime is the handle of the runtime the cluster is running on
+    runtime: Handle,
+
+    // hasher is the hash of the keys the backends are picked by, and hash_tag the delimiters of the part
+    // of the keys it hashes, empty for the whole keys
+    hasher: fn(&[u8]) -> u64,
     hash_tag: Vec<u8>,
     auth: String,
 
     ring: RingKeeper<T>,
+    canary: Option<Canary<T>>,
+
+    // mirror is the migration target receiving a copy of the write commands
+    mirror: Option<RingKeeper<T>>,
+
+    // reader is the backend set serving the read commands instead of the ring
+    reader: Option<RingKeeper<T>>,
+
+    // pools are the named backend sets, and routes the ones serving each routed command type instead of
+    // the ring
+    pools: BTreeMap<String, RingKeeper<T>>,
+    routes: HashMap<CmdType, RingKeeper<T>>,
+
+    // policy is the set of cluster limits the commands are checked against before forwarding
+    policy: Policy,
+
+    // throughput counts the forwarded commands and keeps their moving rate for the admin endpoints
+    throughput: Throughput,
+
+    // shedder rejects a fraction of the commands while their p99 latency exceeds the SLO, never if None
+    shedder: Option<Shedder>,
+
+    // retry_budget bounds the retries of all the backend connections of the cluster, unlimited if None
+    retry_budget: Option<Arc<RetryBudget>>,
+
+    // backend_source is the local address the backend connections are made from, chosen by the OS if None
+    backend_source: Option<IpAddr>,
+
+    // warming is set until the first backend connection of the cluster is established
+    warming: AtomicBool,
+
+    // overloaded is set while the backends can not keep up and the new clients are not accepted
+    overloaded: watch::Sender<bool>,
+
+    // shutdown stops the cluster gracefully once started, and fronts is the number of the clients the
+    // cluster is serving, waited on before the backends are closed
+    shutdown: Shutdown,
+    fronts: AtomicUsize,
+
+    // clients are the connections of the cluster described by CLIENT LIST
+    clients: Clients,
+
+    // tls terminates the TLS of the clients, which are served over plain TCP if None
+    tls: Option<TlsAcceptor>,
+
+    // backend_tls connects to the backends over TLS, which are connected over plain TCP if None
+    backend_tls: Option<TlsConnector>,
+
+    // subscriptions is the number of the channels subscribed by the clients of the cluster
+    subscriptions: Arc<AtomicUsize>,
 }
 
 impl<T> StandaloneCluster<T>
@@ -63,32 +187,113 @@ where
     T: Request + Send + Sync + 'static,
 {
     pub(crate) fn new(cc: ClusterConfig) -> Result<StandaloneCluster<T>, AsError> {
+        let hasher: fn(&[u8]) -> u64 = match cc.hash_method() {
+            HashMethod::Fnv1a64 => fnv::fnv1a64,
+            HashMethod::Crc16 => crc16_ring,
+        };
         let cluster = StandaloneCluster {
             cc: cc.clone(),
-            hash_tag: cc
-                .hash_tag
-                .clone()
-                .map(|x| x.into_bytes())
-                .unwrap_or_default(),
-            auth: cc.auth.clone(),
+            runtime: get_runtime_handle(),
+            hasher,
+            hash_tag: cc.hash_tag_bytes()?,
+            auth: cc.backend_auth(),
             ring: RingKeeper::new(),
+            canary: None,
+            mirror: None,
+            reader: None,
+            pools: BTreeMap::new(),
+            routes: HashMap::new(),
+            policy: Policy::default(),
+            throughput: Throughput::default(),
+            shedder: cc.slo_shedding().map(|(slo, max_fraction)| {
+                Shedder::new(cc.name.as_str().into(), slo, max_fraction)
+            }),
+            retry_budget: cc
+                .retry_budget_per_sec
+                .map(|x| Arc::new(RetryBudget::new(x))),
+            backend_source: cc.backend_source_ip()?,
+            warming: AtomicBool::new(true),
+            overloaded: watch::channel(false).0,
+            shutdown: Shutdown::default(),
+            fronts: AtomicUsize::new(0),
+            clients: Clients::default(),
+            tls: cc.tls_acceptor()?,
+            backend_tls: cc.backend_tls_connector()?,
+            subscriptions: Arc::new(AtomicUsize::new(0)),
         };
 
         cluster.init(cc)
     }
 
+    // with_shutdown makes the cluster stop gracefully once the given shutdown is started
+    pub(crate) fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     fn init(mut self, cc: ClusterConfig) -> Result<StandaloneCluster<T>, AsError> {
-        let parsed_servers = ServerLine::parse_servers(&cc.servers)?;
-        let (nodes, alias, weights) = ServerLine::split_spots(&parsed_servers);
+        // the backend connections are made with the limits of the policy
+        self.policy = Policy::new(&cc);
+        self.init_ring(&self.ring, &cc.servers, Pool::Stable, &cc)?;
+
+        self.canary = if cc.canary_servers.is_empty() {
+            None
+        } else {
+            let ring = self.canary.take().map(|x| x.ring).unwrap_or_default();
+            self.init_ring(&ring, &cc.canary_servers, Pool::Canary, &cc)?;
+            Some(Canary {
+                ring,
+                weight: cc.canary_weight_percent(),
+            })
+        };
+
+        self.mirror = if cc.migrate_target.is_empty() {
+            None
+        } else {
+            let ring = self.mirror.take().unwrap_or_default();
+            self.init_ring(&ring, &cc.migrate_target, Pool::Mirror, &cc)?;
+            Some(ring)
+        };
+
+        self.reader = if cc.read_servers.is_empty() {
+            None
+        } else {
+            let ring = self.reader.take().unwrap_or_default();
+            self.init_ring(&ring, &cc.read_servers, Pool::Read, &cc)?;
+            Some(ring)
+        };
+
+        let mut pools = std::mem::take(&mut self.pools);
+        for (name, servers) in &cc.pools {
+            let ring = pools.remove(name).unwrap_or_default();
+            self.init_ring(&ring, servers, Pool::Routed, &cc)?;
+            self.pools.insert(name.clone(), ring);
+        }
+        self.routes = cc
+            .pool_routes()?
+            .into_iter()
+            .map(|(cmd_type, pool)| (cmd_type, self.pools[pool].clone()))
+            .collect();
+
+        self.cc = cc;
+        Ok(self)
+    }
+
+    // init_ring builds the hash ring of the given servers and connects to the newly added ones
+    fn init_ring(
+        &self,
+        ring: &RingKeeper<T>,
+        servers: &[String],
+        pool: Pool,
+        cc: &ClusterConfig,
+    ) -> Result<(), AsError> {
+        let servers = ServerLine::resolve_srv(servers, &DnsSrvResolver)?;
+        let parsed_servers = ServerLine::parse_servers(&servers)?;
+        let (nodes, alias, weights) = ServerLine::split_spots(&parsed_servers)?;
 
         let alias_map: HashMap<String, String> =
             alias.clone().into_iter().zip(nodes.clone()).collect();
 
-        // let alias_rev: HashMap<String, String> = alias_map
-        //     .iter()
-        //     .map(|(x, y)| (y.clone(), x.clone()))
-        //     .collect();
-
         let spots_map: HashMap<String, usize> = if alias.is_empty() {
             nodes.clone().into_iter().zip(weights.clone()).collect()
         } else {
@@ -107,68 +312,196 @@ where
             spots_map.keys().map(|x| x.to_string()).collect()
         };
 
-        let old_addrs = self.ring.get().addrs();
+        let old_spots = ring.get().spots.clone();
+        let old_addrs = ring.get().addrs();
         let new_addrs = addrs.difference(&old_addrs);
         let unused_addrs = old_addrs.difference(&addrs);
 
         for addr in new_addrs {
-            self.connect(addr);
+            self.connect(ring, addr, pool, Vec::new());
         }
 
+        // the ring stops routing to the removed backends before they are dropped, so they only drain
+        // the commands already queued to them
+        let mut inner = ring.get_mut();
         for addr in unused_addrs {
-            self.ring.get_mut().remove_conn(addr);
+            inner.remove_conn(addr);
         }
+        inner.pool = pool;
 
-        self.cc = cc;
-        self.ring.get_mut().coordinates = hash_ring;
-        self.ring.alias = alias_map;
-        self.ring.spots = spots_map;
+        // the reads missing on the changed ring are read again from the previous one for a while
+        let coordinates = std::mem::replace(&mut inner.coordinates, hash_ring);
+        let alias = std::mem::replace(&mut inner.alias, alias_map);
+        let balance = std::mem::replace(&mut inner.balance, cc.load_balance());
+        inner.previous = match cc.rehash_double_read() {
+            Some(window) if pool == Pool::Stable && !old_spots.is_empty() => Some(PreviousRing {
+                coordinates,
+                alias,
+                balance,
+                until: Instant::now() + window,
+            }),
+            _ => None,
+        };
 
-        Ok(self)
+        // the backends added to a running ring start with a small share of the keys
+        if let Some(window) = cc.slow_start().filter(|_| !old_spots.is_empty()) {
+            let now = Instant::now();
+            for name in spots_map.keys().filter(|x| !old_spots.contains_key(*x)) {
+                info!("slow starting backend {} over {:?}", name, window);
+                inner.ramping.insert(name.clone(), now);
+            }
+            inner.spots = spots_map;
+            inner.ramp(window, now);
+        } else {
+            inner.ramping.clear();
+            inner.spots = spots_map;
+        }
+
+        Ok(())
     }
 
-    pub(crate) fn run(self) -> JoinHandle<()> {
+    // run starts serving the cluster. Failing to listen only fails this cluster, the others keep running.
+    pub(crate) fn run(self) -> Result<JoinHandle<()>, AsError> {
         let addr = self
             .cc
             .listen_addr
             .parse::<SocketAddr>()
             .expect("Listening address must be OK here");
 
-        get_runtime_handle().spawn(async move {
-            let listener = match create_reuse_port_listener(addr) {
-                Ok(listener) => listener,
-                Err(err) => {
-                    error!("fail to create listener due to {}", err);
-                    process::exit(CODE_PORT_IN_USE);
+        let listener = create_reuse_port_listener(addr).map_err(|err| {
+            error!(
+                "cluster {} fail to listen on {} due to {}",
+                self.cc.name, addr, err
+            );
+            AsError::IoError(err)
+        })?;
+        info!("proxy is listening on {}", addr);
+
+        let this = Arc::new(self);
+        admin::register(&this.cc.name, Arc::new(this.clone()));
+
+        // background is the tasks of the cluster which are stopped with its accept loop
+        let mut background = Vec::new();
+
+        let sampled = this.clone();
+        background.push(get_runtime_handle().spawn(async move {
+            let mut interval = time::interval(THROUGHPUT_SAMPLE_INTERVAL);
+            let mut last = interval.tick().await;
+            loop {
+                let now = interval.tick().await;
+                sampled.throughput.sample(now - last);
+                last = now;
+            }
+        }));
+
+        if this.shedder.is_some() {
+            let shedding = this.clone();
+            background.push(get_runtime_handle().spawn(async move {
+                let mut interval = time::interval(SHED_SAMPLE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Some(shedder) = shedding.shedder.as_ref() {
+                        shedder.sample();
+                    }
                 }
-            };
+            }));
+        }
+
+        if let Some(fill) = this.cc.overload_queue_fill {
+            let sampled = this.clone();
+            cluster_overloaded_add(&this.cc.name, 0);
+            background.push(get_runtime_handle().spawn(async move {
+                let mut interval = time::interval(OVERLOAD_SAMPLE_INTERVAL);
+                let mut saturated = 0;
+                loop {
+                    interval.tick().await;
+                    sampled.sample_overload(fill, &mut saturated);
+                }
+            }));
+        }
 
-            info!("proxy is listening on {}", addr);
+        if let Some(window) = this.cc.slow_start() {
+            let ramped = this.clone();
+            background.push(get_runtime_handle().spawn(async move {
+                let mut interval = time::interval(window / SLOW_START_STEPS);
+                loop {
+                    let now = interval.tick().await.into_std();
+                    for ring in ramped.rings() {
+                        ring.get_mut().ramp(window, now);
+                    }
+                }
+            }));
+        }
 
-            let timeout = self.cc.timeout;
-            let name = self.cc.name;
+        Ok(get_runtime_handle().spawn(async move {
+            let name = this.cc.name.clone();
+            // the clients queue in the listen backlog until the backends are ready
+            if let Some((min, timeout)) = this.cc.prewarm() {
+                this.prewarm(min, timeout).await;
+            }
+            cluster_serving_incr(&name);
+            accept_loop_alive(&name);
+            let mut accept_limit = this.cc.max_accept_rate.map(TokenBucket::new);
+            let mut shutdown = pin!(this.shutdown.started());
+            let mut overloaded = this.overloaded.subscribe();
 
             loop {
-                match listener.accept().await {
+                // shed the load at the connection level by leaving the new clients in the listen
+                // backlog until the backends catch up
+                if *overloaded.borrow_and_update() {
+                    let recovered = pin!(overloaded.wait_for(|x| !*x));
+                    match future::select(recovered, shutdown.as_mut()).await {
+                        Either::Left(_) => continue,
+                        Either::Right(_) => break,
+                    }
+                }
+
+                // smooth the connection bursts by leaving the excess in the listen backlog
+                if let Some(bucket) = accept_limit.as_mut() {
+                    let wait = bucket.reserve();
+                    if !wait.is_zero() {
+                        time::sleep(wait).await;
+                    }
+                }
+
+                // a pending accept is given up once the cluster is overloaded, so the clients coming
+                // after it wait in the listen backlog too
+                let accepting = pin!(accept(&listener, &name));
+                let overload = pin!(overloaded.wait_for(|x| *x));
+                let accepting = future::select(accepting, overload);
+                let accepted = match future::select(accepting, shutdown.as_mut()).await {
+                    Either::Left((Either::Left((accepted, _)), _)) => accepted,
+                    Either::Left((Either::Right(_), _)) => continue,
+                    Either::Right(_) => break,
+                };
+                match accepted {
                     Ok((socket, addr)) => {
                         debug!("accepting connection from client at {}", addr);
                         if socket.set_nodelay(true).is_err() {
                             warn!(" cluster {} failed to set nodelay for {}", name, addr);
                         }
-
-                        let codec = T::FrontCodec::default();
-                        let (sink, stream) = codec.framed(socket).split();
-
-                        let front = Front::new(
-                            addr.to_string(),
-                            self.hash_tag.clone(),
-                            self.ring.clone(),
-                            stream,
-                            sink,
-                            Duration::from_millis(timeout.unwrap_or(1000)),
+                        debug!(
+                            "cluster {} accepted {} with {}",
+                            name,
+                            addr,
+                            SocketOptions::read(&socket)
                         );
-                        get_runtime_handle().spawn(front);
-                        front_conn_incr();
+
+                        // the handshakes are done off the accept loop so a slow client does not
+                        // hold the others back
+                        match this.tls.clone() {
+                            None => this.serve(addr, socket),
+                            Some(acceptor) => {
+                                let cluster = this.clone();
+                                get_runtime_handle().spawn(async move {
+                                    if let Some(socket) =
+                                        handshake(&acceptor, socket, &cluster.cc.name, addr).await
+                                    {
+                                        cluster.serve(addr, socket);
+                                    }
+                                });
+                            }
+                        }
                     }
                     Err(err) => {
                         error!("fail to accept connection due to {}", err);
@@ -176,26 +509,155 @@ where
                     }
                 }
             }
-        })
+
+            cluster_serving_decr(&name);
+            if this.overloaded.send_replace(false) {
+                cluster_overloaded_decr(&name);
+            }
+            this.stop(addr).await;
+            for task in background {
+                task.abort();
+            }
+        }))
     }
 
-    pub(crate) fn connect(&mut self, addr: &str) {
-        debug!("trying to connect to {}", addr);
+    // serve spawns the frontend of the accepted client, over plain TCP or TLS
+    fn serve<S>(self: &Arc<Self>, client: SocketAddr, socket: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        serve(client, socket, &self.policy, |client, stream, sink| {
+            Front::new(client, self.clone(), stream, sink)
+        });
+    }
 
-        self.ring.get_mut().remove_conn(addr);
-        match connect(addr, Duration::from_millis(self.cc.timeout.unwrap_or(1000))) {
-            Ok(sender) => {
-                if !self.auth.is_empty() {
-                    let auth_cmd = T::auth_request(&self.auth);
-                    let _ = sender.send(auth_cmd);
-                }
+    // stop closes the cluster once its accept loop ends, either for the shutdown or as it broke
+    async fn stop(&self, addr: SocketAddr) {
+        match self.shutdown.deadline() {
+            Some(deadline) => self.close(deadline).await,
+            // the supervisor of the worker restarts the cluster, the stopped one must not hold its
+            // backends
+            None => {
+                error!(
+                    "cluster {} stopped accepting connections on {}",
+                    self.cc.name, addr
+                );
+                self.close(Instant::now()).await;
+            }
+        }
+    }
 
-                self.ring.get_mut().insert_conn(addr, sender);
+    // close waits until the clients are replied the commands they sent before the shutdown, or until the
+    // deadline, and only then closes the backend connections so no command is failed by its backend
+    // closing first.
+    async fn close(&self, deadline: Instant) {
+        info!("cluster {} is shutting down", self.cc.name);
+        drain(&self.cc.name, &self.fronts, deadline).await;
+
+        // the backends serve the commands already queued to them once they are removed
+        for ring in self.rings() {
+            let mut inner = ring.get_mut();
+            for addr in inner.addrs() {
+                inner.remove_conn(&addr);
             }
-            Err(err) => {
-                error!("fail to connect to {} due {:?}", addr, err);
+        }
+        info!("cluster {} is shut down", self.cc.name);
+    }
+
+    // connect replaces the connections of the backend of the ring with new ones, taking over the sockets
+    // established beforehand and dialing the others in the background
+    fn connect(&self, ring: &RingKeeper<T>, addr: &str, pool: Pool, sockets: Vec<BackendStream>) {
+        if let Some(conn) = self.open(addr, pool, sockets, Vec::new()) {
+            ring.get_mut().insert_conn(conn);
+        }
+    }
+
+    // open creates the connections of the backend, taking over the sockets established beforehand and
+    // dialing the others in the background once the draining connections are closed
+    fn open(
+        &self,
+        addr: &str,
+        pool: Pool,
+        mut sockets: Vec<BackendStream>,
+        draining: Vec<Weak<AtomicUsize>>,
+    ) -> Option<Conn<T>> {
+        debug!("trying to connect to {}", addr);
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let unreachable = Arc::new(AtomicBool::new(false));
+        let mut senders = Vec::with_capacity(self.cc.node_connections());
+        let mut outstanding = Vec::with_capacity(self.cc.node_connections());
+        for _ in 0..self.cc.node_connections() {
+            let count = Arc::new(AtomicUsize::new(0));
+            match connect(
+                addr,
+                pool,
+                &self.policy,
+                self.retry_budget.clone(),
+                self.backend_source,
+                connected.clone(),
+                unreachable.clone(),
+                count.clone(),
+                None,
+                self.backend_tls.clone(),
+                &self.auth,
+                sockets.pop(),
+                draining.clone(),
+            ) {
+                Ok(sender) => {
+                    senders.push(sender);
+                    outstanding.push(count);
+                }
+                Err(err) => {
+                    error!("fail to connect to {} due {:?}", addr, err);
+                }
             }
         }
+
+        (!senders.is_empty()).then(|| Conn::new(addr, senders, outstanding, connected, unreachable))
+    }
+
+    // pool_of returns the backend set the given backend is part of with its pool
+    fn pool_of(&self, addr: &str) -> Option<(&RingKeeper<T>, Pool)> {
+        let pools = [
+            (Some(&self.ring), Pool::Stable),
+            (
+                self.canary.as_ref().map(|canary| &canary.ring),
+                Pool::Canary,
+            ),
+            (self.mirror.as_ref(), Pool::Mirror),
+            (self.reader.as_ref(), Pool::Read),
+        ];
+        let routed = self.pools.values().map(|ring| (Some(ring), Pool::Routed));
+        pools.into_iter().chain(routed).find_map(|(ring, pool)| {
+            ring.filter(|ring| ring.get().addrs().contains(addr))
+                .map(|ring| (ring, pool))
+        })
+    }
+
+    // reconnect_backend dials the given backend as many times as its connections and replaces them
+    // only once all are established, so the backend failing to be dialed keeps its connections
+    async fn reconnect_backend(&self, addr: &str) -> Result<(), AsError> {
+        let (ring, pool) = self
+            .pool_of(addr)
+            .ok_or_else(|| AsError::UnknownBackend(addr.to_string()))?;
+
+        let backend = BackendAddr::resolve_blocking(addr).await?;
+        let mut sockets = Vec::with_capacity(self.cc.node_connections());
+        for _ in 0..self.cc.node_connections() {
+            let socket = dial(
+                addr,
+                &backend,
+                self.backend_source,
+                self.backend_tls.as_ref(),
+                self.policy.dial_timeout,
+            )
+            .await
+            .map_err(AsError::IoError)?;
+            sockets.push(socket);
+        }
+        self.connect(ring, addr, pool, sockets);
+        Ok(())
     }
 
     //     fn has_alias(&self) -> bool {
@@ -240,21 +702,308 @@ where
     //     }
 }
 
+impl<T> StandaloneCluster<T>
+where
+    T: Request + Send + Sync + 'static,
+{
+    // cmd_hash returns the hash of the key of the command the backends are picked by
+    fn cmd_hash(&self, cmd: &T) -> u64 {
+        cmd.key_hash(&self.hash_tag, self.hasher)
+    }
+
+    // key_hash returns the hash of the given key the backends are picked by
+    fn key_hash(&self, key: &[u8]) -> u64 {
+        (self.hasher)(trim_hash_tag(key, &self.hash_tag))
+    }
+
+    // get_sender returns the connection of the backend the balance policy of the given ring picks for
+    // the hash. The connections closed while idle are established again on their first use.
+    fn get_sender(&self, ring: &RingKeeper<T>, hash: u64, rng: &mut StdRng) -> Option<Sender<T>> {
+        let addr = ring.get_addr(hash, rng)?;
+        self.get_addr_sender(ring, &addr)
+    }
+
+    // get_addr_sender returns the connection of the given backend of the ring, established again if
+    // closed while idle. The ring is locked while reconnecting so the backend is reconnected only once, and
+    // the new connections are dialed after the closed ones are drained to keep the order of the commands.
+    fn get_addr_sender(&self, ring: &RingKeeper<T>, addr: &str) -> Option<Sender<T>> {
+        if ring.is_closed(addr) {
+            let mut inner = ring.get_mut();
+            if let Some(draining) = inner.closed.remove(addr) {
+                info!("reconnecting idle backend {}", addr);
+                let _guard = self.runtime.enter();
+                if let Some(conn) = self.open(addr, inner.pool, Vec::new(), draining) {
+                    inner.insert_conn(conn);
+                }
+            }
+        }
+        ring.get_sender(addr)
+    }
+
+    // warming_error returns the reply of the commands received before any backend is connected, if
+    // configured and the cluster is still warming up
+    fn warming_error(&self) -> Option<AsError> {
+        let reply = self.policy.warming_reply?;
+        if !self.warming.load(Ordering::Relaxed) {
+            return None;
+        }
+        if self.connected() > 0 {
+            self.warming.store(false, Ordering::Relaxed);
+            return None;
+        }
+        Some(AsError::ClusterWarming(reply))
+    }
+
+    // connected returns the number of the established backend connections of all the backend sets
+    fn connected(&self) -> usize {
+        self.rings()
+            .into_iter()
+            .map(|ring| {
+                ring.get()
+                    .inner
+                    .values()
+                    .filter(|conn| conn.connected.load(Ordering::Relaxed))
+                    .count()
+            })
+            .sum()
+    }
+
+    // prewarm waits until the given number of the backend connections, or all of them if fewer, are
+    // established or the timeout passes. It reports if the connections are ready.
+    async fn prewarm(&self, min: usize, timeout: Duration) -> bool {
+        let total: usize = self
+            .rings()
+            .into_iter()
+            .map(|ring| ring.get().inner.len())
+            .sum();
+        let min = min.min(total);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let connected = self.connected();
+            if connected >= min {
+                info!(
+                    "cluster {} prewarmed {} backend connections",
+                    self.cc.name, connected
+                );
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "cluster {} accepts clients with {} of {} backend connections prewarmed",
+                    self.cc.name, connected, min
+                );
+                return false;
+            }
+            time::sleep(PREWARM_CHECK_INTERVAL).await;
+        }
+    }
+
+    // sample_overload marks the cluster overloaded once the fullest backend queue is filled above the given
+    // fraction or the commands are shed for OVERLOAD_SAMPLES samples in a row, saturated counting them, so
+    // a burst the backends catch up with is not mistaken for the overload. It is marked recovered once the
+    // queues drain below half of the fraction with nothing shed, so the accept loop does not flap around
+    // the threshold.
+    fn sample_overload(&self, fill: f64, saturated: &mut u32) {
+        let queued = self
+            .rings()
+            .into_iter()
+            .flat_map(|ring| {
+                let ring = ring.get();
+                ring.inner
+                    .values()
+                    .map(|conn| conn.queue_fill(self.policy.backend_queue_size))
+                    .collect::<Vec<_>>()
+            })
+            .fold(0.0, f64::max);
+        let shedding = self
+            .shedder
+            .as_ref()
+            .is_some_and(|shedder| shedder.fraction() > 0.0);
+
+        *saturated = match queued >= fill || shedding {
+            true => saturated.saturating_add(1),
+            false => 0,
+        };
+
+        let overloaded = *self.overloaded.borrow();
+        if !overloaded && *saturated >= OVERLOAD_SAMPLES {
+            warn!(
+                "cluster {} is overloaded with backend queues {:.0}% full, pausing accepting clients",
+                self.cc.name,
+                queued * 100.0
+            );
+            self.overloaded.send_replace(true);
+            cluster_overloaded_incr(&self.cc.name);
+        } else if overloaded && queued <= fill / 2.0 && !shedding {
+            info!(
+                "cluster {} recovered from the overload, resuming accepting clients",
+                self.cc.name
+            );
+            self.overloaded.send_replace(false);
+            cluster_overloaded_decr(&self.cc.name);
+        }
+    }
+
+    // rings returns all the backend sets of the cluster
+    fn rings(&self) -> Vec<&RingKeeper<T>> {
+        let mut rings = vec![&self.ring];
+        rings.extend(self.canary.as_ref().map(|canary| &canary.ring));
+        rings.extend(self.mirror.as_ref());
+        rings.extend(self.reader.as_ref());
+        rings.extend(self.pools.values());
+        rings
+    }
+}
+
+// the admin operations reconnect the backends in the background, which needs the shared cluster
+impl<T> ClusterAdmin for Arc<StandaloneCluster<T>>
+where
+    T: Request + Send + Sync + 'static,
+{
+    fn reconnect(&self, addr: &str) -> Reconnect {
+        // the connections are dialed on the cluster runtime rather than the caller one
+        let cluster = self.clone();
+        let addr = addr.to_string();
+        let reconnect = self
+            .runtime
+            .spawn(async move { cluster.reconnect_backend(&addr).await });
+        let name = self.cc.name.clone();
+        Box::pin(async move {
+            reconnect
+                .await
+                .unwrap_or(Err(AsError::ClusterStopped(name)))
+        })
+    }
+
+    fn reload(&self, cc: &ClusterConfig) -> Result<(), AsError> {
+        // the connection tasks must be spawned on the cluster runtime rather than the caller one
+        let _guard = self.runtime.enter();
+
+        self.init_ring(&self.ring, &cc.servers, Pool::Stable, cc)?;
+        let sets = [
+            (
+                self.canary.as_ref().map(|canary| &canary.ring),
+                &cc.canary_servers,
+                Pool::Canary,
+            ),
+            (self.mirror.as_ref(), &cc.migrate_target, Pool::Mirror),
+            (self.reader.as_ref(), &cc.read_servers, Pool::Read),
+        ];
+        for (ring, servers, pool) in sets {
+            match ring {
+                Some(ring) if !servers.is_empty() => self.init_ring(ring, servers, pool, cc)?,
+                None if servers.is_empty() => {}
+                _ => warn!(
+                    "cluster {} must be restarted to add or remove its {} servers",
+                    self.cc.name,
+                    pool.as_str()
+                ),
+            }
+        }
+        for (name, servers) in &cc.pools {
+            match self.pools.get(name) {
+                Some(ring) => self.init_ring(ring, servers, Pool::Routed, cc)?,
+                None => warn!(
+                    "cluster {} must be restarted to add the pool {}",
+                    self.cc.name, name
+                ),
+            }
+        }
+        info!("cluster {} reloaded its servers", self.cc.name);
+        Ok(())
+    }
+
+    fn gc(&self, idle: Option<Duration>) -> usize {
+        let idle = idle.unwrap_or(Duration::from_millis(self.cc.backend_idle_timeout_ms()));
+        self.rings()
+            .into_iter()
+            .map(|ring| ring.get_mut().close_idle(idle))
+            .sum()
+    }
+
+    fn info(&self) -> ClusterInfo {
+        ClusterInfo {
+            name: self.cc.name.clone(),
+            commands: self.throughput.commands(),
+            ops_per_sec: self.throughput.rate(),
+        }
+    }
+
+    fn inflight(&self) -> Vec<BackendInflight> {
+        let mut backends: Vec<_> = self
+            .rings()
+            .into_iter()
+            .flat_map(|ring| {
+                let ring = ring.get();
+                ring.inner
+                    .values()
+                    .map(|conn| BackendInflight {
+                        pool: ring.pool.as_str().to_string(),
+                        backend: conn.addr.clone(),
+                        inflight: conn.outstanding(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        backends.sort_by(|a, b| (&a.pool, &a.backend).cmp(&(&b.pool, &b.backend)));
+        backends
+    }
+
+    fn ring(&self) -> Vec<RingInfo> {
+        self.rings()
+            .into_iter()
+            .map(|ring| {
+                let ring = ring.get();
+                let nodes = ring
+                    .coordinates
+                    .ownership()
+                    .into_iter()
+                    .map(|(node, points, share)| RingNode {
+                        node: node.to_string(),
+                        weight: ring.spots.get(node).copied().unwrap_or_default(),
+                        points,
+                        share,
+                    })
+                    .collect();
+                let points = ring
+                    .coordinates
+                    .points()
+                    .map(|(hash, node)| RingPoint {
+                        hash,
+                        node: node.to_string(),
+                    })
+                    .collect();
+                RingInfo {
+                    pool: ring.pool.as_str().to_string(),
+                    nodes,
+                    points,
+                }
+            })
+            .collect()
+    }
+
+    fn refresh_slots(&self) -> RefreshSlots {
+        let err = AsError::NotSlotted(self.cc.name.clone());
+        Box::pin(async move { Err(err) })
+    }
+}
+
 // RingKeeper is a convenient wrapper around the ring to make it easier to access the ring
 #[derive(Clone)]
 struct RingKeeper<T> {
     ring: Arc<ShardedLock<Ring<T>>>,
+}
 
-    spots: HashMap<String, usize>,
-    alias: HashMap<String, String>,
+impl<T> Default for RingKeeper<T> {
+    fn default() -> Self {
+        RingKeeper::new()
+    }
 }
 
 impl<T> RingKeeper<T> {
     fn new() -> Self {
         RingKeeper {
             ring: Arc::new(ShardedLock::new(Ring::<T>::new())),
-            spots: HashMap::new(),
-            alias: HashMap::new(),
         }
     }
 
@@ -266,45 +1015,72 @@ impl<T> RingKeeper<T> {
         self.ring.write().unwrap()
     }
 
-    fn get_sender(&self, hash: u64) -> Option<Sender<T>> {
-        debug!(
-            "trying to find a backend node connection with hash {}",
-            hash.to_string()
-        );
-        match self.get().coordinates.get_node(hash) {
-            Some(node_name) => match self.get().get_inner(self.alias_or_default(node_name)) {
-                Some(conn) => {
-                    debug!(
-                        "found node {} with addr {} for hash {}",
-                        node_name,
-                        conn.addr,
-                        hash.to_string()
-                    );
-                    Some(conn.sender.clone())
-                }
-                None => {
-                    error!(
-                        "node {} does not have any connection on the ring",
-                        node_name
-                    );
-                    None
-                }
-            },
+    // get_addr returns the address of the backend the balance policy picks for the given hash, drawing
+    // from the given random source of the connection for the random policy
+    fn get_addr(&self, hash: u64, rng: &mut StdRng) -> Option<String> {
+        let ring = self.get();
+        match ring.select(hash, rng) {
+            Some(node_name) => Some(ring.alias_or_default(node_name).to_string()),
             None => {
-                error!("no node found on the ring for hash {}", hash.to_string());
+                error!("no node found on the ring for hash {}", hash);
                 None
             }
         }
     }
 
-    fn alias_or_default<'a>(&'a self, node_name: &'a str) -> &str {
-        match self.alias.is_empty() {
+    // get_nth_addr returns the address of the backend at the given index in the order of the servers and
+    // whether it is the last one, None past the last one
+    fn get_nth_addr(&self, index: usize) -> Option<(String, bool)> {
+        let ring = self.get();
+        let nodes = ring.coordinates.nodes();
+        let node_name = nodes.get(index)?;
+        let last = index + 1 == nodes.len();
+        Some((ring.alias_or_default(node_name).to_string(), last))
+    }
+
+    // get_previous_addr returns the address of the backend the ring before its last change picked for
+    // the given hash, if it is still double read and differs from the current one
+    fn get_previous_addr(&self, hash: u64, rng: &mut StdRng) -> Option<String> {
+        let ring = self.get();
+        let previous = ring
+            .previous
+            .as_ref()
+            .filter(|x| x.until > Instant::now())?;
+        let node_name = match previous.balance {
+            LoadBalance::Ketama => previous.coordinates.get_node(hash),
+            LoadBalance::Modulo => previous.coordinates.get_node_by_weight(hash),
+            // the other policies don't route by the keys
+            _ => None,
+        }?;
+        let addr = match previous.alias.is_empty() {
             true => node_name,
-            false => self
-                .alias
-                .get(node_name)
-                .expect("alias must exists")
-                .as_str(),
+            false => previous.alias.get(node_name)?.as_str(),
+        };
+        let current = ring.select(hash, rng).map(|x| ring.alias_or_default(x));
+        (current != Some(addr)).then(|| addr.to_string())
+    }
+
+    // is_closed checks if the connection of the given backend is closed while idle
+    fn is_closed(&self, addr: &str) -> bool {
+        self.get().closed.contains_key(addr)
+    }
+
+    fn get_sender(&self, addr: &str) -> Option<Sender<T>> {
+        let ring = self.get();
+        match ring.get_inner(addr) {
+            Some(conn) if conn.unreachable.load(Ordering::Relaxed) => {
+                debug!("backend {} is unreachable, failing the command fast", addr);
+                None
+            }
+            Some(conn) => {
+                debug!("found connection of backend {}", addr);
+                conn.last_used.store(clock_millis(), Ordering::Relaxed);
+                Some(conn.sender())
+            }
+            None => {
+                error!("backend {} does not have any connection on the ring", addr);
+                None
+            }
         }
     }
 }
@@ -312,6 +1088,38 @@ impl<T> RingKeeper<T> {
 struct Ring<T> {
     coordinates: HashRing,
     inner: HashMap<String, Conn<T>>,
+
+    spots: HashMap<String, usize>,
+    alias: HashMap<String, String>,
+
+    // pool is the backend set the ring serves
+    pool: Pool,
+
+    // closed is the backends whose connections are closed while idle, with the outstanding counters of
+    // the closed connections which are only alive until their backend tasks are drained
+    closed: HashMap<String, Vec<Weak<AtomicUsize>>>,
+
+    // ramping is the nodes in slow start with the time they are added to the ring
+    ramping: HashMap<String, Instant>,
+
+    // balance is the policy picking the node of each command
+    balance: LoadBalance,
+
+    // cursor is the position of the round robin, also rotating the ties of the least connections
+    cursor: AtomicUsize,
+
+    // previous is the routing before the last change of the ring, double read until it expires
+    previous: Option<PreviousRing>,
+}
+
+// PreviousRing is the routing of a ring before its servers or its balance policy changed
+struct PreviousRing {
+    coordinates: HashRing,
+    alias: HashMap<String, String>,
+    balance: LoadBalance,
+
+    // until is the time the reads stop being double read
+    until: Instant,
 }
 
 impl<T> Ring<T> {
@@ -319,11 +1127,98 @@ impl<T> Ring<T> {
         Ring {
             coordinates: HashRing::empty(),
             inner: HashMap::new(),
+            spots: HashMap::new(),
+            alias: HashMap::new(),
+            pool: Pool::Stable,
+            closed: HashMap::new(),
+            ramping: HashMap::new(),
+            balance: LoadBalance::default(),
+            cursor: AtomicUsize::new(0),
+            previous: None,
+        }
+    }
+
+    // select picks the node of the command with the given key hash by the balance policy, the random one
+    // drawing from the given source
+    fn select(&self, hash: u64, rng: &mut StdRng) -> Option<&str> {
+        let nodes = self.coordinates.nodes();
+        if nodes.is_empty() {
+            return None;
+        }
+
+        match self.balance {
+            LoadBalance::Ketama => self.coordinates.get_node(hash),
+            LoadBalance::Modulo => self.coordinates.get_node_by_weight(hash),
+            LoadBalance::Random => self.coordinates.get_node_by_weight(rng.gen()),
+            LoadBalance::RoundRobin => {
+                let next = self.cursor.fetch_add(1, Ordering::Relaxed);
+                Some(&nodes[next % nodes.len()])
+            }
+            LoadBalance::LeastConnections => {
+                // the idle closed connections have nothing in flight, the failed ones are picked last
+                let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+                (0..nodes.len())
+                    .map(|i| nodes[(start + i) % nodes.len()].as_str())
+                    .min_by_key(|node| {
+                        let addr = self.alias_or_default(node);
+                        match self.inner.get(addr) {
+                            Some(conn) => conn.outstanding(),
+                            None if self.closed.contains_key(addr) => 0,
+                            None => usize::MAX,
+                        }
+                    })
+            }
+        }
+    }
+
+    // ramp rebuilds the hash ring with the weights of the slow starting nodes grown in proportion to
+    // the time elapsed in the window. The nodes are left out of the ramp once they reach their weight.
+    fn ramp(&mut self, window: Duration, now: Instant) {
+        if self.ramping.is_empty() {
+            return;
+        }
+
+        let nodes = self.coordinates.nodes().to_vec();
+        let weights = nodes
+            .iter()
+            .map(|node| {
+                let weight = self.spots.get(node).copied().unwrap_or(1) * SLOW_START_SCALE;
+                match self.ramping.get(node) {
+                    Some(added) => {
+                        let elapsed = now.saturating_duration_since(*added);
+                        let ramped = weight as f64 * elapsed.as_secs_f64() / window.as_secs_f64();
+                        (ramped as usize).clamp(1, weight)
+                    }
+                    None => weight,
+                }
+            })
+            .collect();
+
+        self.ramping
+            .retain(|_, added| now.saturating_duration_since(*added) < window);
+        if self.ramping.is_empty() {
+            info!(
+                "backends of the {} pool finished slow start",
+                self.pool.as_str()
+            );
+        }
+
+        match HashRing::new(nodes, weights) {
+            Ok(ring) => self.coordinates = ring,
+            Err(err) => error!(
+                "fail to ramp the {} pool due to {}",
+                self.pool.as_str(),
+                err
+            ),
         }
     }
 
     fn addrs(&self) -> HashSet<String> {
-        self.inner.keys().cloned().collect()
+        self.inner
+            .keys()
+            .chain(self.closed.keys())
+            .cloned()
+            .collect()
     }
 
     fn get_inner(&self, s: &str) -> Option<&Conn<T>> {
@@ -335,51 +1230,284 @@ impl<T> Ring<T> {
     // }
 
     fn remove_conn(&mut self, addr: &str) -> Option<Conn<T>> {
+        self.closed.remove(addr);
         self.inner.remove(addr)
     }
 
-    fn insert_conn(&mut self, s: &str, sender: Sender<T>) {
-        let conn = Conn {
-            addr: s.to_string(),
-            sender,
-        };
-        self.inner.insert(s.to_string(), conn);
+    fn insert_conn(&mut self, conn: Conn<T>) {
+        self.closed.remove(&conn.addr);
+        self.inner.insert(conn.addr.clone(), conn);
+    }
+
+    // close_idle closes the connections which are not used for the given duration and have nothing queued
+    // or in flight, and returns their count. Dropping the sender lets the backend task serve the commands
+    // sent meanwhile and close the connection.
+    fn close_idle(&mut self, idle: Duration) -> usize {
+        let now = clock_millis();
+        let idle_addrs: Vec<String> = self
+            .inner
+            .values()
+            .filter(|conn| {
+                conn.last_used.load(Ordering::Relaxed) + idle.as_millis() as u64 <= now
+                    && conn.outstanding() == 0
+                    && conn.senders.iter().all(|sender| sender.is_empty())
+            })
+            .map(|conn| conn.addr.clone())
+            .collect();
+
+        for addr in &idle_addrs {
+            info!("closing idle backend connection {}", addr);
+            if let Some(conn) = self.inner.remove(addr) {
+                let draining = conn.outstanding.iter().map(Arc::downgrade).collect();
+                self.closed.insert(addr.clone(), draining);
+            }
+        }
+        idle_addrs.len()
+    }
+
+    fn alias_or_default<'a>(&'a self, node_name: &'a str) -> &'a str {
+        match self.alias.is_empty() {
+            true => node_name,
+            false => self
+                .alias
+                .get(node_name)
+                .expect("alias must exists")
+                .as_str(),
+        }
     }
 }
 
+// Pool is the backend set serving a command, the canary set only exists in the canary mode
+// and the mirror set only exists while migrating to a new backend set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Pool {
+    Stable,
+    Canary,
+    Mirror,
+    Read,
+    Routed,
+}
+
+impl Pool {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Pool::Stable => "stable",
+            Pool::Canary => "canary",
+            Pool::Mirror => "mirror",
+            Pool::Read => "read",
+            Pool::Routed => "routed",
+        }
+    }
+}
+
+// Canary is the backend set which receives a weighted random share of the cluster traffic.
+// The share is drawn regardless of the key hash so it breaks the key affinity between the two sets
+// and must only be used with read-only canaries.
+#[derive(Clone)]
+struct Canary<T> {
+    ring: RingKeeper<T>,
+
+    // weight is the percentage of the commands routed to the canary set
+    weight: u8,
+}
+
 struct Conn<T> {
     addr: String,
-    sender: Sender<T>,
+
+    // senders are the channels of the node_connections connections to the backend, each served by its
+    // own backend task, and cursor is the one taken by the next command
+    senders: Vec<Sender<T>>,
+    cursor: AtomicUsize,
+
+    // last_used is the time on the clock_millis clock the connection was last picked for a command
+    last_used: AtomicU64,
+
+    // outstanding is the number of the commands sent and not replied yet on each of the connections
+    outstanding: Vec<Arc<AtomicUsize>>,
+
+    // connected is set once any of the connections to the backend is established
+    connected: Arc<AtomicBool>,
+
+    // unreachable is set while the backend can not be connected and its commands are failed fast
+    unreachable: Arc<AtomicBool>,
 }
 
-fn connect<T>(node: &str, resp_timeout: Duration) -> Result<Sender<T>, AsError>
+impl<T> Conn<T> {
+    fn new(
+        addr: &str,
+        senders: Vec<Sender<T>>,
+        outstanding: Vec<Arc<AtomicUsize>>,
+        connected: Arc<AtomicBool>,
+        unreachable: Arc<AtomicBool>,
+    ) -> Self {
+        Conn {
+            addr: addr.to_string(),
+            senders,
+            cursor: AtomicUsize::new(0),
+            last_used: AtomicU64::new(clock_millis()),
+            outstanding,
+            connected,
+            unreachable,
+        }
+    }
+
+    // sender returns the channel of the connections to the backend in turn
+    fn sender(&self) -> Sender<T> {
+        let next = self.cursor.fetch_add(1, Ordering::Relaxed);
+        self.senders[next % self.senders.len()].clone()
+    }
+
+    // outstanding returns the number of the commands sent to the backend and not replied yet
+    fn outstanding(&self) -> usize {
+        self.outstanding
+            .iter()
+            .map(|x| x.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // queue_fill returns the fraction of the given capacity taken by the fullest channel of the backend
+    fn queue_fill(&self, capacity: usize) -> f64 {
+        self.senders
+            .iter()
+            .map(|sender| sender.len() as f64 / capacity as f64)
+            .fold(0.0, f64::max)
+    }
+}
+
+// crc16_ring spans the 32 bits of the ketama ring with the 16 bits of the crc16 of the key, repeating them
+// in both halves of the hash. 65537 is prime, so the modulo balance picking by the hash modulo the total
+// weight spreads the keys as evenly as the crc16 itself.
+fn crc16_ring(key: &[u8]) -> u64 {
+    crc16(key) * 65537
+}
+
+// clock_millis returns the milliseconds passed on the monotonic clock since it is first read
+fn clock_millis() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn connect<T>(
+    node: &str,
+    pool: Pool,
+    policy: &Policy,
+    retry_budget: Option<Arc<RetryBudget>>,
+    source: Option<IpAddr>,
+    connected: Arc<AtomicBool>,
+    unreachable: Arc<AtomicBool>,
+    outstanding: Arc<AtomicUsize>,
+    redirector: Option<Redirector<T>>,
+    tls: Option<TlsConnector>,
+    auth: &str,
+    socket: Option<BackendStream>,
+    draining: Vec<Weak<AtomicUsize>>,
+) -> Result<Sender<T>, AsError>
 where
     T: Request + Send + 'static,
 {
     let node_addr = node.to_string();
     let node_new = node_addr.clone();
 
-    // TODO: the buffer size should be configurable
-    let (tx, rx) = bounded(1024 * 8);
+    let (tx, rx) = bounded(policy.backend_queue_size);
 
-    let addr = get_host_by_name(node_addr.as_str()).expect("Socket address must be OK here");
-    let report_addr = format!("{:?}", &addr);
+    // a reloaded server line may name a host which fails to resolve, which only fails its own ring
+    let addr = BackendAddr::resolve(node_addr.as_str())?;
+    let report_addr = addr.to_string();
+    let resp_timeout = policy.timeout;
+    let dial_timeout = policy.dial_timeout;
+    let drain_grace = policy.drain_grace;
+    let keepalive_ping = policy.keepalive_ping;
+    let (flush_batch, flush_delay) = (policy.flush_batch, policy.flush_delay);
+    let codec = Metered::new(T::back_codec(policy), &policy.cluster);
+    let cluster = policy.cluster.clone();
+
+    // each connection is authenticated on its own before it is connected, so no command is sent ahead
+    // of the AUTH and its reply is checked
+    let auth = (!auth.is_empty()).then(|| (auth.to_string(), policy.clone()));
+
+    let fail_fast = policy.fail_fast;
 
     get_runtime_handle().spawn(async move {
-        let connection = TcpStream::connect(addr).await.map_err(|err| {
-            error!("fail to connect ot backend {} due to {}", report_addr, err);
-            AsError::SystemError
-        });
+        // the backend tasks of the previous connections hold their outstanding counters until drained
+        while draining.iter().any(|count| count.strong_count() > 0) {
+            time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+
+        let mut socket = socket;
+        let connection = loop {
+            let connection = match socket.take() {
+                Some(socket) => Ok(socket),
+                None => dial(&node_new, &addr, source, tls.as_ref(), dial_timeout).await,
+            };
+            let connection = match (connection, &auth) {
+                (Ok(socket), Some((auth, policy))) => {
+                    authenticate::<T>(socket, &node_new, auth, policy, dial_timeout).await
+                }
+                (connection, _) => connection,
+            };
+            let err = match connection {
+                Ok(socket) => break Ok(socket),
+                Err(err) => err,
+            };
+            if !fail_fast {
+                error!("fail to connect to backend {} due to {}", report_addr, err);
+                break Err(AsError::SystemError);
+            }
+
+            // the commands of the dead backend are failed without being queued while it is reconnected,
+            // the ones queued before are failed here
+            if !unreachable.swap(true, Ordering::Relaxed) {
+                error!(
+                    "fail to connect to backend {} due to {}, failing its commands fast",
+                    report_addr, err
+                );
+                backend_unreachable(&cluster, &node_new);
+            }
+            let reconnect_at = Instant::now() + FAIL_FAST_RECONNECT_INTERVAL;
+            while Instant::now() < reconnect_at {
+                if !fail_queued(&node_new, &rx) {
+                    debug!("backend {} is removed while unreachable", report_addr);
+                    return;
+                }
+                time::sleep(FAIL_FAST_CHECK_INTERVAL).await;
+            }
+        };
         match connection {
             Ok(socket) => {
                 info!("connected to backend {}", report_addr);
+                connected.store(true, Ordering::Relaxed);
+                unreachable.store(false, Ordering::Relaxed);
+                if let BackendStream::Tcp(socket) = &socket {
+                    debug!(
+                        "backend {} connection has {}",
+                        report_addr,
+                        SocketOptions::read(socket)
+                    );
+                }
 
-                let codec = T::BackCodec::default();
                 let (sink, stream) = codec.framed(socket).split();
-                let backend = Back::new(node_new, rx, sink, stream, resp_timeout);
+                backend_up(&cluster, &node_new);
+                let backend = Back::new(
+                    node_new,
+                    cluster,
+                    pool,
+                    rx,
+                    sink,
+                    stream,
+                    resp_timeout,
+                    retry_budget,
+                    outstanding,
+                    redirector,
+                    drain_grace,
+                    keepalive_ping,
+                    flush_batch,
+                    flush_delay,
+                );
                 get_runtime_handle().spawn(backend);
             }
             Err(_) => {
+                backend_unreachable(&cluster, &node_new);
                 let black_hole = BlackHole::new(node_new, rx);
                 get_runtime_handle().spawn(black_hole);
             }
@@ -389,16 +1517,100 @@ where
     Ok(tx)
 }
 
-pub fn spawn(cc: ClusterConfig) -> JoinHandle<()> {
+// dial connects to the backend of the server line, over TLS if a connector is given, failing once the
+// connection is not established within the dial timeout
+pub(crate) async fn dial(
+    node: &str,
+    addr: &BackendAddr,
+    source: Option<IpAddr>,
+    tls: Option<&TlsConnector>,
+    dial_timeout: Duration,
+) -> io::Result<BackendStream> {
+    let connection = async {
+        let socket = addr.connect(source).await?;
+        match tls {
+            Some(connector) => socket.tls(connector, tls_host(node)).await,
+            None => Ok(socket),
+        }
+    };
+    match time::timeout(dial_timeout, connection).await {
+        Ok(connection) => connection,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection is not established within the dial timeout",
+        )),
+    }
+}
+
+// authenticate sends the auth request on the new backend connection and waits for its reply, failing the
+// connection like an unreachable one if the backend rejects it or does not reply within the dial timeout.
+// The rejections are logged and counted apart, as they are not fixed by reconnecting.
+async fn authenticate<T: Request>(
+    mut socket: BackendStream,
+    backend: &str,
+    auth: &str,
+    policy: &Policy,
+    dial_timeout: Duration,
+) -> io::Result<BackendStream> {
+    let mut framed = T::back_codec(policy).framed(&mut socket);
+    let exchange = async {
+        framed
+            .send(T::auth_request(auth))
+            .await
+            .map_err(io::Error::other)?;
+        match framed.next().await {
+            Some(Ok(reply)) => match T::auth_failure(&reply) {
+                Some(reason) => {
+                    warn!(
+                        "backend {} rejected the authentication: {}",
+                        backend, reason
+                    );
+                    auth_failure_incr(&policy.cluster, backend);
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+                }
+                None => Ok(()),
+            },
+            Some(Err(err)) => Err(io::Error::other(err)),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection is closed before the authentication is replied",
+            )),
+        }
+    };
+    match time::timeout(dial_timeout, exchange).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => return Err(err),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "authentication is not replied within the dial timeout",
+            ))
+        }
+    }
+    drop(framed);
+    Ok(socket)
+}
+
+// fail_queued fails the commands queued to the unreachable backend, it reports if the backend is still
+// on the ring
+fn fail_queued<T: Request>(node: &str, rx: &Receiver<T>) -> bool {
+    loop {
+        match rx.try_recv() {
+            Ok(cmd) => cmd.set_error(&AsError::BackendClosedError(node.to_string())),
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+        }
+    }
+}
+
+pub fn spawn(cc: ClusterConfig, shutdown: Shutdown) -> Result<JoinHandle<()>, AsError> {
     match cc.cache_type {
-        CacheType::Redis => StandaloneCluster::<redis::Cmd>::new(cc)
-            .expect("cluster encountered an error")
+        CacheType::Redis => StandaloneCluster::<redis::Cmd>::new(cc)?
+            .with_shutdown(shutdown)
             .run(),
-        CacheType::Memcache | CacheType::MemcacheBinary => StandaloneCluster::<mc::Cmd>::new(cc)
-            .expect("cluster encountered an error")
+        CacheType::Memcache | CacheType::MemcacheBinary => StandaloneCluster::<mc::Cmd>::new(cc)?
+            .with_shutdown(shutdown)
             .run(),
-        _ => {
-            unreachable!("other cache types has to be check before calling spawn")
-        }
+        CacheType::RedisCluster => crate::proxy::cluster::spawn(cc, shutdown),
     }
 }
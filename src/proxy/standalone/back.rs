@@ -1,14 +1,26 @@
-use crossbeam_channel::{Receiver, RecvTimeoutError};
+use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
 use futures::{Future, Sink, Stream};
 use log::{debug, error, info, warn};
 use pin_project::pin_project;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
+    collections::VecDeque,
     pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
-use crate::{com::AsError, proxy::Request};
+use crate::{
+    com::AsError,
+    metrics::{
+        backend_busy_incr, backend_down, bug_incr, pool_reply_incr, retry_denied_incr, retry_incr,
+    },
+    proxy::{standalone::Pool, Redirector, ReplyError, Request},
+    utils::bucket::RetryBudget,
+};
 
 const DOWNSTREAM_MAX_POLL_ERROR: u8 = 10;
 
@@ -17,6 +29,14 @@ const DOWNSTREAM_MAX_POLL_ERROR: u8 = 10;
 // meanwhile preventing the task from instant wakeup and bruting the CPU usage.
 const CHANNEL_FETCH_TIMEOUT: Duration = Duration::from_secs(1);
 
+// BACKEND_MAX_PIPELINE is the maximum number of commands taken from the front and sent to the backend
+// without waiting for their replies.
+pub(crate) const BACKEND_MAX_PIPELINE: usize = 1024;
+
+// LOADING_RETRY_DELAY is the time to wait before resending a command which is replied with LOADING
+// while the backend is loading its dataset after a restart.
+const LOADING_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 #[pin_project]
 pub struct Back<T, S, R>
 where
@@ -27,9 +47,22 @@ where
     // conn_addr is the address of the backend server
     conn_addr: String,
 
-    // store is the request which is waiting for the response
-    // store is None if there is no request available from the front
-    store: Option<T>,
+    // cluster is the name of the cluster the backend serves, labelling its metrics
+    cluster: Arc<str>,
+
+    // pool is the backend set the server belongs to
+    pool: Pool,
+
+    // pending is the queue of the requests received from the front which are not sent yet
+    pending: VecDeque<T>,
+
+    // retries is the queue of the requests to be resent before the pending ones, e.g. the ones replied
+    // with LOADING. It keeps them in the order they were first sent.
+    retries: VecDeque<T>,
+
+    // inflight is the queue of the requests which are sent to the backend and waiting for the response.
+    // The backend replies in the order of the requests, so the replies are matched from the front.
+    inflight: VecDeque<T>,
 
     // input is the channel which receives the request from the front
     input: Receiver<T>,
@@ -50,12 +83,42 @@ where
     // and the backend will be closed
     downstream_poll_error: u8,
 
-    // sub_cmds is the stack to store the sub commands
-    sub_cmds: Vec<T>,
-
-    // delayed is the number of delayed commands which should be skipped in the case of
-    // any late reply received from the backend
+    // delayed is the number of the late replies which should be skipped once received from the
+    // backend, one for each timed out command and two for the ones sent with ASKING. They are sent but
+    // not replied yet like the inflight ones, so they are counted together against BACKEND_MAX_PIPELINE.
     delayed: u32,
+
+    // retry_at is the time after which the retried commands can be resent to the backend
+    retry_at: Option<Instant>,
+
+    // retry_budget is the retry budget of the cluster shared by all of its backends, unlimited if None
+    retry_budget: Option<Arc<RetryBudget>>,
+
+    // outstanding is the number of the commands sent and not replied yet, shared with the ring to be
+    // reported by the admin endpoints
+    outstanding: Arc<AtomicUsize>,
+
+    // redirector re-dispatches the commands replied with MOVED or ASK by a cluster node, None for the
+    // standalone backends which never redirect
+    redirector: Option<Redirector<T>>,
+
+    // drain_grace is the time the queued commands are still served after the backend is removed, and
+    // drain_deadline is when the ones left are failed, set once the channel from the front disconnects
+    drain_grace: Duration,
+    drain_deadline: Option<Instant>,
+
+    // keepalive_ping is the idle time after which the backend is pinged, never if None, and last_sent is
+    // the time the last command was sent to the backend
+    keepalive_ping: Option<Duration>,
+    last_sent: Instant,
+
+    // flush_batch is the number of the commands written before the backend is flushed, and flush_delay
+    // the longest the commands of an incomplete batch wait. unflushed is the number of the commands
+    // written since the last flush, and unflushed_since the time the first of them was written.
+    flush_batch: usize,
+    flush_delay: Duration,
+    unflushed: usize,
+    unflushed_since: Option<Instant>,
 }
 
 impl<T, S, R> Back<T, S, R>
@@ -64,23 +127,48 @@ where
     S: Sink<T, Error = AsError>,
     R: Stream<Item = Result<T::Reply, AsError>>,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         conn_addr: String,
+        cluster: Arc<str>,
+        pool: Pool,
         input: Receiver<T>,
         downstream: S,
         upstream: R,
         read_timeout: Duration,
+        retry_budget: Option<Arc<RetryBudget>>,
+        outstanding: Arc<AtomicUsize>,
+        redirector: Option<Redirector<T>>,
+        drain_grace: Duration,
+        keepalive_ping: Option<Duration>,
+        flush_batch: usize,
+        flush_delay: Duration,
     ) -> Self {
         Back {
             conn_addr,
-            store: None,
+            cluster,
+            pool,
+            pending: VecDeque::new(),
+            retries: VecDeque::new(),
+            inflight: VecDeque::new(),
             input,
             downstream,
             upstream,
             resp_timeout: read_timeout,
             downstream_poll_error: 0,
-            sub_cmds: Vec::new(),
             delayed: 0,
+            retry_at: None,
+            retry_budget,
+            outstanding,
+            redirector,
+            drain_grace,
+            drain_deadline: None,
+            keepalive_ping,
+            last_sent: Instant::now(),
+            flush_batch,
+            flush_delay,
+            unflushed: 0,
+            unflushed_since: None,
         }
     }
 }
@@ -95,157 +183,349 @@ where
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.as_mut().project();
-        let store = this.store;
 
         let mut downstream = this.downstream;
-        let upstream = this.upstream;
+        let mut upstream = this.upstream;
+        let pending = this.pending;
+        let retries = this.retries;
+        let inflight = this.inflight;
         let delayed = this.delayed;
 
-        if store.is_none() {
-            match this.sub_cmds.is_empty() {
-                true => match this.input.recv_timeout(CHANNEL_FETCH_TIMEOUT) {
-                    Ok(cmd) => {
-                        match cmd.waker().is_some() {
-                            true => {
-                                debug!("backend {} received a command", this.conn_addr);
-
-                                // if there are sub commands, push them into a stack and process them first at order.
-                                // because the sub_cmds is a Vec, we need to reverse it to keep the incoming order.
-                                if let Some(mut subs) = cmd.subs() {
-                                    subs.iter_mut().for_each(|sub| {
-                                        sub.register_waker(
-                                            cmd.waker().expect("waker should not be empty here"),
-                                        )
-                                    });
-                                    this.sub_cmds.extend(subs.into_iter().rev());
-                                    *store = Some(
-                                        this.sub_cmds.pop().expect("sub_cmds should not be empty"),
-                                    );
-                                } else {
-                                    *store = Some(cmd);
-                                }
-                            }
-                            false => debug!("dropping the command due to incorrect arrival path. waker was empty"),
-                        }
-                    }
-                    Err(err) => match err {
-                        RecvTimeoutError::Timeout => {
-                            // wait for another wakeup
-                        }
-                        RecvTimeoutError::Disconnected => {
-                            info!(
-                                "channel from front is disconnected for backend {}",
-                                this.conn_addr
-                            );
-                            return Poll::Ready(());
-                        }
-                    },
-                },
-                false => {
-                    debug!(
-                        "backend {} process the already available sub command",
+        // block for a while only if there is nothing to be done, otherwise take whatever is available
+        // without waiting to pipeline it along the other commands.
+        if pending.is_empty() && retries.is_empty() && inflight.is_empty() {
+            match this.input.recv_timeout(CHANNEL_FETCH_TIMEOUT) {
+                Ok(cmd) => accept(this.cluster, this.conn_addr, pending, cmd),
+                Err(RecvTimeoutError::Timeout) => {
+                    // wait for another wakeup
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!(
+                        "channel from front is disconnected for backend {}",
                         this.conn_addr
                     );
-
-                    // TODO: sub command error chain check
-                    // sub commands should be handled in a more efficient way.
-                    // if any sub command is failed, the whole command should be failed.
-                    *store = Some(this.sub_cmds.pop().expect("sub_cmds should not be empty"));
+                    backend_down(this.cluster, this.conn_addr);
+                    return Poll::Ready(());
                 }
             }
         }
-
-        if let Some(cmd) = store.take() {
-            match cmd.get_sent_time() {
-                Some(sent_time) => {
-                    if sent_time.elapsed() > *this.resp_timeout {
-                        error!("backend {} read timeout", this.conn_addr);
-                        cmd.set_error(&AsError::CmdTimeout);
-                        *delayed += 1;
-                        *store = None;
-                    } else {
-                        *store = Some(cmd);
+        while pending.len() + inflight.len() < BACKEND_MAX_PIPELINE {
+            match this.input.try_recv() {
+                Ok(cmd) => accept(this.cluster, this.conn_addr, pending, cmd),
+                Err(TryRecvError::Empty) => break,
+                // a disconnected channel is handled once the queued commands are served
+                Err(TryRecvError::Disconnected) => {
+                    if this.drain_deadline.is_none() {
+                        info!(
+                            "backend {} is removed, draining its queued commands",
+                            this.conn_addr
+                        );
+                        *this.drain_deadline = Some(Instant::now() + *this.drain_grace);
                     }
+                    break;
                 }
-                None => match downstream.as_mut().poll_ready(cx) {
-                    Poll::Ready(Ok(())) => {
-                        debug!("backend {} sent a command", this.conn_addr);
-                        cmd.mark_sent();
-                        let waited_cmd = cmd.clone();
-                        if let Err(err) = downstream.as_mut().start_send(cmd) {
-                            error!(
-                                "backend {} failed to send a command due to {}",
-                                this.conn_addr, err
-                            );
-                            waited_cmd.set_error(&AsError::ProxyFail);
-                            *store = None;
-                        } else {
-                            let _ = downstream.poll_flush(cx);
-                            *store = Some(waited_cmd);
-                        }
+            }
+        }
+
+        // the idle connection is kept warm, so the NATs and the firewalls on the way do not drop it
+        let idle = pending.is_empty() && retries.is_empty() && inflight.is_empty() && *delayed == 0;
+        if idle
+            && this
+                .keepalive_ping
+                .is_some_and(|keepalive| this.last_sent.elapsed() >= keepalive)
+        {
+            debug!(
+                "backend {} is idle, sending a keepalive ping",
+                this.conn_addr
+            );
+            // the ping is not tracked by the remote timer, so its deadline bounds the wait of its reply
+            let ping = T::ping_request();
+            ping.set_deadline(Instant::now() + *this.resp_timeout);
+            pending.push_back(ping);
+        }
+
+        if this.drain_deadline.is_some_and(|at| Instant::now() >= at) {
+            warn!(
+                "backend {} is closed before its queued commands are served",
+                this.conn_addr
+            );
+            fail_all(this.conn_addr, [pending, retries, inflight]);
+            this.outstanding.store(0, Ordering::Relaxed);
+            backend_down(this.cluster, this.conn_addr);
+            return Poll::Ready(());
+        }
+
+        if this.retry_at.is_some_and(|at| Instant::now() >= at) {
+            *this.retry_at = None;
+        }
+
+        while this.retry_at.is_none() && outstanding(inflight, *delayed) < BACKEND_MAX_PIPELINE {
+            let queue = match retries.is_empty() {
+                true => &mut *pending,
+                false => &mut *retries,
+            };
+            let cmd = match queue.pop_front() {
+                Some(cmd) => cmd,
+                None => break,
+            };
+
+            // the deadline of the client connection takes precedence over the backend read timeout
+            if is_expired(&cmd) {
+                debug!("backend {} dropped an expired command", this.conn_addr);
+                cmd.set_error(&AsError::CmdTimeout);
+                continue;
+            }
+
+            match downstream.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    debug!("backend {} sent a command", this.conn_addr);
+                    if !cmd.is_keepalive() {
+                        cmd.mark_sent(this.conn_addr);
                     }
-                    Poll::Ready(Err(err)) => {
-                        warn!(
+                    let waited_cmd = cmd.clone();
+                    if let Err(err) = downstream.as_mut().start_send(cmd) {
+                        error!(
                             "backend {} failed to send a command due to {}",
                             this.conn_addr, err
                         );
-                        if cmd.can_cycle() {
-                            cmd.add_cycle();
-                        } else {
-                            cmd.set_error(&AsError::ProxyFail);
-                            *store = None;
-                        }
-
-                        *this.downstream_poll_error += 1;
-                        if *this.downstream_poll_error > DOWNSTREAM_MAX_POLL_ERROR {
-                            error!("backend {} is not stable to send commands", this.conn_addr);
-                            return Poll::Ready(());
-                        }
+                        waited_cmd.set_error(&AsError::ProxyFail);
+                    } else {
+                        inflight.push_back(waited_cmd);
+                        *this.last_sent = Instant::now();
+                        *this.unflushed += 1;
+                        this.unflushed_since.get_or_insert(*this.last_sent);
                     }
-                    Poll::Pending => {
-                        debug!("backend {} is not ready yet", this.conn_addr);
-                        *store = Some(cmd);
+                }
+                Poll::Ready(Err(err)) => {
+                    warn!(
+                        "backend {} failed to send a command due to {}",
+                        this.conn_addr, err
+                    );
+                    if cmd.can_cycle() && may_retry(this.cluster, this.conn_addr, this.retry_budget)
+                    {
+                        cmd.add_cycle();
+                        queue.push_front(cmd);
+                    } else {
+                        cmd.set_error(&AsError::ProxyFail);
                     }
-                },
+
+                    *this.downstream_poll_error += 1;
+                    if *this.downstream_poll_error > DOWNSTREAM_MAX_POLL_ERROR {
+                        error!("backend {} is not stable to send commands", this.conn_addr);
+                        fail_all(this.conn_addr, [pending, retries, inflight]);
+                        this.outstanding.store(0, Ordering::Relaxed);
+                        backend_down(this.cluster, this.conn_addr);
+                        return Poll::Ready(());
+                    }
+                    break;
+                }
+                Poll::Pending => {
+                    debug!("backend {} is not ready yet", this.conn_addr);
+                    queue.push_front(cmd);
+                    break;
+                }
+            }
+        }
+
+        // the commands are flushed once a batch is written, or once the first of an incomplete batch
+        // waited for the flush delay
+        let flush = *this.unflushed >= *this.flush_batch
+            || this
+                .unflushed_since
+                .is_some_and(|since| since.elapsed() >= *this.flush_delay);
+        if flush {
+            if let Poll::Ready(Ok(())) = downstream.as_mut().poll_flush(cx) {
+                *this.unflushed = 0;
+                *this.unflushed_since = None;
             }
         }
 
-        if let Some(cmd) = &store {
-            match upstream.poll_next(cx) {
-                Poll::Ready(Some(may_reply)) => match may_reply {
-                    Ok(reply) => {
-                        debug!("backend {} received a reply", this.conn_addr);
-
-                        if *delayed > 0 {
-                            warn!(
-                                "backend {} received a late reply, skipping {} delayed commands",
-                                this.conn_addr, delayed
-                            );
-                            *delayed -= 1;
+        // the replies of the timed out commands are skipped when they are received late
+        while let Some(cmd) = inflight.front() {
+            let timed_out = cmd
+                .get_sent_time()
+                .is_some_and(|sent_time| sent_time.elapsed() > *this.resp_timeout);
+            if !timed_out && !is_expired(cmd) {
+                break;
+            }
+
+            error!("backend {} read timeout", this.conn_addr);
+            cmd.set_error(&AsError::CmdTimeout);
+            // the command still waiting for the reply of its ASKING is replied twice
+            let late_replies = if cmd.take_ask() { 2 } else { 1 };
+            inflight.pop_front();
+            *delayed = delayed.saturating_add(late_replies);
+        }
+
+        while !inflight.is_empty() || *delayed > 0 {
+            match upstream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(reply))) => {
+                    debug!("backend {} received a reply", this.conn_addr);
+
+                    if skip_late_reply(delayed) {
+                        warn!(
+                            "backend {} received a late reply, {} delayed commands left",
+                            this.conn_addr, delayed
+                        );
+                        continue;
+                    }
+
+                    // the replies are only polled while a command is waiting for one, so an
+                    // unsolicited reply is dropped rather than paired with a later command
+                    let Some(cmd) = inflight.pop_front() else {
+                        warn!("backend {} received an unexpected reply", this.conn_addr);
+                        continue;
+                    };
+                    // the command sent with ASKING is replied twice, the first reply is the one of ASKING
+                    if cmd.take_ask() {
+                        debug!("backend {} received the reply of ASKING", this.conn_addr);
+                        inflight.push_front(cmd);
+                        continue;
+                    }
+
+                    // the command is sent to the node of the redirection, the redirections are bound
+                    // by the cycles of the command so the nodes redirecting to each other can not loop,
+                    // and charged to the retry budget like the other resends
+                    let redirected = this.redirector.as_ref().and_then(|redirector| {
+                        T::reply_redirect(&reply)
+                            .filter(|_| {
+                                cmd.can_cycle()
+                                    && may_retry(this.cluster, this.conn_addr, this.retry_budget)
+                            })
+                            .map(|redirect| (redirector, redirect))
+                    });
+                    if let Some((redirector, redirect)) = redirected {
+                        debug!(
+                            "backend {} redirected a command by {:?}",
+                            this.conn_addr, redirect
+                        );
+                        cmd.add_cycle();
+                        if redirect.is_ask() {
+                            cmd.set_ask();
                         } else {
-                            cmd.set_reply(reply);
-                            *store = None;
+                            cmd.set_moved();
+                        }
+                        cmd.reset_sent();
+                        redirector(cmd, redirect);
+                    } else if T::reply_error(&reply) == Some(ReplyError::Loading)
+                        && cmd.can_cycle()
+                        && may_retry(this.cluster, this.conn_addr, this.retry_budget)
+                    {
+                        warn!(
+                            "backend {} is loading the dataset, retrying the command",
+                            this.conn_addr
+                        );
+                        cmd.add_cycle();
+                        cmd.reset_sent();
+                        retries.push_back(cmd);
+                        *this.retry_at = Some(Instant::now() + LOADING_RETRY_DELAY);
+                    } else {
+                        // BUSY is understood by the clients, so it is passed through as is and the
+                        // connection is kept since the backend is healthy but running a long script.
+                        if T::reply_error(&reply) == Some(ReplyError::Busy) {
+                            warn!("backend {} is busy running a script", this.conn_addr);
+                            backend_busy_incr(this.cluster, this.conn_addr);
+                        }
+                        if !cmd.is_keepalive() {
+                            pool_reply_incr(this.cluster, this.pool.as_str());
                         }
+                        cmd.set_reply(reply);
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    debug!("backend {} received an error", this.conn_addr);
+                    if skip_late_reply(delayed) {
+                        continue;
                     }
-                    Err(err) => {
-                        debug!("backend {} received an error", this.conn_addr);
+                    if let Some(cmd) = inflight.pop_front() {
                         cmd.set_error(&err);
-                        *store = None;
                     }
-                },
+                }
                 Poll::Ready(None) => {
                     debug!("backend {} is disconnected", this.conn_addr);
+                    fail_all(this.conn_addr, [pending, retries, inflight]);
+                    this.outstanding.store(0, Ordering::Relaxed);
+                    backend_down(this.cluster, this.conn_addr);
                     return Poll::Ready(());
                 }
-                Poll::Pending => {}
+                Poll::Pending => break,
             }
         }
 
+        this.outstanding
+            .store(outstanding(inflight, *delayed), Ordering::Relaxed);
         cx.waker().wake_by_ref();
         Poll::Pending
     }
 }
 
+// accept queues the command received from the front to be sent to the backend
+fn accept<T: Request>(cluster: &str, conn_addr: &str, pending: &mut VecDeque<T>, cmd: T) {
+    match cmd.waker().is_some() {
+        true => {
+            debug!("backend {} received a command", conn_addr);
+            pending.push_back(cmd);
+        }
+        // the command is never replied to the client if dropped, so it is failed instead
+        false => {
+            error!(
+                "backend {} received a command without a waker, failing it",
+                conn_addr
+            );
+            bug_incr(cluster, "missing_waker");
+            cmd.set_error(&AsError::ProxyFail);
+        }
+    }
+}
+
+// is_expired checks if the deadline of the client connection is passed for the command
+fn is_expired<T: Request>(cmd: &T) -> bool {
+    cmd.get_deadline()
+        .is_some_and(|deadline| Instant::now() > deadline)
+}
+
+// may_retry consults the retry budget before resending a command and counts the outcome
+fn may_retry(cluster: &str, conn_addr: &str, retry_budget: &Option<Arc<RetryBudget>>) -> bool {
+    if retry_budget
+        .as_ref()
+        .is_some_and(|budget| !budget.acquire())
+    {
+        warn!(
+            "backend {} retry budget is exhausted, failing fast",
+            conn_addr
+        );
+        retry_denied_incr(cluster, conn_addr);
+        return false;
+    }
+
+    retry_incr(cluster, conn_addr);
+    true
+}
+
+// outstanding is the number of the commands sent to the backend which are not replied yet
+fn outstanding<T>(inflight: &VecDeque<T>, delayed: u32) -> usize {
+    inflight.len() + delayed as usize
+}
+
+// skip_late_reply consumes a delayed command if any, in which case the received reply belongs to it
+fn skip_late_reply(delayed: &mut u32) -> bool {
+    match delayed.checked_sub(1) {
+        Some(left) => {
+            *delayed = left;
+            true
+        }
+        None => false,
+    }
+}
+
+// fail_all replies the queued commands with an error once the backend connection is closed
+fn fail_all<T: Request, const N: usize>(conn_addr: &str, queues: [&mut VecDeque<T>; N]) {
+    let err = AsError::BackendClosedError(conn_addr.to_string());
+    for queue in queues {
+        queue.drain(..).for_each(|cmd| cmd.set_error(&err));
+    }
+}
+
 pub struct BlackHole<T>
 where
     T: Request,
@@ -295,3 +575,38 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{init_test_instruments, test_metric_value};
+    use crate::protocol::redis::{init_redis_supported_cmds, Cmd};
+
+    #[test]
+    fn test_accept_without_waker() {
+        init_test_instruments();
+        init_redis_supported_cmds();
+        let cluster = "test-accept-without-waker";
+        let bugs = || {
+            test_metric_value(
+                "repust_bugs_total",
+                &[("cluster", cluster), ("bug", "missing_waker")],
+            )
+        };
+
+        // the command forwarded without a waker is failed rather than dropped
+        let mut pending = VecDeque::new();
+        let cmd = Cmd::ping_request();
+        accept(cluster, "127.0.0.1:6379", &mut pending, cmd.clone());
+        assert!(pending.is_empty());
+        assert!(cmd.is_done());
+        assert!(cmd.is_error());
+        assert_eq!(bugs(), 1.0);
+
+        let mut cmd = Cmd::ping_request();
+        cmd.register_waker(futures::task::noop_waker());
+        accept(cluster, "127.0.0.1:6379", &mut pending, cmd);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(bugs(), 1.0);
+    }
+}
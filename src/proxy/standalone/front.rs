@@ -1,25 +1,33 @@
-use crossbeam_channel::SendTimeoutError;
-use futures::{Future, Sink, Stream};
+use crossbeam_channel::TrySendError;
+use futures::{task::noop_waker, Future, Sink, Stream};
 use log::{debug, error};
 use pin_project::{pin_project, pinned_drop};
+use rand::{rngs::StdRng, Rng};
 use std::{
     collections::VecDeque,
     pin::Pin,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     com::AsError,
-    metrics::front_conn_decr,
+    metrics::{command_incr, mirror_dropped_incr},
+    protocol::CmdType,
     proxy::{
-        standalone::{fnv::fnv1a64, RingKeeper},
-        Request,
+        front::{
+            dispatch, poll_command, send, set_client_timeout, Closing, ConnStats, Head, SentQueue,
+        },
+        routing_rng,
+        standalone::{
+            pubsub::{Outcome, PubSub},
+            RingKeeper, StandaloneCluster,
+        },
+        ClientConn, ProxyCmd, ProxyReply, Request,
     },
 };
 
-const FRONTEND_MAX_POLL_ERROR: u8 = 10;
-
 #[pin_project(PinnedDrop)]
 pub struct Front<T, I, O>
 where
@@ -30,12 +38,11 @@ where
     // client is the name of the client, usually the address of the client
     client: String,
 
-    // hash_tag ensures that multiple keys are allocated in the same hash slot.
-    // This is useful for situations when multiple keys are stored in the same hash slot.
-    hash_tag: Vec<u8>,
+    // conn is the connection of the client registered to the cluster, as described by CLIENT INFO
+    conn: Arc<ClientConn>,
 
-    // ring is the entire cluster information including addresses, connections and their associated sender channels.
-    ring: RingKeeper<T>,
+    // cluster is the cluster the client is connected to, holding the rings, the policy and the hash tag
+    cluster: Arc<StandaloneCluster<T>>,
 
     // downstream here represent the stream which takes commands from the client.
     // Since the proxy is sat between clients and the backends is is act as a downstream to the clients.
@@ -46,15 +53,31 @@ where
     // upstream here represent the sink which sends the response to the client.
     upstream: O,
 
-    // timeout is the time after which the request will be considered as failed
-    timeout: Duration,
+    // client_timeout is the end-to-end deadline of the commands set by the client using PROXY TIMEOUT
+    client_timeout: Option<Duration>,
+
+    // read_only routes the reads to the read servers, if any. The connections start reading their own
+    // writes from the primaries until READONLY sets it, and READWRITE clears it again.
+    read_only: bool,
 
     // sent_queue is the queue which holds the requests which are sent to the back but not yet received the response.
-    // This queue is used to check the reply of the requests on the order they were sent.
-    sent_queue: VecDeque<T>,
+    // This queue is used to check the reply of the requests on the order they were sent, along with the
+    // backend of the ring before its last change a miss of the request is read again from, if any.
+    sent_queue: SentQueue<T, Option<String>>,
+
+    // pubsub is the subscribed session of the client, if any. The client is served by its dedicated
+    // backend connection until its last subscription ends.
+    pubsub: Option<PubSub<T>>,
+
+    // rng is the source of the random routing decisions of the connection, e.g. the canary split and the
+    // random balance policy
+    rng: StdRng,
+
+    // stats describes the connection for the metrics recorded on close
+    stats: ConnStats,
 
-    // upstream_poll_error is the counter to record the send error of the upstream
-    upstream_poll_error: u8,
+    // closing drains the connection once the cluster shuts down
+    closing: Closing,
 }
 
 impl<T, I, O> Front<T, I, O>
@@ -65,28 +88,31 @@ where
 {
     pub fn new(
         client: String,
-        hash_tag: Vec<u8>,
-        ring: RingKeeper<T>,
+        cluster: Arc<StandaloneCluster<T>>,
         downstream: I,
         upstream: O,
-        timeout: Duration,
     ) -> Self {
+        cluster.fronts.fetch_add(1, Ordering::Relaxed);
         Front {
+            conn: cluster.clients.register(&client),
+            closing: Closing::new(cluster.shutdown.started()),
             client,
-            hash_tag,
-            ring,
+            rng: routing_rng(&cluster.cc),
+            cluster,
             downstream,
             upstream,
-            timeout,
-            sent_queue: VecDeque::new(),
-            upstream_poll_error: 0,
+            client_timeout: None,
+            read_only: false,
+            sent_queue: SentQueue::new(),
+            pubsub: None,
+            stats: ConnStats::new(),
         }
     }
 }
 
 impl<T, I, O> Future for Front<T, I, O>
 where
-    T: Request,
+    T: Request + Send + Sync + 'static,
     O: Sink<T, Error = AsError>,
     I: Stream<Item = Result<T, AsError>>,
 {
@@ -94,128 +120,371 @@ where
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = self.project();
+        let cluster = &**this.cluster;
 
-        let downstream = this.downstream;
+        let mut downstream = this.downstream;
         let mut upstream = this.upstream;
 
-        if let Some(cmd) = this.sent_queue.pop_front() {
-            if cmd.is_done() {
-                debug!("command is done, sending the reply to the client");
-
-                // send the reply to the client
-                match upstream.as_mut().poll_ready(cx) {
-                    Poll::Ready(Ok(())) => {
-                        if let Err(err) = upstream.as_mut().start_send(cmd) {
-                            error!(
-                                "frontend {} failed to send reply to client: {}",
-                                this.client, err
-                            );
-                        } else {
-                            let _ = upstream.poll_flush(cx);
-                        }
-                    }
-                    Poll::Ready(Err(err)) => {
-                        error!(
-                            "frontend {} failed to send reply to client: {}",
-                            this.client, err
-                        );
-
-                        *this.upstream_poll_error += 1;
-                        if *this.upstream_poll_error > FRONTEND_MAX_POLL_ERROR {
-                            error!(
-                                "frontend {} is not stable to send replies, closing the connection",
-                                this.client
-                            );
-                            return Poll::Ready(());
-                        }
+        // the commands of several steps forward the subs of their next one and stay queued until those are
+        // done too. The writes of the step are copied to the migration target as well. The misses of the
+        // reads routed by a changed ring are read again from their previous backend.
+        let client = &**this.client;
+        let rng = &mut *this.rng;
+        let head = |queue: &mut VecDeque<(T, Option<String>)>, cx: &mut Context| {
+            if let Some(subs) = queue.front().and_then(|(cmd, _)| cmd.next_subs()) {
+                for mut sub in subs {
+                    if let Some(mirror) = cluster.mirror.as_ref() {
+                        let mut copy = sub.mirror();
+                        copy.register_waker(noop_waker());
+                        forward_mirror(copy, mirror, cluster, client, rng);
                     }
-                    Poll::Pending => {}
+                    sub.register_waker(cx.waker().clone());
+                    forward(sub, &cluster.ring, cluster, client, rng);
                 }
-            } else {
-                // push the command back to the sent queue to check the response later in order
-                this.sent_queue.push_front(cmd);
+                return Head::Requeued;
+            }
+            if queue
+                .front()
+                .is_some_and(|(cmd, previous)| previous.is_some() && cmd.is_miss())
+            {
+                let (cmd, previous) = queue.pop_front().expect("front command must exist");
+                let addr = previous.expect("previous backend must exist");
+                let cmd = reread(cmd, &addr, cluster, client, cx);
+                queue.push_front((cmd, None));
+                return Head::Requeued;
             }
+            Head::Reply
+        };
+        let replying = |cmd: &T| {
+            if cluster.policy.compression_threshold.is_some() {
+                cmd.decompress_reply();
+            }
+        };
+        if this
+            .sent_queue
+            .poll_replies(cx, upstream.as_mut(), client, this.stats, head, replying)
+            .is_ready()
+        {
+            return Poll::Ready(());
         }
 
-        match downstream.poll_next(cx) {
-            Poll::Ready(Some(may_cmd)) => {
-                match may_cmd {
-                    Ok(mut cmd) => {
-                        // if the command is invalid or done, send it to the client for immediate response.
-                        if cmd.valid() && !cmd.is_done() {
-                            debug!("frontend received a command from client {}", this.client);
-
-                            // register the waker to the command to wake up the task when the response is ready
-                            cmd.register_waker(cx.waker().clone());
-
-                            // find the output connection for the command based on the hash of the cmd key
-                            let key_hash = cmd.key_hash("".as_bytes(), fnv1a64);
-                            match this.ring.get_sender(key_hash) {
-                                Some(output) => {
-                                    // send the command to the back for processing
-                                    // Note: cloning the cmd produces a new pointer to the same underlying data because of
-                                    // using Rc in the cmd interior. So, it is not an expensive operation.
-                                    match output.send_timeout(cmd.clone(), *this.timeout) {
-                                        Ok(_) => {
-                                            debug!(
-                                                "frontend {} forwarded command to back",
-                                                this.client
-                                            )
-                                        }
-                                        Err(err) => match err {
-                                            SendTimeoutError::Timeout(cmd) => {
-                                                error!(
-                                                    "frontend {} faced timeout to forward command",
-                                                    this.client
-                                                );
-                                                cmd.set_error(&AsError::CmdTimeout);
-                                            }
-                                            SendTimeoutError::Disconnected(cmd) => {
-                                                error!(
-                                                    "frontend {} has no backend consumer",
-                                                    this.client
-                                                );
-                                                cmd.set_error(&AsError::ClusterFailDispatch);
-                                            }
-                                        },
-                                    }
-                                }
-                                None => {
-                                    error!(
-                                        "frontend {} failed to find output channel for the command based on cmd hash",
-                                        this.client
-                                    );
-                                    cmd.set_error(&AsError::ClusterFailDispatch);
-                                }
-                            };
-                        }
-                        // push the command to the sent queue to check the response later in order
-                        this.sent_queue.push_back(cmd);
-
-                        // Wake the task until there are no values to be received from stream.
-                        // After stream returns Pending, waker is automatically registered to wake up the task in the
-                        // case of new value is ready to be received.
-                        cx.waker().wake_by_ref();
-                    }
-                    Err(err) => {
-                        error!(
-                            "frontend {} failed to receive command from client due to: {}",
-                            this.client, err
-                        );
-                    }
+        let replied = this.sent_queue.is_empty();
+        if let Some(closed) = this
+            .closing
+            .poll_close(cx, upstream.as_mut(), client, replied)
+        {
+            return closed;
+        }
+
+        // the subscribed session starts once the commands before it are replied
+        if let Some(pubsub) = this.pubsub.as_mut() {
+            if !this.sent_queue.is_empty() {
+                return Poll::Pending;
+            }
+            match pubsub.poll(
+                cx,
+                cluster,
+                this.rng,
+                downstream.as_mut(),
+                upstream.as_mut(),
+            ) {
+                Poll::Ready(Outcome::Unsubscribed) => {
+                    *this.pubsub = None;
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Ready(Outcome::Closed) => {
+                    debug!("frontend terminated for subscribed client {}", this.client);
+                    return Poll::Ready(());
+                }
+                Poll::Ready(Outcome::Failed(cmd)) => {
+                    *this.pubsub = None;
+                    this.sent_queue.push_back(cmd, None);
+                    cx.waker().wake_by_ref();
                 }
+                Poll::Pending => {}
             }
-            Poll::Ready(None) => {
-                debug!("frontend terminated for client {}", this.client);
-                return Poll::Ready(());
+            return Poll::Pending;
+        }
+
+        let mut cmd = match poll_command(cx, downstream, client, this.stats) {
+            Poll::Ready(Some(cmd)) => cmd,
+            Poll::Ready(None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut previous = None;
+
+        // label the metrics of the command by the cluster and start its end-to-end timer. The commands
+        // shed while the cluster misses its SLO are replied at once, and have no timer so their latency
+        // doesn't hide the one of the forwarded commands. The connection setup commands, e.g. AUTH, are
+        // never shed as the clients would not recover the connection by retrying the next commands.
+        cmd.set_cluster(&cluster.policy.cluster);
+        let shed = cluster.shedder.as_ref().map_or(0.0, |x| x.fraction());
+        let setup = matches!(cmd.cmd_type(), CmdType::Auth | CmdType::Ctrl);
+        if shed > 0.0
+            && cmd.valid()
+            && !setup
+            && cmd.proxy_cmd().is_none()
+            && this.rng.gen::<f64>() < shed
+        {
+            cmd.set_error(&AsError::Overloaded);
+        } else {
+            cmd.mark_total();
+        }
+
+        // if the command is invalid or done, send it to the client for immediate response.
+        if cmd.valid() && !cmd.is_done() && cmd.check_policy(&cluster.policy) {
+            if let Some(proxy_cmd) = cmd.proxy_cmd() {
+                handle_proxy_cmd(
+                    &cmd,
+                    proxy_cmd,
+                    cluster,
+                    this.conn,
+                    this.client_timeout,
+                    this.read_only,
+                    this.rng,
+                );
+            } else if cmd.cmd_type().is_pub_sub() {
+                // the subscribed client is streamed to its backend from now on
+                match PubSub::new(cmd, cluster, this.rng) {
+                    Ok(pubsub) => *this.pubsub = Some(pubsub),
+                    Err(cmd) => this.sent_queue.push_back(cmd, None),
+                }
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            } else {
+                debug!("frontend received a command from client {}", client);
+
+                // register the waker to the command to wake up the task when the response is ready
+                cmd.register_waker(cx.waker().clone());
+
+                if let Some(client_timeout) = this.client_timeout {
+                    cmd.set_deadline(Instant::now() + *client_timeout);
+                }
+
+                if let Some(min_bytes) = cluster.policy.compression_threshold {
+                    cmd.compress_value(min_bytes);
+                }
+                if let Some(ttl_secs) = cluster.policy.default_ttl {
+                    cmd.set_default_ttl(ttl_secs);
+                }
+
+                // the writes are copied to the migration target and its replies are ignored,
+                // the reads are only served by the primary backends.
+                if let Some(mirror) = cluster.mirror.as_ref().filter(|_| cmd.is_write()) {
+                    dispatch(&cmd.mirror(), &noop_waker(), |mut copy| {
+                        copy.register_waker(noop_waker());
+                        forward_mirror(copy, mirror, cluster, client, this.rng)
+                    });
+                }
+
+                let read = *this.read_only && cmd.is_read();
+                let ring = select_ring(cluster, cmd.cmd_type(), read, this.rng);
+
+                // the sub commands are routed by their own keys, so the ones of the same backend
+                // are pipelined together and the ones of different backends are served in parallel.
+                // the reply is assembled in the order of the subs once all of them are done.
+                // Note: cloning the cmd produces a new pointer to the same underlying data because of
+                // using Rc in the cmd interior. So, it is not an expensive operation. The misses of a
+                // whole read routed by a changed ring are read again from the backend before the change.
+                let whole = dispatch(&cmd, cx.waker(), |cmd| {
+                    forward(cmd, ring, cluster, client, this.rng)
+                });
+                if whole
+                    && std::ptr::eq(ring, &cluster.ring)
+                    && cmd.is_read()
+                    && cmd.scan_node().is_none()
+                {
+                    previous = cluster
+                        .ring
+                        .get_previous_addr(cluster.cmd_hash(&cmd), this.rng);
+                }
             }
-            Poll::Pending => {}
         }
+        // push the command to the sent queue to check the response later in order
+        this.sent_queue.push_back(cmd, previous);
 
+        // Wake the task until there are no values to be received from stream.
+        // After stream returns Pending, waker is automatically registered to wake up the task in the
+        // case of new value is ready to be received.
+        cx.waker().wake_by_ref();
         Poll::Pending
     }
 }
 
+// select_ring returns the ring the commands of the given type are routed on. The command types routed to
+// a pool are served by it, the reads by the read servers, a share of the others drawn from the given
+// source by the canary, and the rest by the servers.
+fn select_ring<'a, T: Request>(
+    cluster: &'a StandaloneCluster<T>,
+    cmd_type: CmdType,
+    read: bool,
+    rng: &mut StdRng,
+) -> &'a RingKeeper<T> {
+    match (
+        cluster.routes.get(&cmd_type),
+        &cluster.reader,
+        &cluster.canary,
+    ) {
+        (Some(pool), _, _) => pool,
+        (_, Some(reader), _) if read => reader,
+        (_, _, Some(canary)) if rng.gen_range(0..100) < canary.weight => &canary.ring,
+        _ => &cluster.ring,
+    }
+}
+
+// forward sends the command to the backend its key is routed to on the given ring
+fn forward<T: Request + Send + Sync + 'static>(
+    cmd: T,
+    ring: &RingKeeper<T>,
+    cluster: &StandaloneCluster<T>,
+    client: &str,
+    rng: &mut StdRng,
+) {
+    // the commands received before any backend is connected are asked to be retried
+    if let Some(err) = cluster.warming_error() {
+        debug!("frontend {} replied {} while warming up", client, err);
+        cmd.set_error(&err);
+        return;
+    }
+
+    // find the output connection for the command based on the hash of the cmd key. The SCAN cursor
+    // resumes the backend which replied it instead, and the scan is complete past the last one.
+    let output = match cmd.scan_node() {
+        Some(node) => match ring.get_nth_addr(node) {
+            Some((addr, last)) => {
+                if last {
+                    cmd.set_scan_last();
+                }
+                cluster.get_addr_sender(ring, &addr)
+            }
+            None => {
+                cmd.finish_scan();
+                return;
+            }
+        },
+        None => cluster.get_sender(ring, cluster.cmd_hash(&cmd), rng),
+    };
+    send(output, cmd, &cluster.policy, &cluster.throughput, client);
+}
+
+// forward_mirror sends the copy of a write to the migration target. The copy is dropped rather than
+// waited on if the queue of its backend is full, so the lagging target never holds the client, and it is
+// not counted as a command of the cluster.
+fn forward_mirror<T: Request + Send + Sync + 'static>(
+    copy: T,
+    mirror: &RingKeeper<T>,
+    cluster: &StandaloneCluster<T>,
+    client: &str,
+    rng: &mut StdRng,
+) {
+    let output = match cluster.get_sender(mirror, cluster.cmd_hash(&copy), rng) {
+        Some(output) => output,
+        None => {
+            mirror_dropped_incr(&cluster.cc.name);
+            return;
+        }
+    };
+    match output.try_send(copy) {
+        Ok(_) => debug!(
+            "frontend {} mirrored command to the migration target",
+            client
+        ),
+        Err(TrySendError::Full(_)) => {
+            debug!(
+                "frontend {} dropped the mirrored command of the full migration target",
+                client
+            );
+            mirror_dropped_incr(&cluster.cc.name);
+        }
+        Err(TrySendError::Disconnected(_)) => mirror_dropped_incr(&cluster.cc.name),
+    }
+}
+
+// reread sends a copy of the missed read to the given backend of the ring before its last change, and
+// returns the copy to be replied instead. The miss is replied as is if the backend can't be reached.
+fn reread<T: Request + Send + Sync + 'static>(
+    cmd: T,
+    addr: &str,
+    cluster: &StandaloneCluster<T>,
+    client: &str,
+    cx: &mut Context,
+) -> T {
+    let output = match cluster.get_addr_sender(&cluster.ring, addr) {
+        Some(output) => output,
+        None => return cmd,
+    };
+    let mut copy = cmd.mirror();
+    copy.set_cluster(&cluster.policy.cluster);
+    copy.register_waker(cx.waker().clone());
+    let cmd_type = copy.cmd_type();
+    match output.send_timeout(copy.clone(), cluster.policy.timeout) {
+        Ok(_) => {
+            command_incr(&cluster.policy.cluster, cmd_type);
+            debug!("frontend {} read a miss again from {}", client, addr);
+            copy
+        }
+        Err(_) => {
+            error!(
+                "frontend {} failed to read a miss again from {}",
+                client, addr
+            );
+            cmd
+        }
+    }
+}
+
+// handle_proxy_cmd replies to the commands addressed to the proxy itself on behalf of the connection
+fn handle_proxy_cmd<T: Request + Send + Sync + 'static>(
+    cmd: &T,
+    proxy_cmd: ProxyCmd,
+    cluster: &StandaloneCluster<T>,
+    conn: &ClientConn,
+    client_timeout: &mut Option<Duration>,
+    read_only: &mut bool,
+    rng: &mut StdRng,
+) {
+    match proxy_cmd {
+        ProxyCmd::Timeout(millis) => {
+            set_client_timeout(cmd, millis, client_timeout, &cluster.policy)
+        }
+        // the key is looked up as a read of the connection, e.g. a GET
+        ProxyCmd::Where(key) => {
+            let ring = select_ring(cluster, CmdType::Read, *read_only, rng);
+            let key_hash = cluster.key_hash(&key);
+            match ring.get_addr(key_hash, rng) {
+                Some(addr) => {
+                    let reply = format!("{} hash={}", addr, key_hash);
+                    cmd.set_proxy_reply(ProxyReply::Bulk(reply.into_bytes()));
+                }
+                None => cmd.set_reply(T::Reply::from(AsError::ClusterFailDispatch)),
+            }
+        }
+        ProxyCmd::ReadOnly(set) => {
+            *read_only = set;
+            cmd.set_proxy_reply(ProxyReply::Ok);
+        }
+        ProxyCmd::Client(client_cmd) => {
+            let laddr = &cluster.cc.listen_addr;
+            cmd.set_proxy_reply(cluster.clients.reply(client_cmd, conn, laddr));
+        }
+        ProxyCmd::Explain(explain) => {
+            let read = *read_only && explain.kind == "read";
+            let ring = select_ring(cluster, explain.cmd_type, read, rng);
+            let mut lines = vec![format!("command={} kind={}", explain.command, explain.kind)];
+            for key in explain.keys {
+                let key_hash = cluster.key_hash(&key);
+                lines.push(format!(
+                    "key={} hash={} node={}",
+                    String::from_utf8_lossy(&key),
+                    key_hash,
+                    ring.get_addr(key_hash, rng).as_deref().unwrap_or("none")
+                ));
+            }
+            cmd.set_proxy_reply(ProxyReply::Bulk(lines.join("\n").into_bytes()));
+        }
+    }
+}
+
 #[pinned_drop]
 impl<T, I, O> PinnedDrop for Front<T, I, O>
 where
@@ -225,6 +494,8 @@ where
 {
     fn drop(self: Pin<&mut Self>) {
         debug!("frontend dropped for client {}", self.client);
-        front_conn_decr();
+        self.cluster.fronts.fetch_sub(1, Ordering::Relaxed);
+        self.cluster.clients.unregister(self.conn.id);
+        self.stats.closed(&self.cluster.policy.cluster);
     }
 }
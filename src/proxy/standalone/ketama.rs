@@ -1,11 +1,14 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::HashMap};
 
 use crate::com::AsError;
 
-use log::debug;
+use log::{debug, warn};
 
 const POINTER_PER_SERVER: f64 = 160.0;
 
+// HASH_SPACE is the size of the ring, the node and key hashes are both 32 bits
+const HASH_SPACE: f64 = 4_294_967_296.0;
+
 #[derive(Eq, Debug)]
 struct NodeHash {
     pub node: String,
@@ -64,6 +67,11 @@ impl HashRing {
         Ok(ring)
     }
 
+    // nodes returns the nodes of the ring in the configured order
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
     fn node_hash(key: &str, align: usize) -> u64 {
         let md5::Digest(bs) = md5::compute(key.as_bytes());
         ((u64::from(bs[3 + align * 4]) & 0xFF) << 24)
@@ -78,6 +86,10 @@ impl HashRing {
         let ptr_per_hash = 4;
         let servern = self.nodes.len() as f64;
 
+        // owners is the node of each point, a point colliding with the one of another node would silently
+        // take over its keys, so it is salted again until it is unique
+        let mut owners: HashMap<u64, usize> = HashMap::new();
+
         let totalw = self.spots.iter().sum::<usize>() as f64;
         for (i, node) in self.nodes.iter().enumerate() {
             let percent = (self.spots[i] as f64) / totalw;
@@ -86,7 +98,17 @@ impl HashRing {
             for pidx in 1..=(per_servern / ptr_per_hash) {
                 let host = format!("{}-{}", node, pidx - 1);
                 for x in 0..ptr_per_hash {
-                    let value = Self::node_hash(&host, x as usize);
+                    let mut value = Self::node_hash(&host, x as usize);
+                    let mut salt = 0;
+                    while owners.get(&value).is_some_and(|owner| *owner != i) {
+                        salt += 1;
+                        warn!(
+                            "ring point {} of {} collides with {}, salting it by {}",
+                            value, node, self.nodes[owners[&value]], salt
+                        );
+                        value = Self::node_hash(&format!("{}#{}", host, salt), x as usize);
+                    }
+                    owners.insert(value, i);
                     let n = NodeHash {
                         node: node.clone(),
                         hash: value,
@@ -135,6 +157,56 @@ impl HashRing {
         let pos = self.get_pos_by_hash(hash);
         self.ticks.get(pos).map(|x| x.node.as_ref())
     }
+
+    // get_node_by_weight returns the node of the hash modulo the total weight, each node owning a range
+    // as long as its weight in the order the nodes are configured
+    pub fn get_node_by_weight(&self, hash: u64) -> Option<&str> {
+        let total: usize = self.spots.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = (hash % total as u64) as usize;
+        for (node, spot) in self.nodes.iter().zip(&self.spots) {
+            if point < *spot {
+                return Some(node);
+            }
+            point -= spot;
+        }
+        None
+    }
+
+    // points returns the virtual nodes of the ring as (hash, node) pairs in the ring order
+    pub fn points(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.ticks.iter().map(|x| (x.hash, x.node.as_str()))
+    }
+
+    // ownership returns the number of virtual nodes and the fraction of the hash space owned by
+    // each node, in the order the nodes are configured. A virtual node owns the keys hashed after
+    // the previous one up to its own hash, and the first one also owns the wrap around the ring.
+    pub fn ownership(&self) -> Vec<(&str, usize, f64)> {
+        let mut owned: HashMap<&str, (usize, u64)> = HashMap::new();
+        for (i, tick) in self.ticks.iter().enumerate() {
+            let arc = match i {
+                0 => {
+                    let last = self.ticks[self.ticks.len() - 1].hash;
+                    (HASH_SPACE as u64 - last) + tick.hash
+                }
+                _ => tick.hash - self.ticks[i - 1].hash,
+            };
+            let entry = owned.entry(tick.node.as_str()).or_default();
+            entry.0 += 1;
+            entry.1 += arc;
+        }
+
+        self.nodes
+            .iter()
+            .map(|node| {
+                let (points, arc) = owned.get(node.as_str()).copied().unwrap_or_default();
+                (node.as_str(), points, arc as f64 / HASH_SPACE)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -167,4 +239,50 @@ mod test_ketama {
             Some("mc-x")
         )
     }
+
+    #[test]
+    fn test_colliding_points_salted() {
+        // two points of these nodes collide in the 32 bits of the ring
+        let nodes: Vec<String> = (0..1000).map(|i| format!("mc-{}", i)).collect();
+        let ring = HashRing::new(nodes.clone(), vec![1; nodes.len()])
+            .expect("create new hash ring success");
+
+        let mut owners: HashMap<u64, &str> = HashMap::new();
+        for (hash, node) in ring.points() {
+            let owner = *owners.entry(hash).or_insert(node);
+            assert_eq!(owner, node, "point {} is shared by two nodes", hash);
+        }
+        assert_eq!(ring.points().count(), 160 * nodes.len());
+    }
+
+    #[test]
+    fn test_ownership_follows_weights() {
+        let ring = HashRing::new(
+            vec!["mc-1".to_owned(), "mc-2".to_owned(), "mc-3".to_owned()],
+            vec![1, 3, 4],
+        )
+        .expect("create new hash ring success");
+
+        let ownership = ring.ownership();
+        assert_eq!(
+            ownership.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec!["mc-1", "mc-2", "mc-3"]
+        );
+        assert_eq!(
+            ownership.iter().map(|x| x.1).sum::<usize>(),
+            ring.points().count()
+        );
+
+        let total: f64 = ownership.iter().map(|x| x.2).sum();
+        assert!((total - 1.0).abs() < 1e-9, "total share {}", total);
+        for ((node, _, share), expected) in ownership.iter().zip([0.125, 0.375, 0.5]) {
+            assert!(
+                (share - expected).abs() < 0.05,
+                "{} owns {} instead of about {}",
+                node,
+                share,
+                expected
+            );
+        }
+    }
 }
@@ -1,5 +1,61 @@
+use hickory_resolver::Resolver;
+use log::{error, info};
+use std::thread;
+
 use crate::com::AsError;
 
+// SRV_SCHEME marks the server lines resolved through the DNS SRV records, for example:
+// srv://_redis._tcp.example.com:10 where the optional weight is applied to each published target
+pub const SRV_SCHEME: &str = "srv://";
+
+// SrvTarget is a backend published by a DNS SRV record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+// SrvResolver looks up the targets of the SRV records of a name
+pub trait SrvResolver {
+    fn lookup_srv(&self, name: &str) -> Result<Vec<SrvTarget>, AsError>;
+}
+
+// DnsSrvResolver resolves the SRV records using the system DNS configuration
+pub struct DnsSrvResolver;
+
+impl SrvResolver for DnsSrvResolver {
+    fn lookup_srv(&self, name: &str) -> Result<Vec<SrvTarget>, AsError> {
+        // the blocking resolver runs its own runtime, so it is kept off the cluster runtime thread
+        let query = name.to_string();
+        let lookup = thread::spawn(move || {
+            let resolver = Resolver::from_system_conf()?;
+            resolver.srv_lookup(query)
+        })
+        .join()
+        .map_err(|_| AsError::SrvLookupError(name.to_string()))?;
+
+        let records = lookup.map_err(|err| {
+            error!("fail to lookup SRV record {} due to {}", name, err);
+            AsError::SrvLookupError(name.to_string())
+        })?;
+
+        Ok(records
+            .iter()
+            .map(|srv| SrvTarget {
+                host: srv.target().to_utf8(),
+                port: srv.port(),
+                priority: srv.priority(),
+                weight: srv.weight(),
+            })
+            .collect())
+    }
+}
+
+// Spots is the nodes, aliases and weights of the server lines
+pub type Spots = (Vec<String>, Vec<String>, Vec<usize>);
+
 pub struct ServerLine {
     addr: String,
     weight: usize,
@@ -8,30 +64,44 @@ pub struct ServerLine {
 
 impl ServerLine {
     // parse_servers parse the server line into a list of ServerLine
-    // for example: 192.168.1.2:1074:10 redis-20
-    // the first part is the address (including port), the second part is the weight
+    // for example: 192.168.1.2:1074:10 redis-20 or [::1]:1074:10 redis-20
+    // the first part is the address (including port) with an optional weight, the second part is
+    // the optional alias. The weight defaults to 1 and must be a positive integer.
     pub fn parse_servers(servers: &[String]) -> Result<Vec<ServerLine>, AsError> {
         let mut sl = Vec::with_capacity(servers.len());
         for server in servers {
-            let mut iter = server.split(' ');
-            let first_part = iter.next().expect("first partation must exists");
-            if first_part.chars().filter(|x| *x == ':').count() == 1 {
-                let alias = iter.next().map(|x| x.to_string());
-                sl.push(ServerLine {
-                    addr: first_part.to_string(),
-                    weight: 1,
-                    alias,
-                });
+            let mut iter = server.split_whitespace();
+            let first_part = iter
+                .next()
+                .ok_or_else(|| AsError::BadConfig("servers: empty server line".to_string()))?;
+            let alias = iter.next().map(|x| x.to_string());
+            if iter.next().is_some() {
+                return Err(AsError::BadConfig(format!(
+                    "servers: {} has more than an address and an alias",
+                    server
+                )));
             }
 
-            let mut fp_sp = first_part.rsplitn(2, ':').filter(|x| !x.is_empty());
-            let weight = {
-                let weight_str = fp_sp.next().unwrap_or("1");
-                weight_str.parse::<usize>()?
+            // the bracketed IPv6 host has colons of its own, only the ones after it separate the port and
+            // the weight
+            let port_part = match first_part.strip_prefix('[') {
+                Some(rest) => rest.split_once(']').map_or("", |(_, port)| port),
+                None => first_part,
             };
-            let addr = fp_sp.next().expect("addr never be absent").to_owned();
-            drop(fp_sp);
-            let alias = iter.next().map(|x| x.to_string());
+            let (addr, weight) = match port_part.matches(':').count() {
+                1 => (first_part.to_string(), 1),
+                2 => {
+                    let (addr, weight) = first_part.rsplit_once(':').expect("weight must exists");
+                    (addr.to_string(), Self::parse_weight(server, weight)?)
+                }
+                _ => {
+                    return Err(AsError::BadConfig(format!(
+                        "servers: {} must be host:port or host:port:weight",
+                        server
+                    )))
+                }
+            };
+
             sl.push(ServerLine {
                 addr,
                 weight,
@@ -41,24 +111,214 @@ impl ServerLine {
         Ok(sl)
     }
 
-    // split_spots splits the ServerLine into three parts: nodes, alias, weights
-    pub fn split_spots(sls: &[ServerLine]) -> (Vec<String>, Vec<String>, Vec<usize>) {
-        let mut nodes = Vec::new();
-        let mut alias = Vec::new();
-        let mut weights = Vec::new();
+    // parse_weight parses the weight of the server line which must be a positive integer
+    fn parse_weight(server: &str, weight: &str) -> Result<usize, AsError> {
+        match weight.parse::<usize>() {
+            Ok(weight) if weight > 0 => Ok(weight),
+            _ => Err(AsError::BadConfig(format!(
+                "servers: weight of {} must be a positive integer",
+                server
+            ))),
+        }
+    }
+
+    // resolve_srv replaces the srv:// server lines with a line for each target of their SRV records.
+    // Only the targets of the lowest priority are used, the others are the backups of the publisher.
+    pub fn resolve_srv<R: SrvResolver>(
+        servers: &[String],
+        resolver: &R,
+    ) -> Result<Vec<String>, AsError> {
+        let mut resolved = Vec::with_capacity(servers.len());
+        for server in servers {
+            let name = match server.strip_prefix(SRV_SCHEME) {
+                Some(name) => name,
+                None => {
+                    resolved.push(server.clone());
+                    continue;
+                }
+            };
+            if name.contains(' ') {
+                return Err(AsError::BadConfig(format!(
+                    "servers: {} can not have an alias",
+                    server
+                )));
+            }
+
+            let (name, weight) = match name.rsplit_once(':') {
+                Some((name, weight)) => (name, Some(weight.parse::<usize>()?)),
+                None => (name, None),
+            };
+
+            let targets = resolver.lookup_srv(name)?;
+            let priority = targets.iter().map(|x| x.priority).min();
+            let targets: Vec<_> = targets
+                .into_iter()
+                .filter(|x| Some(x.priority) == priority)
+                .collect();
+            if targets.is_empty() {
+                return Err(AsError::SrvLookupError(name.to_string()));
+            }
+
+            for target in targets {
+                let weight = weight.unwrap_or(usize::from(target.weight.max(1)));
+                let host = target.host.trim_end_matches('.');
+                info!("resolved SRV record {} to {}:{}", name, host, target.port);
+                resolved.push(format!("{}:{}:{}", host, target.port, weight));
+            }
+        }
+        Ok(resolved)
+    }
+
+    // split_spots splits the ServerLine into three parts: nodes, alias, weights.
+    // The weights are in the order of the nodes, and of the aliases if the servers have them, so
+    // either all the servers or none of them must have an alias.
+    pub fn split_spots(sls: &[ServerLine]) -> Result<Spots, AsError> {
+        let mut nodes = Vec::with_capacity(sls.len());
+        let mut alias = Vec::with_capacity(sls.len());
+        let mut weights = Vec::with_capacity(sls.len());
 
         for sl in sls {
-            if sl.alias.is_some() {
-                alias.push(
-                    sl.alias
-                        .as_ref()
-                        .cloned()
-                        .expect("node addr can't be empty"),
-                );
+            if let Some(name) = &sl.alias {
+                alias.push(name.clone());
             }
             nodes.push(sl.addr.clone());
             weights.push(sl.weight);
         }
-        (nodes, alias, weights)
+
+        if !alias.is_empty() && alias.len() != nodes.len() {
+            return Err(AsError::BadConfig(
+                "servers: all server must have(or not) alias together".to_string(),
+            ));
+        }
+        Ok((nodes, alias, weights))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MockResolver(HashMap<&'static str, Vec<SrvTarget>>);
+
+    impl SrvResolver for MockResolver {
+        fn lookup_srv(&self, name: &str) -> Result<Vec<SrvTarget>, AsError> {
+            self.0
+                .get(name)
+                .cloned()
+                .ok_or_else(|| AsError::SrvLookupError(name.to_string()))
+        }
+    }
+
+    fn target(host: &str, port: u16, priority: u16, weight: u16) -> SrvTarget {
+        SrvTarget {
+            host: host.to_string(),
+            port,
+            priority,
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_resolve_srv() {
+        let resolver = MockResolver(HashMap::from([(
+            "_redis._tcp.example.com",
+            vec![
+                target("redis-0.example.com.", 6379, 10, 2),
+                target("redis-1.example.com.", 6380, 10, 0),
+                target("backup.example.com.", 6379, 20, 1),
+            ],
+        )]));
+
+        let servers = vec![
+            "127.0.0.1:6378:1 local".to_string(),
+            "srv://_redis._tcp.example.com".to_string(),
+        ];
+        assert_eq!(
+            ServerLine::resolve_srv(&servers, &resolver).unwrap(),
+            vec![
+                "127.0.0.1:6378:1 local",
+                "redis-0.example.com:6379:2",
+                "redis-1.example.com:6380:1",
+            ]
+        );
+
+        // the configured weight overrides the published ones
+        let servers = vec!["srv://_redis._tcp.example.com:5".to_string()];
+        assert_eq!(
+            ServerLine::resolve_srv(&servers, &resolver).unwrap(),
+            vec!["redis-0.example.com:6379:5", "redis-1.example.com:6380:5"]
+        );
+
+        let servers = vec!["srv://_memcache._tcp.example.com".to_string()];
+        assert_eq!(
+            ServerLine::resolve_srv(&servers, &resolver).unwrap_err(),
+            AsError::SrvLookupError("_memcache._tcp.example.com".to_string())
+        );
+        let servers = vec!["srv://_redis._tcp.example.com redis".to_string()];
+        assert!(ServerLine::resolve_srv(&servers, &resolver).is_err());
+    }
+
+    fn parse(servers: &[&str]) -> Result<Spots, AsError> {
+        let servers: Vec<_> = servers.iter().map(|x| x.to_string()).collect();
+        ServerLine::split_spots(&ServerLine::parse_servers(&servers)?)
+    }
+
+    #[test]
+    fn test_parse_servers() {
+        let s = |xs: &[&str]| xs.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        // alias and weight
+        assert_eq!(
+            parse(&["127.0.0.1:6379:10 redis-1", "127.0.0.1:6380:20 redis-2"]).unwrap(),
+            (
+                s(&["127.0.0.1:6379", "127.0.0.1:6380"]),
+                s(&["redis-1", "redis-2"]),
+                vec![10, 20]
+            )
+        );
+
+        // alias only
+        assert_eq!(
+            parse(&["127.0.0.1:6379 redis-1", "127.0.0.1:6380 redis-2"]).unwrap(),
+            (
+                s(&["127.0.0.1:6379", "127.0.0.1:6380"]),
+                s(&["redis-1", "redis-2"]),
+                vec![1, 1]
+            )
+        );
+
+        // weight only
+        assert_eq!(
+            parse(&["127.0.0.1:6379:3", "127.0.0.1:6380:1"]).unwrap(),
+            (s(&["127.0.0.1:6379", "127.0.0.1:6380"]), vec![], vec![3, 1])
+        );
+
+        // neither
+        assert_eq!(
+            parse(&["127.0.0.1:6379", "127.0.0.1:6380"]).unwrap(),
+            (s(&["127.0.0.1:6379", "127.0.0.1:6380"]), vec![], vec![1, 1])
+        );
+
+        // bracketed IPv6 hosts
+        assert_eq!(
+            parse(&["[::1]:6379:2 redis-1", "[fe80::1]:6380 redis-2"]).unwrap(),
+            (
+                s(&["[::1]:6379", "[fe80::1]:6380"]),
+                s(&["redis-1", "redis-2"]),
+                vec![2, 1]
+            )
+        );
+        assert!(parse(&["[::1]"]).is_err());
+        assert!(parse(&["[::1:6379"]).is_err());
+        assert!(parse(&["[::1]:6379:1:2"]).is_err());
+
+        assert!(parse(&["127.0.0.1:6379:0"]).is_err());
+        assert!(parse(&["127.0.0.1:6379:-1"]).is_err());
+        assert!(parse(&["127.0.0.1:6379:x redis-1"]).is_err());
+        assert!(parse(&["127.0.0.1"]).is_err());
+        assert!(parse(&["127.0.0.1:6379:1:2"]).is_err());
+        assert!(parse(&["127.0.0.1:6379 redis-1 extra"]).is_err());
+        assert!(parse(&["127.0.0.1:6379 redis-1", "127.0.0.1:6380"]).is_err());
     }
 }
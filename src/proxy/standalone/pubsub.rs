@@ -0,0 +1,340 @@
+use futures::{Future, Sink, Stream};
+use log::{debug, error, info};
+use rand::rngs::StdRng;
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    com::AsError,
+    metrics::command_incr,
+    proxy::{
+        standalone::{
+            authenticate, dial,
+            transport::{BackendAddr, BackendStream},
+            StandaloneCluster,
+        },
+        Metered, Policy, Request,
+    },
+};
+
+// Outcome is how a subscribed session of a client ends
+pub(crate) enum Outcome<T> {
+    // Unsubscribed is the end of the last subscription, the client is served as usual again
+    Unsubscribed,
+
+    // Closed is the end of the client or of its backend connection, the client is closed as well
+    Closed,
+
+    // Failed returns the subscription which could not reach its backend, replied with the error
+    Failed(T),
+}
+
+enum State<T: Request> {
+    // Dialing connects to the backend the subscription is routed to, and authenticates the connection
+    Dialing {
+        cmd: T,
+        dial: Pin<Box<dyn Future<Output = io::Result<BackendStream>> + Send>>,
+    },
+
+    // Streaming relays the pub/sub commands of the client to the backend and the messages of the
+    // backend to the client
+    Streaming {
+        backend: Framed<BackendStream, Metered<T::BackCodec>>,
+
+        // queued are the commands to be sent to the backend before reading the client again
+        queued: VecDeque<T>,
+    },
+}
+
+// PubSub is a subscribed session of a client. The shared backend connections pipeline one reply per
+// command, so a subscribed client is given a dedicated connection to the backend of the channel of its
+// first subscription, and every message the backend pushes is relayed to it. The messages are published
+// to the backend of their channel, so the subscriptions to the channels of the other backends are
+// rejected like the cross slot commands of Redis Cluster. The channels of a client are to share a hash
+// tag.
+pub(crate) struct PubSub<T: Request> {
+    // node is the backend the session is streamed to
+    node: String,
+
+    state: State<T>,
+
+    // channels is the number of the channels subscribed by the session, charged to the subscriptions of
+    // the cluster until the session ends
+    channels: usize,
+    subscriptions: Arc<AtomicUsize>,
+}
+
+impl<T> PubSub<T>
+where
+    T: Request + Send + Sync + 'static,
+{
+    // new starts dialing the backend the channel of the subscription is routed to
+    pub(crate) fn new(
+        cmd: T,
+        cluster: &StandaloneCluster<T>,
+        rng: &mut StdRng,
+    ) -> Result<PubSub<T>, T> {
+        let node = match cluster.ring.get_addr(cluster.cmd_hash(&cmd), rng) {
+            Some(node) => node,
+            None => {
+                cmd.set_error(&AsError::ClusterFailDispatch);
+                return Err(cmd);
+            }
+        };
+        let addr = match BackendAddr::resolve(&node) {
+            Ok(addr) => addr,
+            Err(err) => {
+                cmd.set_error(&err);
+                return Err(cmd);
+            }
+        };
+        let mut channels = 0;
+        if !same_node(&cmd, &node, cluster, rng)
+            || !admit(&cmd, &mut channels, &cluster.subscriptions, &cluster.policy)
+        {
+            return Err(cmd);
+        }
+
+        // the dedicated connection is authenticated on its own, failing the session if rejected
+        let source = cluster.backend_source;
+        let tls = cluster.backend_tls.clone();
+        let dial_timeout = cluster.policy.dial_timeout;
+        let auth =
+            (!cluster.auth.is_empty()).then(|| (cluster.auth.clone(), cluster.policy.clone()));
+        let target = node.clone();
+        let dial = Box::pin(async move {
+            let socket = dial(&target, &addr, source, tls.as_ref(), dial_timeout).await?;
+            match auth {
+                Some((auth, policy)) => {
+                    authenticate::<T>(socket, &target, &auth, &policy, dial_timeout).await
+                }
+                None => Ok(socket),
+            }
+        });
+        Ok(PubSub {
+            node,
+            state: State::Dialing { cmd, dial },
+            channels,
+            subscriptions: cluster.subscriptions.clone(),
+        })
+    }
+
+    // poll drives the session until it ends. The client is only read while both the backend and the
+    // client can be written, so neither side is buffered without bound.
+    pub(crate) fn poll<I, O>(
+        &mut self,
+        cx: &mut Context,
+        cluster: &StandaloneCluster<T>,
+        rng: &mut StdRng,
+        mut downstream: Pin<&mut I>,
+        mut upstream: Pin<&mut O>,
+    ) -> Poll<Outcome<T>>
+    where
+        I: Stream<Item = Result<T, AsError>>,
+        O: Sink<T, Error = AsError>,
+    {
+        if let State::Dialing { cmd, dial } = &mut self.state {
+            let socket = match dial.as_mut().poll(cx) {
+                Poll::Ready(Ok(socket)) => socket,
+                Poll::Ready(Err(err)) => {
+                    error!("fail to subscribe to backend {} due to {}", self.node, err);
+                    cmd.set_error(&AsError::BackendClosedError(self.node.clone()));
+                    return Poll::Ready(Outcome::Failed(cmd.clone()));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            info!("subscribed to backend {}", self.node);
+
+            let codec = Metered::new(T::back_codec(&cluster.policy), &cluster.policy.cluster);
+            self.state = State::Streaming {
+                backend: codec.framed(socket),
+                queued: VecDeque::from([cmd.clone()]),
+            };
+        }
+        let State::Streaming { backend, queued } = &mut self.state else {
+            unreachable!("subscribed session must be streaming once dialed");
+        };
+        let mut backend = Pin::new(backend);
+
+        // relay the commands of the client to the backend
+        loop {
+            match backend.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => {
+                    error!("subscribed backend {} is closed due to {}", self.node, err);
+                    return Poll::Ready(Outcome::Closed);
+                }
+                Poll::Pending => break,
+            }
+            if let Some(cmd) = queued.pop_front() {
+                let cmd_type = cmd.cmd_type();
+                if backend.as_mut().start_send(cmd).is_err() {
+                    return Poll::Ready(Outcome::Closed);
+                }
+                command_incr(&cluster.policy.cluster, cmd_type);
+                continue;
+            }
+
+            match upstream.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(_)) => return Poll::Ready(Outcome::Closed),
+                Poll::Pending => break,
+            }
+            match downstream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(cmd))) => {
+                    cmd.set_cluster(&cluster.policy.cluster);
+
+                    // the invalid and the done commands are replied at once, e.g. PING. The commands
+                    // besides the pub/sub ones are not served on a subscribed connection.
+                    if cmd.valid() && !cmd.is_done() {
+                        if !cmd.cmd_type().is_pub_sub() {
+                            cmd.set_error(&AsError::PubSubOnly);
+                        } else if same_node(&cmd, &self.node, cluster, rng)
+                            && admit(
+                                &cmd,
+                                &mut self.channels,
+                                &self.subscriptions,
+                                &cluster.policy,
+                            )
+                        {
+                            queued.push_back(cmd);
+                            continue;
+                        }
+                    }
+                    if upstream.as_mut().start_send(cmd).is_err() {
+                        return Poll::Ready(Outcome::Closed);
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    error!("subscribed client failed to send command due to: {}", err);
+                }
+                Poll::Ready(None) => return Poll::Ready(Outcome::Closed),
+                Poll::Pending => break,
+            }
+        }
+        if let Poll::Ready(Err(err)) = backend.as_mut().poll_flush(cx) {
+            error!("subscribed backend {} is closed due to {}", self.node, err);
+            return Poll::Ready(Outcome::Closed);
+        }
+
+        // relay the messages of the backend to the client, until no subscription is left
+        let mut outcome = Poll::Pending;
+        loop {
+            match upstream.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(_)) => return Poll::Ready(Outcome::Closed),
+                Poll::Pending => break,
+            }
+            match backend.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(reply))) => {
+                    let left = T::reply_subscriptions(&reply);
+                    if let Some(left) = left {
+                        recount(&mut self.channels, &self.subscriptions, left);
+                    }
+                    if upstream
+                        .as_mut()
+                        .start_send(T::push_message(reply))
+                        .is_err()
+                    {
+                        return Poll::Ready(Outcome::Closed);
+                    }
+                    if left == Some(0) {
+                        debug!("unsubscribed from backend {}", self.node);
+                        outcome = Poll::Ready(Outcome::Unsubscribed);
+                        break;
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    error!("subscribed backend {} is closed due to {}", self.node, err);
+                    return Poll::Ready(Outcome::Closed);
+                }
+                Poll::Ready(None) => {
+                    info!("subscribed backend {} is closed", self.node);
+                    return Poll::Ready(Outcome::Closed);
+                }
+                Poll::Pending => break,
+            }
+        }
+        let _ = upstream.as_mut().poll_flush(cx);
+        outcome
+    }
+}
+
+impl<T: Request> Drop for PubSub<T> {
+    fn drop(&mut self) {
+        self.subscriptions
+            .fetch_sub(self.channels, Ordering::Relaxed);
+    }
+}
+
+// same_node checks the channels of a (P)SUBSCRIBE are all served by the backend of the session, or replies
+// it with the cross slot error as the messages published to the others would never reach the session
+fn same_node<T>(cmd: &T, node: &str, cluster: &StandaloneCluster<T>, rng: &mut StdRng) -> bool
+where
+    T: Request + Send + Sync + 'static,
+{
+    let crossed = cmd.subscribe_channels().iter().any(|channel| {
+        cluster
+            .ring
+            .get_addr(cluster.key_hash(channel), rng)
+            .as_deref()
+            != Some(node)
+    });
+    if crossed {
+        cmd.set_error(&AsError::CrossSlotChannels);
+    }
+    !crossed
+}
+
+// admit charges the channels of a (P)SUBSCRIBE to the connection and to the cluster, or replies it with
+// the error of the limit it would exceed
+fn admit<T: Request>(
+    cmd: &T,
+    channels: &mut usize,
+    subscriptions: &AtomicUsize,
+    policy: &Policy,
+) -> bool {
+    let added = cmd.subscribe_channels().len();
+    if added == 0 {
+        return true;
+    }
+    if let Some(max) = policy.max_channels_per_conn {
+        if *channels + added > max {
+            cmd.set_error(&AsError::MaxConnChannels(max));
+            return false;
+        }
+    }
+    let charged = subscriptions.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+        match policy.max_subscriptions {
+            Some(max) if count + added > max => None,
+            _ => Some(count + added),
+        }
+    });
+    if charged.is_err() {
+        let max = policy.max_subscriptions.unwrap_or_default();
+        cmd.set_error(&AsError::MaxSubscriptions(max));
+        return false;
+    }
+    *channels += added;
+    true
+}
+
+// recount sets the channels of a session to the number its backend replied, which is only known once the
+// subscriptions are replied, e.g. without the channels subscribed twice
+fn recount(channels: &mut usize, subscriptions: &AtomicUsize, replied: usize) {
+    if replied > *channels {
+        subscriptions.fetch_add(replied - *channels, Ordering::Relaxed);
+    } else {
+        subscriptions.fetch_sub(*channels - replied, Ordering::Relaxed);
+    }
+    *channels = replied;
+}
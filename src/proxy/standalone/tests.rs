@@ -0,0 +1,3851 @@
+use super::back::BACKEND_MAX_PIPELINE;
+use super::*;
+use crate::com::config::WarmingReply;
+use crate::metrics::{init_test_instruments, test_histogram_value, test_metric_value};
+use crate::protocol::mc::{init_memcached_text_finder, msg::BinHeader};
+use crate::protocol::redis::{init_redis_supported_cmds, RedisHandleCodec};
+use crate::proxy::test_support::{self, serve_fake_backend, Client, TEST_REPLY_TIMEOUT};
+use rand::Rng;
+use rand::SeedableRng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Encoder;
+use tower::ServiceExt;
+
+// spawn_backend starts a fake redis backend which replies to each request with the handler result.
+// the request is never replied if the handler returns None.
+async fn spawn_backend<F>(handler: F) -> String
+where
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    spawn_counted_backend(handler).await.0
+}
+
+// spawn_counted_backend is like spawn_backend but also returns the number of accepted connections
+async fn spawn_counted_backend<F>(handler: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    spawn_delayed_backend(Duration::ZERO, handler).await
+}
+
+// spawn_delayed_backend is like spawn_counted_backend but each reply is written the given delay after
+// its request is received, simulating the round trip of a remote backend. The pipelined requests are
+// delayed concurrently and still replied in order.
+async fn spawn_delayed_backend<F>(delay: Duration, handler: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handler = Arc::new(handler);
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(serve_fake_backend(socket, delay, handler.clone()));
+        }
+    });
+
+    (addr, accepted)
+}
+
+// spawn_unix_backend is like spawn_backend but listens on a unix domain socket and returns its path
+async fn spawn_unix_backend<F>(handler: F) -> String
+where
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    let path = std::env::temp_dir().join(format!(
+        "repust-backend-{}-{}.sock",
+        std::process::id(),
+        rand::thread_rng().gen::<u32>()
+    ));
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve_fake_backend(socket, Duration::ZERO, handler.clone()));
+        }
+    });
+
+    path.to_string_lossy().to_string()
+}
+
+// spawn_tls_backend is like spawn_backend but serves over TLS with a certificate self-signed for
+// 127.0.0.1, returning the path of the certificate along with the address
+async fn spawn_tls_backend<F>(handler: F) -> (String, String, Arc<AtomicUsize>)
+where
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    use tokio_rustls::rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+
+    // ResumedSessions counts the handshakes resuming a session stored by the backend
+    struct ResumedSessions {
+        sessions: Arc<ServerSessionMemoryCache>,
+        resumed: Arc<AtomicUsize>,
+    }
+
+    impl ResumedSessions {
+        fn found(&self, session: Option<Vec<u8>>) -> Option<Vec<u8>> {
+            if session.is_some() {
+                self.resumed.fetch_add(1, Ordering::SeqCst);
+            }
+            session
+        }
+    }
+
+    impl StoresServerSessions for ResumedSessions {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.sessions.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.found(self.sessions.get(key))
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.found(self.sessions.take(key))
+        }
+
+        fn can_cache(&self) -> bool {
+            true
+        }
+    }
+
+    let (mut config, cert_path) = test_support::tls_server_config();
+    let resumed = Arc::new(AtomicUsize::new(0));
+    config.session_storage = Arc::new(ResumedSessions {
+        sessions: ServerSessionMemoryCache::new(16),
+        resumed: resumed.clone(),
+    });
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let handler = handler.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(socket) = acceptor.accept(socket).await {
+                    serve_fake_backend(socket, Duration::ZERO, handler).await;
+                }
+            });
+        }
+    });
+
+    (addr, cert_path, resumed)
+}
+
+// spawn_proxy runs a redis standalone cluster in front of the given servers and returns its address
+fn spawn_proxy<F>(servers: Vec<String>, configure: F) -> String
+where
+    F: FnOnce(&mut ClusterConfig),
+{
+    let cc = ClusterConfig {
+        name: "test".to_string(),
+        servers,
+        ..Default::default()
+    };
+    test_support::spawn_proxy(cc, configure, |cc| {
+        StandaloneCluster::<redis::Cmd>::new(cc)
+            .unwrap()
+            .run()
+            .unwrap();
+    })
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_timeout_overrides_cluster_timeout() {
+    let backend = spawn_backend(|args| match args[1].as_slice() {
+        b"slow" => None,
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.timeout = Some(10_000);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nfast\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+
+    assert_eq!(
+        client
+            .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$5\r\n20000\r\n")
+            .await,
+        b"-proxy timeout must not exceed 10000 ms\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\ntimeout\r\n$3\r\n100\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+
+    let start = Instant::now();
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nslow\r\n").await,
+        b"-command timeout\r\n"
+    );
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_late_reply_not_paired_with_fresh_command() {
+    // each key is replied with itself, so a reply paired with the wrong command is noticed
+    let (backend, _) = spawn_delayed_backend(Duration::from_millis(200), |args| {
+        Some(
+            format!(
+                "${}\r\n{}\r\n",
+                args[1].len(),
+                String::from_utf8_lossy(&args[1])
+            )
+            .into_bytes(),
+        )
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.timeout = Some(5_000);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$2\r\n50\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nslow\r\n").await,
+        b"-command timeout\r\n"
+    );
+
+    // the reply of the timed out command arrives while the fresh one is inflight
+    assert_eq!(
+        client
+            .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$1\r\n0\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$5\r\nfresh\r\n").await,
+        b"$5\r\nfresh\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nnext\r\n").await,
+        b"$4\r\nnext\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_connection_metrics_on_close() {
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.timeout = Some(100);
+    });
+    let (closed, commands) = test_histogram_value("repust_connection_commands", &[]);
+    let (_, lifetime) = test_histogram_value("repust_connection_duration_seconds", &[]);
+
+    let mut client = Client::connect(&proxy).await;
+    for _ in 0..3 {
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+            b"$1\r\nv\r\n"
+        );
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(client);
+
+    // the other tests may close their connections meanwhile, so only the lower bounds are checked
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while test_histogram_value("repust_connection_commands", &[]).0 == closed
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let (closed_after, commands_after) = test_histogram_value("repust_connection_commands", &[]);
+    assert!(closed_after > closed);
+    assert!(commands_after >= commands + 3.0);
+    assert!(test_histogram_value("repust_connection_duration_seconds", &[]).1 >= lifetime + 0.05);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_remote_timer_node_label() {
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    for _ in 0..3 {
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+            b"$1\r\nv\r\n"
+        );
+    }
+
+    // the backend of each test has its own port, so the node label only counts this one
+    let (count, _) = test_histogram_value("repust_remote_timer", &[("node", &backend)]);
+    assert_eq!(count, 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_source_addr() {
+    // the whole 127.0.0.0/8 block is routed to the loopback interface on linux
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.backend_source_addr = Some("127.0.0.2".to_string());
+    });
+
+    let (_, peer) = tokio::time::timeout(TEST_REPLY_TIMEOUT, listener.accept())
+        .await
+        .expect("proxy must connect to the backend")
+        .unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_loading_reply() {
+    let loaded = Arc::new(AtomicBool::new(false));
+    let backend_loaded = loaded.clone();
+    let backend = spawn_backend(move |args| match args[1].as_slice() {
+        b"always" => Some(b"-LOADING Redis is loading the dataset in memory\r\n".to_vec()),
+        _ if !backend_loaded.swap(true, Ordering::SeqCst) => {
+            Some(b"-LOADING Redis is loading the dataset in memory\r\n".to_vec())
+        }
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+
+    // the first LOADING reply is retried transparently
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    assert!(loaded.load(Ordering::SeqCst));
+
+    // once the retries are exhausted LOADING reaches the client as is
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$6\r\nalways\r\n").await,
+        b"-LOADING Redis is loading the dataset in memory\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_retry_budget_exhausted() {
+    let loading = b"-LOADING Redis is loading the dataset in memory\r\n";
+    let requests = Arc::new(AtomicUsize::new(0));
+    let backend_requests = requests.clone();
+    let (backend, _) = spawn_counted_backend(move |_| {
+        backend_requests.fetch_add(1, Ordering::SeqCst);
+        Some(loading.to_vec())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.retry_budget_per_sec = Some(1);
+    });
+    let retries = |name: &str| test_metric_value(name, &[("backend", backend.as_str())]);
+
+    let mut client = Client::connect(&proxy).await;
+
+    // the first command spends the whole budget on its retry
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n").await,
+        loading
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // the next one fails fast without being resent
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n").await,
+        loading
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert_eq!(retries("repust_retries_total"), 1.0);
+    assert_eq!(retries("repust_retries_denied_total"), 1.0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_slo_shedding() {
+    let overloaded = b"-ERR overloaded, try again\r\n";
+    let (backend, _) =
+        spawn_delayed_backend(Duration::from_millis(20), |_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "test-slo-shedding".to_string();
+        cc.slo_p99_ms = Some(5);
+        cc.max_shed_fraction = Some(0.5);
+    });
+
+    // each batch is pipelined, so all of its commands take the round trip of the backend
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    let shed_of = |replies: Vec<Vec<u8>>| replies.iter().filter(|x| x == &overloaded).count();
+
+    // the commands are shed once the p99 is sampled above the SLO
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while shed_of(client.pipeline(get, 50).await) == 0 {
+        assert!(Instant::now() < deadline, "no command is shed");
+    }
+
+    // but never more than the max fraction of them
+    let mut shed = 0;
+    for _ in 0..20 {
+        shed += shed_of(client.pipeline(get, 50).await);
+    }
+    assert!(shed > 0 && shed < 650, "shed {} of 1000", shed);
+
+    // the connection setup commands are never shed
+    let ping = b"*1\r\n$4\r\nPING\r\n";
+    assert_eq!(shed_of(client.pipeline(ping, 50).await), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_busy_reply() {
+    let busy = b"-BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.\r\n";
+    let backend = spawn_backend(move |args| match args[1].as_slice() {
+        b"busy" => Some(busy.to_vec()),
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+    let busy_total = || {
+        test_metric_value(
+            "repust_backend_busy_total",
+            &[("backend", backend.as_str())],
+        )
+    };
+
+    let mut client = Client::connect(&proxy).await;
+    let before = busy_total();
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nbusy\r\n").await,
+        busy
+    );
+    assert_eq!(busy_total(), before + 1.0);
+
+    // the backend connection survives the BUSY reply
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_proxy_where_matches_routing() {
+    let mut backends = Vec::new();
+    for name in ["a", "b", "c"] {
+        let reply = format!("$1\r\n{}\r\n", name).into_bytes();
+        let addr = spawn_backend(move |_| Some(reply.clone())).await;
+        backends.push((name, addr));
+    }
+    let servers = backends.iter().map(|(_, x)| format!("{}:1", x)).collect();
+    let proxy = spawn_proxy(servers, |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    for key in ["k1", "k2", "k3", "user:1000", "session"] {
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+        let reply = client.request(get.as_bytes()).await;
+        let (_, routed) = backends
+            .iter()
+            .find(|(name, _)| reply == format!("$1\r\n{}\r\n", name).as_bytes())
+            .expect("reply must come from a backend");
+
+        let expected = format!("{} hash={}", routed, fnv::fnv1a64(key.as_bytes()));
+        let proxy_where = format!(
+            "*3\r\n$5\r\nPROXY\r\n$5\r\nWHERE\r\n${}\r\n{}\r\n",
+            key.len(),
+            key
+        );
+        assert_eq!(
+            client.request(proxy_where.as_bytes()).await,
+            format!("${}\r\n{}\r\n", expected.len(), expected).as_bytes()
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_hash_tag_colocates_keys() {
+    let mut backends = Vec::new();
+    for name in ["a", "b", "c"] {
+        let reply = format!("$1\r\n{}\r\n", name).into_bytes();
+        backends.push(spawn_backend(move |_| Some(reply.clone())).await);
+    }
+    let servers = backends.iter().map(|x| format!("{}:1", x)).collect();
+    let proxy = spawn_proxy(servers, |cc| cc.hash_tag = Some("[]".to_string()));
+
+    // the keys sharing a tag are hashed by it and served by the same backend. The tags differ in their
+    // first bytes, which the fnv hash spreads over the ring, so the distinct tags are spread over the
+    // backends.
+    let mut client = Client::connect(&proxy).await;
+    let mut spread = HashSet::new();
+    for tag in (0..20).map(|x| format!("{}-session", x)) {
+        let mut replies = HashSet::new();
+        for field in ["name", "age"] {
+            let key = format!("user:[{}]:{}", tag, field);
+            let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+            replies.insert(client.request(get.as_bytes()).await);
+
+            let proxy_where = format!(
+                "*3\r\n$5\r\nPROXY\r\n$5\r\nWHERE\r\n${}\r\n{}\r\n",
+                key.len(),
+                key
+            );
+            let reply = client.request(proxy_where.as_bytes()).await;
+            let hash = format!("hash={}\r\n", fnv::fnv1a64(tag.as_bytes()));
+            assert!(reply.ends_with(hash.as_bytes()));
+        }
+        assert_eq!(replies.len(), 1);
+        spread.extend(replies);
+    }
+    assert!(spread.len() > 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_crc16_hash_method() {
+    let mut backends = Vec::new();
+    for name in ["a", "b", "c"] {
+        let reply = format!("$1\r\n{}\r\n", name).into_bytes();
+        backends.push(spawn_backend(move |_| Some(reply.clone())).await);
+    }
+    let servers = backends.iter().map(|x| format!("{}:1", x)).collect();
+    let proxy = spawn_proxy(servers, |cc| {
+        cc.hash_method = Some(HashMethod::Crc16);
+        cc.hash_tag = Some("{}".to_string());
+    });
+
+    // the keys of the same hash tag are hashed alike and served by the same backend
+    let mut client = Client::connect(&proxy).await;
+    let mut replies = HashSet::new();
+    for i in 0..20 {
+        let key = format!("{{user}}:{}", i);
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+        replies.insert(client.request(get.as_bytes()).await);
+
+        let proxy_where = format!(
+            "*3\r\n$5\r\nPROXY\r\n$5\r\nWHERE\r\n${}\r\n{}\r\n",
+            key.len(),
+            key
+        );
+        let reply = client.request(proxy_where.as_bytes()).await;
+        let hash = format!("hash={}\r\n", crc16_ring(b"user"));
+        assert!(reply.ends_with(hash.as_bytes()));
+    }
+    assert_eq!(replies.len(), 1);
+
+    // while the keys of distinct tags are spread over the ring
+    let mut spread = HashSet::new();
+    for tag in 0..20 {
+        let key = format!("{{user{}}}:name", tag);
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+        spread.insert(client.request(get.as_bytes()).await);
+    }
+    assert!(spread.len() > 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_proxy_explain_matches_routing() {
+    let mut backends = Vec::new();
+    for name in ["a", "b", "c"] {
+        let reply = format!("$1\r\n{}\r\n", name).into_bytes();
+        let addr = spawn_backend(move |_| Some(reply.clone())).await;
+        backends.push((name, addr));
+    }
+    let servers = backends.iter().map(|(_, x)| format!("{}:1", x)).collect();
+    let proxy = spawn_proxy(servers, |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    let mut expected = vec!["command=MGET kind=read".to_string()];
+    for key in ["k1", "k2", "user:1000"] {
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+        let reply = client.request(get.as_bytes()).await;
+        let (_, routed) = backends
+            .iter()
+            .find(|(name, _)| reply == format!("$1\r\n{}\r\n", name).as_bytes())
+            .expect("reply must come from a backend");
+        expected.push(format!(
+            "key={} hash={} node={}",
+            key,
+            fnv::fnv1a64(key.as_bytes()),
+            routed
+        ));
+    }
+
+    let explain = b"*6\r\n$5\r\nPROXY\r\n$7\r\nEXPLAIN\r\n$4\r\nMGET\r\n$2\r\nk1\r\n$2\r\nk2\r\n$9\r\nuser:1000\r\n";
+    let expected = expected.join("\n");
+    assert_eq!(
+        client.request(explain).await,
+        format!("${}\r\n{}\r\n", expected.len(), expected).as_bytes()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_proxy_where_follows_pools_and_readers() {
+    let servers = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let reader = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let primaries = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", servers)], |cc| {
+        cc.read_servers = vec![format!("{}:1", reader)];
+        cc.pools = BTreeMap::from([("primaries".to_string(), vec![format!("{}:1", primaries)])]);
+        cc.pool_routes = BTreeMap::from([("write".to_string(), "primaries".to_string())]);
+    });
+    let hash = fnv::fnv1a64(b"k");
+    let bulk = |reply: String| format!("${}\r\n{}\r\n", reply.len(), reply).into_bytes();
+
+    // the key is looked up as a read, served by the read servers once the connection is READONLY
+    let mut client = Client::connect(&proxy).await;
+    let proxy_where = b"*3\r\n$5\r\nPROXY\r\n$5\r\nWHERE\r\n$1\r\nk\r\n";
+    assert_eq!(
+        client.request(proxy_where).await,
+        bulk(format!("{} hash={}", servers, hash))
+    );
+    assert_eq!(
+        client.request(b"*1\r\n$8\r\nREADONLY\r\n").await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client.request(proxy_where).await,
+        bulk(format!("{} hash={}", reader, hash))
+    );
+
+    // the writes are explained on the pool their type is routed to
+    let explain = b"*5\r\n$5\r\nPROXY\r\n$7\r\nEXPLAIN\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    assert_eq!(
+        client.request(explain).await,
+        bulk(format!(
+            "command=SET kind=write\nkey=k hash={} node={}",
+            hash, primaries
+        ))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_accept_loop_alive() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+    let backend = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let cc = ClusterConfig {
+        name: "test-accept-loop-alive".to_string(),
+        listen_addr: listen_addr.clone(),
+        servers: vec![format!("{}:1", backend)],
+        ..Default::default()
+    };
+    let accept_loop = StandaloneCluster::<redis::Cmd>::new(cc)
+        .unwrap()
+        .run()
+        .unwrap();
+    let alive = || {
+        test_metric_value(
+            "repust_accept_loop_alive",
+            &[("cluster", "test-accept-loop-alive")],
+        )
+    };
+
+    // the loop is marked alive once serving and on each accepted client
+    let mut client = Client::connect(&listen_addr).await;
+    assert_eq!(client.request(b"*1\r\n$4\r\nPING\r\n").await, b"+PONG\r\n");
+    let started = alive();
+    assert!(started > 0.0);
+    time::sleep(Duration::from_millis(10)).await;
+    let _second = Client::connect(&listen_addr).await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while alive() == started && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    let accepted = alive();
+    assert!(accepted > started);
+
+    // the gauge is left behind once the loop ends
+    accept_loop.abort();
+    let _ = accept_loop.await;
+    assert!(TcpStream::connect(&listen_addr).await.is_err());
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(alive(), accepted);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_read_only() {
+    init_test_instruments();
+    init_memcached_text_finder();
+
+    let backend = spawn_memcached().await;
+    mc_transcript(&backend, &["set a 0 0 1\r\nx\r\n"]).await;
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    spawn(
+        ClusterConfig {
+            name: "memcache-read-only".to_string(),
+            listen_addr: listen_addr.clone(),
+            cache_type: CacheType::Memcache,
+            servers: vec![format!("{}:1", backend)],
+            read_only: Some(true),
+            ..Default::default()
+        },
+        Shutdown::default(),
+    )
+    .unwrap();
+
+    // the touching reads modify the expiry of the keys, so they are rejected alike the writes
+    let transcript = mc_transcript(
+        &listen_addr,
+        &[
+            "set a 0 0 1\r\ny\r\n",
+            "touch a 10\r\n",
+            "gat 10 a\r\n",
+            "gats 10 a b\r\n",
+            "get a\r\n",
+        ],
+    )
+    .await;
+    let read_only = "ERROR READONLY You can't write against a read only proxy.\r\n";
+    assert_eq!(transcript[..4], [read_only; 4]);
+    assert_eq!(transcript[4], "VALUE a 0 1\r\nx\r\nEND\r\n");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_pause_accept_on_overload() {
+    // the backend replies slowly, so a deep pipeline fills its small queue and keeps it full
+    let (backend, _) =
+        spawn_delayed_backend(Duration::from_secs(2), |_| Some(b"+OK\r\n".to_vec())).await;
+    let listen_addr = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "test-overload".to_string();
+        cc.backend_queue_size = Some(8);
+        cc.timeout = Some(10_000);
+        cc.overload_queue_fill = Some(0.5);
+    });
+    let overloaded =
+        || test_metric_value("repust_cluster_overloaded", &[("cluster", "test-overload")]);
+
+    let mut busy = Client::connect(&listen_addr).await;
+    assert_eq!(busy.request(b"*1\r\n$4\r\nPING\r\n").await, b"+PONG\r\n");
+    assert_eq!(overloaded(), 0.0);
+    let count = 1024 + 8;
+    busy.requests
+        .write_all(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n".repeat(count))
+        .await
+        .unwrap();
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while overloaded() == 0.0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(overloaded(), 1.0);
+
+    // the new client waits in the listen backlog while the backend is saturated
+    let mut waiting = Client::connect(&listen_addr).await;
+    waiting
+        .requests
+        .write_all(b"*1\r\n$4\r\nPING\r\n")
+        .await
+        .unwrap();
+    assert!(
+        time::timeout(Duration::from_millis(200), waiting.replies.next())
+            .await
+            .is_err()
+    );
+
+    // and is served once the backend drains its queue
+    for _ in 0..count {
+        busy.receive().await;
+    }
+    assert_eq!(waiting.receive().await, b"+PONG\r\n");
+    assert_eq!(overloaded(), 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_commands_by_type() {
+    let backend = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+    let commands =
+        |cmd_type: &str| test_metric_value("repust_commands_total", &[("type", cmd_type)]);
+
+    let mut client = Client::connect(&proxy).await;
+    let (read, write, mget) = (commands("read"), commands("write"), commands("mget"));
+    client.request(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n").await;
+    client
+        .request(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+        .await;
+    client
+        .request(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n")
+        .await;
+
+    // the sub commands of MGET are counted each
+    assert!(commands("read") >= read + 1.0);
+    assert!(commands("write") >= write + 1.0);
+    assert!(commands("mget") >= mget + 2.0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_metrics_cluster_label() {
+    let backend = spawn_backend(|args| match args[1].as_slice() {
+        b"slow" => None,
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "labelled-redis".to_string();
+    });
+    let label = [("cluster", "labelled-redis")];
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    client
+        .request(b"*3\r\n$5\r\nPROXY\r\n$7\r\nTIMEOUT\r\n$3\r\n100\r\n")
+        .await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$4\r\nslow\r\n").await,
+        b"-command timeout\r\n"
+    );
+    assert!(client
+        .request(b"*1\r\n$8\r\nSHUTDOWN\r\n")
+        .await
+        .starts_with(b"-"));
+
+    assert_eq!(test_metric_value("repust_connection", &label), 1.0);
+    assert_eq!(
+        test_metric_value(
+            "repust_error_total",
+            &[("cluster", "labelled-redis"), ("kind", "CmdTimeout")]
+        ),
+        1.0
+    );
+    assert_eq!(
+        test_metric_value(
+            "repust_commands_rejected_total",
+            &[("cluster", "labelled-redis"), ("reason", "unsupported")]
+        ),
+        1.0
+    );
+    assert_eq!(test_metric_value("repust_pool_replies_total", &label), 1.0);
+    assert_eq!(test_metric_value("repust_backend_up", &label), 1.0);
+    // the timed out command is timed until it is dropped
+    assert_eq!(test_histogram_value("repust_remote_timer", &label).0, 2);
+    assert!(test_histogram_value("repust_total_timer", &label).0 >= 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_metrics_cluster_label() {
+    init_test_instruments();
+    init_memcached_text_finder();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (read, mut write) = socket.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply: &[u8] = match line.starts_with("get ") {
+                        true => b"VALUE k 0 1\r\nv\r\nEND\r\n",
+                        false => b"VERSION 1.6.0\r\n",
+                    };
+                    if write.write_all(reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let cc = ClusterConfig {
+        name: "labelled-memcache".to_string(),
+        listen_addr: listen_addr.clone(),
+        cache_type: CacheType::Memcache,
+        servers: vec![format!("{}:1", backend)],
+        ..Default::default()
+    };
+    StandaloneCluster::<mc::Cmd>::new(cc)
+        .unwrap()
+        .run()
+        .unwrap();
+    let label = [("cluster", "labelled-memcache")];
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    let socket = loop {
+        match TcpStream::connect(&listen_addr).await {
+            Ok(socket) => break socket,
+            Err(err) if Instant::now() > deadline => panic!("proxy is not up: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let (read, mut write) = socket.into_split();
+    let mut replies = BufReader::new(read);
+    for _ in 0..2 {
+        write.write_all(b"get k\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(b"END\r\n") {
+            tokio::time::timeout(TEST_REPLY_TIMEOUT, replies.read_until(b'\n', &mut reply))
+                .await
+                .expect("reply must be received in time")
+                .unwrap();
+        }
+        assert_eq!(reply, b"VALUE k 0 1\r\nv\r\nEND\r\n");
+    }
+
+    assert_eq!(test_metric_value("repust_connection", &label), 1.0);
+    assert_eq!(
+        test_metric_value(
+            "repust_commands_total",
+            &[("cluster", "labelled-memcache"), ("type", "read")]
+        ),
+        2.0
+    );
+    assert_eq!(test_metric_value("repust_pool_replies_total", &label), 2.0);
+    assert_eq!(test_metric_value("repust_backend_up", &label), 1.0);
+    assert_eq!(test_histogram_value("repust_remote_timer", &label).0, 2);
+    assert!(test_histogram_value("repust_total_timer", &label).0 >= 1);
+}
+
+// spawn_memcached starts a fake memcached backend serving the storage, the retrieval and the cas
+// commands of the text protocol. Every stored value is given a new cas unique.
+async fn spawn_memcached() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let store = Arc::new(std::sync::Mutex::new(HashMap::<
+        String,
+        (String, Vec<u8>, u64),
+    >::new()));
+    let uniques = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let (store, uniques) = (store.clone(), uniques.clone());
+            tokio::spawn(async move {
+                let (read, mut write) = socket.into_split();
+                let mut lines = BufReader::new(read);
+                let mut line = String::new();
+                while lines.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+                    line.clear();
+                    let reply = match args[0].as_str() {
+                        "get" | "gets" => {
+                            let store = store.lock().unwrap();
+                            let mut reply = String::new();
+                            for key in &args[1..] {
+                                if let Some((flags, data, unique)) = store.get(key) {
+                                    reply += &format!("VALUE {} {} {}", key, flags, data.len());
+                                    if args[0] == "gets" {
+                                        reply += &format!(" {}", unique);
+                                    }
+                                    reply += &format!("\r\n{}\r\n", String::from_utf8_lossy(data));
+                                }
+                            }
+                            reply + "END\r\n"
+                        }
+                        "set" | "cas" => {
+                            let mut data = vec![0u8; args[4].parse::<usize>().unwrap() + 2];
+                            if tokio::io::AsyncReadExt::read_exact(&mut lines, &mut data)
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            data.truncate(data.len() - 2);
+                            let mut store = store.lock().unwrap();
+                            let current = store.get(&args[1]).map(|x| x.2);
+                            let stored = match (args[0].as_str(), current) {
+                                ("set", _) => "STORED",
+                                (_, None) => "NOT_FOUND",
+                                (_, Some(unique)) if unique.to_string() != args[5] => "EXISTS",
+                                _ => "STORED",
+                            };
+                            if stored == "STORED" {
+                                let unique = uniques.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+                                store.insert(args[1].clone(), (args[2].clone(), data, unique));
+                            }
+                            format!("{}\r\n", stored)
+                        }
+                        _ => "VERSION 1.6.0\r\n".to_string(),
+                    };
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+// mc_transcript sends the text commands one after the other to the given memcached and returns their
+// replies
+async fn mc_transcript(addr: &str, commands: &[&str]) -> Vec<String> {
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    let socket = loop {
+        match TcpStream::connect(addr).await {
+            Ok(socket) => break socket,
+            Err(err) if Instant::now() > deadline => panic!("memcached is not up: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let (read, mut write) = socket.into_split();
+    let mut replies = BufReader::new(read);
+    let mut transcript = Vec::new();
+    for command in commands {
+        write.write_all(command.as_bytes()).await.unwrap();
+        // a retrieval is replied until its END, or with a single error line if it fails
+        let retrieval = command.starts_with("get");
+        let mut reply = Vec::new();
+        while reply.is_empty()
+            || (retrieval && !reply.ends_with(b"END\r\n") && !reply.starts_with(b"ERROR "))
+        {
+            tokio::time::timeout(TEST_REPLY_TIMEOUT, replies.read_until(b'\n', &mut reply))
+                .await
+                .expect("reply must be received in time")
+                .unwrap();
+        }
+        transcript.push(String::from_utf8(reply).unwrap());
+    }
+    transcript
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_gets_and_cas() {
+    init_test_instruments();
+    init_memcached_text_finder();
+
+    let commands = [
+        "set a 5 0 1\r\nx\r\n",
+        "gets a\r\n",
+        "cas a 5 0 1 1\r\ny\r\n",
+        "cas a 5 0 1 1\r\nz\r\n",
+        "cas missing 0 0 1 1\r\nz\r\n",
+        "set b 0 0 2\r\nbb\r\n",
+        "gets a missing b\r\n",
+        "get a\r\n",
+    ];
+    let direct = mc_transcript(&spawn_memcached().await, &commands).await;
+    assert_eq!(
+        direct,
+        vec![
+            "STORED\r\n",
+            "VALUE a 5 1 1\r\nx\r\nEND\r\n",
+            "STORED\r\n",
+            "EXISTS\r\n",
+            "NOT_FOUND\r\n",
+            "STORED\r\n",
+            "VALUE a 5 1 2\r\ny\r\nVALUE b 0 2 3\r\nbb\r\nEND\r\n",
+            "VALUE a 5 1\r\ny\r\nEND\r\n",
+        ]
+    );
+
+    // the cas uniques of the backend are passed through untouched
+    let backend = spawn_memcached().await;
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    spawn(
+        ClusterConfig {
+            name: "memcache-gets-and-cas".to_string(),
+            listen_addr: listen_addr.clone(),
+            cache_type: CacheType::Memcache,
+            servers: vec![format!("{}:1", backend)],
+            ..Default::default()
+        },
+        Shutdown::default(),
+    )
+    .unwrap();
+    assert_eq!(mc_transcript(&listen_addr, &commands).await, direct);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_multi_get_misses() {
+    init_test_instruments();
+    init_memcached_text_finder();
+
+    let mc_proxy = |name: &str, servers: Vec<String>| {
+        let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|x| x.local_addr())
+            .unwrap()
+            .to_string();
+        spawn(
+            ClusterConfig {
+                name: name.to_string(),
+                listen_addr: listen_addr.clone(),
+                cache_type: CacheType::Memcache,
+                servers,
+                fail_fast_on_dead_node: Some(true),
+                ..Default::default()
+            },
+            Shutdown::default(),
+        )
+        .unwrap();
+        listen_addr
+    };
+    // the keys differ in their first bytes, which the fnv hash spreads over the ring
+    let keys: Vec<String> = (0..40).map(|i| format!("{}-multi-get", i)).collect();
+    let get_all = format!("get {}\r\n", keys.join(" "));
+
+    // every other key is stored, spread over both backends
+    let backends = vec![spawn_memcached().await, spawn_memcached().await];
+    let listen_addr = mc_proxy(
+        "memcache-multi-get",
+        backends.iter().map(|x| format!("{}:1", x)).collect(),
+    );
+    let sets: Vec<String> = keys
+        .iter()
+        .step_by(2)
+        .map(|key| format!("set {} 0 0 1\r\nv\r\n", key))
+        .collect();
+    let sets: Vec<&str> = sets.iter().map(String::as_str).collect();
+    assert!(mc_transcript(&listen_addr, &sets)
+        .await
+        .iter()
+        .all(|x| x == "STORED\r\n"));
+    for backend in &backends {
+        let direct = mc_transcript(backend, &[&get_all]).await;
+        assert!(direct[0].starts_with("VALUE "));
+    }
+
+    // the values of the hits are replied in order under a single END
+    let hits: String = keys
+        .iter()
+        .step_by(2)
+        .map(|key| format!("VALUE {} 0 1\r\nv\r\n", key))
+        .collect();
+    assert_eq!(
+        mc_transcript(
+            &listen_addr,
+            &[
+                &get_all,
+                "get 1-multi-get 3-multi-get\r\n",
+                "get 0-multi-get\r\n"
+            ]
+        )
+        .await,
+        vec![
+            hits + "END\r\n",
+            "END\r\n".to_string(),
+            "VALUE 0-multi-get 0 1\r\nv\r\nEND\r\n".to_string()
+        ]
+    );
+
+    // a key of a dead backend fails the whole retrieval with a single error line, and the replies
+    // after it stay in line
+    let dead = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let listen_addr = mc_proxy(
+        "memcache-multi-get-dead",
+        vec![format!("{}:1", backends[0]), format!("{}:1", dead)],
+    );
+    let gets: Vec<String> = keys
+        .iter()
+        .step_by(2)
+        .map(|key| format!("get {}\r\n", key))
+        .collect();
+    let mut commands = vec![get_all.as_str()];
+    commands.extend(gets.iter().map(String::as_str));
+    let replies = mc_transcript(&listen_addr, &commands).await;
+    assert!(replies[0].starts_with("ERROR "));
+    assert_eq!(replies[0].matches("\r\n").count(), 1);
+    // each key is now a hit or a miss of the live backend, or an error of the dead one
+    let mut served = 0;
+    for (key, reply) in keys.iter().step_by(2).zip(&replies[1..]) {
+        if reply.starts_with("VALUE ") {
+            assert_eq!(reply, &format!("VALUE {} 0 1\r\nv\r\nEND\r\n", key));
+            served += 1;
+        } else if reply != "END\r\n" {
+            assert!(reply.starts_with("ERROR "));
+            assert_eq!(reply.matches("\r\n").count(), 1);
+        }
+    }
+    assert!(served > 0);
+}
+
+// bin_frame returns a message of the memcached binary protocol
+fn bin_frame(
+    magic: u8,
+    opcode: u8,
+    status: u16,
+    opaque: u32,
+    extras: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Vec<u8> {
+    let mut frame = bytes::BytesMut::new();
+    BinHeader {
+        magic,
+        opcode,
+        key_len: key.len() as u16,
+        extras_len: extras.len() as u8,
+        status,
+        body_len: (extras.len() + key.len() + value.len()) as u32,
+        opaque,
+        ..Default::default()
+    }
+    .write(&mut frame);
+    frame.extend_from_slice(extras);
+    frame.extend_from_slice(key);
+    frame.extend_from_slice(value);
+    frame.to_vec()
+}
+
+// read_bin_frame reads a message of the memcached binary protocol, None once the peer is closed
+async fn read_bin_frame<R: tokio::io::AsyncRead + Unpin>(
+    read: &mut R,
+) -> Option<(BinHeader, Vec<u8>)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; 24];
+    read.read_exact(&mut header).await.ok()?;
+    let header = BinHeader::read(&header)?;
+    let mut body = vec![0u8; header.body_len as usize];
+    read.read_exact(&mut body).await.ok()?;
+    Some((header, body))
+}
+
+// receive_bin_frame reads the reply of the proxy in the memcached binary protocol
+async fn receive_bin_frame<R: tokio::io::AsyncRead + Unpin>(read: &mut R) -> (BinHeader, Vec<u8>) {
+    tokio::time::timeout(TEST_REPLY_TIMEOUT, read_bin_frame(read))
+        .await
+        .expect("reply must be received in time")
+        .expect("proxy must not close the client")
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_binary_round_trip() {
+    init_test_instruments();
+
+    // the backend only speaks the binary protocol and replies every command, as it is never sent the
+    // quiet ones
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    let opcodes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = opcodes.clone();
+    tokio::spawn(async move {
+        let store = Arc::new(std::sync::Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+        while let Ok((socket, _)) = listener.accept().await {
+            let (store, received) = (store.clone(), received.clone());
+            tokio::spawn(async move {
+                let (mut read, mut write) = socket.into_split();
+                while let Some((header, body)) = read_bin_frame(&mut read).await {
+                    assert_eq!(
+                        header.magic, 0x80,
+                        "backend must only receive binary requests"
+                    );
+                    received.lock().unwrap().push(header.opcode);
+                    let key_begin = header.extras_len as usize;
+                    let key = body[key_begin..key_begin + header.key_len as usize].to_vec();
+                    let value = &body[key_begin + key.len()..];
+                    let (status, extras, value) = match header.opcode {
+                        0x00 => match store.lock().unwrap().get(&key) {
+                            Some(value) => (0, vec![0u8; 4], value.clone()),
+                            None => (1, Vec::new(), b"Not found".to_vec()),
+                        },
+                        0x01 => {
+                            store.lock().unwrap().insert(key, value.to_vec());
+                            (0, Vec::new(), Vec::new())
+                        }
+                        0x0b => (0, Vec::new(), b"1.6.0".to_vec()),
+                        _ => (0, Vec::new(), Vec::new()),
+                    };
+                    let reply = bin_frame(
+                        0x81,
+                        header.opcode,
+                        status,
+                        header.opaque,
+                        &extras,
+                        &[],
+                        &value,
+                    );
+                    if write.write_all(&reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    spawn(
+        ClusterConfig {
+            name: "binary-memcache".to_string(),
+            listen_addr: listen_addr.clone(),
+            cache_type: CacheType::MemcacheBinary,
+            servers: vec![format!("{}:1", backend)],
+            max_key_bytes: Some(250),
+            max_reply_bulk_len: Some(1024),
+            ..Default::default()
+        },
+        Shutdown::default(),
+    )
+    .unwrap();
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    let socket = loop {
+        match TcpStream::connect(&listen_addr).await {
+            Ok(socket) => break socket,
+            Err(err) if Instant::now() > deadline => panic!("proxy is not up: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let (mut read, mut write) = socket.into_split();
+    let set = bin_frame(0x80, 0x01, 0, 1, &[0u8; 8], b"k", b"v");
+    let get = bin_frame(0x80, 0x00, 0, 2, &[], b"k", &[]);
+    write.write_all(&[set, get].concat()).await.unwrap();
+    let (header, body) = receive_bin_frame(&mut read).await;
+    assert_eq!(
+        (header.magic, header.opcode, header.status, header.opaque),
+        (0x81, 0x01, 0, 1)
+    );
+    assert!(body.is_empty());
+    let (header, body) = receive_bin_frame(&mut read).await;
+    assert_eq!((header.opcode, header.status, header.opaque), (0x00, 0, 2));
+    assert_eq!(body, b"\0\0\0\0v");
+
+    // the quiet commands are only replied as memcached does, a successful SETQ and a missing GETQ are
+    // not replied and the NOOP after them is
+    let setq = bin_frame(0x80, 0x11, 0, 3, &[0u8; 8], b"q", b"w");
+    let getq = bin_frame(0x80, 0x09, 0, 4, &[], b"missing", &[]);
+    let hitq = bin_frame(0x80, 0x09, 0, 5, &[], b"q", &[]);
+    let noop = bin_frame(0x80, 0x0a, 0, 6, &[], &[], &[]);
+    write
+        .write_all(&[setq, getq, hitq, noop].concat())
+        .await
+        .unwrap();
+    let (header, body) = receive_bin_frame(&mut read).await;
+    assert_eq!((header.opcode, header.status, header.opaque), (0x09, 0, 5));
+    assert_eq!(body, b"\0\0\0\0w");
+    let (header, _) = receive_bin_frame(&mut read).await;
+    assert_eq!((header.opcode, header.opaque), (0x0a, 6));
+    assert!(opcodes
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|opcode| ![0x09, 0x11].contains(opcode)));
+
+    // the errors of the proxy are replied in the binary protocol as well
+    let long_key = vec![b'k'; 251];
+    write
+        .write_all(&bin_frame(0x80, 0x00, 0, 7, &[], &long_key, &[]))
+        .await
+        .unwrap();
+    let (header, _) = receive_bin_frame(&mut read).await;
+    assert_eq!(
+        (header.magic, header.opcode, header.status, header.opaque),
+        (0x81, 0x00, 0x84, 7)
+    );
+
+    // the STAT and the flushes are rejected, the quits are answered by the proxy and none of them
+    // reaches the backend connection shared by the clients
+    let stat = bin_frame(0x80, 0x10, 0, 8, &[], &[], &[]);
+    let flush = bin_frame(0x80, 0x08, 0, 9, &[], &[], &[]);
+    let flushq = bin_frame(0x80, 0x18, 0, 10, &[], &[], &[]);
+    let quitq = bin_frame(0x80, 0x17, 0, 11, &[], &[], &[]);
+    let quit = bin_frame(0x80, 0x07, 0, 12, &[], &[], &[]);
+    let get = bin_frame(0x80, 0x00, 0, 13, &[], b"k", &[]);
+    write
+        .write_all(&[stat, flush, flushq, quitq, quit, get].concat())
+        .await
+        .unwrap();
+    for (opcode, opaque) in [(0x10, 8), (0x08, 9), (0x18, 10)] {
+        let (header, _) = receive_bin_frame(&mut read).await;
+        assert_eq!(
+            (header.opcode, header.status, header.opaque),
+            (opcode, 0x84, opaque)
+        );
+    }
+    let (header, body) = receive_bin_frame(&mut read).await;
+    assert_eq!((header.opcode, header.status, header.opaque), (0x07, 0, 12));
+    assert!(body.is_empty());
+    let (header, body) = receive_bin_frame(&mut read).await;
+    assert_eq!((header.opcode, header.status, header.opaque), (0x00, 0, 13));
+    assert_eq!(body, b"\0\0\0\0v");
+    assert!(opcodes
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|opcode| ![0x07, 0x08, 0x10, 0x17, 0x18].contains(opcode)));
+
+    // the request claiming a body longer than the limit closes the client rather than being buffered
+    let large = bin_frame(0x80, 0x01, 0, 14, &[0u8; 8], b"k", &[0u8; 2048]);
+    write.write_all(&large).await.unwrap();
+    let closed = tokio::time::timeout(TEST_REPLY_TIMEOUT, read_bin_frame(&mut read)).await;
+    assert!(matches!(closed, Ok(None)));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memcache_binary_sasl_auth() {
+    init_test_instruments();
+
+    // the backend fails every command of a connection until it is authenticated with SASL PLAIN
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    let auths = Arc::new(AtomicUsize::new(0));
+    let backend_auths = auths.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let auths = backend_auths.clone();
+            tokio::spawn(async move {
+                let (mut read, mut write) = socket.into_split();
+                let mut authenticated = false;
+                while let Some((header, body)) = read_bin_frame(&mut read).await {
+                    let (status, value) = match header.opcode {
+                        0x21 => {
+                            authenticated = body == b"PLAIN\0user\0secret";
+                            auths.fetch_add(authenticated as usize, Ordering::SeqCst);
+                            match authenticated {
+                                true => (0, &b"Authenticated"[..]),
+                                false => (0x20, &b"Auth failure"[..]),
+                            }
+                        }
+                        _ if !authenticated => (0x20, &b"Auth failure"[..]),
+                        0x00 => (1, &b"Not found"[..]),
+                        _ => (0, &b""[..]),
+                    };
+                    let reply =
+                        bin_frame(0x81, header.opcode, status, header.opaque, &[], &[], value);
+                    if write.write_all(&reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    spawn(
+        ClusterConfig {
+            name: "binary-memcache-sasl".to_string(),
+            listen_addr: listen_addr.clone(),
+            cache_type: CacheType::MemcacheBinary,
+            servers: vec![format!("{}:1", backend)],
+            node_connections: Some(2),
+            auth: "user:secret".to_string(),
+            ..Default::default()
+        },
+        Shutdown::default(),
+    )
+    .unwrap();
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    let socket = loop {
+        match TcpStream::connect(&listen_addr).await {
+            Ok(socket) => break socket,
+            Err(err) if Instant::now() > deadline => panic!("proxy is not up: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let (mut read, mut write) = socket.into_split();
+
+    // each of the connections is authenticated before serving its share of the commands
+    for opaque in 0..4 {
+        let get = bin_frame(0x80, 0x00, 0, opaque, &[], b"k", &[]);
+        write.write_all(&get).await.unwrap();
+        let (header, _) = receive_bin_frame(&mut read).await;
+        assert_eq!(
+            (header.opcode, header.status, header.opaque),
+            (0x00, 1, opaque)
+        );
+    }
+    assert_eq!(auths.load(Ordering::SeqCst), 2);
+
+    // the clients can not authenticate the shared connections again as another user
+    let sasl = bin_frame(0x80, 0x21, 0, 4, &[], b"PLAIN", b"\0user\0other");
+    write.write_all(&sasl).await.unwrap();
+    let (header, _) = receive_bin_frame(&mut read).await;
+    assert_eq!(
+        (header.opcode, header.status, header.opaque),
+        (0x21, 0x84, 4)
+    );
+    assert_eq!(auths.load(Ordering::SeqCst), 2);
+
+    // the connections rejected by the backend are counted rather than retried silently
+    let failures = || test_metric_value("repust_auth_failures_total", &[("backend", &backend)]);
+    let before = failures();
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    spawn(
+        ClusterConfig {
+            name: "binary-memcache-sasl-rejected".to_string(),
+            listen_addr,
+            cache_type: CacheType::MemcacheBinary,
+            servers: vec![format!("{}:1", backend)],
+            auth: "user:wrong".to_string(),
+            ..Default::default()
+        },
+        Shutdown::default(),
+    )
+    .unwrap();
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while failures() < before + 1.0 {
+        assert!(Instant::now() < deadline, "auth failure must be counted");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shutdown_drains_clients_before_backends() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let (backend, _) = spawn_delayed_backend(Duration::from_millis(200), move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Some(b"$1\r\nv\r\n".to_vec())
+    })
+    .await;
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let cluster = StandaloneCluster::<redis::Cmd>::new(ClusterConfig {
+        name: "shutdown".to_string(),
+        listen_addr: listen_addr.clone(),
+        servers: vec![format!("{}:1", backend)],
+        // the backends closed before the clients are drained would fail the commands right away
+        drain_grace_ms: Some(0),
+        ..Default::default()
+    })
+    .unwrap();
+    let shutdown = cluster.shutdown.clone();
+    let serving = cluster.run().unwrap();
+
+    // the pipelined commands are all waiting for the backend when the shutdown starts
+    let mut client = Client::connect(&listen_addr).await;
+    client
+        .requests
+        .write_all(&b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".repeat(10))
+        .await
+        .unwrap();
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while received.load(Ordering::SeqCst) < 10 {
+        assert!(
+            Instant::now() < deadline,
+            "backend must receive the commands"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    shutdown.start(TEST_REPLY_TIMEOUT);
+
+    for _ in 0..10 {
+        let reply = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+            .await
+            .expect("reply must be received in time")
+            .expect("connection must be open until drained")
+            .unwrap();
+        assert_eq!(reply.raw_data(), b"$1\r\nv\r\n");
+    }
+
+    // the drained client is closed and the cluster stops once its backends are closed
+    let closed = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+        .await
+        .expect("drained client must be closed");
+    assert!(closed.is_none());
+    tokio::time::timeout(TEST_REPLY_TIMEOUT, serving)
+        .await
+        .expect("cluster must stop after the shutdown")
+        .unwrap();
+    assert!(TcpStream::connect(&listen_addr).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_canary_split_ratio() {
+    let stable = spawn_backend(|_| Some(b"$6\r\nstable\r\n".to_vec())).await;
+    let canary = spawn_backend(|_| Some(b"$6\r\ncanary\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", stable)], |cc| {
+        cc.canary_servers = vec![format!("{}:1", canary)];
+        cc.canary_weight = Some(20);
+    });
+    let pool_replies =
+        |pool: &str| test_metric_value("repust_pool_replies_total", &[("pool", pool)]);
+
+    let mut client = Client::connect(&proxy).await;
+    let canary_before = pool_replies("canary");
+
+    let total = 2000;
+    let mut canary_count = 0;
+    for _ in 0..total {
+        let reply = client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+        if reply == b"$6\r\ncanary\r\n" {
+            canary_count += 1;
+        } else {
+            assert_eq!(reply, b"$6\r\nstable\r\n");
+        }
+    }
+
+    let ratio = canary_count as f64 / total as f64;
+    assert!((0.15..0.25).contains(&ratio), "canary ratio {}", ratio);
+    assert!(pool_replies("canary") >= canary_before + canary_count as f64);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_deterministic_routing() {
+    let stable = spawn_backend(|_| Some(b"$6\r\nstable\r\n".to_vec())).await;
+    let canary = spawn_backend(|_| Some(b"$6\r\ncanary\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", stable)], |cc| {
+        cc.canary_servers = vec![format!("{}:1", canary)];
+        cc.canary_weight = Some(50);
+        cc.deterministic_routing = Some(7);
+    });
+    let route = || async {
+        let mut client = Client::connect(&proxy).await;
+        let mut replies = Vec::new();
+        for _ in 0..50 {
+            let reply = client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+            replies.push(reply == b"$6\r\ncanary\r\n");
+        }
+        replies
+    };
+
+    // each connection follows the split drawn from the seed
+    let mut rng = StdRng::seed_from_u64(7);
+    let expected: Vec<bool> = (0..50).map(|_| rng.gen_range(0..100u8) < 50).collect();
+    assert!(expected.contains(&true) && expected.contains(&false));
+    assert_eq!(route().await, expected);
+    assert_eq!(route().await, expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_admin_reconnect_backend() {
+    let (backend, accepted) = spawn_counted_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "admin-reconnect".to_string();
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    let reconnect = |name: &str, addr: &str| {
+        admin::router().oneshot(
+            axum::http::Request::post(format!("/cluster/{}/nodes/{}/reconnect", name, addr))
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let resp = reconnect("admin-reconnect", &backend).await.unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::OK);
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while accepted.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+
+    let resp = reconnect("admin-reconnect", "127.0.0.1:1").await.unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+    let resp = reconnect("admin-unknown", &backend).await.unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+
+    // the backend failing to be dialed is reported as a bad gateway rather than an unknown one
+    spawn_proxy(
+        vec![format!("{}:1", backend), "127.0.0.1:1:1".to_string()],
+        |cc| {
+            cc.name = "admin-reconnect-dead".to_string();
+        },
+    );
+    let resp = reconnect("admin-reconnect-dead", "127.0.0.1:1")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_tls() {
+    use tokio::io::AsyncReadExt;
+    use tokio_rustls::{
+        rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("repust-front-{}.crt", std::process::id()));
+    let key_path = dir.join(format!("repust-front-{}.key", std::process::id()));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "client-tls".to_string();
+        cc.tls_cert = Some(cert_path.to_string_lossy().to_string());
+        cc.tls_key = Some(key_path.to_string_lossy().to_string());
+    });
+    std::fs::remove_file(&cert_path).unwrap();
+    std::fs::remove_file(&key_path).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let socket = TcpStream::connect(&proxy).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector.connect(server_name, socket).await.unwrap();
+
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+        .await
+        .unwrap();
+    let mut reply = [0; 7];
+    tokio::time::timeout(TEST_REPLY_TIMEOUT, stream.read_exact(&mut reply))
+        .await
+        .expect("reply must be received in time")
+        .unwrap();
+    assert_eq!(&reply, b"$1\r\nv\r\n");
+
+    // the plain clients fail the handshake rather than being served
+    let mut client = Client::connect(&proxy).await;
+    client
+        .requests
+        .write_all(b"*1\r\n$4\r\nPING\r\n")
+        .await
+        .unwrap();
+    let closed = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+        .await
+        .expect("plain client must be closed");
+    assert!(!matches!(closed, Some(Ok(_))));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_tls() {
+    let (backend, ca, _) = spawn_tls_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.backend_tls = Some(true);
+        cc.backend_tls_ca = Some(ca.clone());
+    });
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+
+    // the backend is not trusted without its certificate, so the commands fail
+    let (untrusted, _, _) = spawn_tls_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", untrusted)], |cc| {
+        cc.backend_tls = Some(true);
+        cc.backend_tls_ca = Some(ca.clone());
+    });
+    let mut client = Client::connect(&proxy).await;
+    assert!(client
+        .request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+        .await
+        .starts_with(b"-"));
+    std::fs::remove_file(&ca).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_tls_session_resumption() {
+    let (backend, ca, resumed) = spawn_tls_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "backend-tls-resumption".to_string();
+        cc.backend_tls = Some(true);
+        cc.backend_tls_ca = Some(ca.clone());
+    });
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    assert_eq!(resumed.load(Ordering::SeqCst), 0);
+
+    // the reconnect resumes the session of the first connection
+    let resp = admin::router()
+        .oneshot(
+            axum::http::Request::post(format!(
+                "/cluster/backend-tls-resumption/nodes/{}/reconnect",
+                backend
+            ))
+            .body(axum::body::Body::empty())
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::OK);
+    assert_eq!(resumed.load(Ordering::SeqCst), 1);
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    std::fs::remove_file(&ca).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_dial_timeout() {
+    // the backend takes the connection but never answers the TLS handshake
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap();
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.backend_tls = Some(true);
+        cc.timeout = Some(60_000);
+        cc.dial_timeout = Some(100);
+    });
+
+    // the commands fail once the dial times out, long before the reply timeout
+    let mut client = Client::connect(&proxy).await;
+    assert!(client
+        .request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+        .await
+        .starts_with(b"-"));
+    drop(listener);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_reload_servers_keeps_connections() {
+    let (kept, kept_accepted) = spawn_counted_backend(|_| Some(b"$4\r\nkept\r\n".to_vec())).await;
+    let (added, added_accepted) =
+        spawn_counted_backend(|_| Some(b"$5\r\nadded\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", kept)], |cc| {
+        cc.name = "reload".to_string();
+    });
+    let reload = |servers: Vec<&String>| {
+        admin::reload(&ClusterConfig {
+            name: "reload".to_string(),
+            servers: servers.into_iter().map(|x| format!("{}:1", x)).collect(),
+            ..Default::default()
+        })
+    };
+    let get = |i: usize| {
+        let key = format!("{}:key", i * 7919);
+        format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key)
+    };
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(client.request(get(0).as_bytes()).await, b"$4\r\nkept\r\n");
+
+    // the added backend is connected next to the kept one
+    reload(vec![&kept, &added]).unwrap();
+    let mut replies = HashSet::new();
+    for i in 0..100 {
+        replies.insert(client.request(get(i).as_bytes()).await);
+    }
+    assert_eq!(replies.len(), 2);
+    assert_eq!(kept_accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(added_accepted.load(Ordering::SeqCst), 1);
+
+    // the removed backend is not routed to anymore
+    reload(vec![&added]).unwrap();
+    for i in 0..100 {
+        assert_eq!(client.request(get(i).as_bytes()).await, b"$5\r\nadded\r\n");
+    }
+    assert_eq!(added_accepted.load(Ordering::SeqCst), 1);
+
+    // the backend failing to resolve only fails its own keys
+    admin::reload(&ClusterConfig {
+        name: "reload".to_string(),
+        servers: vec![
+            format!("{}:1", added),
+            "unresolvable.invalid:6379:1".to_string(),
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+    let mut replies = HashSet::new();
+    for i in 0..100 {
+        let reply = client.request(get(i).as_bytes()).await;
+        replies.insert(if reply.starts_with(b"-") {
+            b"-".to_vec()
+        } else {
+            reply
+        });
+    }
+    assert_eq!(
+        replies,
+        HashSet::from([b"$5\r\nadded\r\n".to_vec(), b"-".to_vec()])
+    );
+
+    let unknown = ClusterConfig {
+        name: "reload-unknown".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        admin::reload(&unknown),
+        Err(AsError::ClusterStopped("reload-unknown".to_string()))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_evalsha_routed_by_key() {
+    let mut backends = Vec::new();
+    for name in ["a", "b", "c"] {
+        let reply = format!("$1\r\n{}\r\n", name).into_bytes();
+        let addr = spawn_backend(move |_| Some(reply.clone())).await;
+        backends.push(addr);
+    }
+    let servers = backends.iter().map(|x| format!("{}:1", x)).collect();
+    let proxy = spawn_proxy(servers, |_| {});
+
+    let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+    let mut client = Client::connect(&proxy).await;
+    for key in ["k1", "k2", "k3", "user:1000", "session"] {
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+        let evalsha = format!(
+            "*4\r\n$7\r\nEVALSHA\r\n$40\r\n{}\r\n$1\r\n1\r\n${}\r\n{}\r\n",
+            sha,
+            key.len(),
+            key
+        );
+        assert_eq!(
+            client.request(evalsha.as_bytes()).await,
+            client.request(get.as_bytes()).await
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_fail_fast_on_dead_node() {
+    use tokio::io::AsyncReadExt;
+
+    // the dead node never answers the dials as the listen queue of its socket is full
+    let dead_socket =
+        socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    dead_socket
+        .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+        .unwrap();
+    dead_socket.listen(0).unwrap();
+    let dead = dead_socket
+        .local_addr()
+        .unwrap()
+        .as_socket()
+        .unwrap()
+        .to_string();
+    let queued = std::net::TcpStream::connect(&dead).unwrap();
+
+    let request = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    let latency = |proxy: String| async move {
+        let mut client = Client::connect(&proxy).await;
+        let mut slowest = Duration::ZERO;
+        for _ in 0..5 {
+            let start = Instant::now();
+            assert!(client.request(request).await.starts_with(b"-"));
+            slowest = slowest.max(start.elapsed());
+        }
+        slowest
+    };
+
+    // the commands wait for the dial to the dead node before they are black holed, while the ones
+    // failing fast are failed at once after the first failed dial
+    let black_hole = spawn_proxy(vec![format!("{}:1", dead)], |cc| {
+        cc.dial_timeout = Some(300);
+    });
+    let fail_fast = spawn_proxy(vec![format!("{}:1", dead)], |cc| {
+        cc.dial_timeout = Some(300);
+        cc.fail_fast_on_dead_node = Some(true);
+        cc.auth = "secret".to_string();
+    });
+    let black_hole_latency = latency(black_hole).await;
+    let fail_fast_latency = latency(fail_fast.clone()).await;
+    debug!(
+        "dead node errors within {:?} failing fast, {:?} black holed",
+        fail_fast_latency, black_hole_latency
+    );
+    assert!(fail_fast_latency < Duration::from_millis(200));
+    assert!(fail_fast_latency < black_hole_latency);
+
+    // the node is reconnected in the background once it is back, and authenticated again before
+    // serving the commands
+    drop((queued, dead_socket));
+    let listener = TcpListener::bind(&dead).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 64];
+                let mut authenticated = false;
+                while let Ok(n) = socket.read(&mut buf).await {
+                    let reply: &[u8] = match &buf[..n] {
+                        [] => break,
+                        req if req.windows(4).any(|x| x == b"AUTH") => {
+                            authenticated = true;
+                            b"+OK\r\n"
+                        }
+                        _ if authenticated => b"$1\r\nv\r\n",
+                        _ => b"-NOAUTH Authentication required.\r\n",
+                    };
+                    if socket.write_all(reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    let mut client = Client::connect(&fail_fast).await;
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while client.request(request).await != b"$1\r\nv\r\n" {
+        assert!(Instant::now() < deadline, "dead node must be reconnected");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_node_connections() {
+    let auths = Arc::new(AtomicUsize::new(0));
+    let backend_auths = auths.clone();
+    let (backend, accepted) = spawn_counted_backend(move |args| match args[0].as_slice() {
+        b"AUTH" => {
+            backend_auths.fetch_add(1, Ordering::SeqCst);
+            Some(b"+OK\r\n".to_vec())
+        }
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.node_connections = Some(3);
+        cc.auth = "secret".to_string();
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    for _ in 0..6 {
+        assert_eq!(
+            client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+            b"$1\r\nv\r\n"
+        );
+    }
+
+    // each of the connections is authenticated before serving its share of the commands
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    assert_eq!(auths.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_keepalive_ping() {
+    let pings = Arc::new(AtomicUsize::new(0));
+    let backend_pings = pings.clone();
+    let backend = spawn_backend(move |args| match args[0].as_slice() {
+        b"PING" => {
+            backend_pings.fetch_add(1, Ordering::SeqCst);
+            Some(b"+PONG\r\n".to_vec())
+        }
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "test-keepalive".to_string();
+        cc.backend_keepalive_ping_secs = Some(1);
+    });
+    let cluster = [("cluster", "test-keepalive")];
+    let counts = || {
+        (
+            test_metric_value("repust_pool_replies_total", &cluster),
+            test_histogram_value("repust_remote_timer", &[("node", &backend)]).0,
+        )
+    };
+
+    // the idle connection is pinged every second, the pings are neither timed nor counted
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while pings.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(pings.load(Ordering::SeqCst) >= 2);
+    assert_eq!(counts(), (0.0, 0));
+
+    // the replies of the pings are not taken for the ones of the commands
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    assert_eq!(counts(), (1.0, 1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_bytes_counters() {
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "bytes".to_string();
+    });
+    let bytes = |name: &str| test_metric_value(name, &[("cluster", "bytes")]);
+
+    let mut client = Client::connect(&proxy).await;
+    let (bytes_in, bytes_out) = (
+        bytes("repust_bytes_in_total"),
+        bytes("repust_bytes_out_total"),
+    );
+    let request = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    assert_eq!(client.request(request).await, b"$1\r\nv\r\n");
+
+    // the request is read from the client and written to the backend, and the reply the other way
+    let traffic = (request.len() + b"$1\r\nv\r\n".len()) as f64;
+    assert!(bytes("repust_bytes_in_total") - bytes_in >= traffic);
+    assert!(bytes("repust_bytes_out_total") - bytes_out >= traffic);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_backend_up_gauge() {
+    // the backend closes its connection on the first request
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 64];
+        let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await;
+    });
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "backend-up".to_string();
+    });
+    let up = || test_metric_value("repust_backend_up", &[("backend", backend.as_str())]);
+    let wait_for = |value: f64| async move {
+        let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+        while up() != value && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    wait_for(1.0).await;
+    assert_eq!(up(), 1.0);
+
+    let mut client = Client::connect(&proxy).await;
+    let reply = client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+    assert!(reply.starts_with(b"-"));
+    wait_for(0.0).await;
+    assert_eq!(up(), 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_admin_backend_inflight() {
+    let (backend, _) = spawn_delayed_backend(Duration::from_millis(500), |_| {
+        Some(b"$1\r\nv\r\n".to_vec())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "admin-inflight".to_string();
+    });
+    let inflight = || async {
+        let resp = admin::router()
+            .oneshot(
+                axum::http::Request::get("/cluster/admin-inflight/inflight")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    };
+    let expect_inflight = |count: usize| {
+        format!(
+            "[{{\"pool\":\"stable\",\"backend\":\"{}\",\"inflight\":{}}}]",
+            backend, count
+        )
+    };
+
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    assert_eq!(inflight().await, expect_inflight(0));
+
+    // the pipelined commands wait for the slow backend
+    for _ in 0..5 {
+        client.requests.write_all(get).await.unwrap();
+    }
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while inflight().await != expect_inflight(5) {
+        assert!(Instant::now() < deadline, "{}", inflight().await);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // and drain once it replies
+    for _ in 0..5 {
+        let reply = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.raw_data(), b"$1\r\nv\r\n");
+    }
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while inflight().await != expect_inflight(0) {
+        assert!(Instant::now() < deadline, "{}", inflight().await);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let resp = admin::router()
+        .oneshot(
+            axum::http::Request::get("/cluster/admin-unknown/inflight")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+}
+
+// removed_backend_replies queues GETs to a slow backend, removes it from the servers of the cluster
+// while they are in flight and returns their replies
+async fn removed_backend_replies(drain_grace_ms: u64) -> Vec<Vec<u8>> {
+    init_test_instruments();
+    init_redis_supported_cmds();
+    let delay = Duration::from_millis(200);
+    let (removed, _) = spawn_delayed_backend(delay, |_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let kept = spawn_backend(|_| Some(b"$1\r\nw\r\n".to_vec())).await;
+
+    let cc = ClusterConfig {
+        name: format!("drain-grace-{}", drain_grace_ms),
+        servers: vec![format!("{}:1", removed)],
+        drain_grace_ms: Some(drain_grace_ms),
+        ..Default::default()
+    };
+    let cluster = StandaloneCluster::<redis::Cmd>::new(cc.clone()).unwrap();
+    assert!(cluster.prewarm(1, TEST_REPLY_TIMEOUT).await);
+
+    let cmds: Vec<redis::Cmd> = (0..3)
+        .map(|_| {
+            let mut src = bytes::BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n"[..]);
+            let mut cmd = RedisHandleCodec::default()
+                .decode(&mut src)
+                .unwrap()
+                .unwrap();
+            cmd.register_waker(futures::task::noop_waker());
+            let sender = cluster
+                .get_sender(&cluster.ring, 0, &mut StdRng::seed_from_u64(0))
+                .unwrap();
+            sender.send(cmd.clone()).unwrap();
+            cmd
+        })
+        .collect();
+    cluster
+        .init_ring(&cluster.ring, &[format!("{}:1", kept)], Pool::Stable, &cc)
+        .unwrap();
+    assert_eq!(
+        cluster.ring.get_addr(0, &mut StdRng::seed_from_u64(0)),
+        Some(kept)
+    );
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while !cmds.iter().all(|cmd| cmd.is_done()) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    cmds.iter()
+        .map(|cmd| {
+            let mut buf = bytes::BytesMut::new();
+            RedisHandleCodec::default()
+                .encode(cmd.clone(), &mut buf)
+                .unwrap();
+            buf.to_vec()
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_removed_backend_drains_inflight() {
+    // the commands in flight are served by the removed backend within the grace
+    for reply in removed_backend_replies(1000).await {
+        assert_eq!(reply, b"$1\r\nv\r\n");
+    }
+
+    // and failed once it passes
+    for reply in removed_backend_replies(50).await {
+        assert!(reply.starts_with(b"-remote connection has been active closed"));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_admin_gc_idle_backends() {
+    let (backend, accepted) = spawn_counted_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.name = "admin-gc".to_string();
+    });
+    let gc = |uri: &str| {
+        admin::router().oneshot(
+            axum::http::Request::post(uri)
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+    };
+    let body = |resp: axum::response::Response| async {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    };
+
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+
+    // the connection is just used, so it is kept by the default idle timeout
+    let resp = gc("/cluster/admin-gc/gc").await.unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::OK);
+    assert_eq!(body(resp).await, "0");
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let resp = gc("/cluster/admin-gc/gc?idle_ms=50").await.unwrap();
+    assert_eq!(body(resp).await, "1");
+    let resp = gc("/cluster/admin-gc/gc?idle_ms=50").await.unwrap();
+    assert_eq!(body(resp).await, "0");
+
+    // the closed connection is established again on demand
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    let resp = gc("/cluster/admin-unknown/gc").await.unwrap();
+    assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_close_idle_keeps_busy_connections() {
+    let mut ring = Ring::<redis::Cmd>::new();
+    let (tx, rx) = bounded(1);
+    let count = Arc::new(AtomicUsize::new(0));
+    ring.insert_conn(Conn::new(
+        "a",
+        vec![tx.clone()],
+        vec![count.clone()],
+        Default::default(),
+        Default::default(),
+    ));
+
+    // the queued and the in flight commands keep the connection open
+    tx.send(redis::Cmd::auth_request("pass")).unwrap();
+    assert_eq!(ring.close_idle(Duration::ZERO), 0);
+    rx.recv().unwrap();
+    count.store(1, Ordering::Relaxed);
+    assert_eq!(ring.close_idle(Duration::ZERO), 0);
+    count.store(0, Ordering::Relaxed);
+    assert_eq!(ring.close_idle(Duration::from_secs(60)), 0);
+
+    // the closed connection is drained once its backend task drops the outstanding counter
+    assert_eq!(ring.close_idle(Duration::ZERO), 1);
+    let draining = ring.closed.get("a").unwrap();
+    assert_eq!(draining[0].strong_count(), 1);
+    drop(count);
+    assert_eq!(draining[0].strong_count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_slow_start_added_backend() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+
+    let old = spawn_backend(|_| Some(b"$3\r\nold\r\n".to_vec())).await;
+    let new = spawn_backend(|_| Some(b"$3\r\nnew\r\n".to_vec())).await;
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let cc = ClusterConfig {
+        name: "slow-start".to_string(),
+        listen_addr: listen_addr.clone(),
+        servers: vec![format!("{}:1", old)],
+        slow_start_secs: Some(3),
+        ..Default::default()
+    };
+
+    // the new backend is added to the running ring as a reload would do
+    let cluster = StandaloneCluster::<redis::Cmd>::new(cc.clone()).unwrap();
+    let servers = vec![format!("{}:1", old), format!("{}:1", new)];
+    cluster
+        .init_ring(&cluster.ring, &servers, Pool::Stable, &cc)
+        .unwrap();
+    cluster.run().unwrap();
+
+    async fn new_share(client: &mut Client) -> f64 {
+        let mut count = 0;
+        for i in 0..200 {
+            let key = format!("{}:key", i * 7919);
+            let req = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+            if client.request(req.as_bytes()).await == b"$3\r\nnew\r\n" {
+                count += 1;
+            }
+        }
+        count as f64 / 200.0
+    }
+
+    let mut client = Client::connect(&listen_addr).await;
+    let started = new_share(&mut client).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let ramping = new_share(&mut client).await;
+    tokio::time::sleep(Duration::from_millis(2000)).await;
+    let ramped = new_share(&mut client).await;
+
+    assert!(started < 0.2, "started with share {}", started);
+    assert!(started < ramping, "share {} after {}", ramping, started);
+    assert!(ramping < ramped, "share {} after {}", ramped, ramping);
+    assert!(ramped > 0.3, "ramped to share {}", ramped);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_rehash_double_read() {
+    let old = spawn_backend(|_| Some(b"$3\r\nold\r\n".to_vec())).await;
+    let new = spawn_backend(|_| Some(b"$-1\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", old)], |cc| {
+        cc.name = "double-read".to_string();
+    });
+    let reload = |double_read: Option<u64>| {
+        admin::reload(&ClusterConfig {
+            name: "double-read".to_string(),
+            servers: vec![format!("{}:1", old), format!("{}:1", new)],
+            rehash_double_read_secs: double_read,
+            ..Default::default()
+        })
+    };
+    async fn misses(client: &mut Client) -> usize {
+        let mut count = 0;
+        for i in 0..100 {
+            let key = format!("{}:key", i * 7919);
+            let req = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+            if client.request(req.as_bytes()).await == b"$-1\r\n" {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(misses(&mut client).await, 0);
+
+    // the keys moved to the added backend miss there and are read again from the old one
+    reload(Some(60)).unwrap();
+    assert_eq!(misses(&mut client).await, 0);
+
+    // the misses are replied as is once the ring changes without the double read
+    reload(None).unwrap();
+    assert!(misses(&mut client).await > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_mget_pipelined_across_backends() {
+    const ROUND_TRIP: Duration = Duration::from_millis(200);
+
+    // each backend echoes the key back after a round trip
+    let echo = |args: &[Vec<u8>]| {
+        let key = &args[1];
+        Some([format!("${}\r\n", key.len()).as_bytes(), key, b"\r\n"].concat())
+    };
+    let (first, _) = spawn_delayed_backend(ROUND_TRIP, echo).await;
+    let (second, _) = spawn_delayed_backend(ROUND_TRIP, echo).await;
+    let proxy = spawn_proxy(
+        vec![format!("{}:1", first), format!("{}:1", second)],
+        |_| {},
+    );
+
+    // the varying part leads the keys so that their hashes are spread over the ring
+    let keys: Vec<String> = (0..100).map(|i| format!("{}-key", i)).collect();
+    let mut request = format!("*{}\r\n$4\r\nMGET\r\n", keys.len() + 1);
+    let mut expected = format!("*{}\r\n", keys.len());
+    for key in &keys {
+        let bulk = format!("${}\r\n{}\r\n", key.len(), key);
+        request.push_str(&bulk);
+        expected.push_str(&bulk);
+    }
+
+    let mut client = Client::connect(&proxy).await;
+    // warm up the backend connections before timing
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nk\r\n"
+    );
+
+    let start = Instant::now();
+    let reply = client.request(request.as_bytes()).await;
+    let elapsed = start.elapsed();
+
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+    // serving the keys one at a time would take 100 round trips, pipelining takes one per backend
+    // and the backends are served in parallel.
+    assert!(elapsed < ROUND_TRIP * 4, "MGET took {:?}", elapsed);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_pipelined_replies_drained_in_order() {
+    const ROUND_TRIP: Duration = Duration::from_millis(200);
+
+    // the replies of the pipeline are written together, so their wakeups are coalesced
+    let echo = |args: &[Vec<u8>]| {
+        let key = &args[1];
+        Some([format!("${}\r\n", key.len()).as_bytes(), key, b"\r\n"].concat())
+    };
+    let (backend, _) = spawn_delayed_backend(ROUND_TRIP, echo).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nk\r\n"
+    );
+
+    let keys: Vec<String> = (0..200).map(|i| format!("key-{}", i)).collect();
+    let request: String = keys
+        .iter()
+        .map(|key| format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key))
+        .collect();
+    let start = Instant::now();
+    client.requests.write_all(request.as_bytes()).await.unwrap();
+    for key in &keys {
+        let reply = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+            .await
+            .expect("reply must be received in time")
+            .expect("connection must be open")
+            .unwrap();
+        assert_eq!(
+            reply.raw_data(),
+            format!("${}\r\n{}\r\n", key.len(), key).as_bytes()
+        );
+    }
+
+    // all the replies done together are sent in a single poll of the front, without waiting for
+    // a wakeup of their own
+    let elapsed = start.elapsed();
+    assert!(elapsed < ROUND_TRIP * 4, "pipeline took {:?}", elapsed);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_unlink_sums_across_backends() {
+    // each backend removes the keys it holds, the even ones exist before the first removal
+    let existing: HashSet<Vec<u8>> = (0..20)
+        .step_by(2)
+        .map(|i| format!("{}-key", i).into_bytes())
+        .collect();
+    let remover = |served: Arc<AtomicUsize>| {
+        let keys = std::sync::Mutex::new(existing.clone());
+        move |args: &[Vec<u8>]| {
+            assert_eq!(args[0], b"UNLINK", "UNLINK must be sent as is");
+            served.fetch_add(1, Ordering::SeqCst);
+            let removed = keys.lock().unwrap().remove(&args[1]);
+            Some(format!(":{}\r\n", removed as u8).into_bytes())
+        }
+    };
+    let first_served = Arc::new(AtomicUsize::new(0));
+    let second_served = Arc::new(AtomicUsize::new(0));
+    let first = spawn_backend(remover(first_served.clone())).await;
+    let second = spawn_backend(remover(second_served.clone())).await;
+    let proxy = spawn_proxy(
+        vec![format!("{}:1", first), format!("{}:1", second)],
+        |_| {},
+    );
+
+    let mut request = "*21\r\n$6\r\nUNLINK\r\n".to_string();
+    for i in 0..20 {
+        let key = format!("{}-key", i);
+        request.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+    }
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(client.request(request.as_bytes()).await, b":10\r\n");
+    assert!(first_served.load(Ordering::SeqCst) > 0);
+    assert!(second_served.load(Ordering::SeqCst) > 0);
+
+    // the keys are gone once unlinked
+    assert_eq!(client.request(request.as_bytes()).await, b":0\r\n");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_msetnx_across_backends() {
+    // each backend holds the pairs set on it
+    let store = |served: Arc<AtomicUsize>| {
+        let pairs = std::sync::Mutex::new(HashMap::new());
+        move |args: &[Vec<u8>]| {
+            served.fetch_add(1, Ordering::SeqCst);
+            let mut pairs = pairs.lock().unwrap();
+            let reply = match args[0].as_slice() {
+                b"EXISTS" => pairs.contains_key(&args[1]),
+                b"MSETNX" if pairs.contains_key(&args[1]) => false,
+                b"MSETNX" => pairs.insert(args[1].clone(), args[2].clone()).is_none(),
+                b"GET" => {
+                    let value = &pairs[&args[1]];
+                    let len = format!("${}\r\n", value.len());
+                    return Some([len.as_bytes(), value, b"\r\n"].concat());
+                }
+                _ => unreachable!("only EXISTS, MSETNX and GET are sent"),
+            };
+            Some(format!(":{}\r\n", reply as u8).into_bytes())
+        }
+    };
+    let first_served = Arc::new(AtomicUsize::new(0));
+    let second_served = Arc::new(AtomicUsize::new(0));
+    let first = spawn_backend(store(first_served.clone())).await;
+    let second = spawn_backend(store(second_served.clone())).await;
+    let proxy = spawn_proxy(
+        vec![format!("{}:1", first), format!("{}:1", second)],
+        |_| {},
+    );
+    let msetnx = |keys: std::ops::Range<usize>| {
+        let mut request = format!("*{}\r\n$6\r\nMSETNX\r\n", keys.len() * 2 + 1);
+        for i in keys {
+            let key = format!("{}-key", i);
+            request.push_str(&format!("${}\r\n{}\r\n$1\r\nv\r\n", key.len(), key));
+        }
+        request
+    };
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(client.request(msetnx(0..10).as_bytes()).await, b":1\r\n");
+    assert!(first_served.load(Ordering::SeqCst) > 0);
+    assert!(second_served.load(Ordering::SeqCst) > 0);
+
+    // only the keys are checked once some of them exist
+    let served = first_served.load(Ordering::SeqCst) + second_served.load(Ordering::SeqCst);
+    assert_eq!(client.request(msetnx(5..15).as_bytes()).await, b":0\r\n");
+    let checked = first_served.load(Ordering::SeqCst) + second_served.load(Ordering::SeqCst);
+    assert_eq!(checked - served, 10);
+    assert_eq!(client.request(msetnx(10..15).as_bytes()).await, b":1\r\n");
+
+    // a repeated key is set once, to its last value
+    let req = b"*5\r\n$6\r\nMSETNX\r\n$3\r\ndup\r\n$1\r\n1\r\n$3\r\ndup\r\n$1\r\n2\r\n";
+    assert_eq!(client.request(req).await, b":1\r\n");
+    let get = b"*2\r\n$3\r\nGET\r\n$3\r\ndup\r\n";
+    assert_eq!(client.request(get).await, b"$1\r\n2\r\n");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_mget_reply_order_with_uneven_backends() {
+    let echo = |served: Arc<AtomicUsize>| {
+        move |args: &[Vec<u8>]| {
+            served.fetch_add(1, Ordering::SeqCst);
+            let key = &args[1];
+            Some([format!("${}\r\n", key.len()).as_bytes(), key, b"\r\n"].concat())
+        }
+    };
+    let slow_served = Arc::new(AtomicUsize::new(0));
+    let fast_served = Arc::new(AtomicUsize::new(0));
+    let (slow, _) =
+        spawn_delayed_backend(Duration::from_millis(300), echo(slow_served.clone())).await;
+    let (fast, _) = spawn_delayed_backend(Duration::ZERO, echo(fast_served.clone())).await;
+    let proxy = spawn_proxy(vec![format!("{}:10", slow), format!("{}:10", fast)], |_| {});
+
+    // the varying part leads the keys so that their hashes are spread over the ring
+    let keys: Vec<String> = (0..100).map(|i| format!("{}-key", i)).collect();
+    let mut request = format!("*{}\r\n$4\r\nMGET\r\n", keys.len() + 1);
+    let mut expected = format!("*{}\r\n", keys.len());
+    for key in &keys {
+        let bulk = format!("${}\r\n{}\r\n", key.len(), key);
+        request.push_str(&bulk);
+        expected.push_str(&bulk);
+    }
+
+    let mut client = Client::connect(&proxy).await;
+    let reply = client.request(request.as_bytes()).await;
+
+    // the keys span both backends and the fast one replied first, yet the values are in request order
+    assert!(slow_served.load(Ordering::SeqCst) > 0);
+    assert!(fast_served.load(Ordering::SeqCst) > 0);
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_value_compression_round_trip() {
+    let store = Arc::new(std::sync::Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+    let backend_store = store.clone();
+    let backend = spawn_backend(move |args| {
+        let mut store = backend_store.lock().unwrap();
+        let bulk = |value: Option<Vec<u8>>| match value {
+            Some(value) => [format!("${}\r\n", value.len()).as_bytes(), &value, b"\r\n"].concat(),
+            None => b"$-1\r\n".to_vec(),
+        };
+        match args[0].as_slice() {
+            b"SET" if args.len() > 3 => Some(bulk(store.insert(args[1].clone(), args[2].clone()))),
+            b"SET" => {
+                store.insert(args[1].clone(), args[2].clone());
+                Some(b"+OK\r\n".to_vec())
+            }
+            b"GETSET" => Some(bulk(store.insert(args[1].clone(), args[2].clone()))),
+            _ => Some(match store.get(&args[1]) {
+                Some(value) => {
+                    [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat()
+                }
+                None => b"$-1\r\n".to_vec(),
+            }),
+        }
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.value_compression = Some(true);
+        cc.value_compression_min_bytes = Some(64);
+    });
+
+    let large = "repust ".repeat(100);
+    let large_bulk = format!("${}\r\n{}\r\n", large.len(), large);
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(format!("*3\r\n$3\r\nSET\r\n$5\r\nlarge\r\n{}", large_bulk).as_bytes())
+            .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$3\r\nSET\r\n$5\r\nsmall\r\n$5\r\nvalue\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+
+    // the backend only sees the compressed form of the large value
+    {
+        let store = store.lock().unwrap();
+        let stored = &store[b"large".as_slice()];
+        assert!(stored.len() < large.len());
+        assert!(stored.starts_with(b"\x00RPZ1"));
+        assert_eq!(store[b"small".as_slice()], b"value");
+    }
+
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$5\r\nlarge\r\n").await,
+        large_bulk.as_bytes()
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$4\r\nMGET\r\n$5\r\nsmall\r\n$5\r\nlarge\r\n")
+            .await,
+        format!("*2\r\n$5\r\nvalue\r\n{}", large_bulk).as_bytes()
+    );
+
+    // the old values replied by GETSET and SET ... GET are decompressed and the new ones compressed
+    let larger = "repust! ".repeat(100);
+    let larger_bulk = format!("${}\r\n{}\r\n", larger.len(), larger);
+    assert_eq!(
+        client
+            .request(format!("*3\r\n$6\r\ngetset\r\n$5\r\nlarge\r\n{}", larger_bulk).as_bytes())
+            .await,
+        large_bulk.as_bytes()
+    );
+    assert!(store.lock().unwrap()[b"large".as_slice()].starts_with(b"\x00RPZ1"));
+    assert_eq!(
+        client
+            .request(
+                format!(
+                    "*4\r\n$3\r\nSET\r\n$5\r\nlarge\r\n{}$3\r\nGET\r\n",
+                    large_bulk
+                )
+                .as_bytes()
+            )
+            .await,
+        larger_bulk.as_bytes()
+    );
+
+    // the commands working on a part of the stored bytes would corrupt or misread them
+    for cmd in [
+        &b"*3\r\n$6\r\nAPPEND\r\n$5\r\nlarge\r\n$1\r\nx\r\n"[..],
+        b"*4\r\n$8\r\nGETRANGE\r\n$5\r\nlarge\r\n$1\r\n0\r\n$1\r\n9\r\n",
+        b"*2\r\n$6\r\nstrlen\r\n$5\r\nlarge\r\n",
+    ] {
+        assert_eq!(
+            client.request(cmd).await,
+            b"-ERR APPEND, GETRANGE, SETRANGE and STRLEN are not supported with the value compression\r\n"
+        );
+    }
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$5\r\nlarge\r\n").await,
+        large_bulk.as_bytes()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_default_ttl() {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let backend_received = received.clone();
+    let backend = spawn_backend(move |args| {
+        backend_received.lock().unwrap().push(args.to_vec());
+        Some(b"+OK\r\n".to_vec())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.default_ttl_secs = Some(60);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    for req in [
+        b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n".as_slice(),
+        b"*5\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n$2\r\nPX\r\n$3\r\n500\r\n",
+        b"*3\r\n$4\r\nMSET\r\n$1\r\nc\r\n$1\r\n3\r\n",
+    ] {
+        assert_eq!(client.request(req).await, b"+OK\r\n");
+    }
+
+    let args = |x: &[&str]| x.iter().map(|x| x.as_bytes().to_vec()).collect::<Vec<_>>();
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            args(&["SET", "a", "1", "EX", "60"]),
+            args(&["SET", "b", "2", "PX", "500"]),
+            args(&["SET", "c", "3", "EX", "60"]),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_append_reply_passthrough() {
+    let store = Arc::new(std::sync::Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+    let backend = spawn_backend(move |args| {
+        let mut store = store.lock().unwrap();
+        match args[0].to_ascii_uppercase().as_slice() {
+            b"APPEND" => {
+                let value = store.entry(args[1].clone()).or_default();
+                value.extend_from_slice(&args[2]);
+                Some(format!(":{}\r\n", value.len()).into_bytes())
+            }
+            b"GET" => Some(match store.get(&args[1]) {
+                Some(value) => {
+                    let mut reply = format!("${}\r\n", value.len()).into_bytes();
+                    reply.extend_from_slice(value);
+                    reply.extend_from_slice(b"\r\n");
+                    reply
+                }
+                None => b"$-1\r\n".to_vec(),
+            }),
+            _ => None,
+        }
+    })
+    .await;
+    let target_appends = Arc::new(AtomicUsize::new(0));
+    let counter = target_appends.clone();
+    let target = spawn_backend(move |args| {
+        if args[0].eq_ignore_ascii_case(b"APPEND") {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        Some(b":0\r\n".to_vec())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.migrate_target = vec![format!("{}:1", target)];
+    });
+    let commands = || test_metric_value("repust_commands_total", &[]);
+
+    let mut client = Client::connect(&proxy).await;
+    let before = commands();
+
+    // the new length of the value is replied as is
+    assert_eq!(
+        client
+            .request(b"*3\r\n$6\r\nAPPEND\r\n$1\r\nk\r\n$3\r\nabc\r\n")
+            .await,
+        b":3\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$6\r\nappend\r\n$1\r\nk\r\n$4\r\ndefg\r\n")
+            .await,
+        b":7\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$7\r\nabcdefg\r\n"
+    );
+
+    // APPEND is a write, so it is counted and copied to the migration target like the other writes
+    assert!(commands() >= before + 3.0);
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while target_appends.load(Ordering::SeqCst) < 2 {
+        assert!(Instant::now() < deadline, "APPEND is not mirrored");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_migrate_target_mirrors_writes() {
+    let recorder = |seen: Arc<std::sync::Mutex<Vec<String>>>, reply: &'static [u8]| {
+        move |args: &[Vec<u8>]| {
+            seen.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&args[0]).to_string());
+            Some(reply.to_vec())
+        }
+    };
+    let primary_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let target_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let primary = spawn_backend(recorder(primary_seen.clone(), b":1\r\n")).await;
+    let target = spawn_backend(recorder(target_seen.clone(), b"-ERR ignored\r\n")).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", primary)], |cc| {
+        cc.migrate_target = vec![format!("{}:1", target)];
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    for request in [
+        b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n".as_slice(),
+        b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n",
+    ] {
+        // the clients only see the replies of the primary backends
+        let reply = client.request(request).await;
+        assert!(
+            !reply.starts_with(b"-"),
+            "{}",
+            String::from_utf8_lossy(&reply)
+        );
+    }
+
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    while target_seen.lock().unwrap().len() < 2 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(*primary_seen.lock().unwrap(), vec!["SET", "GET", "DEL"]);
+    assert_eq!(*target_seen.lock().unwrap(), vec!["SET", "DEL"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_migrate_target_drops_copies_when_full() {
+    let primary = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let target = spawn_backend(|_| None).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", primary)], |cc| {
+        cc.name = "mirror-full".to_string();
+        cc.migrate_target = vec![format!("{}:1", target)];
+        cc.backend_queue_size = Some(8);
+        cc.timeout = Some(10_000);
+    });
+
+    // the target never replies, so its backend stops taking the copies once its pipeline is full
+    // and the copies beyond its queue are dropped without holding the client
+    let count = BACKEND_MAX_PIPELINE + 100;
+    let mut client = Client::connect(&proxy).await;
+    let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    client.requests.write_all(&set.repeat(count)).await.unwrap();
+    for _ in 0..count {
+        let reply = tokio::time::timeout(TEST_REPLY_TIMEOUT, client.replies.next())
+            .await
+            .expect("reply must be received in time")
+            .expect("connection must be open")
+            .unwrap();
+        assert_eq!(reply.raw_data(), b"+OK\r\n");
+    }
+    assert!(test_metric_value("repust_mirror_dropped_total", &[("cluster", "mirror-full")]) > 0.0);
+
+    // only the commands of the primary backends are counted
+    assert_eq!(
+        test_metric_value("repust_commands_total", &[("cluster", "mirror-full")]),
+        count as f64
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_pool_routes_serve_command_types() {
+    let recorder = |seen: Arc<std::sync::Mutex<Vec<String>>>, reply: &'static [u8]| {
+        move |args: &[Vec<u8>]| {
+            seen.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&args[0]).to_string());
+            Some(reply.to_vec())
+        }
+    };
+    let servers_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let replicas_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let primaries_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let servers = spawn_backend(recorder(servers_seen.clone(), b":1\r\n")).await;
+    let replicas = spawn_backend(recorder(replicas_seen.clone(), b"$1\r\nv\r\n")).await;
+    let primaries = spawn_backend(recorder(primaries_seen.clone(), b"+OK\r\n")).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", servers)], |cc| {
+        cc.pools = BTreeMap::from([
+            ("replicas".to_string(), vec![format!("{}:1", replicas)]),
+            ("primaries".to_string(), vec![format!("{}:1", primaries)]),
+        ]);
+        cc.pool_routes = BTreeMap::from([
+            ("read".to_string(), "replicas".to_string()),
+            ("write".to_string(), "primaries".to_string()),
+        ]);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n").await,
+        b":1\r\n"
+    );
+
+    // the command types which are not routed are served by the servers
+    assert_eq!(*primaries_seen.lock().unwrap(), vec!["SET"]);
+    assert_eq!(*replicas_seen.lock().unwrap(), vec!["GET"]);
+    assert_eq!(*servers_seen.lock().unwrap(), vec!["DEL"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_read_servers_serve_reads() {
+    let recorder = |seen: Arc<std::sync::Mutex<Vec<String>>>, reply: &'static [u8]| {
+        move |args: &[Vec<u8>]| {
+            seen.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&args[0]).to_string());
+            Some(reply.to_vec())
+        }
+    };
+    let primary_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reader_seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let primary = spawn_backend(recorder(primary_seen.clone(), b":1\r\n")).await;
+    let reader = spawn_backend(recorder(reader_seen.clone(), b"$1\r\nv\r\n")).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", primary)], |cc| {
+        cc.read_servers = vec![format!("{}:1", reader)];
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*1\r\n$8\r\nREADONLY\r\n").await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await,
+        b":1\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .await,
+        b"*2\r\n$1\r\nv\r\n$1\r\nv\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n").await,
+        b":1\r\n"
+    );
+
+    assert_eq!(*primary_seen.lock().unwrap(), vec!["SET", "DEL"]);
+    assert_eq!(*reader_seen.lock().unwrap(), vec!["GET", "GET", "GET"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_scan_across_backends() {
+    // each backend has two pages of keys, named by the backend
+    let scanner = |name: &'static str| {
+        move |args: &[Vec<u8>]| {
+            assert_eq!(args[2..], [b"COUNT".to_vec(), b"1".to_vec()]);
+            let (next, page) = match args[1].as_slice() {
+                b"0" => ("5", 1),
+                b"5" => ("0", 2),
+                cursor => panic!("unexpected cursor {:?}", cursor),
+            };
+            Some(format!("*2\r\n$1\r\n{}\r\n*1\r\n$2\r\n{}{}\r\n", next, name, page).into_bytes())
+        }
+    };
+    let first = spawn_backend(scanner("a")).await;
+    let second = spawn_backend(scanner("b")).await;
+    let proxy = spawn_proxy(
+        vec![format!("{}:1", first), format!("{}:1", second)],
+        |_| {},
+    );
+    let scan = |cursor: &str| {
+        format!(
+            "*4\r\n$4\r\nSCAN\r\n${}\r\n{}\r\n$5\r\nCOUNT\r\n$1\r\n1\r\n",
+            cursor.len(),
+            cursor
+        )
+        .into_bytes()
+    };
+
+    // the cursor packs the index of the backend in its low bits, and the cursor of the backend above
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(&scan("0")).await,
+        b"*2\r\n$4\r\n5120\r\n*1\r\n$2\r\na1\r\n"
+    );
+    assert_eq!(
+        client.request(&scan("5120")).await,
+        b"*2\r\n$1\r\n1\r\n*1\r\n$2\r\na2\r\n"
+    );
+    assert_eq!(
+        client.request(&scan("1")).await,
+        b"*2\r\n$4\r\n5121\r\n*1\r\n$2\r\nb1\r\n"
+    );
+    // the scan is complete once the cursor of the last backend is
+    assert_eq!(
+        client.request(&scan("5121")).await,
+        b"*2\r\n$1\r\n0\r\n*1\r\n$2\r\nb2\r\n"
+    );
+
+    // the cursors past the last backend, e.g. of a ring shrunk mid-scan, complete the scan too
+    assert_eq!(client.request(&scan("2")).await, b"*2\r\n$1\r\n0\r\n*0\r\n");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_read_write_connection() {
+    let primary = spawn_backend(|_| Some(b"$7\r\nprimary\r\n".to_vec())).await;
+    let reader = spawn_backend(|_| Some(b"$6\r\nreader\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", primary)], |cc| {
+        cc.read_servers = vec![format!("{}:1", reader)];
+    });
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+
+    // the fresh connections read from the primaries until READONLY switches them to the read servers,
+    // the others keep reading the primaries
+    let mut client = Client::connect(&proxy).await;
+    let mut other = Client::connect(&proxy).await;
+    assert_eq!(client.request(get).await, b"$7\r\nprimary\r\n");
+    assert_eq!(
+        client.request(b"*1\r\n$8\r\nREADONLY\r\n").await,
+        b"+OK\r\n"
+    );
+    assert_eq!(client.request(get).await, b"$6\r\nreader\r\n");
+    assert_eq!(other.request(get).await, b"$7\r\nprimary\r\n");
+
+    // READWRITE reverts it
+    assert_eq!(
+        client.request(b"*1\r\n$9\r\nREADWRITE\r\n").await,
+        b"+OK\r\n"
+    );
+    assert_eq!(client.request(get).await, b"$7\r\nprimary\r\n");
+}
+
+// client_fields parses the lines of a CLIENT INFO or CLIENT LIST bulk reply into their fields
+fn client_fields(reply: &[u8]) -> Vec<HashMap<String, String>> {
+    let reply = String::from_utf8(reply.to_vec()).unwrap();
+    let (_, body) = reply.split_once("\r\n").expect("reply must be a bulk");
+    body.trim_end_matches("\r\n")
+        .lines()
+        .map(|line| {
+            line.split(' ')
+                .filter_map(|x| x.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_info_and_list() {
+    let backend = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+    let info = b"*2\r\n$6\r\nCLIENT\r\n$4\r\nINFO\r\n";
+
+    let mut client = Client::connect(&proxy).await;
+    let mut other = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*3\r\n$6\r\nclient\r\n$7\r\nsetname\r\n$3\r\napp\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+
+    // INFO describes the connection itself
+    let fields = client_fields(&client.request(info).await);
+    assert_eq!(fields.len(), 1);
+    assert_eq!(fields[0]["addr"], client.local_addr());
+    assert_eq!(fields[0]["laddr"], proxy);
+    assert_eq!(fields[0]["name"], "app");
+    let other_fields = client_fields(&other.request(info).await);
+    assert_eq!(other_fields[0]["addr"], other.local_addr());
+    assert_eq!(other_fields[0]["name"], "");
+    assert_ne!(fields[0]["id"], other_fields[0]["id"]);
+
+    // LIST describes all the connections of the cluster in the order they were made
+    let list = client_fields(&other.request(b"*2\r\n$6\r\nCLIENT\r\n$4\r\nLIST\r\n").await);
+    assert_eq!(list, vec![fields[0].clone(), other_fields[0].clone()]);
+
+    // the closed connections are no longer listed
+    drop(other);
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    loop {
+        let list = client_fields(
+            &client
+                .request(b"*2\r\n$6\r\nCLIENT\r\n$4\r\nLIST\r\n")
+                .await,
+        );
+        if list.len() == 1 {
+            assert_eq!(list[0]["id"], fields[0]["id"]);
+            break;
+        }
+        assert!(Instant::now() < deadline, "closed client must be unlisted");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // the other subcommands are acknowledged without effect
+    assert_eq!(
+        client
+            .request(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nNOEVICT\r\n$2\r\nON\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+}
+
+// bench_backend_flush_batch compares the flush batches of the backends by the commands of the clients
+// trickling in concurrently, run with:
+// cargo test --lib bench_backend_flush_batch -- --ignored --nocapture
+#[tokio::test(flavor = "multi_thread", worker_threads = 16)]
+#[ignore]
+async fn bench_backend_flush_batch() {
+    const CLIENTS: usize = 32;
+    const ROUNDS: usize = 500;
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+
+    for batch in [1, 4, 16, 64] {
+        let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+            cc.backend_flush_batch = Some(batch);
+        });
+        let mut clients = Vec::with_capacity(CLIENTS);
+        for _ in 0..CLIENTS {
+            let mut client = Client::connect(&proxy).await;
+            client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+            clients.push(client);
+        }
+
+        let start = Instant::now();
+        let tasks: Vec<_> = clients
+            .into_iter()
+            .map(|mut client| {
+                tokio::spawn(async move {
+                    for _ in 0..ROUNDS {
+                        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "backend_flush_batch={:<3} {:>8.0} commands/s {:>6.1}us per round trip",
+            batch,
+            (CLIENTS * ROUNDS) as f64 / elapsed.as_secs_f64(),
+            elapsed.as_micros() as f64 / ROUNDS as f64
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_subscribe_streams_messages() {
+    const SUBSCRIBED: &[u8] = b"*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n";
+    const MESSAGE: &[u8] = b"*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n";
+    const UNSUBSCRIBED: &[u8] = b"*3\r\n$11\r\nunsubscribe\r\n$2\r\nch\r\n:0\r\n";
+
+    // the backend pushes a message right after the subscription
+    let backend = spawn_backend(|args| match args[0].as_slice() {
+        b"SUBSCRIBE" => Some([SUBSCRIBED, MESSAGE].concat()),
+        b"UNSUBSCRIBE" => Some(UNSUBSCRIBED.to_vec()),
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*2\r\n$9\r\nSUBSCRIBE\r\n$2\r\nch\r\n")
+            .await,
+        SUBSCRIBED
+    );
+    assert_eq!(client.receive().await, MESSAGE);
+
+    // only the pub/sub commands are served while subscribed
+    assert_eq!(
+        client.request(get).await,
+        b"-ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context\r\n"
+    );
+    assert_eq!(client.request(b"*1\r\n$4\r\nPING\r\n").await, b"+PONG\r\n");
+
+    // the client is served as usual once the last subscription ends
+    assert_eq!(
+        client
+            .request(b"*2\r\n$11\r\nUNSUBSCRIBE\r\n$2\r\nch\r\n")
+            .await,
+        UNSUBSCRIBED
+    );
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+
+    // the subscriptions without a channel are rejected
+    assert_eq!(
+        client.request(b"*1\r\n$9\r\nSUBSCRIBE\r\n").await,
+        b"-wrong number of arguments for command\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_subscription_limits() {
+    let reply = |kind: &str, channel: &[u8], left: usize| {
+        let channel = String::from_utf8_lossy(channel);
+        format!(
+            "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
+            kind.len(),
+            kind,
+            channel.len(),
+            channel,
+            left
+        )
+        .into_bytes()
+    };
+    let backend = spawn_backend(move |args| match args[0].as_slice() {
+        b"SUBSCRIBE" => Some(
+            args[1..]
+                .iter()
+                .enumerate()
+                .flat_map(|(i, channel)| reply("subscribe", channel, i + 1))
+                .collect(),
+        ),
+        b"UNSUBSCRIBE" => {
+            Some([reply("unsubscribe", b"a", 1), reply("unsubscribe", b"b", 0)].concat())
+        }
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.max_channels_per_conn = Some(2);
+        cc.max_subscriptions = Some(3);
+    });
+
+    let mut first = Client::connect(&proxy).await;
+    first
+        .requests
+        .write_all(b"*3\r\n$9\r\nSUBSCRIBE\r\n$1\r\na\r\n$1\r\nb\r\n")
+        .await
+        .unwrap();
+    assert_eq!(first.receive().await, reply("subscribe", b"a", 1));
+    assert_eq!(first.receive().await, reply("subscribe", b"b", 2));
+
+    // the connection can not subscribe to more channels than its own limit
+    assert_eq!(
+        first.request(b"*2\r\n$9\r\nSUBSCRIBE\r\n$1\r\nc\r\n").await,
+        b"-ERR max channels 2 of the connection reached\r\n"
+    );
+
+    // nor can the other connections subscribe beyond the limit of the cluster
+    let mut second = Client::connect(&proxy).await;
+    assert_eq!(
+        second
+            .request(b"*3\r\n$9\r\nSUBSCRIBE\r\n$1\r\nd\r\n$1\r\ne\r\n")
+            .await,
+        b"-ERR max subscriptions 3 of the cluster reached\r\n"
+    );
+    assert_eq!(
+        second
+            .request(b"*2\r\n$9\r\nSUBSCRIBE\r\n$1\r\nd\r\n")
+            .await,
+        reply("subscribe", b"d", 1)
+    );
+
+    // the channels are released once unsubscribed
+    first
+        .requests
+        .write_all(b"*1\r\n$11\r\nUNSUBSCRIBE\r\n")
+        .await
+        .unwrap();
+    assert_eq!(first.receive().await, reply("unsubscribe", b"a", 1));
+    assert_eq!(first.receive().await, reply("unsubscribe", b"b", 0));
+    assert_eq!(
+        second
+            .request(b"*2\r\n$9\r\nSUBSCRIBE\r\n$1\r\ne\r\n")
+            .await,
+        reply("subscribe", b"e", 1)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_subscribe_across_backends() {
+    let subscribed = |channel: &[u8]| {
+        let channel = String::from_utf8_lossy(channel);
+        format!(
+            "*3\r\n$9\r\nsubscribe\r\n${}\r\n{}\r\n:1\r\n",
+            channel.len(),
+            channel
+        )
+        .into_bytes()
+    };
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let backend = spawn_backend(move |args| match args[0].as_slice() {
+            b"SUBSCRIBE" => Some(subscribed(&args[1])),
+            _ => Some(b"+OK\r\n".to_vec()),
+        })
+        .await;
+        servers.push(format!("{}:1", backend));
+    }
+    let proxy = spawn_proxy(servers, |_| {});
+
+    // find two channels published to different backends
+    let mut client = Client::connect(&proxy).await;
+    let mut backends: HashMap<Vec<u8>, String> = HashMap::new();
+    for i in 0..20 {
+        let channel = format!("{}-channel", i);
+        let proxy_where = format!(
+            "*3\r\n$5\r\nPROXY\r\n$5\r\nWHERE\r\n${}\r\n{}\r\n",
+            channel.len(),
+            channel
+        );
+        let reply = String::from_utf8(client.request(proxy_where.as_bytes()).await).unwrap();
+        let backend = reply.lines().nth(1).unwrap().split(' ').next().unwrap();
+        backends
+            .entry(backend.as_bytes().to_vec())
+            .or_insert(channel);
+    }
+    let channels: Vec<String> = backends.into_values().collect();
+    assert_eq!(channels.len(), 2);
+    let subscribe = |channels: &[&String]| {
+        let mut req = format!("*{}\r\n$9\r\nSUBSCRIBE\r\n", channels.len() + 1);
+        for channel in channels {
+            req.push_str(&format!("${}\r\n{}\r\n", channel.len(), channel));
+        }
+        req.into_bytes()
+    };
+    let crossed = b"-CROSSSLOT Channels in request don't hash to the backend of the subscribed connection\r\n";
+
+    // the subscriptions to the channels of several backends are rejected
+    assert_eq!(
+        client
+            .request(&subscribe(&[&channels[0], &channels[1]]))
+            .await,
+        crossed
+    );
+
+    // as are the ones of a subscribed connection to the channels of another backend
+    assert_eq!(
+        client.request(&subscribe(&[&channels[0]])).await,
+        subscribed(channels[0].as_bytes())
+    );
+    assert_eq!(client.request(&subscribe(&[&channels[1]])).await, crossed);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_subscribe_auth_failure() {
+    let backend = spawn_backend(|args| match args[0].as_slice() {
+        b"AUTH" => Some(b"-WRONGPASS invalid username-password pair\r\n".to_vec()),
+        _ => Some(b"*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.auth = "secret".to_string();
+    });
+
+    // the session fails once its dedicated connection is rejected by the backend
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*2\r\n$9\r\nSUBSCRIBE\r\n$2\r\nch\r\n")
+            .await,
+        format!("-remote connection has been active closed: {}\r\n", backend).into_bytes()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_strip_command_prefix() {
+    // the backend replies with the command verb it received, the MGET keys are sent as GETs
+    let backend = spawn_backend(|args| {
+        let verb = String::from_utf8_lossy(&args[0]).to_string();
+        Some(format!("${}\r\n{}\r\n", verb.len(), verb).into_bytes())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.strip_command_prefix = Some("app.".to_string());
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$7\r\napp.GET\r\n$1\r\nk\r\n").await,
+        b"$3\r\nGET\r\n"
+    );
+    assert_eq!(
+        client
+            .request(b"*3\r\n$8\r\napp.mget\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .await,
+        b"*2\r\n$3\r\nGET\r\n$3\r\nGET\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$3\r\nGET\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_rename_commands() {
+    // the backend replies with the command verb it received
+    let backend = spawn_backend(|args| {
+        let verb = String::from_utf8_lossy(&args[0]).to_string();
+        Some(format!("${}\r\n{}\r\n", verb.len(), verb).into_bytes())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.rename_commands = HashMap::from([("GET".to_string(), "FETCH".to_string())]);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$5\r\nfetch\r\n$1\r\nk\r\n").await,
+        b"$3\r\nGET\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"-request not supported\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_info_overrides() {
+    let backend = spawn_backend(|args| {
+        if !args[0].eq_ignore_ascii_case(b"INFO") {
+            return Some(b"+OK\r\n".to_vec());
+        }
+        let info = "# Server\r\nredis_version:7.2.4\r\nos:Linux 6.1.0\r\ntcp_port:6379\r\n";
+        Some(format!("${}\r\n{}\r\n", info.len(), info).into_bytes())
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.info_overrides = HashMap::from([
+            ("redis_version".to_string(), "6.2.0".to_string()),
+            ("os".to_string(), String::new()),
+        ]);
+    });
+
+    // the overridden fields are replaced or hidden, the others are kept
+    let mut client = Client::connect(&proxy).await;
+    let info = "# Server\r\nredis_version:6.2.0\r\ntcp_port:6379\r\n";
+    let expected = format!("${}\r\n{}\r\n", info.len(), info).into_bytes();
+    assert_eq!(client.request(b"*1\r\n$4\r\nINFO\r\n").await, expected);
+    assert_eq!(
+        client
+            .request(b"*2\r\n$4\r\nINFO\r\n$6\r\nserver\r\n")
+            .await,
+        expected
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_empty_commands() {
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+    for empty in [&b"*0\r\n"[..], b"*-1\r\n"] {
+        let reply = client.request(empty).await;
+        assert!(reply.starts_with(b"-"), "reply {:?}", reply);
+        assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_unix_socket_backend() {
+    let path = spawn_unix_backend(|args| match args[0].as_slice() {
+        b"SET" => Some(b"+OK\r\n".to_vec()),
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+    let proxy = spawn_proxy(vec![format!("unix:{}:1 local", path)], |_| {});
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client
+            .request(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_listen_conflict_fails_only_its_cluster() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let cluster = |name: &str, listen_addr: &str| {
+        StandaloneCluster::<redis::Cmd>::new(ClusterConfig {
+            name: name.to_string(),
+            listen_addr: listen_addr.to_string(),
+            servers: vec![format!("{}:1", backend)],
+            ..Default::default()
+        })
+        .unwrap()
+    };
+
+    // the address is taken by a listener which does not share its port
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_addr = taken.local_addr().unwrap().to_string();
+    let err = cluster("conflicting", &taken_addr).run().unwrap_err();
+    assert_eq!(err.kind(), "IoError");
+
+    let free_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    cluster("bindable", &free_addr).run().unwrap();
+    let mut client = Client::connect(&free_addr).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"$1\r\nv\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_warming_reply() {
+    // nothing listens on the backend address, so the cluster never leaves the warmup
+    let down = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let proxy = spawn_proxy(vec![format!("{}:1", down)], |cc| {
+        cc.warming_reply = Some(WarmingReply::TryAgain);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(
+        client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await,
+        b"-TRYAGAIN cluster is connecting to the backends\r\n"
+    );
+
+    // the warmup ends once a backend is connected
+    let backend = spawn_backend(|_| Some(b"$1\r\nv\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.warming_reply = Some(WarmingReply::Loading);
+    });
+
+    let mut client = Client::connect(&proxy).await;
+    let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+    loop {
+        let reply = client.request(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await;
+        if reply == b"$1\r\nv\r\n" {
+            break;
+        }
+        assert_eq!(reply, b"-LOADING cluster is connecting to the backends\r\n");
+        assert!(Instant::now() < deadline, "cluster is still warming up");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_prewarm_connections() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+    let first = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let second = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let down = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    let cluster = |servers: Vec<&String>| {
+        StandaloneCluster::<redis::Cmd>::new(ClusterConfig {
+            name: "prewarm".to_string(),
+            servers: servers.into_iter().map(|x| format!("{}:1", x)).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    };
+
+    // the accept loop starts once all the connections of the ring are established
+    let ready = cluster(vec![&first, &second]);
+    assert!(ready.prewarm(2, TEST_REPLY_TIMEOUT).await);
+    assert_eq!(ready.connected(), 2);
+    assert!(ready
+        .ring
+        .get()
+        .inner
+        .values()
+        .all(|conn| conn.connected.load(Ordering::Relaxed)));
+    // the backend tasks block the test runtime until their cluster is gone
+    drop(ready);
+
+    // or once the timeout passes with the backends which are down
+    let partial = cluster(vec![&first, &down]);
+    let start = Instant::now();
+    assert!(!partial.prewarm(2, Duration::from_millis(200)).await);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(partial.connected(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_prewarm_waits_for_auth() {
+    const ROUND_TRIP: Duration = Duration::from_millis(300);
+    let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+
+    // the backend replies after a round trip, so the AUTH reply is only seen after one
+    let (backend, _) = spawn_delayed_backend(ROUND_TRIP, |args| match args[0].as_slice() {
+        b"AUTH" if args[1] == b"secret" => Some(b"+OK\r\n".to_vec()),
+        b"AUTH" => Some(b"-WRONGPASS invalid password\r\n".to_vec()),
+        _ => Some(b"$1\r\nv\r\n".to_vec()),
+    })
+    .await;
+
+    // the clients are accepted once the connection is authenticated, so the first command waits for
+    // the AUTH round trip and its own one
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.auth = "secret".to_string();
+        cc.prewarm_connections = Some(1);
+        cc.prewarm_timeout_ms = Some(TEST_REPLY_TIMEOUT.as_millis() as u64);
+    });
+    let start = Instant::now();
+    let mut client = Client::connect(&proxy).await;
+    assert_eq!(client.request(get).await, b"$1\r\nv\r\n");
+    assert!(start.elapsed() >= ROUND_TRIP * 2, "{:?}", start.elapsed());
+
+    // the rejected connection is never connected, its commands fail rather than being sent
+    // unauthenticated
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.auth = "wrong".to_string();
+        cc.prewarm_connections = Some(1);
+        cc.prewarm_timeout_ms = Some(ROUND_TRIP.as_millis() as u64 * 2);
+    });
+    let mut client = Client::connect(&proxy).await;
+    assert!(client.request(get).await.starts_with(b"-"));
+}
+
+#[test]
+fn test_load_balance_select() {
+    let mut ring = Ring::<redis::Cmd>::new();
+    ring.coordinates = HashRing::new(vec!["a".to_string(), "b".to_string()], vec![1, 3]).unwrap();
+    let ketama_node = ring.coordinates.get_node(12345).unwrap().to_string();
+    let mut outstanding = Vec::new();
+    for node in ["a", "b"] {
+        let count = Arc::new(AtomicUsize::new(0));
+        ring.insert_conn(Conn::new(
+            node,
+            vec![bounded(1).0],
+            vec![count.clone()],
+            Default::default(),
+            Default::default(),
+        ));
+        outstanding.push(count);
+    }
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut select = |balance: LoadBalance, hash: u64| {
+        ring.balance = balance;
+        ring.select(hash, &mut rng).unwrap().to_string()
+    };
+
+    // ketama follows the hash ring
+    assert_eq!(select(LoadBalance::Ketama, 12345), ketama_node);
+
+    // modulo gives each node a range of the total weight
+    let modulo: Vec<_> = (0..8).map(|h| select(LoadBalance::Modulo, h)).collect();
+    assert_eq!(modulo, ["a", "b", "b", "b", "a", "b", "b", "b"]);
+
+    // random draws the nodes in proportion to their weights regardless of the hash
+    let picked_b = (0..4000)
+        .filter(|_| select(LoadBalance::Random, 0) == "b")
+        .count();
+    assert!((2700..3300).contains(&picked_b), "{}", picked_b);
+
+    // round robin takes the nodes in turn regardless of the weights
+    let turns: Vec<_> = (0..4).map(|_| select(LoadBalance::RoundRobin, 0)).collect();
+    assert!(turns == ["a", "b", "a", "b"] || turns == ["b", "a", "b", "a"]);
+
+    // least connections picks the node with the fewest commands in flight and rotates the ties
+    outstanding[0].store(5, Ordering::Relaxed);
+    outstanding[1].store(1, Ordering::Relaxed);
+    assert_eq!(select(LoadBalance::LeastConnections, 0), "b");
+    assert_eq!(select(LoadBalance::LeastConnections, 0), "b");
+    outstanding[1].store(5, Ordering::Relaxed);
+    let ties: HashSet<_> = (0..2)
+        .map(|_| select(LoadBalance::LeastConnections, 0))
+        .collect();
+    assert_eq!(ties.len(), 2);
+
+    // the random draws follow the source of the connection, so they are reproduced by its seed
+    ring.balance = LoadBalance::Random;
+    let draws = |seed: u64| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..20)
+            .map(|_| ring.select(0, &mut rng).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(draws(1), draws(1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_accept_rate_limit() {
+    let backend = spawn_backend(|_| Some(b"+OK\r\n".to_vec())).await;
+    let proxy = spawn_proxy(vec![format!("{}:1", backend)], |cc| {
+        cc.max_accept_rate = Some(20);
+    });
+
+    // the first connection waits for the proxy to be up
+    let mut first = Client::connect(&proxy).await;
+    assert_eq!(first.request(b"*1\r\n$4\r\nPING\r\n").await, b"+PONG\r\n");
+
+    // 40 more connections at once exhaust the burst of 20, the rest is accepted at 20 per second
+    let start = Instant::now();
+    let clients = (0..40).map(|_| {
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let mut client = Client::connect(&proxy).await;
+            assert_eq!(client.request(b"*1\r\n$4\r\nPING\r\n").await, b"+PONG\r\n");
+        })
+    });
+    for client in clients.collect::<Vec<_>>() {
+        client.await.unwrap();
+    }
+    assert!(
+        start.elapsed() >= Duration::from_millis(900),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_stopped_cluster_closes_its_backends() {
+    init_test_instruments();
+    init_redis_supported_cmds();
+
+    // the backend counts its open connections
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    let open = Arc::new(AtomicUsize::new(0));
+    let counter = open.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                while !matches!(socket.read(&mut buf).await, Ok(0) | Err(_)) {}
+                counter.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    let wait_open = |count: usize| {
+        let open = open.clone();
+        async move {
+            let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+            while open.load(Ordering::SeqCst) != count && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(open.load(Ordering::SeqCst), count);
+        }
+    };
+    let cc = ClusterConfig {
+        name: "test-stopped-cluster".to_string(),
+        servers: vec![format!("{}:1", backend)],
+        ..Default::default()
+    };
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    let stopped = StandaloneCluster::<redis::Cmd>::new(cc.clone()).unwrap();
+    wait_open(1).await;
+
+    // the accept loop broke, so the cluster is stopped without a shutdown and then restarted
+    stopped.stop(addr).await;
+    wait_open(0).await;
+    let _restarted = StandaloneCluster::<redis::Cmd>::new(cc).unwrap();
+    wait_open(1).await;
+}
@@ -0,0 +1,188 @@
+use pin_project::pin_project;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixStream},
+    time,
+};
+use tokio_rustls::{client::TlsStream, rustls::ServerName, TlsConnector};
+
+use crate::{
+    com::{
+        config::{connect_from, get_host_by_name},
+        AsError,
+    },
+    proxy::TLS_HANDSHAKE_TIMEOUT,
+};
+
+// UNIX_SCHEME marks the backends reached through a unix domain socket, for example:
+// unix:/var/run/redis.sock:10 redis-1 where the weight and the alias are optional as usual
+pub const UNIX_SCHEME: &str = "unix:";
+
+// BackendAddr is the resolved address of a backend server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl BackendAddr {
+    // resolve resolves the address of the server line, the unix ones are taken as a socket path
+    pub fn resolve(node: &str) -> Result<BackendAddr, AsError> {
+        match node.strip_prefix(UNIX_SCHEME) {
+            Some("") => Err(AsError::BadConfig(format!("servers:{}", node))),
+            Some(path) => Ok(BackendAddr::Unix(PathBuf::from(path))),
+            None => get_host_by_name(node).map(BackendAddr::Tcp),
+        }
+    }
+
+    // resolve_blocking resolves the address on a blocking thread, as the resolution of its host blocks, so
+    // the other tasks of the worker awaiting it are not stalled
+    pub async fn resolve_blocking(node: &str) -> Result<BackendAddr, AsError> {
+        let node = node.to_string();
+        tokio::task::spawn_blocking(move || BackendAddr::resolve(&node))
+            .await
+            .map_err(io::Error::from)?
+    }
+
+    // connect connects to the backend, the tcp ones from the given local IP address if any
+    pub async fn connect(&self, source: Option<IpAddr>) -> Result<BackendStream, io::Error> {
+        match self {
+            BackendAddr::Tcp(addr) => connect_from(*addr, source).await.map(BackendStream::Tcp),
+            BackendAddr::Unix(path) => UnixStream::connect(path).await.map(BackendStream::Unix),
+        }
+    }
+}
+
+// tls_host returns the host of the server line the certificate of the backend must be issued for
+pub fn tls_host(node: &str) -> &str {
+    let host = node.rsplit_once(':').map_or(node, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl std::fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendAddr::Tcp(addr) => write!(f, "{}", addr),
+            BackendAddr::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+// BackendStream is the connection to a backend server, so the backend codecs and tasks are the same
+// whichever transport the server is reached through
+#[pin_project(project = BackendStreamProj)]
+pub enum BackendStream {
+    Tcp(#[pin] TcpStream),
+    Unix(#[pin] UnixStream),
+    // the TLS stream is boxed as its buffers make it much larger than the plain ones
+    Tls(#[pin] Box<TlsStream<TcpStream>>),
+}
+
+impl BackendStream {
+    // tls starts TLS over the tcp connection, verifying the certificate of the backend is issued for the
+    // host. The unix sockets never leave the host, so they are kept plain.
+    pub async fn tls(
+        self,
+        connector: &TlsConnector,
+        host: &str,
+    ) -> Result<BackendStream, io::Error> {
+        let stream = match self {
+            BackendStream::Tcp(stream) => stream,
+            stream => return Ok(stream),
+        };
+        let name = ServerName::try_from(host)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = time::timeout(TLS_HANDSHAKE_TIMEOUT, connector.connect(name, stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        Ok(BackendStream::Tls(Box::new(stream)))
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            BackendStreamProj::Tcp(stream) => stream.poll_read(cx, buf),
+            BackendStreamProj::Unix(stream) => stream.poll_read(cx, buf),
+            BackendStreamProj::Tls(stream) => stream.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            BackendStreamProj::Tcp(stream) => stream.poll_write(cx, buf),
+            BackendStreamProj::Unix(stream) => stream.poll_write(cx, buf),
+            BackendStreamProj::Tls(stream) => stream.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            BackendStreamProj::Tcp(stream) => stream.poll_flush(cx),
+            BackendStreamProj::Unix(stream) => stream.poll_flush(cx),
+            BackendStreamProj::Tls(stream) => stream.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            BackendStreamProj::Tcp(stream) => stream.poll_shutdown(cx),
+            BackendStreamProj::Unix(stream) => stream.poll_shutdown(cx),
+            BackendStreamProj::Tls(stream) => stream.poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_backend_addr() {
+        assert_eq!(
+            BackendAddr::resolve("unix:/var/run/redis.sock").unwrap(),
+            BackendAddr::Unix(PathBuf::from("/var/run/redis.sock"))
+        );
+        assert_eq!(
+            BackendAddr::resolve("127.0.0.1:6379").unwrap(),
+            BackendAddr::Tcp("127.0.0.1:6379".parse().unwrap())
+        );
+        assert!(BackendAddr::resolve("unix:").is_err());
+        assert_eq!(tls_host("redis.example.com:6380"), "redis.example.com");
+        assert_eq!(tls_host("[::1]:6380"), "::1");
+        assert_eq!(
+            BackendAddr::resolve("unix:/tmp/redis.sock")
+                .unwrap()
+                .to_string(),
+            "unix:/tmp/redis.sock"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_blocking() {
+        assert_eq!(
+            BackendAddr::resolve_blocking("127.0.0.1:6379")
+                .await
+                .unwrap(),
+            BackendAddr::Tcp("127.0.0.1:6379".parse().unwrap())
+        );
+        assert!(BackendAddr::resolve_blocking("unix:").await.is_err());
+    }
+}
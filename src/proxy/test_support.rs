@@ -0,0 +1,151 @@
+use futures::{channel::mpsc, StreamExt};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_util::codec::FramedRead;
+
+use crate::{
+    com::config::ClusterConfig,
+    metrics::init_test_instruments,
+    protocol::redis::{init_redis_supported_cmds, RedisHandleCodec, RedisNodeCodec},
+};
+
+// TEST_REPLY_TIMEOUT bounds the wait of the test clients for a reply of the proxy
+pub(crate) const TEST_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// spawn_proxy runs the cluster of the given config on a free local address once configured, and returns
+// the address the proxy listens on
+pub(crate) fn spawn_proxy<F, R>(mut cc: ClusterConfig, configure: F, run: R) -> String
+where
+    F: FnOnce(&mut ClusterConfig),
+    R: FnOnce(ClusterConfig),
+{
+    init_test_instruments();
+    init_redis_supported_cmds();
+
+    let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .unwrap()
+        .to_string();
+    cc.listen_addr = listen_addr.clone();
+    configure(&mut cc);
+    run(cc);
+    listen_addr
+}
+
+// tls_server_config returns the config of a backend served over TLS with a certificate self-signed for
+// 127.0.0.1, along with the path of the certificate the proxy verifies the backend with
+pub(crate) fn tls_server_config() -> (ServerConfig, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert_path = std::env::temp_dir().join(format!(
+        "repust-backend-{}-{}.crt",
+        std::process::id(),
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(cert.serialize_der().unwrap())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    (config, cert_path.to_string_lossy().to_string())
+}
+
+// serve_fake_backend replies to the requests of a connection of a fake redis backend with the handler
+// result, each written the given delay after its request is received to simulate the round trip of a
+// remote backend. The pipelined requests are delayed concurrently and still replied in order, and the
+// request is never replied if the handler returns None.
+pub(crate) async fn serve_fake_backend<S, F>(socket: S, delay: Duration, handler: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn(&[Vec<u8>]) -> Option<Vec<u8>>,
+{
+    let (read, mut write) = tokio::io::split(socket);
+    let (replies, mut delayed_replies) = mpsc::unbounded::<(Instant, Vec<u8>)>();
+    tokio::spawn(async move {
+        while let Some((reply_at, reply)) = delayed_replies.next().await {
+            tokio::time::sleep_until(reply_at.into()).await;
+            if write.write_all(&reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut requests = FramedRead::new(read, RedisHandleCodec::default());
+    while let Some(Ok(cmd)) = requests.next().await {
+        let args: Vec<Vec<u8>> = cmd.take_cmd().req().iter().map(|x| x.to_vec()).collect();
+        if let Some(reply) = handler(&args) {
+            if replies
+                .unbounded_send((Instant::now() + delay, reply))
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+// Client is a redis client of the proxy, sending the raw requests and returning the raw replies
+pub(crate) struct Client {
+    pub(crate) replies: FramedRead<OwnedReadHalf, RedisNodeCodec>,
+    pub(crate) requests: OwnedWriteHalf,
+}
+
+impl Client {
+    // connect retries until the proxy is up, or panics after TEST_REPLY_TIMEOUT
+    pub(crate) async fn connect(addr: &str) -> Client {
+        let deadline = Instant::now() + TEST_REPLY_TIMEOUT;
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(socket) => {
+                    let (read, write) = socket.into_split();
+                    return Client {
+                        replies: FramedRead::new(read, RedisNodeCodec::default()),
+                        requests: write,
+                    };
+                }
+                Err(err) if Instant::now() > deadline => panic!("proxy is not up: {}", err),
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    pub(crate) async fn request(&mut self, req: &[u8]) -> Vec<u8> {
+        self.requests.write_all(req).await.unwrap();
+        self.receive().await
+    }
+
+    // receive returns the next reply, e.g. a message pushed to a subscribed client
+    pub(crate) async fn receive(&mut self) -> Vec<u8> {
+        let reply = tokio::time::timeout(TEST_REPLY_TIMEOUT, self.replies.next())
+            .await
+            .expect("reply must be received in time")
+            .expect("connection must be open")
+            .unwrap();
+        reply.raw_data().to_vec()
+    }
+
+    // local_addr returns the address the proxy sees the client connected from
+    pub(crate) fn local_addr(&self) -> String {
+        self.requests.local_addr().unwrap().to_string()
+    }
+
+    // pipeline sends the request count times at once and returns the replies in order
+    pub(crate) async fn pipeline(&mut self, req: &[u8], count: usize) -> Vec<Vec<u8>> {
+        self.requests.write_all(&req.repeat(count)).await.unwrap();
+        let mut replies = Vec::with_capacity(count);
+        for _ in 0..count {
+            replies.push(self.receive().await);
+        }
+        replies
+    }
+}
@@ -1,4 +1,10 @@
-mod crc;
+pub mod bucket;
+// Path: src/utils/bucket.rs
+
+pub mod compress;
+// Path: src/utils/compress.rs
+
+pub mod crc;
 // Path: src/utils/crc.rs
 
 pub mod helper;
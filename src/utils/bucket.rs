@@ -0,0 +1,113 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// TokenBucket limits the rate of an operation to `rate` per second while allowing bursts of up to
+// `rate` operations at once.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: f64::from(rate.max(1)),
+            tokens: f64::from(rate.max(1)),
+            last: Instant::now(),
+        }
+    }
+
+    // reserve takes a token and returns how long the caller must wait before using it.
+    // The tokens can be borrowed from the future, so the concurrent callers are delayed one after another.
+    pub fn reserve(&mut self) -> Duration {
+        self.refill();
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    // try_take takes a token if one is available now, without borrowing from the future
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    // refill adds the tokens accumulated since the last call, up to the burst size
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+    }
+}
+
+// RetryBudget bounds the retries of a cluster per second, shared by all of its backend connections.
+// It prevents the retries from amplifying the load while the backends are already struggling.
+pub struct RetryBudget {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RetryBudget {
+    pub fn new(per_sec: u32) -> RetryBudget {
+        RetryBudget {
+            bucket: Mutex::new(TokenBucket::new(per_sec)),
+        }
+    }
+
+    // acquire returns true if a retry is allowed, false if the budget is exhausted
+    pub fn acquire(&self) -> bool {
+        self.bucket
+            .lock()
+            .expect("retry budget lock should not be poisoned")
+            .try_take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve_bounds_rate() {
+        let mut bucket = TokenBucket::new(10);
+
+        // the burst is served at once
+        for _ in 0..10 {
+            assert_eq!(bucket.reserve(), Duration::ZERO);
+        }
+
+        // the excess is spread at the rate
+        let first = bucket.reserve();
+        let second = bucket.reserve();
+        assert!(first > Duration::from_millis(90) && first <= Duration::from_millis(100));
+        assert!(second > Duration::from_millis(190) && second <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_retry_budget_exhausted() {
+        let budget = RetryBudget::new(3);
+
+        for _ in 0..3 {
+            assert!(budget.acquire());
+        }
+        assert!(!budget.acquire());
+        assert!(!budget.acquire());
+
+        // the budget is refilled at its rate
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(budget.acquire());
+        assert!(!budget.acquire());
+    }
+}
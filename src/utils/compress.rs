@@ -0,0 +1,39 @@
+// VALUE_COMPRESSION_MAGIC prefixes the values compressed by the proxy so they can be told apart from the
+// values written by the clients. It starts with a NUL byte which is unusual in the textual values.
+const VALUE_COMPRESSION_MAGIC: &[u8] = b"\x00RPZ1";
+
+// compress_value compresses the value with LZ4 and prefixes it with the magic bytes.
+// It returns None if the compressed form is not smaller than the value.
+pub fn compress_value(value: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::compress_prepend_size(value);
+    if VALUE_COMPRESSION_MAGIC.len() + compressed.len() >= value.len() {
+        return None;
+    }
+    Some([VALUE_COMPRESSION_MAGIC, &compressed].concat())
+}
+
+// decompress_value restores the value compressed by compress_value.
+// It returns None if the value is not compressed by the proxy or is corrupted.
+pub fn decompress_value(value: &[u8]) -> Option<Vec<u8>> {
+    let compressed = value.strip_prefix(VALUE_COMPRESSION_MAGIC)?;
+    lz4_flex::decompress_size_prepended(compressed).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let value = "repust ".repeat(200).into_bytes();
+        let compressed = compress_value(&value).unwrap();
+        assert!(compressed.len() < value.len());
+        assert!(compressed.starts_with(VALUE_COMPRESSION_MAGIC));
+        assert_eq!(decompress_value(&compressed).unwrap(), value);
+
+        // values which do not shrink are kept as is, the plain values are not decompressed
+        assert_eq!(compress_value(b"short"), None);
+        assert_eq!(decompress_value(&value), None);
+        assert_eq!(decompress_value(b"\x00RPZ1garbage"), None);
+    }
+}
@@ -32,30 +32,33 @@ pub(crate) fn itoa(input: usize, buf: &mut BytesMut) {
     buf.extend_from_slice(value.as_bytes());
 }
 
-/// Trims a hash tag from a key.
+/// Trims a hash tag from a key following the Redis hash tag algorithm.
+///
+/// The tag is the part between the first opening character and the first closing character after it.
+/// The whole key is used if there is no opening character, no closing character after it or the tag is empty.
 ///
 /// # Arguments
 ///
 /// * `key` - The key to trim the hash tag from.
-/// * `hash_tag` - The hash tag to trim.
+/// * `hash_tag` - The opening and closing characters of the hash tag, e.g. `{}`.
 ///
 /// # Returns
 ///
-/// * A byte slice representing the key with the hash tag trimmed.
+/// * A byte slice representing the hash tag of the key, or the whole key if it has no hash tag.
 #[inline]
 pub(crate) fn trim_hash_tag<'a>(key: &'a [u8], hash_tag: &[u8]) -> &'a [u8] {
     if hash_tag.len() != 2 {
         return key;
     }
-    if let Some(begin) = key.iter().position(|x| *x == hash_tag[0]) {
-        if let Some(end_offset) = key[begin..].iter().position(|x| *x == hash_tag[1]) {
-            // to avoid abc{}de
-            if end_offset > 1 {
-                return &key[begin + 1..begin + end_offset];
-            }
-        }
+    let begin = match key.iter().position(|x| *x == hash_tag[0]) {
+        Some(begin) => begin + 1,
+        None => return key,
+    };
+    match key[begin..].iter().position(|x| *x == hash_tag[1]) {
+        // to avoid abc{}de
+        Some(len) if len > 0 => &key[begin..begin + len],
+        _ => key,
     }
-    key
 }
 
 // get_runtime_handle returns the current runtime handle from tokio.
@@ -120,4 +123,51 @@ mod tests {
         );
         buf.clear();
     }
+
+    #[test]
+    fn test_trim_hash_tag_redis_cases() {
+        let tag = b"{}";
+        assert_eq!(trim_hash_tag(b"{user1000}.following", tag), b"user1000");
+        assert_eq!(trim_hash_tag(b"{user1000}.followers", tag), b"user1000");
+        assert_eq!(trim_hash_tag(b"foo{}{bar}", tag), b"foo{}{bar}");
+        assert_eq!(trim_hash_tag(b"foo{{bar}}zap", tag), b"{bar");
+        assert_eq!(trim_hash_tag(b"foo{bar}{zap}", tag), b"bar");
+        assert_eq!(trim_hash_tag(b"{{a}}", tag), b"{a");
+        assert_eq!(trim_hash_tag(b"{}", tag), b"{}");
+        assert_eq!(trim_hash_tag(b"abc", tag), b"abc");
+        assert_eq!(trim_hash_tag(b"abc{", tag), b"abc{");
+        assert_eq!(trim_hash_tag(b"abc}def{", tag), b"abc}def{");
+        assert_eq!(trim_hash_tag(b"}{a}", tag), b"a");
+        assert_eq!(
+            trim_hash_tag("κλειδί{ταγ}".as_bytes(), tag),
+            "ταγ".as_bytes()
+        );
+        assert_eq!(trim_hash_tag(b"", tag), b"");
+
+        // no hash tag configured
+        assert_eq!(
+            trim_hash_tag(b"{user1000}.following", b""),
+            b"{user1000}.following"
+        );
+    }
+
+    #[test]
+    fn test_trim_hash_tag_custom_delimiters() {
+        assert_eq!(trim_hash_tag(b"[tag]key", b"[]"), b"tag");
+        assert_eq!(trim_hash_tag(b"key[tag]", b"[]"), b"tag");
+        assert_eq!(trim_hash_tag(b"{tag}key", b"[]"), b"{tag}key");
+        assert_eq!(trim_hash_tag(b"]tag[key", b"[]"), b"]tag[key");
+    }
+
+    #[test]
+    fn test_trim_hash_tag_same_delimiters() {
+        // the closing character is searched after the opening one
+        assert_eq!(trim_hash_tag(b"|tag|key", b"||"), b"tag");
+        assert_eq!(trim_hash_tag(b"a$tag$b", b"$$"), b"tag");
+        assert_eq!(trim_hash_tag(b"a$tag$b$c", b"$$"), b"tag");
+
+        // the first tag of ||tag|| is empty, so the whole key is used like {}tag{}
+        assert_eq!(trim_hash_tag(b"||tag||", b"||"), b"||tag||");
+        assert_eq!(trim_hash_tag(b"a$$b", b"$$"), b"a$$b");
+    }
 }